@@ -24,7 +24,10 @@
 use std::sync::{Arc, RwLock};
 use std::thread;
 
-use core::core::{Transaction, Output};
+use core::consensus;
+use core::core::{Block, BlockHeader, Transaction, Output};
+use core::core::hash::Hashed;
+use core::global;
 use core::ser;
 use chain::{self, Tip};
 use pool;
@@ -55,6 +58,15 @@ impl ApiEndpoint for ChainApi {
 	}
 }
 
+/// An unspent output along with the height of the block that created it.
+#[derive(Serialize, Deserialize)]
+pub struct Utxo {
+	/// The output itself
+	pub output: Output,
+	/// Height of the block that created the output
+	pub height: u64,
+}
+
 /// ApiEndpoint implementation for outputs that have been included in the chain.
 #[derive(Clone)]
 pub struct OutputApi {
@@ -64,7 +76,7 @@ pub struct OutputApi {
 
 impl ApiEndpoint for OutputApi {
 	type ID = String;
-	type T = Output;
+	type T = Utxo;
 	type OP_IN = ();
 	type OP_OUT = ();
 
@@ -72,15 +84,112 @@ impl ApiEndpoint for OutputApi {
 		vec![Operation::Get]
 	}
 
-	fn get(&self, id: String) -> ApiResult<Output> {
+	fn get(&self, id: String) -> ApiResult<Utxo> {
 		debug!("GET output {}", id);
 		let c = util::from_hex(id.clone()).map_err(|_| Error::Argument(format!("Not a valid commitment: {}", id)))?;
+		let commit = Commitment::from_vec(c);
+
+		let output = self.chain.get_unspent(&commit).ok_or(Error::NotFound)?;
+		let height = self.chain
+			.get_output_height(&commit)
+			.map_err(|e| Error::Internal(format!("{:?}", e)))?;
+		Ok(Utxo {
+			output: output,
+			height: height,
+		})
+	}
+}
+
+/// Proof that an unspent output is included in the output sum tree of the
+/// chain head.
+#[derive(Serialize, Deserialize)]
+pub struct OutputProof {
+	/// Hex encoded commitment of the output
+	pub commit: String,
+	/// Hex encoded root of the output sum tree the proof verifies against
+	pub utxo_root: String,
+	/// Hex encoded serialized Merkle proof
+	pub proof: String,
+}
+
+/// ApiEndpoint implementation for the Merkle proofs of unspent outputs, so
+/// their inclusion in the chain state can be checked against the UTXO root
+/// alone.
+#[derive(Clone)]
+pub struct OutputProofApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for OutputProofApi {
+	type ID = String;
+	type T = OutputProof;
+	type OP_IN = ();
+	type OP_OUT = ();
 
-    match self.chain.get_unspent(&Commitment::from_vec(c)) {
-      Some(utxo) => Ok(utxo),
-      None => Err(Error::NotFound),
-    }
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
 
+	fn get(&self, id: String) -> ApiResult<OutputProof> {
+		debug!("GET output proof {}", id);
+		let c = util::from_hex(id.clone()).map_err(|_| Error::Argument(format!("Not a valid commitment: {}", id)))?;
+		let (proof, root) = match self.chain.get_unspent_proof(&Commitment::from_vec(c)) {
+			Ok(x) => x,
+			Err(chain::Error::StoreErr(e)) => return Err(Error::from(e)),
+			Err(e) => return Err(Error::Internal(format!("{:?}", e))),
+		};
+		let proof_bin = ser::ser_vec(&proof).map_err(|e| Error::Internal(format!("{:?}", e)))?;
+		Ok(OutputProof {
+			commit: id,
+			utxo_root: util::to_hex(root.to_vec()),
+			proof: util::to_hex(proof_bin),
+		})
+	}
+}
+
+/// Outputs created by the block at the provided height that are still
+/// unspent.
+#[derive(Serialize, Deserialize)]
+pub struct BlockOutputs {
+	/// Height of the block
+	pub height: u64,
+	/// Unspent outputs of the block
+	pub outputs: Vec<Output>,
+}
+
+/// ApiEndpoint implementation listing the unspent outputs of the block at a
+/// given height on the main chain. Lets wallets find their outputs again by
+/// scanning the chain.
+#[derive(Clone)]
+pub struct BlockOutputsApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+}
+
+impl ApiEndpoint for BlockOutputsApi {
+	type ID = u64;
+	type T = BlockOutputs;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, height: u64) -> ApiResult<BlockOutputs> {
+		debug!("GET outputs at height {}", height);
+		let header = self.chain.get_header_by_height(height).map_err(|_| Error::NotFound)?;
+		let block = self.chain.get_block(&header.hash()).map_err(|_| Error::NotFound)?;
+		let outputs = block.outputs
+			.iter()
+			.filter(|out| self.chain.get_unspent(&out.commitment()).is_some())
+			.cloned()
+			.collect();
+		Ok(BlockOutputs {
+			height: height,
+			outputs: outputs,
+		})
 	}
 }
 
@@ -134,12 +243,182 @@ impl<T> ApiEndpoint for PoolApi<T>
 		debug!("Pushing transaction with {} inputs and {} outputs to pool.",
 		       tx.inputs.len(),
 		       tx.outputs.len());
-		self.tx_pool
-			.write()
+		let res = self.tx_pool.write().unwrap().add_to_memory_pool(source, tx);
+		pool::TransactionPool::notify_accepted(&self.tx_pool);
+		match res {
+			// held until it can be mined, it'll make it to the pool then
+			Ok(_) | Err(pool::PoolError::TimeLocked { .. }) => Ok(()),
+			Err(e) => Err(Error::Internal(format!("Addition to transaction pool failed: {:?}", e))),
+		}
+	}
+}
+
+/// Whether an output is created and whether it is spent by transactions
+/// currently in the pool.
+#[derive(Serialize, Deserialize)]
+pub struct PoolOutput {
+	/// A transaction in the pool creates the output
+	pub created: bool,
+	/// A transaction in the pool spends the output
+	pub spent: bool,
+}
+
+/// ApiEndpoint implementation looking up an output, designated by its
+/// commitment, in the transaction pool. Lets wallets know about their
+/// transactions before they get mined.
+#[derive(Clone)]
+pub struct PoolOutputApi<T> {
+	tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+}
+
+impl<T> ApiEndpoint for PoolOutputApi<T>
+    where T: pool::BlockChain + Clone + Send + Sync + 'static
+{
+	type ID = String;
+	type T = PoolOutput;
+	type OP_IN = ();
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get]
+	}
+
+	fn get(&self, id: String) -> ApiResult<PoolOutput> {
+		debug!("GET pool output {}", id);
+		let c = util::from_hex(id.clone()).map_err(|_| Error::Argument(format!("Not a valid commitment: {}", id)))?;
+		let commit = Commitment::from_vec(c);
+
+		let pool = self.tx_pool.read().unwrap();
+		Ok(PoolOutput {
+			created: pool.is_output_created(&commit),
+			spent: pool.is_output_spent(&commit),
+		})
+	}
+}
+
+/// Builds the candidate blocks handed out to external miners. Implemented by
+/// the server, which knows how to claim the block reward.
+pub trait MiningAdapter: Send + Sync {
+	/// Builds a new block on top of the chain head with transactions from the
+	/// pool, returning it along with the transactions it includes.
+	fn build_block_template(&self) -> Result<(Block, Vec<Transaction>), String>;
+}
+
+/// A candidate block for external miners to work on.
+#[derive(Serialize, Deserialize)]
+pub struct BlockTemplate {
+	/// Height of the candidate block
+	pub height: u64,
+	/// Hex encoded serialized header of the candidate block, whose nonce and
+	/// proof of work are for the miner to set
+	pub header: String,
+	/// Hex encoded serialized transactions included in the block
+	pub transactions: Vec<String>,
+	/// Difficulty the proof of work has to reach
+	pub difficulty: u64,
+}
+
+/// A block template header solved by an external miner.
+#[derive(Serialize, Deserialize)]
+pub struct SolvedHeader {
+	/// Hex encoded serialized header, including the nonce and proof of work
+	pub header: String,
+}
+
+/// Maximum number of block templates kept around for the current head,
+/// waiting for a solution.
+const MAX_MINING_TEMPLATES: usize = 20;
+
+/// ApiEndpoint implementation for external miners. Hands out block templates
+/// built on the chain head and takes their solutions back, adding the solved
+/// blocks to the chain.
+#[derive(Clone)]
+pub struct MiningApi {
+	/// data store access
+	chain: Arc<chain::Chain>,
+	adapter: Arc<MiningAdapter>,
+	/// templates handed out for the current head, oldest first
+	templates: Arc<RwLock<Vec<Block>>>,
+}
+
+impl ApiEndpoint for MiningApi {
+	type ID = String;
+	type T = BlockTemplate;
+	type OP_IN = SolvedHeader;
+	type OP_OUT = ();
+
+	fn operations(&self) -> Vec<Operation> {
+		vec![Operation::Get, Operation::Custom("submit".to_string())]
+	}
+
+	fn get(&self, id: String) -> ApiResult<BlockTemplate> {
+		if id != "template" {
+			return Err(Error::NotFound);
+		}
+		let (b, txs) = self.adapter.build_block_template().map_err(|e| Error::Internal(e))?;
+		let header_bin = ser::ser_vec(&b.header).map_err(|e| Error::Internal(format!("{:?}", e)))?;
+		let mut txs_hex = vec![];
+		for tx in &txs {
+			let tx_bin = ser::ser_vec(tx).map_err(|e| Error::Internal(format!("{:?}", e)))?;
+			txs_hex.push(util::to_hex(tx_bin));
+		}
+		debug!("Built block template at height {} with {} transactions",
+		       b.header.height,
+		       txs.len());
+
+		let template = BlockTemplate {
+			height: b.header.height,
+			header: util::to_hex(header_bin),
+			transactions: txs_hex,
+			difficulty: b.header.difficulty.into_num(),
+		};
+
+		// templates built on an older head can't be mined anymore and only
+		// the most recent ones are kept
+		let mut templates = self.templates.write().unwrap();
+		templates.retain(|t| {
+			t.header.previous == b.header.previous && t.header.tx_merkle != b.header.tx_merkle
+		});
+		templates.push(b);
+		if templates.len() > MAX_MINING_TEMPLATES {
+			let excess = templates.len() - MAX_MINING_TEMPLATES;
+			templates.drain(..excess);
+		}
+		Ok(template)
+	}
+
+	fn operation(&self, _: String, input: SolvedHeader) -> ApiResult<()> {
+		let header_bin = util::from_hex(input.header)
+			.map_err(|_| Error::Argument(format!("Invalid hex in solved header.")))?;
+		let header: BlockHeader = ser::deserialize(&mut &header_bin[..]).map_err(|_| {
+				Error::Argument("Could not deserialize header, invalid format.".to_string())
+			})?;
+
+		let template = match self.templates
+			.read()
 			.unwrap()
-			.add_to_memory_pool(source, tx)
-			.map_err(|e| Error::Internal(format!("Addition to transaction pool failed: {:?}", e)))?;
-		Ok(())
+			.iter()
+			.find(|t| t.header.tx_merkle == header.tx_merkle) {
+			Some(t) => t.clone(),
+			None => return Err(Error::Argument("Unknown or stale block template.".to_string())),
+		};
+		let b = Block {
+			header: header,
+			inputs: template.inputs,
+			outputs: template.outputs,
+			kernels: template.kernels,
+		};
+		debug!("Submitting solved block {} at height {}", b.hash(), b.header.height);
+
+		let opts = if global::sizeshift() < consensus::DEFAULT_SIZESHIFT {
+			chain::EASY_POW
+		} else {
+			chain::NONE
+		};
+		self.chain
+			.process_block(b, opts)
+			.map(|_| ())
+			.map_err(|e| Error::Argument(format!("Invalid block: {:?}", e)))
 	}
 }
 
@@ -153,7 +432,8 @@ pub struct TxWrapper {
 /// instance and runs the corresponding HTTP server.
 pub fn start_rest_apis<T>(addr: String,
                           chain: Arc<chain::Chain>,
-                          tx_pool: Arc<RwLock<pool::TransactionPool<T>>>)
+                          tx_pool: Arc<RwLock<pool::TransactionPool<T>>>,
+                          mining_adapter: Arc<MiningAdapter>)
 	where T: pool::BlockChain + Clone + Send + Sync + 'static
 {
 
@@ -165,10 +445,102 @@ pub fn start_rest_apis<T>(addr: String,
 		                       OutputApi {
 			                       chain: chain.clone(),
 		                       });
-		apis.register_endpoint("/pool".to_string(), PoolApi { tx_pool: tx_pool });
+		apis.register_endpoint("/chain/utxos".to_string(),
+		                       BlockOutputsApi {
+			                       chain: chain.clone(),
+		                       });
+		apis.register_endpoint("/chain/utxos/proof".to_string(),
+		                       OutputProofApi {
+			                       chain: chain.clone(),
+		                       });
+		apis.register_endpoint("/pool".to_string(),
+		                       PoolApi {
+			                       tx_pool: tx_pool.clone(),
+		                       });
+		apis.register_endpoint("/pool/output".to_string(),
+		                       PoolOutputApi {
+			                       tx_pool: tx_pool,
+		                       });
+		apis.register_endpoint("/mining".to_string(),
+		                       MiningApi {
+			                       chain: chain.clone(),
+			                       adapter: mining_adapter,
+			                       templates: Arc::new(RwLock::new(vec![])),
+		                       });
 
 		apis.start(&addr[..]).unwrap_or_else(|e| {
 			error!("Failed to start API HTTP server: {}.", e);
 		});
 	});
 }
+
+#[cfg(test)]
+mod test {
+	use std::fs;
+
+	use chain::types::NoopAdapter;
+	use core::core::target::Difficulty;
+	use core::global::MiningParameterMode;
+	use core::pow::{self, cuckoo, MiningWorker};
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+	use super::*;
+
+	// Builds block templates on the chain head the way the server does,
+	// burning the reward.
+	struct TestMiningAdapter {
+		chain: Arc<chain::Chain>,
+	}
+
+	impl MiningAdapter for TestMiningAdapter {
+		fn build_block_template(&self) -> Result<(Block, Vec<Transaction>), String> {
+			let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+			let head = self.chain.head_header().map_err(|e| format!("{:?}", e))?;
+			let reward_key = SecretKey::from_slice(&secp, &[1; 32]).unwrap();
+			let mut b = Block::new(&head, vec![], reward_key).map_err(|e| format!("{:?}", e))?;
+			b.header.difficulty = consensus::next_difficulty(self.chain.difficulty_iter())
+				.map_err(|e| format!("{:?}", e))?;
+			self.chain.set_sumtree_roots(&mut b).map_err(|e| format!("{:?}", e))?;
+			Ok((b, vec![]))
+		}
+	}
+
+	// Gets a template, solves it and submits the solution, which has to end
+	// up as the new chain head.
+	#[test]
+	fn mining_template_submit() {
+		let _ = fs::remove_dir_all(".grin_mining");
+		global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+		let chain = Arc::new(chain::Chain::init(".grin_mining".to_string(), Arc::new(NoopAdapter {}))
+			.unwrap());
+		let api = MiningApi {
+			chain: chain.clone(),
+			adapter: Arc::new(TestMiningAdapter { chain: chain.clone() }),
+			templates: Arc::new(RwLock::new(vec![])),
+		};
+
+		let template = api.get("template".to_string()).unwrap();
+		assert_eq!(template.height, 1);
+		let header_bin = util::from_hex(template.header).unwrap();
+		let mut header: BlockHeader = ser::deserialize(&mut &header_bin[..]).unwrap();
+
+		let mut miner = cuckoo::Miner::new(consensus::EASINESS,
+		                                   global::sizeshift() as u32,
+		                                   global::proofsize());
+		pow::pow_size(&mut miner,
+		              &mut header,
+		              Difficulty::from_num(template.difficulty),
+		              global::sizeshift() as u32)
+			.unwrap();
+		let solved_hex = util::to_hex(ser::ser_vec(&header).unwrap());
+
+		api.operation("submit".to_string(), SolvedHeader { header: solved_hex.clone() }).unwrap();
+		let head = chain.head().unwrap();
+		assert_eq!(head.height, 1);
+		assert_eq!(head.last_block_h, header.hash());
+
+		// the same block can't be added twice
+		assert!(api.operation("submit".to_string(), SolvedHeader { header: solved_hex }).is_err());
+	}
+}
@@ -33,5 +33,6 @@ pub mod client;
 mod endpoints;
 mod rest;
 
-pub use endpoints::start_rest_apis;
+pub use endpoints::{start_rest_apis, BlockOutputs, BlockTemplate, MiningAdapter, PoolOutput,
+                    SolvedHeader, Utxo};
 pub use rest::*;
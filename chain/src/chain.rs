@@ -15,36 +15,113 @@
 //! Facade and handler for the rest of the blockchain implementation
 //! and mostly the chain pipeline.
 
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use secp::pedersen::Commitment;
 
 use core::core::{Block, BlockHeader, Output};
 use core::core::target::Difficulty;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
+use core::core::pmmr::{MerkleProof, NoSum};
+use core::core::snapshot::SumTreeSnapshot;
 use core::{consensus, genesis, pow};
 use core::pow::MiningWorker;
 use grin_store;
 use pipe;
 use store;
+use sumtree::{self, SumTrees};
 use types::*;
 
 use core::global;
 use core::global::{MiningParameterMode,MINING_PARAMETER_MODE};
 
-const MAX_ORPHANS: usize = 20;
-
-/// Helper macro to transform a Result into an Option with None in case
-/// of error
-macro_rules! none_err {
-  ($trying:expr) => {{
-    let tried = $trying;
-    if let Err(_) = tried {
-      return None;
-    }
-    tried.unwrap()
-  }}
+/// Maximum number of orphan headers we keep around, waiting for their parent
+const MAX_ORPHANS: usize = 100;
+
+/// How long an orphan header waits for its parent before being dropped
+const ORPHAN_EXPIRY_SECS: u64 = 600;
+
+/// An orphan block reduced to its header, as its body will be requested again
+/// once its parent is known.
+struct OrphanHeader {
+	header: BlockHeader,
+	opts: Options,
+	added: Instant,
+}
+
+/// Headers of the blocks we received before their parent, indexed by the hash
+/// of that parent. Bounded in size, the oldest headers being evicted first,
+/// and expiring after a while.
+struct OrphanHeaderPool {
+	orphans: HashMap<Hash, Vec<OrphanHeader>>,
+	count: usize,
+}
+
+impl OrphanHeaderPool {
+	fn new() -> OrphanHeaderPool {
+		OrphanHeaderPool {
+			orphans: HashMap::new(),
+			count: 0,
+		}
+	}
+
+	/// Adds the header of an orphan block, unless we already have it.
+	fn add(&mut self, header: BlockHeader, opts: Options) {
+		self.expire();
+		let hash = header.hash();
+		if let Some(siblings) = self.orphans.get(&header.previous) {
+			if siblings.iter().any(|o| o.header.hash() == hash) {
+				return;
+			}
+		}
+		if self.count >= MAX_ORPHANS {
+			self.evict_oldest();
+		}
+		self.orphans.entry(header.previous).or_insert(vec![]).push(OrphanHeader {
+			header: header,
+			opts: opts,
+			added: Instant::now(),
+		});
+		self.count += 1;
+	}
+
+	/// Removes and returns all the orphan headers building on the provided
+	/// parent.
+	fn remove_children(&mut self, parent: &Hash) -> Vec<OrphanHeader> {
+		let children = self.orphans.remove(parent).unwrap_or(vec![]);
+		self.count -= children.len();
+		children
+	}
+
+	/// Drops the orphan headers that have been waiting for too long.
+	fn expire(&mut self) {
+		let expiry = Duration::from_secs(ORPHAN_EXPIRY_SECS);
+		for siblings in self.orphans.values_mut() {
+			siblings.retain(|o| o.added.elapsed() < expiry);
+		}
+		self.orphans.retain(|_, siblings| !siblings.is_empty());
+		self.count = self.orphans.values().map(|siblings| siblings.len()).sum();
+	}
+
+	fn evict_oldest(&mut self) {
+		let oldest = self.orphans
+			.iter()
+			.flat_map(|(prev, siblings)| siblings.iter().map(move |o| (*prev, o.added)))
+			.min_by_key(|&(_, added)| added);
+		if let Some((prev, added)) = oldest {
+			if let Some(siblings) = self.orphans.get_mut(&prev) {
+				if let Some(idx) = siblings.iter().position(|o| o.added == added) {
+					siblings.remove(idx);
+					self.count -= 1;
+				}
+			}
+			if self.orphans.get(&prev).map(|s| s.is_empty()).unwrap_or(false) {
+				self.orphans.remove(&prev);
+			}
+		}
+	}
 }
 
 /// Facade to the blockchain block processing pipeline and storage. Provides
@@ -56,7 +133,8 @@ pub struct Chain {
 
 	head: Arc<Mutex<Tip>>,
 	block_process_lock: Arc<Mutex<bool>>,
-	orphans: Arc<Mutex<VecDeque<(Options, Block)>>>,
+	orphans: Arc<Mutex<OrphanHeaderPool>>,
+	sumtrees: Arc<RwLock<SumTrees>>,
 }
 
 unsafe impl Sync for Chain {}
@@ -72,7 +150,8 @@ impl Chain {
 		db_root: String,
 		adapter: Arc<ChainAdapter>,
 	) -> Result<Chain, Error> {
-		let chain_store = store::ChainKVStore::new(db_root)?;
+		let chain_store = Arc::new(store::ChainKVStore::new(db_root.clone())?);
+		let mut sumtrees = SumTrees::open(db_root, chain_store.clone())?;
 
 		// check if we have a head in store, otherwise the genesis block is it
 		let head = match chain_store.head() {
@@ -88,6 +167,10 @@ impl Chain {
 				let mut internal_miner = pow::cuckoo::Miner::new(consensus::EASINESS, sz as u32, proof_size); 
 				pow::pow_size(&mut internal_miner, &mut gen.header, diff, sz as u32).unwrap();
 				chain_store.save_block(&gen)?;
+				chain_store.apply_block(&gen)?;
+				chain_store.index_sumtrees(&gen, 0, 0)?;
+				sumtrees.apply_block(&gen)?;
+				sumtrees.sync()?;
 
 				// saving a new tip based on genesis
 				let tip = Tip::new(gen.hash());
@@ -102,21 +185,26 @@ impl Chain {
 		// let head = chain_store.head()?;
 
 		Ok(Chain {
-			store: Arc::new(chain_store),
+			store: chain_store,
 			adapter: adapter,
 			head: Arc::new(Mutex::new(head)),
 			block_process_lock: Arc::new(Mutex::new(true)),
-			orphans: Arc::new(Mutex::new(VecDeque::with_capacity(MAX_ORPHANS + 1))),
+			orphans: Arc::new(Mutex::new(OrphanHeaderPool::new())),
+			sumtrees: Arc::new(RwLock::new(sumtrees)),
 		})
 	}
 
 	/// Attempt to add a new block to the chain. Returns the new chain tip if it
 	/// has been added to the longest chain, None if it's added to an (as of
-	/// now) orphan chain.
+	/// now) orphan chain. When the block's parent is missing, only its header
+	/// is kept and the Orphan error carries the hash of the parent to fetch,
+	/// the block itself having to be requested again once its header connects
+	/// (see connect_orphans).
 	pub fn process_block(&self, b: Block, opts: Options) -> Result<Option<Tip>, Error> {
 
 		let head = self.store.head().map_err(&Error::StoreErr)?;
 		let ctx = self.ctx_from_head(head, opts);
+		let pow_opts = ctx.opts;
 
 		let res = pipe::process_block(&b, ctx);
 
@@ -128,13 +216,18 @@ impl Chain {
 					let mut head = chain_head.lock().unwrap();
 					*head = tip.clone();
 				}
-
-				self.check_orphans();
+				self.adapter.head_updated(tip);
 			}
-			Err(Error::Orphan) => {
-				let mut orphans = self.orphans.lock().unwrap();
-				orphans.push_front((opts, b));
-				orphans.truncate(MAX_ORPHANS);
+			Err(Error::Orphan(_)) => {
+				// the header's proof of work is all we can check for now, without
+				// it anyone could fill the orphans with junk
+				if !pow_opts.intersects(SKIP_POW) &&
+					b.header.difficulty < Difficulty::from_num(consensus::MINIMUM_DIFFICULTY)
+				{
+					return Err(Error::DifficultyTooLow);
+				}
+				pipe::verify_pow(&b.header, pow_opts)?;
+				self.orphans.lock().unwrap().add(b.header, opts);
 			}
 			_ => {}
 		}
@@ -171,61 +264,219 @@ impl Chain {
 			adapter: self.adapter.clone(),
 			head: head,
 			lock: self.block_process_lock.clone(),
+			sumtrees: self.sumtrees.clone(),
 		}
 	}
 
-    /// Pop orphans out of the queue and check if we can now accept them.
-	fn check_orphans(&self) {
-		// first check how many we have to retry, unfort. we can't extend the lock
-		// in the loop as it needs to be freed before going in process_block
-		let orphan_count;
-		{
-			let orphans = self.orphans.lock().unwrap();
-			orphan_count = orphans.len();
-		}
-
-		// pop each orphan and retry, if still orphaned, will be pushed again
-		for _ in 0..orphan_count {
-			let popped;
-			{
-				let mut orphans = self.orphans.lock().unwrap();
-				popped = orphans.pop_back();
-			}
-			if let Some((opts, orphan)) = popped {
-				let _process_result = self.process_block(orphan, opts);
+	/// Takes the orphan headers building on the provided block, which has just
+	/// been accepted, and adds the ones that validate to the header chain.
+	/// Returns the hashes of those, whose full blocks can now be requested.
+	pub fn connect_orphans(&self, h: &Hash) -> Vec<Hash> {
+		let children = self.orphans.lock().unwrap().remove_children(h);
+		let mut connected = vec![];
+		for orphan in children {
+			let hash = orphan.header.hash();
+			match self.process_block_header(&orphan.header, orphan.opts) {
+				Ok(_) => connected.push(hash),
+				// the header may have been known already while its full block
+				// wasn't, when syncing
+				Err(Error::Unfit(_)) => {
+					if self.store.get_block(&hash).is_err() {
+						connected.push(hash);
+					}
+				}
+				Err(e) => {
+					debug!("Orphan header {} refused after its parent connected: {:?}", hash, e);
+				}
 			}
 		}
+		connected
 	}
 
 	/// Gets an unspent output from its commitment. With return None if the
-	/// output
-	/// doesn't exist or has been spent. This querying is done in a way that's
-	/// constistent with the current chain state and more specifically the
-	/// current
-	/// branch it is on in case of forks.
+	/// output doesn't exist or has been spent. This querying is done in a way
+	/// that's consistent with the current chain state and more specifically
+	/// the current branch it is on in case of forks.
 	pub fn get_unspent(&self, output_ref: &Commitment) -> Option<Output> {
-		// TODO use an actual UTXO tree
-		// in the meantime doing it the *very* expensive way:
-		//   1. check the output exists
-		//   2. run the chain back from the head to check it hasn't been spent
-		if let Ok(out) = self.store.get_output_by_commit(output_ref) {
-			let head = none_err!(self.store.head());
-			let mut block_h = head.last_block_h;
-			loop {
-				let b = none_err!(self.store.get_block(&block_h));
-				for input in b.inputs {
-					if input.commitment() == *output_ref {
-						return None;
-					}
-				}
-				if b.header.height == 1 {
-					return Some(out);
-				} else {
-					block_h = b.header.previous;
+		self.store.get_unspent(output_ref).ok()
+	}
+
+	/// Builds a proof that an unspent output is included in the output sum
+	/// tree at the current head, returned along with the root of that tree.
+	pub fn get_unspent_proof(
+		&self,
+		output_ref: &Commitment,
+	) -> Result<(MerkleProof<NoSum<Output>>, Hash), Error> {
+		// the head can't move while we're at it
+		let sumtrees = self.sumtrees.read().unwrap();
+		self.store.get_unspent(output_ref)?;
+		sumtrees.output_proof(output_ref)
+	}
+
+	/// Sets the sum tree roots in the header of a block that's being built on
+	/// top of the chain, as committed to once the block is applied.
+	pub fn set_sumtree_roots(&self, b: &mut Block) -> Result<(), Error> {
+		let head = self.store.head().map_err(&Error::StoreErr)?;
+		let ctx = self.ctx_from_head(head, NONE);
+		pipe::set_sumtree_roots(b, ctx)
+	}
+
+	/// Builds a snapshot of the chain state at the provided block, which has
+	/// to be on the current main chain. The sum trees are temporarily rewound
+	/// to that block, so the further back it is, the more expensive.
+	pub fn sumtree_snapshot(&self, h: &Hash) -> Result<SumTreeSnapshot, Error> {
+		let header = self.store.get_block_header(h)?;
+		let mut sumtrees = self.sumtrees.write().unwrap();
+		let head = self.store.head()?;
+		let on_main_chain = match self.store.get_header_by_height(header.height) {
+			Ok(main_header) => main_header.hash() == *h && header.height <= head.height,
+			Err(_) => false,
+		};
+		if !on_main_chain {
+			return Err(Error::Other(format!("block {} not on the main chain", h)));
+		}
+		if header.height < self.store.get_pruned_height()? {
+			return Err(Error::Other(format!("block {} below the pruned height", h)));
+		}
+
+		let mut rewound = vec![];
+		let mut current = head.last_block_h;
+		while current != *h {
+			let b = self.store.get_block(&current)?;
+			current = b.header.previous;
+			rewound.push(b);
+		}
+		// the trees have to be brought back to the head whatever happens, or
+		// no block could be added anymore
+		let mut res = Ok(());
+		let mut n_rewound = 0;
+		for b in &rewound {
+			if let Err(e) = sumtrees.rewind_block(b) {
+				res = Err(e);
+				break;
+			}
+			n_rewound += 1;
+		}
+		let snapshot = res.and_then(|_| self.snapshot_at(h, &rewound, &sumtrees));
+		let mut restored = Ok(());
+		for b in rewound[..n_rewound].iter().rev() {
+			if let Err(e) = sumtrees.apply_block(b) {
+				error!("Could not re-apply block {} to the sum trees: {:?}", b.hash(), e);
+				if restored.is_ok() {
+					restored = Err(e);
 				}
 			}
 		}
-		None
+		restored?;
+		snapshot
+	}
+
+	// Collects the UTXO set and kernels making up a snapshot, the sum trees
+	// being already rewound to the block and the provided blocks being the ones
+	// from the head down to it.
+	fn snapshot_at(
+		&self,
+		h: &Hash,
+		rewound: &[Block],
+		sumtrees: &SumTrees,
+	) -> Result<SumTreeSnapshot, Error> {
+		let mut utxos = HashMap::new();
+		for out in self.store.utxos()? {
+			utxos.insert(out.commitment(), out);
+		}
+		for b in rewound {
+			for out in &b.outputs {
+				utxos.remove(&out.commitment());
+			}
+			for input in &b.inputs {
+				let out = self.store.get_output_by_commit(&input.commitment())?;
+				utxos.insert(input.commitment(), out);
+			}
+		}
+		let mut outputs = vec![];
+		for (commit, out) in utxos {
+			let height = self.store.get_output_height(&commit)?;
+			outputs.push((self.store.get_output_pos(&commit)?, height, out));
+		}
+		outputs.sort_by_key(|&(pos, _, _)| pos);
+		let output_heights = outputs.iter().map(|&(_, height, _)| height).collect();
+		let outputs = outputs.into_iter().map(|(pos, _, out)| (pos, out)).collect();
+
+		let (_, kernel_leaves) = sumtrees.leaves();
+		let mut kernels = vec![];
+		for n in 0..kernel_leaves {
+			kernels.push(self.store.get_kernel(n)?);
+		}
+		Ok(sumtrees.snapshot(*h, outputs, output_heights, kernels))
+	}
+
+	/// Installs a snapshot of the chain state taken at a block we already have
+	/// the header of, after fully validating it. The block becomes the new
+	/// head and the chain can be extended from it without having any of the
+	/// blocks before it. Only possible on a brand new chain.
+	pub fn install_snapshot(&self, snapshot: SumTreeSnapshot) -> Result<(), Error> {
+		let header = self.store.get_block_header(&snapshot.block_hash)?;
+		sumtree::validate_snapshot(&header, &snapshot)?;
+
+		let _lock = self.block_process_lock.lock().unwrap();
+		let mut sumtrees = self.sumtrees.write().unwrap();
+		let head = self.store.head()?;
+		if head.height > 0 {
+			return Err(Error::Unfit("chain not new, can't install snapshot".to_string()));
+		}
+
+		sumtrees.install(&snapshot)?;
+		self.store.save_snapshot(&snapshot)?;
+		self.store.setup_height(&header)?;
+		let tip = Tip::from_block(&header);
+		self.store.save_body_head(&tip)?;
+		*self.head.lock().unwrap() = tip;
+		info!(
+			"Installed snapshot at {} at {} with {} unspent outputs.",
+			header.hash(),
+			header.height,
+			snapshot.outputs.len()
+		);
+		Ok(())
+	}
+
+	/// Cut-through pruning of the main chain. For all blocks that went beyond
+	/// the cut-through horizon since the last run, the outputs they spend are
+	/// removed from storage along with their range proofs. Kernels and headers
+	/// are all kept, as well as the sum trees which unspent outputs still need
+	/// to be proven against. The chain can't be reorganized below the pruned
+	/// height anymore.
+	pub fn compact(&self) -> Result<(), Error> {
+		// keeps the head from moving while we prune
+		let _sumtrees = self.sumtrees.write().unwrap();
+
+		let head = self.store.head()?;
+		let horizon = global::cut_through_horizon() as u64;
+		if head.height <= horizon {
+			return Ok(());
+		}
+		let pruned_height = self.store.get_pruned_height()?;
+		let target_height = head.height - horizon;
+		for height in (pruned_height + 1)..(target_height + 1) {
+			let header = self.store.get_header_by_height(height)?;
+			let b = match self.store.get_block(&header.hash()) {
+				Ok(b) => b,
+				// nothing to prune before a snapshot
+				Err(grin_store::Error::NotFoundErr) => continue,
+				Err(e) => return Err(Error::StoreErr(e)),
+			};
+			self.store.prune_spent(&b)?;
+		}
+		if target_height > pruned_height {
+			self.store.save_pruned_height(target_height)?;
+			debug!("Pruned spent outputs up to height {}.", target_height);
+		}
+		Ok(())
+	}
+
+	/// Height up to which blocks on the main chain have been pruned.
+	pub fn pruned_height(&self) -> Result<u64, Error> {
+		self.store.get_pruned_height().map_err(&Error::StoreErr)
 	}
 
 	/// Total difficulty at the head of the chain
@@ -253,6 +504,19 @@ impl Chain {
 		self.store.get_block_header(h).map_err(&Error::StoreErr)
 	}
 
+	/// Gets the header of the block on the main chain that created the
+	/// provided output.
+	pub fn get_block_header_by_output_commit(&self, commit: &Commitment) -> Result<BlockHeader, Error> {
+		let bh = self.store.get_output_block(commit).map_err(&Error::StoreErr)?;
+		self.store.get_block_header(&bh).map_err(&Error::StoreErr)
+	}
+
+	/// Gets the height of the block that created the provided output, also
+	/// known for outputs installed from a snapshot, which have no block.
+	pub fn get_output_height(&self, commit: &Commitment) -> Result<u64, Error> {
+		self.store.get_output_height(commit).map_err(&Error::StoreErr)
+	}
+
 	/// Gets the block header at the provided height
 	pub fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
 		self.store.get_header_by_height(height).map_err(
@@ -14,17 +14,20 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
 
 use secp;
 use time;
 
 use core::consensus;
 use core::core::hash::{Hash, Hashed};
-use core::core::{BlockHeader, Block};
+use core::core::{BlockHeader, Block, Output, COINBASE_OUTPUT};
 use core::pow;
 use types::*;
+use grin_store;
 use store;
+use sumtree::SumTrees;
 use core::global;
 
 /// Contextual information required to process a new block and either reject or
@@ -36,6 +39,8 @@ pub struct BlockContext {
 	pub store: Arc<ChainStore>,
 	/// The adapter
 	pub adapter: Arc<ChainAdapter>,
+	/// The sum trees, in the state of the head
+	pub sumtrees: Arc<RwLock<SumTrees>>,
 	/// The head
 	pub head: Tip,
 	/// The lock
@@ -46,9 +51,6 @@ pub struct BlockContext {
 /// place for the new block in the chain. Returns the new
 /// chain head if updated.
 pub fn process_block(b: &Block, mut ctx: BlockContext) -> Result<Option<Tip>, Error> {
-	// TODO should just take a promise for a block with a full header so we don't
-	// spend resources reading the full block when its header is invalid
-
 	info!(
 		"Starting validation pipeline for block {} at {} with {} inputs and {} outputs.",
 		b.hash(),
@@ -58,9 +60,13 @@ pub fn process_block(b: &Block, mut ctx: BlockContext) -> Result<Option<Tip>, Er
 	);
 	check_known(b.hash(), &mut ctx)?;
 
+	// everything that can be rejected on the header alone is, before looking
+	// at the block body
 	if !ctx.opts.intersects(SYNC) {
 		// in sync mode, the header has already been validated
 		validate_header(&b.header, &mut ctx)?;
+	} else if b.header.height > ctx.head.height + 1 {
+		return Err(Error::Orphan(b.header.previous));
 	}
 	validate_block(b, &mut ctx)?;
 	debug!(
@@ -69,7 +75,13 @@ pub fn process_block(b: &Block, mut ctx: BlockContext) -> Result<Option<Tip>, Er
 		b.hash()
 	);
 
-	let _ = ctx.lock.lock().unwrap();
+	// only one block gets added at a time, the head it's compared against has
+	// to be read again as another block may have moved it since validation,
+	// changing what the inputs of this one spend
+	let lock = ctx.lock.clone();
+	let _lock = lock.lock().unwrap();
+	ctx.head = ctx.store.head().map_err(&Error::StoreErr)?;
+	validate_inputs(b, &mut ctx)?;
 	add_block(b, &mut ctx)?;
 	update_head(b, &mut ctx)
 }
@@ -86,7 +98,9 @@ pub fn process_block_header(bh: &BlockHeader, mut ctx: BlockContext) -> Result<O
 	validate_header(&bh, &mut ctx)?;
 	add_block_header(bh, &mut ctx)?;
 
-	let _ = ctx.lock.lock().unwrap();
+	let lock = ctx.lock.clone();
+	let _lock = lock.lock().unwrap();
+	ctx.head = ctx.store.get_header_head().map_err(&Error::StoreErr)?;
 	update_header_head(bh, &mut ctx)
 }
 
@@ -113,12 +127,14 @@ fn check_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
 /// TODO require only the block header (with length information)
 fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
 	if header.height > ctx.head.height + 1 {
-		return Err(Error::Orphan);
+		return Err(Error::Orphan(header.previous));
 	}
 
-	let prev = try!(ctx.store.get_block_header(&header.previous).map_err(
-		&Error::StoreErr,
-	));
+	let prev = match ctx.store.get_block_header(&header.previous) {
+		Ok(prev) => prev,
+		Err(grin_store::Error::NotFoundErr) => return Err(Error::Orphan(header.previous)),
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
 
 	if header.height != prev.height + 1 {
 		return Err(Error::InvalidBlockHeight);
@@ -151,46 +167,124 @@ fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), E
 			return Err(Error::DifficultyTooLow);
 		}
 
-		let cycle_size = if ctx.opts.intersects(EASY_POW) {
-			global::sizeshift()
-		} else {
-			consensus::DEFAULT_SIZESHIFT
-		};
-		debug!("Validating block with cuckoo size {}", cycle_size);
-		if !pow::verify_size(header, cycle_size as u32) {
-			return Err(Error::InvalidPow);
-		}
+		verify_pow(header, ctx.opts)?;
 	}
 
 	Ok(())
 }
 
-/// Fully validate the block content.
+/// Checks the proof of work of a header against the header's own difficulty,
+/// which doesn't require knowing its parent.
+pub fn verify_pow(header: &BlockHeader, opts: Options) -> Result<(), Error> {
+	if opts.intersects(SKIP_POW) {
+		return Ok(());
+	}
+	let cycle_size = if opts.intersects(EASY_POW) {
+		global::sizeshift()
+	} else {
+		consensus::DEFAULT_SIZESHIFT
+	};
+	debug!("Validating block with cuckoo size {}", cycle_size);
+	if !pow::verify_size(header, cycle_size as u32) {
+		return Err(Error::InvalidPow);
+	}
+	Ok(())
+}
+
+/// Validates the block content on its own, its inputs are checked against
+/// the chain state separately, once no other block can change it.
 fn validate_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
 	if b.header.height > ctx.head.height + 1 {
-		return Err(Error::Orphan);
+		return Err(Error::Orphan(b.header.previous));
+	}
+
+	// kernels can't be included before their lock height
+	for k in &b.kernels {
+		if k.lock_height > b.header.height {
+			return Err(Error::LockedKernel(k.lock_height));
+		}
 	}
 
 	let curve = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 	try!(b.validate(&curve).map_err(&Error::InvalidBlockProof));
+	Ok(())
+}
+
+/// Checks that every input in the block spends an output that exists and is
+/// still unspent in the chain state at the block's parent, and that spent
+/// coinbase outputs have matured. The parent isn't necessarily the head, so
+/// the blocks between the fork point and both the head and the parent are
+/// taken into account on top of the UTXO index.
+fn validate_inputs(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
+	let mut spent = HashSet::new();
+	for input in &b.inputs {
+		if !spent.insert(input.commitment()) {
+			return Err(Error::DoubleSpend(input.commitment()));
+		}
+	}
 
-	// TODO check every input exists as a UTXO using the UTXO index
+	let (rewound, applied) = if b.header.previous == ctx.head.last_block_h {
+		(vec![], vec![])
+	} else {
+		fork_blocks(&b.header, ctx)?
+	};
 
+	for input in &b.inputs {
+		let commit = input.commitment();
+
+		// blocks on the branch of the new block, more recent first
+		if applied.iter().any(|ab| ab.inputs.iter().any(|i| i.commitment() == commit)) {
+			return Err(Error::DoubleSpend(commit));
+		}
+		if let Some(ab) = applied.iter().find(|ab| ab.outputs.iter().any(|o| o.commitment() == commit)) {
+			let out = ab.outputs.iter().find(|o| o.commitment() == commit).unwrap();
+			check_maturity(b, out, ab.header.height)?;
+			continue;
+		}
+
+		// blocks on the branch of the head, not part of the new block branch
+		if rewound.iter().any(|rb| rb.outputs.iter().any(|o| o.commitment() == commit)) {
+			return Err(Error::UnknownInput(commit));
+		}
+		let rewound_spent = rewound.iter().any(|rb| rb.inputs.iter().any(|i| i.commitment() == commit));
+
+		if !rewound_spent && !ctx.store.is_unspent(&commit).map_err(&Error::StoreErr)? {
+			return Err(match ctx.store.get_output_by_commit(&commit) {
+				Ok(_) => Error::DoubleSpend(commit),
+				Err(_) => Error::UnknownInput(commit),
+			});
+		}
+
+		// the output was created below the fork point, outputs coming from a
+		// snapshot don't have a block but the snapshot provided their height,
+		// without one maturity can't be proven
+		let out = ctx.store.get_output_by_commit(&commit).map_err(&Error::StoreErr)?;
+		if out.features.intersects(COINBASE_OUTPUT) {
+			match ctx.store.get_output_height(&commit) {
+				Ok(height) => check_maturity(b, &out, height)?,
+				Err(grin_store::Error::NotFoundErr) => return Err(Error::ImmatureCoinbase(commit)),
+				Err(e) => return Err(Error::StoreErr(e)),
+			}
+		}
+	}
 	Ok(())
 }
 
-/// Officially adds the block to our chain.
-fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
-	ctx.store.save_block(b).map_err(&Error::StoreErr)?;
-
-	if !ctx.opts.intersects(SYNC) {
-		// broadcast the block
-		let adapter = ctx.adapter.clone();
-		adapter.block_accepted(b);
+/// Checks a coinbase output created at the provided height can be spent in
+/// the provided block.
+fn check_maturity(b: &Block, out: &Output, created_height: u64) -> Result<(), Error> {
+	if out.features.intersects(COINBASE_OUTPUT) &&
+	   b.header.height < created_height + global::coinbase_maturity() {
+		return Err(Error::ImmatureCoinbase(out.commitment()));
 	}
 	Ok(())
 }
 
+/// Officially adds the block to our chain.
+fn add_block(b: &Block, ctx: &mut BlockContext) -> Result<(), Error> {
+	ctx.store.save_block(b).map_err(&Error::StoreErr)
+}
+
 /// Officially adds the block header to our header chain.
 fn add_block_header(bh: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
 	ctx.store.save_block_header(bh).map_err(&Error::StoreErr)
@@ -205,6 +299,25 @@ fn update_head(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error>
 	let tip = Tip::from_block(&b.header);
 	if tip.total_difficulty > ctx.head.total_difficulty {
 
+		// the sum trees stay locked until the new head is saved, so they're
+		// never seen out of line with it
+		let sumtrees_lock = ctx.sumtrees.clone();
+		let mut sumtrees = sumtrees_lock.write().unwrap();
+
+		// bring the sum trees and the UTXO set in line with the new head,
+		// reorganizing the chain if the block isn't on the branch of the current
+		// head
+		let mut reorged = None;
+		if b.header.previous != ctx.head.last_block_h {
+			reorged = Some(reorg(b, &mut sumtrees, ctx)?);
+		} else {
+			let (output_leaves, kernel_leaves) = sumtrees.leaves();
+			apply_sumtrees(&[b], &mut sumtrees)?;
+			sumtrees.sync()?;
+			ctx.store.apply_block(b).map_err(&Error::StoreErr)?;
+			ctx.store.index_sumtrees(b, output_leaves, kernel_leaves).map_err(&Error::StoreErr)?;
+		}
+
 		// update the block height index
 		ctx.store.setup_height(&b.header).map_err(&Error::StoreErr)?;
 
@@ -218,12 +331,183 @@ fn update_head(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error>
 
 		ctx.head = tip.clone();
 		info!("Updated head to {} at {}.", b.hash(), b.header.height);
+
+		// only blocks moving the head are notified, once it has moved, and a
+		// block ending a reorg only as part of it
+		drop(sumtrees);
+		if !ctx.opts.intersects(SYNC) {
+			let adapter = ctx.adapter.clone();
+			match reorged {
+				Some((removed, added)) => adapter.chain_reorg(&removed, &added),
+				None => adapter.block_accepted(b),
+			}
+		}
 		Ok(Some(tip))
 	} else {
 		Ok(None)
 	}
 }
 
+/// Reorganizes the chain so the branch of the provided block becomes the main
+/// one. Finds the fork point with the branch of the current head, un-applies
+/// the blocks of the losing branch and applies the blocks of the winning
+/// branch, up to the provided block. Returns the blocks removed from and
+/// added to the main chain, the provided one last.
+fn reorg(
+	b: &Block,
+	sumtrees: &mut SumTrees,
+	ctx: &mut BlockContext,
+) -> Result<(Vec<Block>, Vec<Block>), Error> {
+	let (removed, mut added) = fork_blocks(&b.header, ctx)?;
+	added.reverse();
+	info!(
+		"Chain reorg to {} at {}, removing {} blocks and adding {}.",
+		b.hash(),
+		b.header.height,
+		removed.len(),
+		added.len() + 1
+	);
+
+	{
+		// the sum trees go first, as the roots committed to by every block of the
+		// winning branch need checking
+		for rb in &removed {
+			sumtrees.rewind_block(rb)?;
+		}
+		let (mut output_leaves, mut kernel_leaves) = sumtrees.leaves();
+		let mut to_apply: Vec<&Block> = added.iter().collect();
+		to_apply.push(b);
+		if let Err(e) = apply_sumtrees(&to_apply, sumtrees) {
+			// the losing branch stays the main one
+			for rb in removed.iter().rev() {
+				sumtrees.apply_block(rb)?;
+			}
+			return Err(e);
+		}
+		sumtrees.sync()?;
+
+		for rb in &removed {
+			ctx.store.rewind_block(rb).map_err(&Error::StoreErr)?;
+		}
+		for ab in to_apply {
+			ctx.store.apply_block(ab).map_err(&Error::StoreErr)?;
+			ctx.store.index_sumtrees(ab, output_leaves, kernel_leaves).map_err(&Error::StoreErr)?;
+			output_leaves += ab.outputs.len() as u64;
+			kernel_leaves += ab.kernels.len() as u64;
+		}
+	}
+
+	added.push(b.clone());
+	Ok((removed, added))
+}
+
+/// Applies the provided blocks to the sum trees in order, checking the roots
+/// committed to by each block header. In case of mismatch or of a spent output
+/// that can't be found, the sum trees are rewound to where they were.
+fn apply_sumtrees(blocks: &[&Block], sumtrees: &mut SumTrees) -> Result<(), Error> {
+	for (n, b) in blocks.iter().enumerate() {
+		if let Err(e) = sumtrees.apply_block(b) {
+			for applied in blocks[..n].iter().rev() {
+				sumtrees.rewind_block(applied)?;
+			}
+			return Err(e);
+		}
+		if let Err(e) = sumtrees.check_roots(&b.header) {
+			info!("Block {} at {} has invalid sum tree roots.", b.hash(), b.header.height);
+			for applied in blocks[..(n + 1)].iter().rev() {
+				sumtrees.rewind_block(applied)?;
+			}
+			return Err(e);
+		}
+	}
+	Ok(())
+}
+
+/// Sets the sum tree roots of the provided block header to the roots the sum
+/// trees have once the block is applied on top of its parent, which doesn't
+/// have to be the head. Leaves the sum trees untouched.
+pub fn set_sumtree_roots(b: &mut Block, mut ctx: BlockContext) -> Result<(), Error> {
+	let sumtrees_lock = ctx.sumtrees.clone();
+	let mut sumtrees = sumtrees_lock.write().unwrap();
+
+	// the head may have moved while waiting on the sum trees
+	ctx.head = ctx.store.head().map_err(&Error::StoreErr)?;
+	let (removed, added) = if b.header.previous == ctx.head.last_block_h {
+		(vec![], vec![])
+	} else {
+		fork_blocks(&b.header, &mut ctx)?
+	};
+
+	for rb in &removed {
+		sumtrees.rewind_block(rb)?;
+	}
+	for ab in added.iter().rev() {
+		sumtrees.apply_block(ab)?;
+	}
+	let res = sumtrees.apply_block(b);
+	if res.is_ok() {
+		sumtrees.set_roots(&mut b.header);
+		sumtrees.rewind_block(b)?;
+	}
+
+	// and back to the head
+	for ab in &added {
+		sumtrees.rewind_block(ab)?;
+	}
+	for rb in removed.iter().rev() {
+		sumtrees.apply_block(rb)?;
+	}
+	res
+}
+
+/// Finds the point where the branch of the provided header forks from the
+/// branch of the current head. Returns the blocks from the head down to the
+/// fork point and the blocks from the header's parent down to the fork point,
+/// both excluding the fork point itself. Forks below the pruned height can't
+/// be handled as the blocks there lost their spent outputs.
+fn fork_blocks(
+	header: &BlockHeader,
+	ctx: &mut BlockContext,
+) -> Result<(Vec<Block>, Vec<Block>), Error> {
+	let mut old_header = ctx.store
+		.get_block_header(&ctx.head.last_block_h)
+		.map_err(&Error::StoreErr)?;
+	let mut new_header = ctx.store.get_block_header(&header.previous).map_err(
+		&Error::StoreErr,
+	)?;
+
+	let pruned_height = ctx.store.get_pruned_height().map_err(&Error::StoreErr)?;
+	let mut rewound = vec![];
+	let mut applied = vec![];
+	while old_header.hash() != new_header.hash() {
+		if old_header.height >= new_header.height {
+			if old_header.height <= pruned_height {
+				return Err(Error::Unfit("fork below the pruned height".to_string()));
+			}
+			rewound.push(ctx.store.get_block(&old_header.hash()).map_err(
+				&Error::StoreErr,
+			)?);
+			old_header = ctx.store
+				.get_block_header(&old_header.previous)
+				.map_err(&Error::StoreErr)?;
+		} else {
+			applied.push(ctx.store.get_block(&new_header.hash()).map_err(
+				&Error::StoreErr,
+			)?);
+			new_header = ctx.store
+				.get_block_header(&new_header.previous)
+				.map_err(&Error::StoreErr)?;
+		}
+	}
+	debug!(
+		"Fork point for {} found at {} at {}.",
+		header.hash(),
+		new_header.hash(),
+		new_header.height
+	);
+	Ok((rewound, applied))
+}
+
 /// Directly updates the head if we've just appended a new block to it or handle
 /// the situation where we've just added enough work to have a fork with more
 /// work than the head.
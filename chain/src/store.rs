@@ -14,13 +14,16 @@
 
 //! Implements storage primitives required by the chain
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use secp::pedersen::Commitment;
 
 use types::*;
 use core::core::hash::{Hash, Hashed};
-use core::core::{Block, BlockHeader, Output};
+use core::core::{Block, BlockHeader, Output, TxKernel};
+use core::core::pmmr;
+use core::core::snapshot::SumTreeSnapshot;
 use core::consensus::TargetError;
 use core::core::target::Difficulty;
 use grin_store::{self, Error, to_key, u64_to_key, option_to_not_found};
@@ -32,7 +35,14 @@ const BLOCK_PREFIX: u8 = 'b' as u8;
 const HEAD_PREFIX: u8 = 'H' as u8;
 const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
 const HEADER_HEIGHT_PREFIX: u8 = '8' as u8;
+const PRUNED_HEIGHT_PREFIX: u8 = 'P' as u8;
 const OUTPUT_COMMIT_PREFIX: u8 = 'o' as u8;
+const UTXO_PREFIX: u8 = 'u' as u8;
+const OUTPUT_POS_PREFIX: u8 = 'p' as u8;
+const KERNEL_PREFIX: u8 = 'k' as u8;
+const OUTPUT_BLOCK_PREFIX: u8 = 'c' as u8;
+const OUTPUT_HEIGHT_PREFIX: u8 = 'e' as u8;
+const SUMTREE_LEAVES_PREFIX: u8 = 'l' as u8;
 
 /// An implementation of the ChainStore trait backed by a simple key-value
 /// store.
@@ -138,6 +148,183 @@ impl ChainStore for ChainKVStore {
 		)))
 	}
 
+	fn get_unspent(&self, commit: &Commitment) -> Result<Output, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(UTXO_PREFIX, &mut commit.as_ref().to_vec())))
+	}
+
+	fn is_unspent(&self, commit: &Commitment) -> Result<bool, Error> {
+		self.db.exists(&to_key(UTXO_PREFIX, &mut commit.as_ref().to_vec()))
+	}
+
+	fn apply_block(&self, b: &Block) -> Result<(), Error> {
+		// inputs leave the UTXO set, outputs join it, all in one batch
+		let mut batch = self.db.batch();
+		for input in &b.inputs {
+			let mut in_bytes = input.commitment().as_ref().to_vec();
+			batch = batch.delete(&to_key(UTXO_PREFIX, &mut in_bytes)[..])?;
+		}
+		for out in &b.outputs {
+			let mut out_bytes = out.commit.as_ref().to_vec();
+			batch = batch.put_ser(&to_key(UTXO_PREFIX, &mut out_bytes)[..], out)?;
+		}
+		batch.write()
+	}
+
+	fn rewind_block(&self, b: &Block) -> Result<(), Error> {
+		// the exact reverse of apply_block, spent outputs are recovered from the
+		// commitment index which is never pruned
+		let mut batch = self.db.batch();
+		let hash = b.hash();
+		for out in &b.outputs {
+			let mut out_bytes = out.commit.as_ref().to_vec();
+			batch = batch.delete(&to_key(UTXO_PREFIX, &mut out_bytes.clone())[..])?;
+			// the output only existed on the losing branch, it has no position in
+			// the sum trees anymore and no block creating it on the main chain
+			let created: Option<Hash> = self.db.get_ser(&to_key(
+				OUTPUT_BLOCK_PREFIX,
+				&mut out_bytes.clone(),
+			))?;
+			if created == Some(hash) {
+				batch = batch
+					.delete(&to_key(OUTPUT_POS_PREFIX, &mut out_bytes.clone())[..])?
+					.delete(&to_key(OUTPUT_BLOCK_PREFIX, &mut out_bytes)[..])?;
+			}
+		}
+		for input in &b.inputs {
+			let out = self.get_output_by_commit(&input.commitment())?;
+			let mut in_bytes = input.commitment().as_ref().to_vec();
+			batch = batch.put_ser(&to_key(UTXO_PREFIX, &mut in_bytes)[..], &out)?;
+		}
+		batch.write()
+	}
+
+	fn utxos(&self) -> Result<Vec<Output>, Error> {
+		Ok(self.db.iter(&to_key(UTXO_PREFIX, &mut vec![])).collect())
+	}
+
+	fn index_sumtrees(
+		&self,
+		b: &Block,
+		output_leaves: u64,
+		kernel_leaves: u64,
+	) -> Result<(), Error> {
+		let mut batch = self.db.batch();
+		for (n, out) in b.outputs.iter().enumerate() {
+			let pos = pmmr::size_from_leaves(output_leaves + n as u64) + 1;
+			let mut out_bytes = out.commit.as_ref().to_vec();
+			batch = batch
+				.put_ser(&to_key(OUTPUT_POS_PREFIX, &mut out_bytes.clone())[..], &pos)?
+				.put_ser(&to_key(OUTPUT_BLOCK_PREFIX, &mut out_bytes)[..], &b.hash())?;
+		}
+		for (n, kernel) in b.kernels.iter().enumerate() {
+			batch = batch.put_ser(&u64_to_key(KERNEL_PREFIX, kernel_leaves + n as u64), kernel)?;
+		}
+		let leaves = (
+			output_leaves + b.outputs.len() as u64,
+			kernel_leaves + b.kernels.len() as u64,
+		);
+		batch = batch.put_ser(&to_key(SUMTREE_LEAVES_PREFIX, &mut b.hash().to_vec())[..], &leaves)?;
+		batch.write()
+	}
+
+	fn get_sumtree_leaves(&self, h: &Hash) -> Result<(u64, u64), Error> {
+		option_to_not_found(self.db.get_ser(&to_key(SUMTREE_LEAVES_PREFIX, &mut h.to_vec())))
+	}
+
+	fn get_output_pos(&self, commit: &Commitment) -> Result<u64, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(
+			OUTPUT_POS_PREFIX,
+			&mut commit.as_ref().to_vec(),
+		)))
+	}
+
+	fn get_output_block(&self, commit: &Commitment) -> Result<Hash, Error> {
+		option_to_not_found(self.db.get_ser(&to_key(
+			OUTPUT_BLOCK_PREFIX,
+			&mut commit.as_ref().to_vec(),
+		)))
+	}
+
+	fn get_output_height(&self, commit: &Commitment) -> Result<u64, Error> {
+		match self.get_output_block(commit) {
+			Ok(bh) => Ok(self.get_block_header(&bh)?.height),
+			Err(Error::NotFoundErr) => option_to_not_found(self.db.get_ser(&to_key(
+				OUTPUT_HEIGHT_PREFIX,
+				&mut commit.as_ref().to_vec(),
+			))),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn get_kernel(&self, leaf_idx: u64) -> Result<TxKernel, Error> {
+		option_to_not_found(self.db.get_ser(&u64_to_key(KERNEL_PREFIX, leaf_idx)))
+	}
+
+	fn prune_spent(&self, b: &Block) -> Result<(), Error> {
+		// spent outputs leave all indexes, grouped by the block that created them
+		// to strip them from it next
+		let mut batch = self.db.batch();
+		let mut spent_by_block: HashMap<Hash, Vec<Commitment>> = HashMap::new();
+		for input in &b.inputs {
+			let commit = input.commitment();
+			let mut in_bytes = commit.as_ref().to_vec();
+			// outputs coming from a snapshot don't have a block
+			let created: Option<Hash> = self.db.get_ser(&to_key(
+				OUTPUT_BLOCK_PREFIX,
+				&mut in_bytes.clone(),
+			))?;
+			if let Some(bh) = created {
+				spent_by_block.entry(bh).or_insert(vec![]).push(commit);
+			}
+			batch = batch
+				.delete(&to_key(OUTPUT_COMMIT_PREFIX, &mut in_bytes.clone())[..])?
+				.delete(&to_key(OUTPUT_POS_PREFIX, &mut in_bytes.clone())[..])?
+				.delete(&to_key(OUTPUT_BLOCK_PREFIX, &mut in_bytes.clone())[..])?
+				.delete(&to_key(OUTPUT_HEIGHT_PREFIX, &mut in_bytes)[..])?;
+		}
+
+		for (bh, commits) in spent_by_block {
+			let mut cb = self.get_block(&bh)?;
+			cb.outputs.retain(|out| !commits.contains(&out.commitment()));
+			batch = batch.put_ser(&to_key(BLOCK_PREFIX, &mut bh.to_vec())[..], &cb)?;
+		}
+		batch.write()
+	}
+
+	fn get_pruned_height(&self) -> Result<u64, Error> {
+		let height: Option<u64> = self.db.get_ser(&vec![PRUNED_HEIGHT_PREFIX])?;
+		Ok(height.unwrap_or(0))
+	}
+
+	fn save_pruned_height(&self, height: u64) -> Result<(), Error> {
+		self.db.put_ser(&vec![PRUNED_HEIGHT_PREFIX], &height)
+	}
+
+	fn save_snapshot(&self, snapshot: &SumTreeSnapshot) -> Result<(), Error> {
+		let mut batch = self.db.batch();
+		// the blocks that created the outputs aren't known, only their height
+		for (&(pos, ref out), height) in snapshot.outputs.iter().zip(snapshot.output_heights.iter()) {
+			let mut out_bytes = out.commit.as_ref().to_vec();
+			batch = batch
+				.put_ser(&to_key(UTXO_PREFIX, &mut out_bytes.clone())[..], out)?
+				.put_ser(&to_key(OUTPUT_COMMIT_PREFIX, &mut out_bytes.clone())[..], out)?
+				.put_ser(&to_key(OUTPUT_POS_PREFIX, &mut out_bytes.clone())[..], &pos)?
+				.put_ser(&to_key(OUTPUT_HEIGHT_PREFIX, &mut out_bytes)[..], height)?;
+		}
+		for (n, kernel) in snapshot.kernels.iter().enumerate() {
+			batch = batch.put_ser(&u64_to_key(KERNEL_PREFIX, n as u64), kernel)?;
+		}
+		let leaves = (
+			pmmr::n_leaves(snapshot.output_mmr_size),
+			snapshot.kernels.len() as u64,
+		);
+		batch = batch.put_ser(
+			&to_key(SUMTREE_LEAVES_PREFIX, &mut snapshot.block_hash.to_vec())[..],
+			&leaves,
+		)?;
+		batch.write()
+	}
+
 	fn setup_height(&self, bh: &BlockHeader) -> Result<(), Error> {
 		self.db.put_ser(
 			&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height),
@@ -147,8 +334,14 @@ impl ChainStore for ChainKVStore {
 		let mut prev_h = bh.previous;
 		let mut prev_height = bh.height - 1;
 		while prev_height > 0 {
-			let prev = self.get_header_by_height(prev_height)?;
-			if prev.hash() != prev_h {
+			// heights may not have been indexed yet at all, as when starting from a
+			// snapshot
+			let indexed = match self.get_header_by_height(prev_height) {
+				Ok(prev) => prev.hash() == prev_h,
+				Err(Error::NotFoundErr) => false,
+				Err(e) => return Err(e),
+			};
+			if !indexed {
 				let real_prev = self.get_block_header(&prev_h)?;
 				self.db.put_ser(
 					&u64_to_key(HEADER_HEIGHT_PREFIX, real_prev.height),
@@ -0,0 +1,551 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utility structs to handle the 3 sumtrees (output, range proof, kernel)
+//! the block headers commit to, more conveniently and together.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs;
+use std::sync::Arc;
+
+use secp::{self, Secp256k1};
+use secp::pedersen::{Commitment, RangeProof};
+
+use core::consensus::REWARD;
+use core::core::{Block, BlockHeader, Output, TxKernel};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::pmmr::{self, Backend, HashSum, MerkleProof, NoSum, NullSum, Summable, PMMR};
+use core::core::snapshot::SumTreeSnapshot;
+use core::ser::Writeable;
+use grin_store;
+use grin_store::sumtree::{PMMRBackend, RemoveLog};
+use types::{ChainStore, Error};
+
+const SUMTREES_SUBDIR: &'static str = "sumtrees";
+const OUTPUT_SUBDIR: &'static str = "output";
+const RANGE_PROOF_SUBDIR: &'static str = "rangeproof";
+const KERNEL_SUBDIR: &'static str = "kernel";
+const PMMR_SPENT_FILE: &'static str = "pmmr_spent.bin";
+
+// A PMMR backend along with the size of the tree it holds and the leaves
+// that have been spent.
+struct PMMRHandle<T> where T: Summable {
+	backend: PMMRBackend<T>,
+	last_pos: u64,
+	// spent leaves, only removed from the tree once they aren't needed to
+	// prove the unspent ones anymore
+	spent: RemoveLog,
+}
+
+impl<T> PMMRHandle<T> where T: Summable + Writeable + ::std::fmt::Debug + Clone {
+	fn new(root_dir: String, subdir: &str) -> Result<PMMRHandle<T>, Error> {
+		let path = format!("{}/{}/{}", root_dir, SUMTREES_SUBDIR, subdir);
+		fs::create_dir_all(path.clone()).map_err(|e| Error::SumTreeErr(e.to_string()))?;
+		let backend = PMMRBackend::new(path.clone()).map_err(|e| Error::SumTreeErr(e.to_string()))?;
+		let spent = RemoveLog::open(format!("{}/{}", path, PMMR_SPENT_FILE))
+			.map_err(|e| Error::SumTreeErr(e.to_string()))?;
+		let last_pos = backend.unpruned_size();
+		Ok(PMMRHandle {
+			backend: backend,
+			last_pos: last_pos,
+			spent: spent,
+		})
+	}
+
+	fn push(&mut self, elmts: Vec<T>) {
+		let mut pmmr = PMMR::at(&self.backend, self.last_pos);
+		for elmt in elmts {
+			pmmr.push(elmt);
+		}
+		self.last_pos = pmmr.unpruned_size();
+	}
+
+	// Drops the last n leaves. The new peaks are always kept, even when they
+	// had been removed along with a sibling that's now gone.
+	fn rewind_leaves(&mut self, n: u64) {
+		let mut pmmr: PMMR<T, _> = PMMR::at(&self.backend, self.last_pos);
+		let leaves = pmmr::n_leaves(self.last_pos);
+		pmmr.rewind(pmmr::size_from_leaves(leaves - n));
+		self.last_pos = pmmr.unpruned_size();
+		self.spent.rewind(self.last_pos);
+		if self.last_pos > 0 {
+			self.backend.restore(pmmr::peaks(self.last_pos));
+		}
+	}
+
+	// Marks the leaf at the provided position as spent and removes the nodes
+	// that aren't needed anymore to prove the unspent leaves. A node goes
+	// along with its sibling, once both their subtrees are fully spent.
+	fn spend(&mut self, pos: u64) {
+		self.spent.append(vec![pos]);
+		let mut to_remove = vec![];
+		for (node, sibling) in pmmr::family_path(pos, self.last_pos) {
+			if !self.fully_spent(sibling) {
+				break;
+			}
+			to_remove.push(node);
+			to_remove.push(sibling);
+		}
+		self.backend.remove(to_remove);
+	}
+
+	// Reverts spend, putting back the nodes the leaf needs to be proven.
+	fn unspend(&mut self, pos: u64) {
+		self.spent.restore(vec![pos]);
+		let mut to_restore = vec![];
+		for (node, sibling) in pmmr::family_path(pos, self.last_pos) {
+			if self.backend.get(node).is_some() {
+				break;
+			}
+			to_restore.push(node);
+			to_restore.push(sibling);
+		}
+		self.backend.restore(to_restore);
+	}
+
+	// A leaf is fully spent when marked so, other nodes when their children
+	// have been removed.
+	fn fully_spent(&self, pos: u64) -> bool {
+		match pmmr::children(pos) {
+			None => self.spent.includes(pos),
+			Some((left, right)) => {
+				self.backend.get(left).is_none() && self.backend.get(right).is_none()
+			}
+		}
+	}
+
+	fn merkle_proof(&self, pos: u64) -> Option<MerkleProof<T>> {
+		let pmmr: PMMR<T, _> = PMMR::at(&self.backend, self.last_pos);
+		pmmr.merkle_proof(pos)
+	}
+
+	fn root(&self) -> Hash {
+		if self.last_pos == 0 {
+			return ZERO_HASH;
+		}
+		let pmmr: PMMR<T, _> = PMMR::at(&self.backend, self.last_pos);
+		pmmr.root().hash
+	}
+
+	fn sync(&mut self) -> Result<(), Error> {
+		self.backend.sync().map_err(|e| Error::SumTreeErr(e.to_string()))?;
+		self.spent.flush().map_err(|e| Error::SumTreeErr(e.to_string()))
+	}
+
+	// All nodes that haven't been pruned, with their position.
+	fn nodes(&self) -> Vec<(u64, Hash)> {
+		(1..(self.last_pos + 1))
+			.filter_map(|pos| self.backend.get(pos).map(|hsum| (pos, hsum.hash)))
+			.collect()
+	}
+
+	fn clear(&mut self) {
+		self.backend.rewind(0);
+		self.spent.rewind(0);
+		self.last_pos = 0;
+	}
+}
+
+impl<T> PMMRHandle<NoSum<T>> where T: Writeable + Debug + Clone {
+	// Replaces the whole tree with the provided nodes, positions missing from
+	// them being pruned ones, and the spent leaves still in the tree. The nodes
+	// have to be ordered by position.
+	fn install(&mut self, size: u64, nodes: &[(u64, Hash)], spent: Vec<u64>) {
+		self.clear();
+		let mut data = vec![];
+		let mut pruned = vec![];
+		let mut idx = 0;
+		for pos in 1..(size + 1) {
+			let mut hash = ZERO_HASH;
+			if idx < nodes.len() && nodes[idx].0 == pos {
+				hash = nodes[idx].1;
+				idx += 1;
+			} else {
+				pruned.push(pos);
+			}
+			data.push(HashSum { hash: hash, sum: NullSum });
+		}
+		self.backend.append(data);
+		self.backend.remove(pruned);
+		self.spent.append(spent);
+		self.last_pos = size;
+	}
+}
+
+/// The output, range proof and kernel sum trees, maintained in the state of
+/// the current chain head. Every block pushes its outputs, their range proofs
+/// and its kernels to the respective trees, in the order they have in the
+/// block, and spends the outputs its inputs refer to.
+pub struct SumTrees {
+	output_pmmr_h: PMMRHandle<NoSum<Output>>,
+	rproof_pmmr_h: PMMRHandle<NoSum<RangeProof>>,
+	kernel_pmmr_h: PMMRHandle<NoSum<TxKernel>>,
+
+	// to find the position of the outputs spent by a block
+	commit_index: Arc<ChainStore>,
+	// positions of the outputs pushed since the last sync, which may not be
+	// indexed in the store yet
+	new_output_pos: HashMap<Commitment, u64>,
+}
+
+impl SumTrees {
+	/// Opens the sum trees stored under the provided root directory, creating
+	/// them empty if necessary. Trees left ahead of the chain head by a crash
+	/// are rewound to it.
+	pub fn open(root_dir: String, commit_index: Arc<ChainStore>) -> Result<SumTrees, Error> {
+		let mut sumtrees = SumTrees {
+			output_pmmr_h: PMMRHandle::new(root_dir.clone(), OUTPUT_SUBDIR)?,
+			rproof_pmmr_h: PMMRHandle::new(root_dir.clone(), RANGE_PROOF_SUBDIR)?,
+			kernel_pmmr_h: PMMRHandle::new(root_dir, KERNEL_SUBDIR)?,
+			commit_index: commit_index,
+			new_output_pos: HashMap::new(),
+		};
+		sumtrees.rewind_to_head()?;
+		Ok(sumtrees)
+	}
+
+	// The trees get synced before the head is saved, a crash in between leaves
+	// them ahead of it. Brings them back to the size they had at the head and
+	// unspends the outputs the lost block may have spent.
+	fn rewind_to_head(&mut self) -> Result<(), Error> {
+		let head = match self.commit_index.head() {
+			Ok(head) => head,
+			Err(grin_store::Error::NotFoundErr) => return Ok(()),
+			Err(e) => return Err(Error::StoreErr(e)),
+		};
+		let (output_leaves, kernel_leaves) =
+			self.commit_index.get_sumtree_leaves(&head.last_block_h)?;
+		let (current_output_leaves, current_kernel_leaves) = self.leaves();
+		if current_output_leaves == output_leaves && current_kernel_leaves == kernel_leaves {
+			return Ok(());
+		}
+		if current_output_leaves < output_leaves || current_kernel_leaves < kernel_leaves {
+			return Err(Error::SumTreeErr("sum trees behind the chain head".to_string()));
+		}
+
+		warn!(
+			"Sum trees ahead of the chain head at {}, rewinding them.",
+			head.height
+		);
+		self.output_pmmr_h.rewind_leaves(current_output_leaves - output_leaves);
+		self.rproof_pmmr_h.rewind_leaves(current_output_leaves - output_leaves);
+		self.kernel_pmmr_h.rewind_leaves(current_kernel_leaves - kernel_leaves);
+		for out in self.commit_index.utxos()? {
+			let pos = self.commit_index.get_output_pos(&out.commitment())?;
+			self.output_pmmr_h.unspend(pos);
+			self.rproof_pmmr_h.unspend(pos);
+		}
+		self.sync()
+	}
+
+	/// Pushes the outputs, range proofs and kernels of the block to the trees
+	/// and spends the outputs of its inputs.
+	pub fn apply_block(&mut self, b: &Block) -> Result<(), Error> {
+		let spent_pos = self.spent_positions(b)?;
+		let (output_leaves, _) = self.leaves();
+		for (n, out) in b.outputs.iter().enumerate() {
+			let pos = pmmr::size_from_leaves(output_leaves + n as u64) + 1;
+			self.new_output_pos.insert(out.commitment(), pos);
+		}
+
+		self.output_pmmr_h.push(b.outputs.iter().map(|out| NoSum(*out)).collect());
+		self.rproof_pmmr_h.push(b.outputs.iter().map(|out| NoSum(out.proof)).collect());
+		self.kernel_pmmr_h.push(b.kernels.iter().map(|kern| NoSum(kern.clone())).collect());
+		for pos in spent_pos {
+			self.output_pmmr_h.spend(pos);
+			self.rproof_pmmr_h.spend(pos);
+		}
+		Ok(())
+	}
+
+	/// Rewinds the trees to their state before the block was applied. The
+	/// block has to be the last one applied.
+	pub fn rewind_block(&mut self, b: &Block) -> Result<(), Error> {
+		let spent_pos = self.spent_positions(b)?;
+		for pos in spent_pos {
+			self.output_pmmr_h.unspend(pos);
+			self.rproof_pmmr_h.unspend(pos);
+		}
+		for out in &b.outputs {
+			self.new_output_pos.remove(&out.commitment());
+		}
+
+		self.output_pmmr_h.rewind_leaves(b.outputs.len() as u64);
+		self.rproof_pmmr_h.rewind_leaves(b.outputs.len() as u64);
+		self.kernel_pmmr_h.rewind_leaves(b.kernels.len() as u64);
+		Ok(())
+	}
+
+	// Positions in the output tree of the outputs spent by the block.
+	fn spent_positions(&self, b: &Block) -> Result<Vec<u64>, Error> {
+		let mut positions = vec![];
+		for input in &b.inputs {
+			let commit = input.commitment();
+			let pos = match self.new_output_pos.get(&commit) {
+				Some(&pos) => pos,
+				None => self.commit_index.get_output_pos(&commit)?,
+			};
+			positions.push(pos);
+		}
+		Ok(positions)
+	}
+
+	/// Proof that the output with the provided commitment is included in the
+	/// output tree, along with the root of the tree it verifies against.
+	pub fn output_proof(
+		&self,
+		commit: &Commitment,
+	) -> Result<(MerkleProof<NoSum<Output>>, Hash), Error> {
+		let pos = self.commit_index.get_output_pos(commit)?;
+		let proof = self.output_pmmr_h.merkle_proof(pos).ok_or(Error::SumTreeErr(
+			format!("No proof for output at {}, pruned.", pos),
+		))?;
+		Ok((proof, self.output_pmmr_h.root()))
+	}
+
+	/// Current roots of the output, range proof and kernel trees.
+	pub fn roots(&self) -> (Hash, Hash, Hash) {
+		(
+			self.output_pmmr_h.root(),
+			self.rproof_pmmr_h.root(),
+			self.kernel_pmmr_h.root(),
+		)
+	}
+
+	/// Sets the roots committed to by the provided header to the current ones.
+	pub fn set_roots(&self, header: &mut BlockHeader) {
+		let (utxo_root, rproof_root, kernel_root) = self.roots();
+		header.utxo_root = utxo_root;
+		header.range_proof_root = rproof_root;
+		header.kernel_root = kernel_root;
+	}
+
+	/// Checks the roots committed to by the provided header against the
+	/// current ones.
+	pub fn check_roots(&self, header: &BlockHeader) -> Result<(), Error> {
+		let (utxo_root, rproof_root, kernel_root) = self.roots();
+		if header.utxo_root != utxo_root || header.range_proof_root != rproof_root ||
+			header.kernel_root != kernel_root
+		{
+			return Err(Error::InvalidRoot);
+		}
+		Ok(())
+	}
+
+	/// Number of leaves in the output (and range proof) tree and in the kernel
+	/// tree.
+	pub fn leaves(&self) -> (u64, u64) {
+		(
+			pmmr::n_leaves(self.output_pmmr_h.last_pos),
+			pmmr::n_leaves(self.kernel_pmmr_h.last_pos),
+		)
+	}
+
+	/// Builds a snapshot of the trees in their current state, taken at the
+	/// provided block. The unspent outputs with the heights they were created
+	/// at and the kernels, which the trees don't hold, have to be provided.
+	pub fn snapshot(
+		&self,
+		block_hash: Hash,
+		outputs: Vec<(u64, Output)>,
+		output_heights: Vec<u64>,
+		kernels: Vec<TxKernel>,
+	) -> SumTreeSnapshot {
+		SumTreeSnapshot {
+			block_hash: block_hash,
+			output_mmr_size: self.output_pmmr_h.last_pos,
+			output_nodes: self.output_pmmr_h.nodes(),
+			rproof_nodes: self.rproof_pmmr_h.nodes(),
+			outputs: outputs,
+			output_heights: output_heights,
+			kernels: kernels,
+		}
+	}
+
+	/// Replaces the content of all trees with the one of the snapshot, which
+	/// needs to have been validated beforehand.
+	pub fn install(&mut self, snapshot: &SumTreeSnapshot) -> Result<(), Error> {
+		// leaves still in the trees but not in the UTXO set have been spent
+		let unspent: HashSet<u64> = snapshot.outputs.iter().map(|&(pos, _)| pos).collect();
+		let spent: Vec<u64> = snapshot
+			.output_nodes
+			.iter()
+			.map(|&(pos, _)| pos)
+			.filter(|&pos| pmmr::children(pos).is_none() && !unspent.contains(&pos))
+			.collect();
+		self.output_pmmr_h.install(snapshot.output_mmr_size, &snapshot.output_nodes, spent.clone());
+		self.rproof_pmmr_h.install(snapshot.output_mmr_size, &snapshot.rproof_nodes, spent);
+		self.kernel_pmmr_h.clear();
+		self.kernel_pmmr_h.push(snapshot.kernels.iter().map(|k| NoSum(k.clone())).collect());
+		self.sync()
+	}
+
+	/// Syncs all trees to disk. All outputs pushed so far are expected to get
+	/// indexed in the store right after.
+	pub fn sync(&mut self) -> Result<(), Error> {
+		self.new_output_pos.clear();
+		self.output_pmmr_h.sync()?;
+		self.rproof_pmmr_h.sync()?;
+		self.kernel_pmmr_h.sync()
+	}
+}
+
+/// Fully validates a snapshot against the header of the block it's been taken
+/// at. The trees rebuilt from the snapshot have to match the header roots,
+/// every unspent output and its range proof have to be in their trees and the
+/// sum of all unspent outputs has to match the one of all kernels, given the
+/// rewards and fees so far. Kernel signatures and range proofs are checked as
+/// well.
+pub fn validate_snapshot(header: &BlockHeader, snapshot: &SumTreeSnapshot) -> Result<(), Error> {
+	if snapshot.block_hash != header.hash() {
+		return Err(Error::InvalidSnapshot("not taken at the expected block".to_string()));
+	}
+
+	// rebuild all trees in memory and compare their roots with the header ones
+	let size = snapshot.output_mmr_size;
+	let output_ba: MemBackend<NoSum<Output>> = MemBackend::from_nodes(size, &snapshot.output_nodes)?;
+	let rproof_ba: MemBackend<NoSum<RangeProof>> =
+		MemBackend::from_nodes(size, &snapshot.rproof_nodes)?;
+	let kernel_ba: MemBackend<NoSum<TxKernel>> = MemBackend::from_nodes(0, &[])?;
+	let output_pmmr = PMMR::at(&output_ba, size);
+	let rproof_pmmr = PMMR::at(&rproof_ba, size);
+	let mut kernel_pmmr = PMMR::new(&kernel_ba);
+	for kernel in &snapshot.kernels {
+		kernel_pmmr.push(NoSum(kernel.clone()));
+	}
+	if mem_root(&output_pmmr) != header.utxo_root ||
+		mem_root(&rproof_pmmr) != header.range_proof_root ||
+		mem_root(&kernel_pmmr) != header.kernel_root
+	{
+		return Err(Error::InvalidRoot);
+	}
+
+	// every unspent output needs the height it was created at, which can't be
+	// above the snapshot block
+	if snapshot.output_heights.len() != snapshot.outputs.len() ||
+		snapshot.output_heights.iter().any(|&h| h > header.height)
+	{
+		return Err(Error::InvalidSnapshot("bad output heights".to_string()));
+	}
+
+	// unspent outputs and their range proofs have to be provably in the trees
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let snap_err = |msg: &str| Error::InvalidSnapshot(msg.to_string());
+	for &(pos, ref out) in &snapshot.outputs {
+		let output_proof = output_pmmr.merkle_proof(pos).ok_or(snap_err("unknown output"))?;
+		if !output_proof.verify(NoSum(*out), &output_pmmr.root()) {
+			return Err(snap_err("output not in tree"));
+		}
+		let rproof_proof = rproof_pmmr.merkle_proof(pos).ok_or(snap_err("unknown range proof"))?;
+		if !rproof_proof.verify(NoSum(out.proof), &rproof_pmmr.root()) {
+			return Err(snap_err("range proof not in tree"));
+		}
+		out.verify_proof(&secp).map_err(|_| snap_err("invalid range proof"))?;
+	}
+	for kernel in &snapshot.kernels {
+		kernel.verify(&secp).map_err(|_| snap_err("invalid kernel signature"))?;
+	}
+
+	// the UTXO set has to sum to the kernels once rewards and fees are
+	// accounted for
+	let mut out_commits = snapshot.outputs.iter().map(|&(_, ref out)| out.commitment()).collect::<Vec<_>>();
+	let mut over_commits = vec![];
+	let fees = snapshot.kernels.iter().fold(0, |acc, k| acc + k.fee);
+	let overage = (fees as i64) - (REWARD as i64) * (header.height as i64);
+	if overage != 0 {
+		let over_commit = secp.commit_value(overage.abs() as u64).map_err(|_| snap_err("bad overage"))?;
+		if overage < 0 {
+			over_commits.push(over_commit);
+		} else {
+			out_commits.push(over_commit);
+		}
+	}
+	let kernel_commits = snapshot.kernels.iter().map(|k| k.excess).collect::<Vec<_>>();
+	let utxo_sum = secp.commit_sum(out_commits, over_commits);
+	let kernel_sum = secp.commit_sum(kernel_commits, vec![]);
+	match (utxo_sum, kernel_sum) {
+		(Ok(ref us), Ok(ref ks)) if us == ks => Ok(()),
+		_ => Err(snap_err("UTXO set doesn't sum to the kernels")),
+	}
+}
+
+// Root of an in-memory tree, zero if the tree is empty.
+fn mem_root<T>(pmmr: &PMMR<T, &MemBackend<T>>) -> Hash
+	where T: Summable + Writeable + Debug + Clone
+{
+	if pmmr.unpruned_size() == 0 {
+		ZERO_HASH
+	} else {
+		pmmr.root().hash
+	}
+}
+
+// Backend keeping tree nodes in memory, only used transiently to validate
+// snapshots.
+struct MemBackend<T> where T: Summable {
+	nodes: RefCell<BTreeMap<u64, HashSum<T>>>,
+}
+
+impl<T> MemBackend<T> where T: Summable {
+	// Builds a backend for a tree of the provided size out of its nodes,
+	// checking they're ordered and that none of the peaks is missing.
+	fn from_nodes(size: u64, nodes: &[(u64, Hash)]) -> Result<MemBackend<T>, Error>
+		where T: Summable<Sum = NullSum>
+	{
+		let peaks = if size == 0 { vec![] } else { pmmr::peaks(size) };
+		if size > 0 && peaks.is_empty() {
+			return Err(Error::InvalidSnapshot("invalid tree size".to_string()));
+		}
+
+		let mut map = BTreeMap::new();
+		let mut last_pos = 0;
+		for &(pos, hash) in nodes {
+			if pos <= last_pos || pos > size {
+				return Err(Error::InvalidSnapshot("misplaced tree node".to_string()));
+			}
+			map.insert(pos, HashSum { hash: hash, sum: NullSum });
+			last_pos = pos;
+		}
+		if peaks.iter().any(|p| !map.contains_key(p)) {
+			return Err(Error::InvalidSnapshot("missing tree peak".to_string()));
+		}
+		Ok(MemBackend { nodes: RefCell::new(map) })
+	}
+}
+
+impl<T> Backend<T> for MemBackend<T> where T: Summable + Clone {
+	fn append(&self, data: Vec<HashSum<T>>) {
+		let mut nodes = self.nodes.borrow_mut();
+		let mut pos = nodes.keys().next_back().map(|&p| p).unwrap_or(0);
+		for hsum in data {
+			pos += 1;
+			nodes.insert(pos, hsum);
+		}
+	}
+	fn get(&self, position: u64) -> Option<HashSum<T>> {
+		self.nodes.borrow().get(&position).cloned()
+	}
+	fn remove(&self, positions: Vec<u64>) {
+		let mut nodes = self.nodes.borrow_mut();
+		for pos in positions {
+			nodes.remove(&pos);
+		}
+	}
+	fn rewind(&self, position: u64) {
+		let mut nodes = self.nodes.borrow_mut();
+		nodes.split_off(&(position + 1));
+	}
+}
@@ -18,8 +18,9 @@ use secp;
 use secp::pedersen::Commitment;
 
 use grin_store as store;
-use core::core::{Block, BlockHeader, Output};
+use core::core::{Block, BlockHeader, Output, TxKernel};
 use core::core::hash::{Hash, Hashed};
+use core::core::snapshot::SumTreeSnapshot;
 use core::core::target::Difficulty;
 use core::ser;
 use grin_store;
@@ -43,8 +44,9 @@ bitflags! {
 pub enum Error {
 	/// The block doesn't fit anywhere in our chain
 	Unfit(String),
-	/// Special case of orphan blocks
-	Orphan,
+	/// Special case of orphan blocks, we don't have the block with the
+	/// provided hash the orphan builds on
+	Orphan(Hash),
 	/// Difficulty is too low either compared to ours or the block PoW hash
 	DifficultyTooLow,
 	/// Addition of difficulties on all previous block is wrong
@@ -57,6 +59,21 @@ pub enum Error {
 	InvalidBlockTime,
 	/// Block height is invalid (not previous + 1)
 	InvalidBlockHeight,
+	/// An input spends an output that has already been spent on this branch
+	DoubleSpend(Commitment),
+	/// An input references an output that doesn't exist on this branch
+	UnknownInput(Commitment),
+	/// An input spends a coinbase output that hasn't matured yet
+	ImmatureCoinbase(Commitment),
+	/// A kernel is locked until a height above the block's
+	LockedKernel(u64),
+	/// The sum tree roots committed to by the header don't match the ones
+	/// obtained by applying the block
+	InvalidRoot,
+	/// Error from the sum trees storage
+	SumTreeErr(String),
+	/// A state snapshot doesn't match the block it's been taken at
+	InvalidSnapshot(String),
 	/// Internal issue when trying to save or load data from store
 	StoreErr(grin_store::Error),
 	/// Error serializing or deserializing a type
@@ -185,6 +202,70 @@ pub trait ChainStore: Send + Sync {
 	/// Checks whether an output commitment exists and returns the output hash
 	fn has_output_commit(&self, commit: &Commitment) -> Result<Hash, store::Error>;
 
+	/// Gets an output by its commitment if it's still unspent on the branch
+	/// the chain head is currently on.
+	fn get_unspent(&self, commit: &Commitment) -> Result<Output, store::Error>;
+
+	/// Whether the provided commitment is in the UTXO set of the current head.
+	fn is_unspent(&self, commit: &Commitment) -> Result<bool, store::Error>;
+
+	/// Applies the effects of a block on the UTXO set: its inputs are removed
+	/// and its outputs are added. Must be called on blocks extending the head.
+	fn apply_block(&self, b: &Block) -> Result<(), store::Error>;
+
+	/// Reverts the effects of a block on the UTXO set, used when the head
+	/// moves away from the branch the block is on. Its outputs are also
+	/// dropped from the position and block indexes.
+	fn rewind_block(&self, b: &Block) -> Result<(), store::Error>;
+
+	/// All the outputs in the UTXO set of the current head.
+	fn utxos(&self) -> Result<Vec<Output>, store::Error>;
+
+	/// Indexes the outputs of a block by their position in the output sum
+	/// tree and saves its kernels by their leaf index in the kernel sum tree,
+	/// given how many leaves both trees had before the block.
+	fn index_sumtrees(
+		&self,
+		b: &Block,
+		output_leaves: u64,
+		kernel_leaves: u64,
+	) -> Result<(), store::Error>;
+
+	/// Gets the number of leaves the output and kernel sum trees have once the
+	/// provided block is applied, as saved when indexing it.
+	fn get_sumtree_leaves(&self, h: &Hash) -> Result<(u64, u64), store::Error>;
+
+	/// Gets the position of an output in the output sum tree.
+	fn get_output_pos(&self, commit: &Commitment) -> Result<u64, store::Error>;
+
+	/// Gets the hash of the block on the main chain that created an output.
+	fn get_output_block(&self, commit: &Commitment) -> Result<Hash, store::Error>;
+
+	/// Gets the height of the block that created an output, either from the
+	/// block itself or, for outputs installed from a snapshot, as provided by
+	/// the snapshot.
+	fn get_output_height(&self, commit: &Commitment) -> Result<u64, store::Error>;
+
+	/// Gets a kernel by its leaf index in the kernel sum tree.
+	fn get_kernel(&self, leaf_idx: u64) -> Result<TxKernel, store::Error>;
+
+	/// Prunes the outputs spent by the provided block from the blocks that
+	/// created them, along with their range proofs and indexes. Kernels and
+	/// headers are kept. The block has to be on the main chain and beyond the
+	/// cut-through horizon.
+	fn prune_spent(&self, b: &Block) -> Result<(), store::Error>;
+
+	/// Height up to which blocks on the main chain have been pruned, 0 if
+	/// nothing has been.
+	fn get_pruned_height(&self) -> Result<u64, store::Error>;
+
+	/// Saves the height up to which blocks on the main chain have been pruned.
+	fn save_pruned_height(&self, height: u64) -> Result<(), store::Error>;
+
+	/// Saves the UTXO set and the kernels of an already validated snapshot,
+	/// indexing them like index_sumtrees does.
+	fn save_snapshot(&self, snapshot: &SumTreeSnapshot) -> Result<(), store::Error>;
+
 	/// Saves the provided block header at the corresponding height. Also check
 	/// the consistency of the height chain in store by assuring previous
 	/// headers
@@ -196,13 +277,27 @@ pub trait ChainStore: Send + Sync {
 /// downstream processing of valid blocks by the rest of the system, most
 /// importantly the broadcasting of blocks to our peers.
 pub trait ChainAdapter {
-	/// The blockchain pipeline has accepted this block as valid and added
-	/// it to our chain.
+	/// The blockchain pipeline has accepted this block as valid and it
+	/// extended the head of our chain. Blocks on a fork aren't notified, nor is
+	/// a block switching the head to another branch, which is a reorg.
 	fn block_accepted(&self, b: &Block);
+
+	/// The head of the chain moved to another branch. The blocks that were
+	/// removed from the main chain are provided from the old head down to the
+	/// fork point, the blocks that were added from the fork point up to the
+	/// new head, which is the last one.
+	fn chain_reorg(&self, removed: &[Block], added: &[Block]);
+
+	/// The head of the chain has been updated to the provided tip, after the
+	/// block extending it (or the reorg leading to it) has been fully
+	/// processed.
+	fn head_updated(&self, tip: &Tip);
 }
 
 /// Dummy adapter used as a placeholder for real implementations
 pub struct NoopAdapter {}
 impl ChainAdapter for NoopAdapter {
 	fn block_accepted(&self, _: &Block) {}
+	fn chain_reorg(&self, _: &[Block], _: &[Block]) {}
+	fn head_updated(&self, _: &Tip) {}
 }
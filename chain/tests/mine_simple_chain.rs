@@ -22,12 +22,13 @@ extern crate secp256k1zkp as secp;
 extern crate grin_grin as grin;
 
 use std::fs;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use rand::os::OsRng;
 
 use grin_chain::types::*;
-use grin_core::core::hash::Hashed;
+use grin_core::core::build;
+use grin_core::core::hash::{Hash, Hashed};
 use grin_core::core::target::Difficulty;
 use grin_core::pow;
 use grin_core::core;
@@ -43,6 +44,38 @@ fn clean_output_dir(dir_name:&str){
     let _ = fs::remove_dir_all(dir_name);
 }
 
+/// Adds empty blocks on top of the head until the coinbase output of the head
+/// block can be spent in the next one.
+fn mature_coinbase(chain: &grin_chain::Chain) {
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	for _ in 1..global::coinbase_maturity() {
+		let prev = chain.head_header().unwrap();
+		let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+			.unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_sumtree_roots(&mut b).unwrap();
+		chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	}
+}
+
+/// Chain adapter keeping track of the reorgs it's notified of.
+struct ReorgAdapter {
+	reorgs: Mutex<Vec<(Vec<Hash>, Vec<Hash>)>>,
+}
+
+impl ChainAdapter for ReorgAdapter {
+	fn block_accepted(&self, _: &core::Block) {}
+	fn chain_reorg(&self, removed: &[core::Block], added: &[core::Block]) {
+		let mut reorgs = self.reorgs.lock().unwrap();
+		reorgs.push((
+			removed.iter().map(|b| b.hash()).collect(),
+			added.iter().map(|b| b.hash()).collect(),
+		));
+	}
+	fn head_updated(&self, _: &Tip) {}
+}
+
 #[test]
 fn mine_empty_chain() {
     let _ = env_logger::init();
@@ -72,6 +105,7 @@ fn mine_empty_chain() {
 
 		let difficulty = consensus::next_difficulty(chain.difficulty_iter()).unwrap();
 		b.header.difficulty = difficulty.clone();
+		chain.set_sumtree_roots(&mut b).unwrap();
 
 		pow::pow_size(
 			&mut cuckoo_miner,
@@ -81,12 +115,16 @@ fn mine_empty_chain() {
 		).unwrap();
 
 		let bhash = b.hash();
+		let coinbase_commit = b.outputs[0].commitment();
 		chain.process_block(b, grin_chain::EASY_POW).unwrap();
 
 		// checking our new head
 		let head = chain.head().unwrap();
 		assert_eq!(head.height, n);
 		assert_eq!(head.last_block_h, bhash);
+
+		// the coinbase output is now part of the UTXO set
+		assert!(chain.get_unspent(&coinbase_commit).is_some());
 	}
 }
 
@@ -108,6 +146,7 @@ fn mine_forks() {
 		let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
 		b.header.total_difficulty = Difficulty::from_num(2 * n);
+		chain.set_sumtree_roots(&mut b).unwrap();
 		let bhash = b.hash();
 		chain.process_block(b, grin_chain::SKIP_POW).unwrap();
 
@@ -122,6 +161,7 @@ fn mine_forks() {
 		let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
 		b.header.total_difficulty = Difficulty::from_num(2 * n + 1);
+		chain.set_sumtree_roots(&mut b).unwrap();
 		let bhash = b.hash();
 		chain.process_block(b, grin_chain::SKIP_POW).unwrap();
 
@@ -133,3 +173,488 @@ fn mine_forks() {
 		assert_eq!(head.prev_block_h, prev.hash());
 	}
 }
+
+#[test]
+fn spend_invalid_inputs() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin3");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin3".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// a first block creating the coinbase output we'll spend
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	mature_coinbase(&chain);
+
+	// spending the coinbase output is fine the first time
+	let (tx1, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::with_fee(2),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx1], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	let spend_height = global::coinbase_maturity() + 1;
+	assert_eq!(chain.head().unwrap().height, spend_height);
+
+	// but not a second time
+	let (tx2, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::with_fee(2),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx2], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	match chain.process_block(b, grin_chain::SKIP_POW) {
+		Err(Error::DoubleSpend(_)) => {}
+		_ => panic!("block with a double spend should have been rejected"),
+	}
+
+	// and an output that never existed can't be spent at all
+	let (tx3, _) = build::transaction(vec![
+		build::input(10, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::output(8, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::with_fee(2),
+	]).unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx3], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	match chain.process_block(b, grin_chain::SKIP_POW) {
+		Err(Error::UnknownInput(_)) => {}
+		_ => panic!("block with an unknown input should have been rejected"),
+	}
+	assert_eq!(chain.head().unwrap().height, spend_height);
+}
+
+#[test]
+fn spend_immature_coinbase() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin8");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin8".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+
+	// the coinbase output can't be spent in the next block
+	let (tx, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::with_fee(2),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	match chain.process_block(b, grin_chain::SKIP_POW) {
+		Err(Error::ImmatureCoinbase(_)) => {}
+		_ => panic!("block spending an immature coinbase should have been rejected"),
+	}
+	assert_eq!(chain.head().unwrap().height, 1);
+
+	// but can once it matured
+	mature_coinbase(&chain);
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().height, global::coinbase_maturity() + 1);
+}
+
+#[test]
+fn spend_time_locked() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin9");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin9".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	mature_coinbase(&chain);
+
+	// a transaction locked until 2 blocks from now can't be in the next one
+	let lock_height = chain.head().unwrap().height + 2;
+	let (tx, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, secp::key::SecretKey::new(&secp, &mut rng)),
+		build::with_fee(2),
+		build::with_lock_height(lock_height),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	match chain.process_block(b, grin_chain::SKIP_POW) {
+		Err(Error::LockedKernel(h)) => assert_eq!(h, lock_height),
+		_ => panic!("block with a locked kernel should have been rejected"),
+	}
+
+	// but it can be in the one after
+	let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().height, lock_height);
+}
+
+#[test]
+fn reorg_spent_output() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin4");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let adapter = Arc::new(ReorgAdapter { reorgs: Mutex::new(vec![]) });
+	let chain = grin_chain::Chain::init(".grin4".to_string(), adapter.clone()).unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// common block creating the coinbase output we'll spend
+	let fork_prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&fork_prev, vec![], reward_key).unwrap();
+	b.header.timestamp = fork_prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let coinbase_commit = b.outputs[0].commitment();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	mature_coinbase(&chain);
+	let fork_prev = chain.head_header().unwrap();
+
+	// first branch spends it
+	let spent_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let (tx, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, spent_key),
+		build::with_fee(2),
+	]).unwrap();
+	let mut b = core::Block::new(&fork_prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = fork_prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let spend_hash = b.hash();
+	let spend_commit = secp.commit(consensus::REWARD - 2, spent_key).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	assert!(chain.get_unspent(&coinbase_commit).is_none());
+	assert!(chain.get_unspent(&spend_commit).is_some());
+	assert_eq!(chain.get_output_height(&spend_commit).unwrap(), fork_prev.height + 1);
+
+	// second branch doesn't but ends up with more work
+	let mut prev_hash = fork_prev.hash();
+	let mut added = vec![];
+	for _ in 1..3 {
+		let prev = chain.get_block_header(&prev_hash).unwrap();
+		let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+			.unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(61);
+		chain.set_sumtree_roots(&mut b).unwrap();
+		prev_hash = b.hash();
+		added.push(b.hash());
+		chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	}
+
+	let head = chain.head().unwrap();
+	assert_eq!(head.height, fork_prev.height + 2);
+	assert_eq!(head.last_block_h, prev_hash);
+
+	// the spend has been rewound and the adapter told about it
+	assert!(chain.get_unspent(&coinbase_commit).is_some());
+	assert!(chain.get_unspent(&spend_commit).is_none());
+	assert!(chain.get_output_height(&spend_commit).is_err());
+	assert!(chain.get_block_header_by_output_commit(&spend_commit).is_err());
+	let reorgs = adapter.reorgs.lock().unwrap();
+	assert_eq!(reorgs.len(), 1);
+	assert_eq!(reorgs[0].0, vec![spend_hash]);
+	assert_eq!(reorgs[0].1, added);
+}
+
+#[test]
+fn reject_bad_sumtree_roots() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin5");
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin5".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	b.header.kernel_root = Hash::from_vec(vec![1; 32]);
+	match chain.process_block(b, grin_chain::SKIP_POW) {
+		Err(Error::InvalidRoot) => {}
+		_ => panic!("block committing to the wrong roots should have been rejected"),
+	}
+	assert_eq!(chain.head().unwrap().height, 0);
+
+	// the sum trees are left untouched, a good block still goes through
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().height, 1);
+}
+
+#[test]
+fn sync_from_snapshot() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin6");
+	clean_output_dir(".grin6b");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin6".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// a coinbase, a block spending it and an empty block
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let coinbase_commit = b.outputs[0].commitment();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	mature_coinbase(&chain);
+
+	let spent_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let (tx, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, spent_key),
+		build::with_fee(2),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let horizon = b.hash();
+	let spend_commit = secp.commit(consensus::REWARD - 2, spent_key).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let last = b.hash();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+
+	// snapshot taken below the head, which is left where it was
+	let snapshot = chain.sumtree_snapshot(&horizon).unwrap();
+	assert_eq!(chain.head().unwrap().last_block_h, last);
+
+	// a new node gets all headers first
+	let chain2 = grin_chain::Chain::init(".grin6b".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+	let mut hs = vec![];
+	let mut header = chain.get_block_header(&last).unwrap();
+	while header.height > 0 {
+		let prev = chain.get_block_header(&header.previous).unwrap();
+		hs.insert(0, header);
+		header = prev;
+	}
+	for header in &hs {
+		chain2.process_block_header(header, grin_chain::SKIP_POW).unwrap();
+	}
+
+	// a snapshot that lost a kernel doesn't match the header roots
+	let mut bad_snapshot = snapshot.clone();
+	bad_snapshot.kernels.pop();
+	assert!(chain2.install_snapshot(bad_snapshot).is_err());
+	assert_eq!(chain2.head().unwrap().height, 0);
+
+	// a snapshot claiming an output was created above its block is refused
+	let mut bad_snapshot = snapshot.clone();
+	bad_snapshot.output_heights[0] = chain.get_block_header(&horizon).unwrap().height + 1;
+	assert!(chain2.install_snapshot(bad_snapshot).is_err());
+
+	// the good one does, then only the last full block is needed
+	chain2.install_snapshot(snapshot).unwrap();
+	assert_eq!(chain2.head().unwrap().last_block_h, horizon);
+	let b = chain.get_block(&last).unwrap();
+	chain2.process_block(b, grin_chain::SKIP_POW).unwrap();
+
+	let head = chain2.head().unwrap();
+	assert_eq!(head.height, global::coinbase_maturity() + 2);
+	assert_eq!(head.last_block_h, last);
+	assert!(chain2.get_unspent(&coinbase_commit).is_none());
+	assert!(chain2.get_unspent(&spend_commit).is_some());
+}
+
+#[test]
+fn prune_spent_outputs() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin7");
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin7".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// a coinbase and a block spending it
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let coinbase_hash = b.hash();
+	let coinbase_commit = b.outputs[0].commitment();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	mature_coinbase(&chain);
+
+	let spent_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let (tx, _) = build::transaction(vec![
+		build::input(consensus::REWARD, reward_key),
+		build::output(consensus::REWARD - 2, spent_key),
+		build::with_fee(2),
+	]).unwrap();
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![&tx], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	let spend_commit = secp.commit(consensus::REWARD - 2, spent_key).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	let spend_height = chain.head().unwrap().height;
+
+	// nothing to prune until the spend goes beyond the horizon
+	let horizon = global::cut_through_horizon() as u64;
+	chain.compact().unwrap();
+	assert_eq!(chain.pruned_height().unwrap(), 0);
+	for _ in 0..horizon {
+		let prev = chain.head_header().unwrap();
+		let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+			.unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_sumtree_roots(&mut b).unwrap();
+		chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	}
+	chain.compact().unwrap();
+	assert_eq!(chain.pruned_height().unwrap(), spend_height);
+
+	// the spent coinbase is gone but its kernel and header stay
+	let coinbase_block = chain.get_block(&coinbase_hash).unwrap();
+	assert!(coinbase_block.outputs.is_empty());
+	assert_eq!(coinbase_block.kernels.len(), 1);
+	assert!(chain.get_block_header(&coinbase_hash).is_ok());
+
+	// UTXO lookups still work and the chain keeps validating new blocks
+	assert!(chain.get_unspent(&coinbase_commit).is_none());
+	assert!(chain.get_unspent(&spend_commit).is_some());
+	let prev = chain.head_header().unwrap();
+	let mut b = core::Block::new(&prev, vec![], secp::key::SecretKey::new(&secp, &mut rng))
+		.unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	chain.set_sumtree_roots(&mut b).unwrap();
+	chain.process_block(b, grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain.head().unwrap().height, horizon + spend_height + 1);
+
+	// snapshots can still be taken at the pruned height but not below it
+	let pruned_header = chain.get_header_by_height(spend_height).unwrap();
+	assert!(chain.sumtree_snapshot(&pruned_header.hash()).is_ok());
+	assert!(chain.sumtree_snapshot(&coinbase_hash).is_err());
+}
+
+#[test]
+fn orphan_headers_connect() {
+    let _ = env_logger::init();
+	clean_output_dir(".grin10");
+	clean_output_dir(".grin10b");
+
+	let mut rng = OsRng::new().unwrap();
+	let chain = grin_chain::Chain::init(".grin10".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+	let chain2 = grin_chain::Chain::init(".grin10b".to_string(), Arc::new(NoopAdapter {}))
+		.unwrap();
+
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+
+	// two blocks built on the first chain
+	let mut blocks = vec![];
+	for _ in 0..2 {
+		let prev = chain.head_header().unwrap();
+		let mut b = core::Block::new(&prev, vec![], reward_key).unwrap();
+		b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+		chain.set_sumtree_roots(&mut b).unwrap();
+		chain.process_block(b.clone(), grin_chain::SKIP_POW).unwrap();
+		blocks.push(b);
+	}
+	let (b1, b2) = (blocks[0].clone(), blocks[1].clone());
+
+	// an orphan without a valid proof of work isn't kept around
+	match chain2.process_block(b2.clone(), grin_chain::NONE) {
+		Err(Error::Orphan(_)) | Ok(_) => panic!("orphan with a bad proof of work should be refused"),
+		Err(_) => {}
+	}
+
+	// the second chain gets them in the wrong order and is told which parent
+	// it's missing
+	match chain2.process_block(b2.clone(), grin_chain::SKIP_POW) {
+		Err(Error::Orphan(prev)) => assert_eq!(prev, b1.hash()),
+		_ => panic!("block without a parent should have been an orphan"),
+	}
+	assert_eq!(chain2.head().unwrap().height, 0);
+
+	// once the parent is in, the orphan header connects and its block can be
+	// processed
+	chain2.process_block(b1.clone(), grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain2.connect_orphans(&b1.hash()), vec![b2.hash()]);
+	assert!(chain2.connect_orphans(&b1.hash()).is_empty());
+	chain2.process_block(b2.clone(), grin_chain::SKIP_POW).unwrap();
+	assert_eq!(chain2.head().unwrap().last_block_h, b2.hash());
+}
@@ -50,10 +50,42 @@ pub const EASINESS: u32 = 50;
 /// easier to reason about.
 pub const CUT_THROUGH_HORIZON: u32 = 48 * 3600 / (BLOCK_TIME_SEC as u32);
 
+/// Number of blocks a coinbase output has to wait, after the block that
+/// created it, before it can be spent. Protects the recipients of mined
+/// coins against the reorgs that could make the block disappear.
+pub const COINBASE_MATURITY: u64 = 1_000;
+
+/// Weight of an input when counted against the max block weight capacity
+pub const BLOCK_INPUT_WEIGHT: u64 = 1;
+
+/// Weight of an output when counted against the max block weight capacity
+pub const BLOCK_OUTPUT_WEIGHT: u64 = 10;
+
+/// Weight of a kernel when counted against the max block weight capacity
+pub const BLOCK_KERNEL_WEIGHT: u64 = 2;
+
+/// Total maximum block weight, limiting the number of inputs, outputs and
+/// kernels a block can hold. Outputs, with their range proofs, are by far
+/// the largest elements, hence their heavier weight.
+pub const MAX_BLOCK_WEIGHT: u64 = 80_000;
+
+/// Weight of a set of inputs, outputs and kernels counted against the max
+/// block weight.
+pub fn block_weight(input_len: usize, output_len: usize, kernel_len: usize) -> u64 {
+	input_len as u64 * BLOCK_INPUT_WEIGHT + output_len as u64 * BLOCK_OUTPUT_WEIGHT +
+		kernel_len as u64 * BLOCK_KERNEL_WEIGHT
+}
+
 /// The maximum size we're willing to accept for any message. Enforced by the
 /// peer-to-peer networking layer only for DoS protection.
 pub const MAX_MSG_LEN: u64 = 20_000_000;
 
+/// The maximum size we're willing to accept for a sum trees snapshot message.
+/// Each unspent output travels with its range proof (about 5KB), so the
+/// snapshot of a long chain is well beyond the general message limit. Allows
+/// for a few hundred thousand unspent outputs.
+pub const MAX_SUMTREES_MSG_LEN: u64 = 2_000_000_000;
+
 /// The minimum mining difficulty we'll allow
 pub const MINIMUM_DIFFICULTY: u64 = 10;
 
@@ -21,7 +21,7 @@ use std::collections::HashSet;
 
 use core::Committed;
 use core::{Input, Output, Proof, TxKernel, Transaction, COINBASE_KERNEL, COINBASE_OUTPUT};
-use core::transaction::merkle_inputs_outputs;
+use core::transaction::{merkle_inputs_outputs, kernel_sig_msg, rewind_nonce};
 use consensus::REWARD;
 use consensus::MINIMUM_DIFFICULTY;
 use core::hash::{Hash, Hashed, ZERO_HASH};
@@ -38,6 +38,7 @@ bitflags! {
 }
 
 /// Block header, fairly standard compared to other blockchains.
+#[derive(Clone)]
 pub struct BlockHeader {
 	/// Height of this block since the genesis block (height 0)
 	pub height: u64,
@@ -45,8 +46,12 @@ pub struct BlockHeader {
 	pub previous: Hash,
 	/// Timestamp at which the block was built.
 	pub timestamp: time::Tm,
-	/// Merkle root of the UTXO set
-	pub utxo_merkle: Hash,
+	/// Root of the output MMR once this block is applied
+	pub utxo_root: Hash,
+	/// Root of the range proof MMR once this block is applied
+	pub range_proof_root: Hash,
+	/// Root of the kernel MMR once this block is applied
+	pub kernel_root: Hash,
 	/// Merkle tree of hashes for all inputs, outputs and kernels in the block
 	pub tx_merkle: Hash,
 	/// Features specific to this block, allowing possible future extensions
@@ -70,7 +75,9 @@ impl Default for BlockHeader {
 			timestamp: time::at_utc(time::Timespec { sec: 0, nsec: 0 }),
 			difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
 			total_difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
-			utxo_merkle: ZERO_HASH,
+			utxo_root: ZERO_HASH,
+			range_proof_root: ZERO_HASH,
+			kernel_root: ZERO_HASH,
 			tx_merkle: ZERO_HASH,
 			features: DEFAULT_BLOCK,
 			nonce: 0,
@@ -86,7 +93,9 @@ impl Writeable for BlockHeader {
 		                [write_u64, self.height],
 		                [write_fixed_bytes, &self.previous],
 		                [write_i64, self.timestamp.to_timespec().sec],
-		                [write_fixed_bytes, &self.utxo_merkle],
+		                [write_fixed_bytes, &self.utxo_root],
+		                [write_fixed_bytes, &self.range_proof_root],
+		                [write_fixed_bytes, &self.kernel_root],
 		                [write_fixed_bytes, &self.tx_merkle],
 		                [write_u8, self.features.bits()]);
 
@@ -107,7 +116,9 @@ impl Readable for BlockHeader {
 		let height = try!(reader.read_u64());
 		let previous = try!(Hash::read(reader));
 		let timestamp = reader.read_i64()?;
-		let utxo_merkle = try!(Hash::read(reader));
+		let utxo_root = try!(Hash::read(reader));
+		let rproof_root = try!(Hash::read(reader));
+		let kernel_root = try!(Hash::read(reader));
 		let tx_merkle = try!(Hash::read(reader));
 		let (features, nonce) = ser_multiread!(reader, read_u8, read_u64);
 		let difficulty = try!(Difficulty::read(reader));
@@ -121,7 +132,9 @@ impl Readable for BlockHeader {
 				sec: timestamp,
 				nsec: 0,
 			}),
-			utxo_merkle: utxo_merkle,
+			utxo_root: utxo_root,
+			range_proof_root: rproof_root,
+			kernel_root: kernel_root,
 			tx_merkle: tx_merkle,
 			features: BlockFeatures::from_bits(features).ok_or(ser::Error::CorruptedData)?,
 			pow: pow,
@@ -136,6 +149,7 @@ impl Readable for BlockHeader {
 /// non-explicit, assumed to be deducible from block height (similar to
 /// bitcoin's schedule) and expressed as a global transaction fee (added v.H),
 /// additive to the total of fees ever collected.
+#[derive(Clone)]
 pub struct Block {
 	/// The header with metadata and commitments to the rest of the data
 	pub header: BlockHeader,
@@ -448,10 +462,19 @@ impl Block {
 	pub fn reward_output(skey: secp::key::SecretKey,
 	                     secp: &Secp256k1)
 	                     -> Result<(Output, TxKernel), secp::Error> {
-		let msg = try!(secp::Message::from_slice(&[0; secp::constants::MESSAGE_SIZE]));
-		let sig = try!(secp.sign(&msg, &skey));
+		Block::reward_output_with_nonce(skey, rewind_nonce(&skey), secp)
+	}
+
+	/// Builds the blinded output and related signature proof for the block
+	/// reward, with a range proof that can be rewound with the provided nonce.
+	pub fn reward_output_with_nonce(skey: secp::key::SecretKey,
+	                                nonce: [u8; 32],
+	                                secp: &Secp256k1)
+	                                -> Result<(Output, TxKernel), secp::Error> {
+		let msg = try!(kernel_sig_msg(0, 0));
+		let sig = try!(secp.sign_schnorr(&msg, &skey));
 		let commit = secp.commit(REWARD, skey).unwrap();
-		let rproof = secp.range_proof(0, REWARD, skey, commit);
+		let rproof = secp.range_proof_with_nonce(0, REWARD, skey, commit, nonce);
 
 		let output = Output {
 			features: COINBASE_OUTPUT,
@@ -466,8 +489,9 @@ impl Block {
 		let proof = TxKernel {
 			features: COINBASE_KERNEL,
 			excess: excess,
-			excess_sig: sig.serialize_der(&secp),
+			excess_sig: sig.serialize(),
 			fee: 0,
+			lock_height: 0,
 		};
 		Ok((output, proof))
 	}
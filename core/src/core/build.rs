@@ -25,12 +25,12 @@
 //! build::transaction(vec![input_rand(75), output_rand(42), output_rand(32),
 //!   with_fee(1)])
 
-use byteorder::{ByteOrder, BigEndian};
 use secp::{self, Secp256k1};
 use secp::key::SecretKey;
 use rand::os::OsRng;
 
 use core::{Transaction, Input, Output, DEFAULT_OUTPUT};
+use core::transaction::{kernel_sig_msg, rewind_nonce};
 
 /// Context information available to transaction combinators.
 pub struct Context {
@@ -106,11 +106,17 @@ pub fn input_rand(value: u64) -> Box<Append> {
 }
 
 /// Adds an output with the provided value and blinding key to the transaction
-/// being built.
+/// being built. The range proof can be rewound with the blinding key.
 pub fn output(value: u64, blinding: SecretKey) -> Box<Append> {
+	output_with_nonce(value, blinding, rewind_nonce(&blinding))
+}
+
+/// Adds an output with the provided value and blinding key to the transaction
+/// being built. The range proof can be rewound with the provided nonce.
+pub fn output_with_nonce(value: u64, blinding: SecretKey, nonce: [u8; 32]) -> Box<Append> {
 	Box::new(move |build, (tx, sum)| -> (Transaction, BlindSum) {
 		let commit = build.secp.commit(value, blinding).unwrap();
-		let rproof = build.secp.range_proof(0, value, blinding, commit);
+		let rproof = build.secp.range_proof_with_nonce(0, value, blinding, commit, nonce);
 		(tx.with_output(Output {
 			features: DEFAULT_OUTPUT,
 			commit: commit,
@@ -142,6 +148,14 @@ pub fn with_fee(fee: u64) -> Box<Append> {
 	Box::new(move |_build, (tx, sum)| -> (Transaction, BlindSum) { (tx.with_fee(fee), sum) })
 }
 
+/// Sets the lock height on the transaction being built, it won't be valid in
+/// blocks below that height.
+pub fn with_lock_height(lock_height: u64) -> Box<Append> {
+	Box::new(move |_build, (tx, sum)| -> (Transaction, BlindSum) {
+		(tx.with_lock_height(lock_height), sum)
+	})
+}
+
 /// Sets a known excess value on the transaction being built. Usually used in
 /// combination with the initial_tx function when a new transaction is built
 /// by adding to a pre-existing one.
@@ -173,19 +187,13 @@ pub fn transaction(elems: Vec<Box<Append>>) -> Result<(Transaction, SecretKey),
 	                                      |acc, elem| elem(&mut ctx, acc));
 
 	let blind_sum = sum.sum(&ctx.secp)?;
-	let msg = secp::Message::from_slice(&u64_to_32bytes(tx.fee))?;
-	let sig = ctx.secp.sign(&msg, &blind_sum)?;
-	tx.excess_sig = sig.serialize_der(&ctx.secp);
+	let msg = kernel_sig_msg(tx.fee, tx.lock_height)?;
+	let sig = ctx.secp.sign_schnorr(&msg, &blind_sum)?;
+	tx.excess_sig = sig.serialize();
 
 	Ok((tx, blind_sum))
 }
 
-fn u64_to_32bytes(n: u64) -> [u8; 32] {
-	let mut bytes = [0; 32];
-	BigEndian::write_u64(&mut bytes[24..32], n);
-	bytes
-}
-
 
 // Just a simple test, most exhaustive tests in the core mod.rs.
 #[cfg(test)]
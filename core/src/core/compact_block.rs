@@ -0,0 +1,263 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compact blocks, relaying a block as its header and short ids of its
+//! kernels. Peers rebuild the full block from the transactions they already
+//! have in their pool.
+
+use std::collections::{HashMap, HashSet};
+
+use secp::{self, Secp256k1};
+
+use core::{Block, BlockHeader, Output, Transaction, TxKernel, COINBASE_KERNEL, COINBASE_OUTPUT};
+use core::hash::{Hash, HashWriter, Hashed};
+use ser::{self, Readable, Reader, Writeable, Writer};
+
+/// Size of a kernel short id, in bytes
+pub const SHORT_ID_SIZE: usize = 6;
+
+/// Short identifier of a kernel within a block. Only relies on the kernel
+/// signature and fee, which a transaction has as well, salted with the block
+/// hash so collisions can't be crafted ahead of time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShortId([u8; SHORT_ID_SIZE]);
+
+impl ShortId {
+	/// Short id of the kernel with the provided signature and fee, in the block
+	/// with the provided hash.
+	pub fn new(block_hash: &Hash, excess_sig: &Vec<u8>, fee: u64) -> ShortId {
+		let mut hasher = HashWriter::default();
+		// writing to a hasher can't fail
+		block_hash.write(&mut hasher).unwrap();
+		hasher.write_bytes(excess_sig).unwrap();
+		hasher.write_u64(fee).unwrap();
+
+		let h = hasher.into_hash();
+		let mut id = [0; SHORT_ID_SIZE];
+		id.copy_from_slice(&h[..SHORT_ID_SIZE]);
+		ShortId(id)
+	}
+}
+
+impl Writeable for ShortId {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_fixed_bytes(&self.0.to_vec())
+	}
+}
+
+impl Readable for ShortId {
+	fn read(reader: &mut Reader) -> Result<ShortId, ser::Error> {
+		let v = try!(reader.read_fixed_bytes(SHORT_ID_SIZE));
+		let mut id = [0; SHORT_ID_SIZE];
+		id.copy_from_slice(&v[..]);
+		Ok(ShortId(id))
+	}
+}
+
+/// A block without its transactions, only the coinbase outputs and kernels
+/// are included in full. All kernels are referenced by their short id, in the
+/// order they have in the block.
+pub struct CompactBlock {
+	/// The header of the block
+	pub header: BlockHeader,
+	/// Coinbase outputs of the block
+	pub out_full: Vec<Output>,
+	/// Coinbase kernels of the block
+	pub kern_full: Vec<TxKernel>,
+	/// Short ids of all the kernels of the block
+	pub kern_ids: Vec<ShortId>,
+}
+
+impl Writeable for CompactBlock {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		try!(self.header.write(writer));
+		ser_multiwrite!(writer,
+		                [write_u64, self.out_full.len() as u64],
+		                [write_u64, self.kern_full.len() as u64],
+		                [write_u64, self.kern_ids.len() as u64]);
+
+		for out in &self.out_full {
+			try!(out.write(writer));
+		}
+		for kern in &self.kern_full {
+			try!(kern.write(writer));
+		}
+		for id in &self.kern_ids {
+			try!(id.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable for CompactBlock {
+	fn read(reader: &mut Reader) -> Result<CompactBlock, ser::Error> {
+		let header = try!(BlockHeader::read(reader));
+		let (out_len, kern_len, id_len) = ser_multiread!(reader, read_u64, read_u64, read_u64);
+
+		let out_full = try!((0..out_len).map(|_| Output::read(reader)).collect());
+		let kern_full = try!((0..kern_len).map(|_| TxKernel::read(reader)).collect());
+		let kern_ids = try!((0..id_len).map(|_| ShortId::read(reader)).collect());
+
+		Ok(CompactBlock {
+			header: header,
+			out_full: out_full,
+			kern_full: kern_full,
+			kern_ids: kern_ids,
+		})
+	}
+}
+
+impl CompactBlock {
+	/// Builds the compact version of the provided block.
+	pub fn from_block(b: &Block) -> CompactBlock {
+		let bh = b.hash();
+		CompactBlock {
+			header: BlockHeader {
+				pow: b.header.pow.clone(),
+				difficulty: b.header.difficulty.clone(),
+				total_difficulty: b.header.total_difficulty.clone(),
+				..b.header
+			},
+			out_full: b.outputs
+				.iter()
+				.filter(|out| out.features.intersects(COINBASE_OUTPUT))
+				.map(|&out| out)
+				.collect(),
+			kern_full: b.kernels
+				.iter()
+				.filter(|k| k.features.intersects(COINBASE_KERNEL))
+				.map(|k| k.clone())
+				.collect(),
+			kern_ids: b.kernels.iter().map(|k| k.short_id(&bh)).collect(),
+		}
+	}
+
+	/// Hash of the block, computed using only the header
+	pub fn hash(&self) -> Hash {
+		self.header.hash()
+	}
+
+	/// Rebuilds the full block out of the provided transactions, typically the
+	/// ones in our pool. Returns None if some kernels couldn't be found or if
+	/// the resulting inputs and outputs aren't the ones the header commits to,
+	/// in which case the full block has to be requested instead.
+	pub fn hydrate(&self, txs: Vec<&Transaction>) -> Option<Block> {
+		let bh = self.hash();
+		let ids = self.kern_ids.iter().cloned().collect::<HashSet<_>>();
+		if ids.len() != self.kern_ids.len() {
+			// colliding short ids can't be told apart
+			return None;
+		}
+
+		let mut kernels_by_id = HashMap::new();
+		for kern in &self.kern_full {
+			kernels_by_id.insert(kern.short_id(&bh), kern.clone());
+		}
+		let mut inputs = vec![];
+		let mut outputs = self.out_full.clone();
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		for tx in txs {
+			let id = tx.kernel_short_id(&bh);
+			if !ids.contains(&id) || kernels_by_id.contains_key(&id) {
+				continue;
+			}
+			if let Ok(kern) = tx.verify_sig(&secp) {
+				kernels_by_id.insert(id, kern);
+				inputs.append(&mut tx.inputs.clone());
+				outputs.append(&mut tx.outputs.clone());
+			}
+		}
+
+		let mut kernels = vec![];
+		for id in &self.kern_ids {
+			match kernels_by_id.get(id) {
+				Some(kern) => kernels.push(kern.clone()),
+				None => return None,
+			}
+		}
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+
+		// compacting recomputes the Merkle root of the inputs and outputs, which
+		// has to be the one of the header
+		let b = Block {
+				header: BlockHeader {
+					pow: self.header.pow.clone(),
+					difficulty: self.header.difficulty.clone(),
+					total_difficulty: self.header.total_difficulty.clone(),
+					..self.header
+				},
+				inputs: inputs,
+				outputs: outputs,
+				kernels: kernels,
+			}
+			.compact();
+		if b.header.tx_merkle != self.header.tx_merkle {
+			return None;
+		}
+		Some(b)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::build::{self, input, output, with_fee};
+	use ser;
+
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+	use rand::os::OsRng;
+
+	#[test]
+	fn compact_block_hydrate() {
+		let mut rng = OsRng::new().unwrap();
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let skey = SecretKey::new(&secp, &mut rng);
+
+		let (tx1, _) = build::transaction(vec![
+			input(10, SecretKey::new(&secp, &mut rng)),
+			output(8, SecretKey::new(&secp, &mut rng)),
+			with_fee(2),
+		]).unwrap();
+		let (tx2, _) = build::transaction(vec![
+			input(20, SecretKey::new(&secp, &mut rng)),
+			output(17, SecretKey::new(&secp, &mut rng)),
+			with_fee(3),
+		]).unwrap();
+		let b = Block::new(&BlockHeader::default(), vec![&tx1, &tx2], skey).unwrap();
+
+		// goes through serialization with only the coinbase in full
+		let cb = CompactBlock::from_block(&b);
+		let mut vec = Vec::new();
+		ser::serialize(&mut vec, &cb).expect("serialization failed");
+		let cb: CompactBlock = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(cb.hash(), b.hash());
+		assert_eq!(cb.out_full.len(), 1);
+		assert_eq!(cb.kern_full.len(), 1);
+		assert_eq!(cb.kern_ids.len(), 3);
+
+		// a missing transaction can't be made up for
+		assert!(cb.hydrate(vec![&tx1]).is_none());
+
+		let hb = cb.hydrate(vec![&tx2, &tx1]).unwrap();
+		assert_eq!(hb.hash(), b.hash());
+		assert_eq!(hb.inputs.len(), b.inputs.len());
+		assert_eq!(hb.outputs.len(), b.outputs.len());
+		for (hk, k) in hb.kernels.iter().zip(b.kernels.iter()) {
+			assert_eq!(hk.excess, k.excess);
+		}
+		hb.validate(&secp).unwrap();
+	}
+}
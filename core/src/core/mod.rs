@@ -16,8 +16,10 @@
 
 pub mod block;
 pub mod build;
+pub mod compact_block;
 pub mod hash;
 pub mod pmmr;
+pub mod snapshot;
 pub mod sumtree;
 pub mod target;
 pub mod transaction;
@@ -31,6 +33,7 @@ use secp::{self, Secp256k1};
 use secp::pedersen::*;
 
 pub use self::block::{Block, BlockHeader, DEFAULT_BLOCK};
+pub use self::compact_block::CompactBlock;
 pub use self::transaction::{Transaction, Input, Output, TxKernel, COINBASE_KERNEL,
                             COINBASE_OUTPUT, DEFAULT_OUTPUT};
 use self::hash::{Hash, Hashed, ZERO_HASH};
@@ -241,7 +244,7 @@ mod test {
 	use ser;
 	use rand::os::OsRng;
 	use core::build::{self, input, output, input_rand, output_rand, with_fee, initial_tx,
-	                  with_excess};
+	                  with_excess, with_lock_height};
 
 	fn new_secp() -> Secp256k1 {
 		secp::Secp256k1::with_caps(secp::ContextFlag::Commit)
@@ -322,6 +325,34 @@ mod test {
 		assert!(info.max == u64::max_value());
 	}
 
+	#[test]
+	fn tx_lock_height() {
+		let ref secp = new_secp();
+
+		let (tx, _) = build::transaction(vec![input_rand(10),
+		                                      output_rand(8),
+		                                      with_fee(2),
+		                                      with_lock_height(5)])
+			.unwrap();
+		let kernel = tx.verify_sig(&secp).unwrap();
+		assert_eq!(kernel.lock_height, 5);
+		kernel.verify(&secp).unwrap();
+
+		// the lock height survives serialization
+		let mut vec = Vec::new();
+		ser::serialize(&mut vec, &tx).expect("serialization failed");
+		let dtx: Transaction = ser::deserialize(&mut &vec[..]).unwrap();
+		assert_eq!(dtx.lock_height, 5);
+
+		// and is covered by the signature
+		let mut unlocked = tx.clone();
+		unlocked.lock_height = 0;
+		assert!(unlocked.verify_sig(&secp).is_err());
+		let mut unlocked_kernel = kernel.clone();
+		unlocked_kernel.lock_height = 0;
+		assert!(unlocked_kernel.verify(&secp).is_err());
+	}
+
 	#[test]
 	fn tx_hash_diff() {
 		let btx1 = tx2i1o();
@@ -364,6 +395,41 @@ mod test {
 		tx_final.validate(&secp).unwrap();
 	}
 
+	/// Same exchange, where each party only communicates its public nonce and
+	/// partial signature, keeping its blinding factors to itself.
+	#[test]
+	fn tx_build_interactive_exchange() {
+		let ref secp = new_secp();
+		let mut rng = OsRng::new().unwrap();
+
+		// Alice builds her part of the transaction, with change
+		let (tx_alice, alice_sum) =
+			build::transaction(vec![input_rand(4), input_rand(3), output_rand(1), with_fee(1)])
+				.unwrap();
+		let msg = transaction::kernel_sig_msg(tx_alice.fee, tx_alice.lock_height).unwrap();
+		let (alice_secnonce, alice_pubnonce) = secp.generate_schnorr_nonce_pair(&msg, &alice_sum)
+			.unwrap();
+
+		// Bob adds his output and signs his part with Alice's public nonce
+		let bob_key = SecretKey::new(secp, &mut rng);
+		let (mut tx_final, _) = build::transaction(vec![initial_tx(tx_alice), output(5, bob_key)])
+			.unwrap();
+		let (bob_secnonce, bob_pubnonce) = secp.generate_schnorr_nonce_pair(&msg, &bob_key).unwrap();
+		let bob_sig = secp.partial_sign_schnorr(&msg, &bob_key, &bob_secnonce, &alice_pubnonce)
+			.unwrap();
+
+		// neither partial signature is enough on its own
+		tx_final.excess_sig = bob_sig.serialize();
+		assert!(tx_final.validate(&secp).is_err());
+
+		// Alice completes the signature
+		let alice_sig = secp.partial_sign_schnorr(&msg, &alice_sum, &alice_secnonce, &bob_pubnonce)
+			.unwrap();
+		let sig = secp.combine_schnorr_partials(&[alice_sig, bob_sig]).unwrap();
+		tx_final.excess_sig = sig.serialize();
+		tx_final.validate(&secp).unwrap();
+	}
+
 	#[test]
 	fn reward_empty_block() {
 		let mut rng = OsRng::new().unwrap();
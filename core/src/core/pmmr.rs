@@ -54,7 +54,7 @@ pub trait Summable {
 
 	/// Length of the Sum type when serialized. Can be used as a hint by
 	/// underlying storages.
-	fn sum_len(&self) -> usize;
+	fn sum_len() -> usize;
 }
 
 /// An empty sum that takes no space, to store elements that do not need summing
@@ -81,17 +81,24 @@ impl Writeable for NullSum {
 }
 
 /// Wrapper for a type that allows it to be inserted in a tree without summing
-pub struct NoSum<T>(T);
+#[derive(Debug, Clone)]
+pub struct NoSum<T>(pub T);
 impl<T> Summable for NoSum<T> {
 	type Sum = NullSum;
 	fn sum(&self) -> NullSum {
 		NullSum
 	}
-	fn sum_len(&self) -> usize {
+	fn sum_len() -> usize {
 		return 0;
 	}
 }
 
+impl<T> Writeable for NoSum<T> where T: Writeable {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		self.0.write(writer)
+	}
+}
+
 /// A utility type to handle (Hash, Sum) pairs more conveniently. The addition
 /// of two HashSums is the (Hash(h1|h2), h1 + h2) HashSum.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -115,6 +122,13 @@ impl<T> HashSum<T> where T: Summable + Writeable {
 	}
 }
 
+impl<T> HashSum<T> where T: Summable {
+	/// Length of a HashSum when serialized, the hash followed by the sum.
+	pub fn serialized_len() -> usize {
+		32 + T::sum_len()
+	}
+}
+
 impl<T> Readable for HashSum<T> where T: Summable {
 	fn read(r: &mut Reader) -> Result<HashSum<T>, ser::Error> {
 		Ok(HashSum {
@@ -150,6 +164,26 @@ pub trait Backend<T> where T: Summable {
 	fn get(&self, position: u64) -> Option<HashSum<T>>;
 	/// Remove HashSums by insertion position
 	fn remove(&self, positions: Vec<u64>);
+	/// Rewind the backend to the provided position, as if all HashSums after
+	/// it had never been appended.
+	fn rewind(&self, position: u64);
+}
+
+/// Backends shared by reference can be used directly, so the owner can keep
+/// handling their storage (syncing, compacting) while a PMMR is using them.
+impl<'a, T, B> Backend<T> for &'a B where T: Summable, B: Backend<T> {
+	fn append(&self, data: Vec<HashSum<T>>) {
+		(**self).append(data)
+	}
+	fn get(&self, position: u64) -> Option<HashSum<T>> {
+		(**self).get(position)
+	}
+	fn remove(&self, positions: Vec<u64>) {
+		(**self).remove(positions)
+	}
+	fn rewind(&self, position: u64) {
+		(**self).rewind(position)
+	}
 }
 
 /// Prunable Merkle Mountain Range implementation. All positions within the tree
@@ -177,6 +211,16 @@ impl<T, B> PMMR<T, B> where T: Summable + Writeable + Debug + Clone, B: Backend<
 		}
 	}
 
+	/// Build a prunable Merkle Mountain Range on top of a backend that already
+	/// holds the provided number of nodes, as when reopening a persistent one.
+	pub fn at(backend: B, last_pos: u64) -> PMMR<T, B> {
+		PMMR {
+			last_pos: last_pos,
+			backend: backend,
+			summable: PhantomData,
+		}
+	}
+
 	/// Computes the root of the MMR. Find all the peaks in the current
 	/// tree and "bags" them to get a single peak.
 	pub fn root(&self) -> HashSum<T> {
@@ -194,6 +238,70 @@ impl<T, B> PMMR<T, B> where T: Summable + Writeable + Debug + Clone, B: Backend<
 		ret.expect("no root, invalid tree")
 	}
 
+	/// Builds a proof that the leaf at the provided position is included in
+	/// the tree, as it currently stands. Returns None if the position isn't
+	/// the one of a leaf or if any node the proof needs has been pruned.
+	pub fn merkle_proof(&self, position: u64) -> Option<MerkleProof<T>> {
+		if position == 0 || position > self.last_pos ||
+			bintree_postorder_height(position) > 0
+		{
+			return None;
+		}
+		let peaks_pos = peaks(self.last_pos);
+
+		// climb up to the peak our leaf is under, collecting siblings on the way
+		let mut path = vec![];
+		let mut current = position;
+		let mut height = 0;
+		while !peaks_pos.contains(&current) {
+			let sibling: u64;
+			let parent: u64;
+			if bintree_postorder_height(current + 1) > height {
+				sibling = bintree_jump_left_sibling(current);
+				parent = current + 1;
+			} else {
+				sibling = bintree_jump_right_sibling(current);
+				parent = sibling + 1;
+			}
+			match self.backend.get(sibling) {
+				Some(hsum) => path.push(hsum),
+				None => return None,
+			}
+			current = parent;
+			height += 1;
+		}
+
+		// peaks on the left get bagged together, the ones on the right are
+		// bagged by the verifier after our own peak
+		let peak_idx = peaks_pos.iter().position(|&p| p == current).unwrap();
+		let mut left_peaks: Option<HashSum<T>> = None;
+		for &pi in &peaks_pos[..peak_idx] {
+			let peak = match self.backend.get(pi) {
+				Some(hsum) => hsum,
+				None => return None,
+			};
+			left_peaks = match left_peaks {
+				None => Some(peak),
+				Some(lhsum) => Some(lhsum + peak),
+			};
+		}
+		let mut right_peaks = vec![];
+		for &pi in &peaks_pos[(peak_idx + 1)..] {
+			match self.backend.get(pi) {
+				Some(hsum) => right_peaks.push(hsum),
+				None => return None,
+			}
+		}
+
+		Some(MerkleProof {
+			mmr_size: self.last_pos,
+			position: position,
+			path: path,
+			left_peaks: left_peaks,
+			right_peaks: right_peaks,
+		})
+	}
+
 	/// Push a new Summable element in the MMR. Computes new related peaks at
 	/// the same time if applicable.
 	pub fn push(&mut self, elmt: T) -> u64 {
@@ -273,6 +381,17 @@ impl<T, B> PMMR<T, B> where T: Summable + Writeable + Debug + Clone, B: Backend<
 		self.backend.remove(to_prune);
 	}
 
+	/// Rewind the tree to the provided position, dropping all nodes after it.
+	/// The position has to be the size of the tree at some point in its
+	/// history.
+	pub fn rewind(&mut self, position: u64) {
+		if position >= self.last_pos {
+			return;
+		}
+		self.backend.rewind(position);
+		self.last_pos = position;
+	}
+
 	/// Total size of the tree, including intermediary nodes an ignoring any
 	/// pruning.
 	pub fn unpruned_size(&self) -> u64 {
@@ -280,11 +399,184 @@ impl<T, B> PMMR<T, B> where T: Summable + Writeable + Debug + Clone, B: Backend<
 	}
 }
 
+/// Proof that a leaf is included in a MMR of a given size. Holds the siblings
+/// of all nodes from the leaf up to the peak it's under, as well as the other
+/// peaks, so the root can be rebuilt from the leaf alone and compared.
+#[derive(Debug, Clone)]
+pub struct MerkleProof<T> where T: Summable {
+	/// Size of the MMR the proof was built against
+	pub mmr_size: u64,
+	/// Position of the proven leaf
+	pub position: u64,
+	/// Siblings of the nodes on the path from the leaf to its peak, bottom up
+	pub path: Vec<HashSum<T>>,
+	/// Peaks to the left of the leaf's peak, already bagged together
+	pub left_peaks: Option<HashSum<T>>,
+	/// Peaks to the right of the leaf's peak, left to right
+	pub right_peaks: Vec<HashSum<T>>,
+}
+
+impl<T> MerkleProof<T> where T: Summable + Writeable + Clone {
+	/// Verifies the proof for the provided element against a MMR root. Only
+	/// the root hash is checked, the sums are committed to by leaf hashes.
+	pub fn verify(&self, elmt: T, root: &HashSum<T>) -> bool {
+		if self.position == 0 || self.position > self.mmr_size ||
+			bintree_postorder_height(self.position) > 0
+		{
+			return false;
+		}
+
+		// rebuild the peak of the leaf, the position of each node telling on
+		// which side its sibling is
+		let mut current = HashSum::from_summable(self.position, elmt);
+		let mut pos = self.position;
+		let mut height = 0;
+		for sibling in &self.path {
+			if bintree_postorder_height(pos + 1) > height {
+				current = sibling.clone() + current;
+				pos += 1;
+			} else {
+				current = current + sibling.clone();
+				pos = bintree_jump_right_sibling(pos) + 1;
+			}
+			height += 1;
+		}
+
+		// the rebuilt node has to be a peak with as many peaks on each side as
+		// the proof has
+		let peaks_pos = peaks(self.mmr_size);
+		let peak_idx = match peaks_pos.iter().position(|&p| p == pos) {
+			Some(idx) => idx,
+			None => return false,
+		};
+		if self.left_peaks.is_some() != (peak_idx > 0) ||
+			self.right_peaks.len() != peaks_pos.len() - peak_idx - 1
+		{
+			return false;
+		}
+
+		// and bagging it with the other peaks gets us the root
+		let mut bagged = match self.left_peaks.clone() {
+			Some(lhsum) => lhsum + current,
+			None => current,
+		};
+		for peak in &self.right_peaks {
+			bagged = bagged + peak.clone();
+		}
+		bagged.hash == root.hash
+	}
+}
+
+// A MMR can't be higher or have more peaks than there are bits in its size.
+const MAX_PROOF_NODES: u64 = 64;
+
+impl<T> Writeable for MerkleProof<T> where T: Summable {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.mmr_size)?;
+		writer.write_u64(self.position)?;
+		writer.write_u64(self.path.len() as u64)?;
+		for hsum in &self.path {
+			hsum.write(writer)?;
+		}
+		match self.left_peaks {
+			Some(ref hsum) => {
+				writer.write_u8(1)?;
+				hsum.write(writer)?;
+			}
+			None => writer.write_u8(0)?,
+		}
+		writer.write_u64(self.right_peaks.len() as u64)?;
+		for hsum in &self.right_peaks {
+			hsum.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<T> Readable for MerkleProof<T> where T: Summable {
+	fn read(reader: &mut Reader) -> Result<MerkleProof<T>, ser::Error> {
+		let mmr_size = reader.read_u64()?;
+		let position = reader.read_u64()?;
+		let path_len = reader.read_u64()?;
+		if path_len > MAX_PROOF_NODES {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut path = Vec::with_capacity(path_len as usize);
+		for _ in 0..path_len {
+			path.push(HashSum::read(reader)?);
+		}
+		let left_peaks = match reader.read_u8()? {
+			0 => None,
+			1 => Some(HashSum::read(reader)?),
+			_ => return Err(ser::Error::CorruptedData),
+		};
+		let right_len = reader.read_u64()?;
+		if right_len > MAX_PROOF_NODES {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut right_peaks = Vec::with_capacity(right_len as usize);
+		for _ in 0..right_len {
+			right_peaks.push(HashSum::read(reader)?);
+		}
+		Ok(MerkleProof {
+			mmr_size: mmr_size,
+			position: position,
+			path: path,
+			left_peaks: left_peaks,
+			right_peaks: right_peaks,
+		})
+	}
+}
+
+/// Number of leaves in a MMR of the provided size.
+pub fn n_leaves(size: u64) -> u64 {
+	if size == 0 {
+		return 0;
+	}
+	peaks(size).iter().map(|&p| (1 as u64) << bintree_postorder_height(p)).sum()
+}
+
+/// Size of a MMR holding the provided number of leaves. Each leaf comes with
+/// as many parents as it completes full subtrees.
+pub fn size_from_leaves(n: u64) -> u64 {
+	2 * n - n.count_ones() as u64
+}
+
+/// The nodes on the way from the provided position up to the peak it's under
+/// in a MMR of the provided size, each along with its sibling. The peak
+/// itself isn't included.
+pub fn family_path(pos: u64, last_pos: u64) -> Vec<(u64, u64)> {
+	let mut path = vec![];
+	let mut current = pos;
+	loop {
+		let height = bintree_postorder_height(current);
+		let (sibling, parent) = if bintree_postorder_height(current + 1) > height {
+			let sibling = bintree_jump_left_sibling(current);
+			(sibling, current + 1)
+		} else {
+			let sibling = bintree_jump_right_sibling(current);
+			(sibling, sibling + 1)
+		};
+		if parent > last_pos {
+			break;
+		}
+		path.push((current, sibling));
+		current = parent;
+	}
+	path
+}
+
+/// The children of the node at the provided position, none for a leaf.
+pub fn children(pos: u64) -> Option<(u64, u64)> {
+	bintree_move_down_left(pos).map(|left| (left, pos - 1))
+}
+
 /// Gets the postorder traversal index of all peaks in a MMR given the last
 /// node's position. Starts with the top peak, which is always on the left
 /// side of the range, and navigates toward lower siblings toward the right
-/// of the range.
-fn peaks(num: u64) -> Vec<u64> {
+/// of the range. Returns no peaks at all if the size isn't the one of a
+/// valid MMR.
+pub fn peaks(num: u64) -> Vec<u64> {
 
 	// detecting an invalid mountain range, when siblings exist but no parent
 	// exists
@@ -510,6 +802,30 @@ mod test {
 		assert_eq!(peaks(42), vec![31, 38, 41, 42]);
 	}
 
+	#[test]
+	fn some_families() {
+		assert_eq!(family_path(4, 11), vec![(4, 5), (6, 3)]);
+		assert_eq!(family_path(9, 11), vec![(9, 8)]);
+		assert!(family_path(11, 11).is_empty());
+		assert_eq!(children(7), Some((3, 6)));
+		assert_eq!(children(10), Some((8, 9)));
+		assert_eq!(children(4), None);
+	}
+
+	#[test]
+	fn leaves_and_sizes() {
+		assert_eq!(n_leaves(0), 0);
+		assert_eq!(n_leaves(1), 1);
+		assert_eq!(n_leaves(3), 2);
+		assert_eq!(n_leaves(4), 3);
+		assert_eq!(n_leaves(16), 9);
+		assert_eq!(n_leaves(42), 23);
+		for n in 0..100 {
+			assert_eq!(n_leaves(size_from_leaves(n)), n);
+		}
+		assert_eq!(size_from_leaves(9), 16);
+	}
+
 	#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 	struct TestElem([u32; 4]);
 	impl Summable for TestElem {
@@ -520,8 +836,8 @@ mod test {
 			self.0[0] as u64 * 0x1000 + self.0[1] as u64 * 0x100 + self.0[2] as u64 * 0x10 +
 				self.0[3] as u64
 		}
-		fn sum_len(&self) -> usize {
-			4
+		fn sum_len() -> usize {
+			8
 		}
 	}
 
@@ -553,6 +869,10 @@ mod test {
 				elems[(n-1) as usize] = None
 			}
 		}
+		fn rewind(&self, position: u64) {
+			let mut elems = self.elems.lock().unwrap();
+			elems.truncate(position as usize);
+		}
 	}
 	impl VecBackend {
 		fn used_size(&self) -> usize {
@@ -641,6 +961,96 @@ mod test {
 		assert_eq!(pmmr.unpruned_size(), 16);
 	}
 
+	#[test]
+	fn pmmr_rewind() {
+		let elems = [
+			TestElem([0, 0, 0, 1]),
+			TestElem([0, 0, 0, 2]),
+			TestElem([0, 0, 0, 3]),
+			TestElem([0, 0, 0, 4]),
+			TestElem([0, 0, 0, 5]),
+		];
+
+		let ba = VecBackend{elems: Arc::new(Mutex::new(vec![]))};
+		let mut pmmr = PMMR::new(ba.clone());
+		for elem in &elems[..3] {
+			pmmr.push(*elem);
+		}
+		let root3 = pmmr.root();
+		let size3 = pmmr.unpruned_size();
+		for elem in &elems[3..] {
+			pmmr.push(*elem);
+		}
+
+		pmmr.rewind(size3);
+		assert_eq!(pmmr.unpruned_size(), 4);
+		assert_eq!(ba.used_size(), 4);
+		assert_eq!(pmmr.root(), root3);
+
+		// pushing the same elements again gets us back where we were
+		for elem in &elems[3..] {
+			pmmr.push(*elem);
+		}
+		assert_eq!(pmmr.unpruned_size(), size_from_leaves(5));
+	}
+
+	#[test]
+	fn pmmr_merkle_proof() {
+		let elems = [
+			TestElem([0, 0, 0, 1]),
+			TestElem([0, 0, 0, 2]),
+			TestElem([0, 0, 0, 3]),
+			TestElem([0, 0, 0, 4]),
+			TestElem([0, 0, 0, 5]),
+			TestElem([0, 0, 0, 6]),
+			TestElem([0, 0, 0, 7]),
+			TestElem([0, 0, 0, 8]),
+			TestElem([1, 0, 0, 0]),
+			TestElem([1, 0, 0, 1]),
+			TestElem([1, 0, 0, 2]),
+		];
+
+		let ba = VecBackend{elems: Arc::new(Mutex::new(vec![]))};
+		let mut pmmr = PMMR::new(ba.clone());
+		let mut positions = vec![];
+		for elem in &elems[..] {
+			positions.push(pmmr.push(*elem));
+		}
+		let root = pmmr.root();
+
+		// every leaf can be proven, and only with its own element
+		for (n, &pos) in positions.iter().enumerate() {
+			let proof = pmmr.merkle_proof(pos).unwrap();
+			assert!(proof.verify(elems[n], &root), "proof failed for {}", pos);
+			assert!(!proof.verify(elems[(n + 1) % elems.len()], &root));
+
+			// serialization roundtrip
+			let mut vec = Vec::new();
+			ser::serialize(&mut vec, &proof).unwrap();
+			let proof2: MerkleProof<TestElem> = ser::deserialize(&mut &vec[..]).unwrap();
+			assert!(proof2.verify(elems[n], &root));
+		}
+
+		// proofs are against a given root
+		let proof = pmmr.merkle_proof(1).unwrap();
+		pmmr.push(TestElem([1, 0, 0, 3]));
+		assert!(!proof.verify(elems[0], &pmmr.root()));
+		assert!(pmmr.merkle_proof(1).unwrap().verify(elems[0], &pmmr.root()));
+
+		// a proof moved to another position doesn't hold
+		let mut proof = pmmr.merkle_proof(1).unwrap();
+		proof.position = 2;
+		assert!(!proof.verify(elems[0], &pmmr.root()));
+
+		// non-leaf nodes, out of range positions and pruned siblings can't be proven
+		assert!(pmmr.merkle_proof(3).is_none());
+		assert!(pmmr.merkle_proof(0).is_none());
+		assert!(pmmr.merkle_proof(100).is_none());
+		pmmr.prune(2);
+		assert!(pmmr.merkle_proof(1).is_none());
+		assert!(pmmr.merkle_proof(4).is_some());
+	}
+
 	#[test]
 	fn pmmr_prune() {
 		let elems = [
@@ -0,0 +1,110 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Snapshot of the chain state at a given block, as sent over the network to
+//! nodes that would rather not replay the whole history.
+
+use core::{Output, TxKernel};
+use core::hash::Hash;
+use ser::{self, Readable, Reader, Writeable, Writer};
+
+/// State of the output, range proof and kernel sum trees at a given block.
+/// Holds enough for a node to check the trees against the roots committed to
+/// by the block header and to validate blocks built on top of it.
+///
+/// Outputs and range proofs are pushed together so both trees always share
+/// the same shape and size. Pruned nodes are absent from the node lists.
+#[derive(Debug, Clone)]
+pub struct SumTreeSnapshot {
+	/// Hash of the block the snapshot was taken at
+	pub block_hash: Hash,
+	/// Size of the output and range proof trees
+	pub output_mmr_size: u64,
+	/// Nodes of the output tree that haven't been pruned, by position
+	pub output_nodes: Vec<(u64, Hash)>,
+	/// Nodes of the range proof tree that haven't been pruned, by position
+	pub rproof_nodes: Vec<(u64, Hash)>,
+	/// Unspent outputs, along with their position in the output tree
+	pub outputs: Vec<(u64, Output)>,
+	/// Height of the block that created each unspent output, in the same
+	/// order, so coinbase maturity can still be checked
+	pub output_heights: Vec<u64>,
+	/// All the kernels, in the order of the kernel tree
+	pub kernels: Vec<TxKernel>,
+}
+
+impl Writeable for SumTreeSnapshot {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		try!(self.block_hash.write(writer));
+		ser_multiwrite!(writer,
+		                [write_u64, self.output_mmr_size],
+		                [write_u64, self.output_nodes.len() as u64],
+		                [write_u64, self.rproof_nodes.len() as u64],
+		                [write_u64, self.outputs.len() as u64],
+		                [write_u64, self.kernels.len() as u64]);
+
+		for &(pos, ref h) in self.output_nodes.iter().chain(self.rproof_nodes.iter()) {
+			try!(writer.write_u64(pos));
+			try!(h.write(writer));
+		}
+		for (&(pos, ref out), height) in self.outputs.iter().zip(self.output_heights.iter()) {
+			try!(writer.write_u64(pos));
+			try!(writer.write_u64(*height));
+			try!(out.write(writer));
+		}
+		for kernel in &self.kernels {
+			try!(kernel.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable for SumTreeSnapshot {
+	fn read(reader: &mut Reader) -> Result<SumTreeSnapshot, ser::Error> {
+		let block_hash = try!(Hash::read(reader));
+		let (output_mmr_size, onodes_len, rnodes_len, outputs_len, kernels_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_u64, read_u64, read_u64);
+
+		// no pre-allocation, lengths are only checked by running out of data
+		let mut output_nodes = vec![];
+		for _ in 0..onodes_len {
+			output_nodes.push((try!(reader.read_u64()), try!(Hash::read(reader))));
+		}
+		let mut rproof_nodes = vec![];
+		for _ in 0..rnodes_len {
+			rproof_nodes.push((try!(reader.read_u64()), try!(Hash::read(reader))));
+		}
+		let mut outputs = vec![];
+		let mut output_heights = vec![];
+		for _ in 0..outputs_len {
+			let pos = try!(reader.read_u64());
+			output_heights.push(try!(reader.read_u64()));
+			outputs.push((pos, try!(Output::read(reader))));
+		}
+		let mut kernels = vec![];
+		for _ in 0..kernels_len {
+			kernels.push(try!(TxKernel::read(reader)));
+		}
+
+		Ok(SumTreeSnapshot {
+			block_hash: block_hash,
+			output_mmr_size: output_mmr_size,
+			output_nodes: output_nodes,
+			rproof_nodes: rproof_nodes,
+			outputs: outputs,
+			output_heights: output_heights,
+			kernels: kernels,
+		})
+	}
+}
@@ -15,12 +15,17 @@
 //! Transactions
 
 use byteorder::{ByteOrder, BigEndian};
-use secp::{self, Secp256k1, Message, Signature};
+use std::cmp;
+use secp::{self, Secp256k1, Message};
+use secp::key::SecretKey;
+use secp::schnorr::Signature;
 use secp::pedersen::{RangeProof, Commitment};
 
+use consensus;
 use core::Committed;
 use core::MerkleRow;
-use core::hash::{Hash, Hashed};
+use core::compact_block::ShortId;
+use core::hash::{Hash, HashWriter, Hashed};
 use ser::{self, Reader, Writer, Readable, Writeable};
 
 bitflags! {
@@ -35,8 +40,8 @@ bitflags! {
 
 /// A proof that a transaction sums to zero. Includes both the transaction's
 /// Pedersen commitment and the signature, that guarantees that the commitments
-/// amount to zero. The signature signs the fee, which is retained for
-/// signature validation.
+/// amount to zero. The signature signs the fee and the lock height, which are
+/// retained for signature validation.
 #[derive(Debug, Clone)]
 pub struct TxKernel {
 	/// Options for a kernel's structure or use
@@ -46,10 +51,13 @@ pub struct TxKernel {
 	/// is hence a valid public key.
 	pub excess: Commitment,
 	/// The signature proving the excess is a valid public key, which signs
-	/// the transaction fee.
+	/// the transaction fee and lock height.
 	pub excess_sig: Vec<u8>,
 	/// Fee originally included in the transaction this proof is for.
 	pub fee: u64,
+	/// Height of the block from which the kernel, and the transaction it's
+	/// for, can be included in the chain.
+	pub lock_height: u64,
 }
 
 impl Writeable for TxKernel {
@@ -58,7 +66,8 @@ impl Writeable for TxKernel {
 		                [write_u8, self.features.bits()],
 		                [write_fixed_bytes, &self.excess],
 		                [write_bytes, &self.excess_sig],
-		                [write_u64, self.fee]);
+		                [write_u64, self.fee],
+		                [write_u64, self.lock_height]);
 		Ok(())
 	}
 }
@@ -71,19 +80,25 @@ impl Readable for TxKernel {
 			excess: Commitment::read(reader)?,
 			excess_sig: reader.read_vec()?,
 			fee: reader.read_u64()?,
+			lock_height: reader.read_u64()?,
 		})
 	}
 }
 
 impl TxKernel {
 	/// Verify the transaction proof validity. Entails handling the commitment
-	/// as a public key and checking the signature verifies with the fee as
-	/// message.
+	/// as a public key and checking the signature verifies with the fee and
+	/// lock height as message.
 	pub fn verify(&self, secp: &Secp256k1) -> Result<(), secp::Error> {
-		let msg = try!(Message::from_slice(&u64_to_32bytes(self.fee)));
+		let msg = try!(kernel_sig_msg(self.fee, self.lock_height));
 		let pubk = try!(self.excess.to_pubkey(secp));
-		let sig = try!(Signature::from_der(secp, &self.excess_sig));
-		secp.verify(&msg, &sig, &pubk)
+		let sig = try!(kernel_sig(&self.excess_sig));
+		secp.verify_schnorr(&msg, &sig, &pubk)
+	}
+
+	/// Short id of the kernel within the block with the provided hash.
+	pub fn short_id(&self, block_hash: &Hash) -> ShortId {
+		ShortId::new(block_hash, &self.excess_sig, self.fee)
 	}
 }
 
@@ -96,8 +111,10 @@ pub struct Transaction {
 	pub outputs: Vec<Output>,
 	/// Fee paid by the transaction.
 	pub fee: u64,
+	/// Height from which the transaction can be included in a block.
+	pub lock_height: u64,
 	/// The signature proving the excess is a valid public key, which signs
-	/// the transaction fee.
+	/// the transaction fee and lock height.
 	pub excess_sig: Vec<u8>,
 }
 
@@ -107,6 +124,7 @@ impl Writeable for Transaction {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
 		ser_multiwrite!(writer,
 		                [write_u64, self.fee],
+		                [write_u64, self.lock_height],
 		                [write_bytes, &self.excess_sig],
 		                [write_u64, self.inputs.len() as u64],
 		                [write_u64, self.outputs.len() as u64]);
@@ -124,14 +142,15 @@ impl Writeable for Transaction {
 /// transaction from a binary stream.
 impl Readable for Transaction {
 	fn read(reader: &mut Reader) -> Result<Transaction, ser::Error> {
-		let (fee, excess_sig, input_len, output_len) =
-			ser_multiread!(reader, read_u64, read_vec, read_u64, read_u64);
+		let (fee, lock_height, excess_sig, input_len, output_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_vec, read_u64, read_u64);
 
 		let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
 		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
 
 		Ok(Transaction {
 			fee: fee,
+			lock_height: lock_height,
 			excess_sig: excess_sig,
 			inputs: inputs,
 			outputs: outputs,
@@ -164,6 +183,7 @@ impl Transaction {
 	pub fn empty() -> Transaction {
 		Transaction {
 			fee: 0,
+			lock_height: 0,
 			excess_sig: vec![],
 			inputs: vec![],
 			outputs: vec![],
@@ -175,6 +195,7 @@ impl Transaction {
 	pub fn new(inputs: Vec<Input>, outputs: Vec<Output>, fee: u64) -> Transaction {
 		Transaction {
 			fee: fee,
+			lock_height: 0,
 			excess_sig: vec![],
 			inputs: inputs,
 			outputs: outputs,
@@ -202,6 +223,23 @@ impl Transaction {
 		Transaction { fee: fee, ..self }
 	}
 
+	/// Builds a new transaction with the provided lock height.
+	pub fn with_lock_height(self, lock_height: u64) -> Transaction {
+		Transaction { lock_height: lock_height, ..self }
+	}
+
+	/// Weight of the transaction used to compute its fee, see the weight
+	/// function.
+	pub fn weight(&self) -> u64 {
+		weight(self.inputs.len(), self.outputs.len())
+	}
+
+	/// Weight of the transaction counted against the max block weight,
+	/// including the kernel it will produce.
+	pub fn block_weight(&self) -> u64 {
+		consensus::block_weight(self.inputs.len(), self.outputs.len(), 1)
+	}
+
 	/// The verification for a MimbleWimble transaction involves getting the
 	/// excess of summing all commitments and using it as a public key
 	/// to verify the embedded signature. The rational is that if the values
@@ -214,18 +252,25 @@ impl Transaction {
 		// pretend the sum is a public key (which it is, being of the form r.G) and
 		// verify the transaction sig with it
 		let pubk = rsum.to_pubkey(secp)?;
-		let msg = Message::from_slice(&u64_to_32bytes(self.fee))?;
-		let sig = Signature::from_der(secp, &self.excess_sig)?;
-		secp.verify(&msg, &sig, &pubk)?;
+		let msg = kernel_sig_msg(self.fee, self.lock_height)?;
+		let sig = kernel_sig(&self.excess_sig)?;
+		secp.verify_schnorr(&msg, &sig, &pubk)?;
 
 		Ok(TxKernel {
 			features: DEFAULT_KERNEL,
 			excess: rsum,
 			excess_sig: self.excess_sig.clone(),
 			fee: self.fee,
+			lock_height: self.lock_height,
 		})
 	}
 
+	/// Short id the kernel of the transaction would have within the block with
+	/// the provided hash.
+	pub fn kernel_short_id(&self, block_hash: &Hash) -> ShortId {
+		ShortId::new(block_hash, &self.excess_sig, self.fee)
+	}
+
 	/// Validates all relevant parts of a fully built transaction. Checks the
 	/// excess value against the signature as well as range proofs for each
 	/// output.
@@ -237,6 +282,14 @@ impl Transaction {
 	}
 }
 
+/// Weight of a transaction with the provided number of inputs and outputs,
+/// used to compute its fee. Inputs reduce the weight and outputs increase it,
+/// which favors transactions shrinking the UTXO set. Never less than 1.
+pub fn weight(input_len: usize, output_len: usize) -> u64 {
+	let tx_weight = -1 * (input_len as i64) + 4 * (output_len as i64) + 1;
+	cmp::max(tx_weight, 1) as u64
+}
+
 /// A transaction input, mostly a reference to an output being spent by the
 /// transaction.
 #[derive(Debug, Copy, Clone)]
@@ -345,8 +398,30 @@ pub fn merkle_inputs_outputs(inputs: &Vec<Input>, outputs: &Vec<Output>) -> Hash
 	MerkleRow::new(all_hs).root()
 }
 
-fn u64_to_32bytes(n: u64) -> [u8; 32] {
+/// Nonce used to build the range proof of an output, derived from its
+/// blinding factor so the owner of the output can rewind the proof to find
+/// its value again.
+pub fn rewind_nonce(blinding: &SecretKey) -> [u8; 32] {
+	let mut hasher = HashWriter::default();
+	// writing to a hasher can't fail
+	hasher.write_fixed_bytes(blinding).unwrap();
+	hasher.into_hash().0
+}
+
+/// Message signed by the kernel excess, committing to the fee and the lock
+/// height.
+pub fn kernel_sig_msg(fee: u64, lock_height: u64) -> Result<Message, secp::Error> {
 	let mut bytes = [0; 32];
-	BigEndian::write_u64(&mut bytes[24..32], n);
-	bytes
+	BigEndian::write_u64(&mut bytes[16..24], lock_height);
+	BigEndian::write_u64(&mut bytes[24..32], fee);
+	Message::from_slice(&bytes)
 }
+
+/// Reads the Schnorr signature of a kernel out of its serialized form.
+pub fn kernel_sig(excess_sig: &Vec<u8>) -> Result<Signature, secp::Error> {
+	if excess_sig.len() != secp::constants::SCHNORR_SIGNATURE_SIZE {
+		return Err(secp::Error::InvalidSignature);
+	}
+	Ok(Signature::deserialize(excess_sig))
+}
+
@@ -18,7 +18,7 @@ use time;
 
 use core;
 use consensus::MINIMUM_DIFFICULTY;
-use core::hash::Hashed;
+use core::hash::{Hashed, ZERO_HASH};
 use core::target::Difficulty;
 use global;
 
@@ -38,7 +38,9 @@ pub fn genesis() -> core::Block {
 			},
 			difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
 			total_difficulty: Difficulty::from_num(MINIMUM_DIFFICULTY),
-			utxo_merkle: [].hash(),
+			utxo_root: ZERO_HASH,
+			range_proof_root: ZERO_HASH,
+			kernel_root: ZERO_HASH,
 			tx_merkle: [].hash(),
 			features: core::DEFAULT_BLOCK,
 			nonce: global::get_genesis_nonce(),
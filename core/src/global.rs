@@ -24,6 +24,8 @@
 use std::sync::{RwLock};
 use consensus::PROOFSIZE;
 use consensus::DEFAULT_SIZESHIFT;
+use consensus::CUT_THROUGH_HORIZON;
+use consensus::COINBASE_MATURITY;
 
 /// Define these here, as they should be developer-set, not really tweakable
 /// by users
@@ -34,6 +36,14 @@ pub const AUTOMATED_TESTING_SIZESHIFT:u8 = 10;
 /// Automated testing proof size
 pub const AUTOMATED_TESTING_PROOF_SIZE:usize = 4;
 
+/// Automated testing cut-through horizon, short enough for tests to go
+/// beyond it
+pub const AUTOMATED_TESTING_CUT_THROUGH_HORIZON:u32 = 5;
+
+/// Automated testing coinbase maturity, short enough for tests to spend
+/// their rewards quickly
+pub const AUTOMATED_TESTING_COINBASE_MATURITY:u64 = 3;
+
 /// User testing sizeshift
 pub const USER_TESTING_SIZESHIFT:u8 = 16;
 
@@ -84,6 +94,26 @@ pub fn proofsize() -> usize {
 	}
 }
 
+/// The cut-through horizon
+pub fn cut_through_horizon() -> u32 {
+	let param_ref=MINING_PARAMETER_MODE.read().unwrap();
+	match *param_ref {
+		MiningParameterMode::AutomatedTesting => AUTOMATED_TESTING_CUT_THROUGH_HORIZON,
+		MiningParameterMode::UserTesting => CUT_THROUGH_HORIZON,
+		MiningParameterMode::Production => CUT_THROUGH_HORIZON,
+	}
+}
+
+/// The coinbase maturity
+pub fn coinbase_maturity() -> u64 {
+	let param_ref=MINING_PARAMETER_MODE.read().unwrap();
+	match *param_ref {
+		MiningParameterMode::AutomatedTesting => AUTOMATED_TESTING_COINBASE_MATURITY,
+		MiningParameterMode::UserTesting => COINBASE_MATURITY,
+		MiningParameterMode::Production => COINBASE_MATURITY,
+	}
+}
+
 /// Are we in automated testing mode?
 pub fn is_automated_testing_mode() -> bool {
 	let param_ref=MINING_PARAMETER_MODE.read().unwrap();
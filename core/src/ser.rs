@@ -287,6 +287,12 @@ impl Readable for RangeProof {
 	}
 }
 
+impl Writeable for RangeProof {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_bytes(self)
+	}
+}
+
 /// Utility wrapper for an underlying byte Writer. Defines higher level methods
 /// to write numbers, byte vectors, hashes, etc.
 struct BinWriter<'a> {
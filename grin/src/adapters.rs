@@ -12,14 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::VecDeque;
 use std::net::SocketAddr;
-use std::sync::{Arc, RwLock};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
 use std::io;
 
 use chain::{self, ChainAdapter};
 use core::core::{self, Output};
 use core::core::hash::{Hash, Hashed};
+use core::core::snapshot::SumTreeSnapshot;
 use core::core::target::Difficulty;
 use p2p::{self, NetAdapter, Server, PeerStore, PeerData, State};
 use pool;
@@ -27,7 +29,11 @@ use secp::pedersen::Commitment;
 use util::OneTime;
 use store;
 use sync;
-use core::global::{MiningParameterMode,MINING_PARAMETER_MODE};
+use core::global::{self, MiningParameterMode,MINING_PARAMETER_MODE};
+
+/// How many blocks below the cut-through horizon of our head we still serve
+/// sum trees snapshots at, for peers a little behind us
+const MAX_SNAPSHOT_LAG: u64 = 10;
 
 /// Implementation of the NetAdapter for the blockchain. Gets notified when new
 /// blocks and transactions are received and forwards to the chain and pool
@@ -38,6 +44,10 @@ pub struct NetToChainAdapter {
 	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
 
 	syncer: OneTime<Arc<sync::Syncer>>,
+
+	// last sum trees snapshot served, peers syncing around the same time
+	// all asking for the same one
+	sumtrees_snapshot: Mutex<Option<SumTreeSnapshot>>,
 }
 
 impl NetAdapter for NetToChainAdapter {
@@ -50,11 +60,31 @@ impl NetAdapter for NetToChainAdapter {
 			debug_name: "p2p".to_string(),
 			identifier: "?.?.?.?".to_string(),
 		};
-		if let Err(e) = self.tx_pool.write().unwrap().add_to_memory_pool(source, tx) {
-			error!("Transaction rejected: {:?}", e);
-			return Err(p2p::Error::Invalid);
+		let tx_hash = pool::graph::transaction_identifier(&tx);
+		if self.tx_pool.read().unwrap().transactions.contains_key(&tx_hash) {
+			// already relayed by us or another peer, nothing more to do
+			return Ok(());
+		}
+		let res = self.tx_pool.write().unwrap().add_to_memory_pool(source, tx);
+		pool::TransactionPool::notify_accepted(&self.tx_pool);
+		match res {
+			Ok(_) => Ok(()),
+			// a peer relaying a transaction we already have, whose parents we
+			// haven't seen yet, that's locked for now (or for longer than we
+			// care to hold it) or that pays a fee too low for our own limits
+			// isn't misbehaving
+			Err(pool::PoolError::AlreadyInPool) |
+			Err(pool::PoolError::OrphanTransaction) |
+			Err(pool::PoolError::TimeLocked { .. }) |
+			Err(pool::PoolError::LockHeightTooHigh { .. }) |
+			Err(pool::PoolError::TimeLockedOrphan) |
+			Err(pool::PoolError::LowFeeTransaction(_)) |
+			Err(pool::PoolError::OverCapacity) => Ok(()),
+			Err(e) => {
+				error!("Transaction rejected: {:?}", e);
+				Err(p2p::Error::Invalid)
+			}
 		}
-		Ok(())
 	}
 
 	fn block_received(&self, b: core::Block) -> Result<(), p2p::Error> {
@@ -66,18 +96,58 @@ impl NetAdapter for NetToChainAdapter {
 		// pushing the new block through the chain pipeline
 		let res = self.chain.process_block(b, self.chain_opts());
 
-		if let Err(e) = res {
-			debug!("Block {} refused by chain: {:?}", bhash, e);
-			return Err(p2p::Error::Invalid);			
+		match res {
+			Ok(_) => {}
+			Err(chain::Error::Orphan(prev)) => {
+				// only its header is kept, the block will be asked for again once
+				// its parent got in
+				debug!("Block {} is an orphan, requesting its parent {}.", bhash, prev);
+				if self.syncer.borrow().syncing() {
+					self.syncer.borrow().block_received(bhash);
+				}
+				self.syncer.borrow().request_block(prev);
+				return Ok(());
+			}
+			Err(e) => {
+				debug!("Block {} refused by chain: {:?}", bhash, e);
+				return Err(p2p::Error::Invalid);
+			}
 		}
 
 		if self.syncer.borrow().syncing() {
 			self.syncer.borrow().block_received(bhash);
 		}
 
+		// the orphans waiting on this block can now be downloaded in full
+		for h in self.chain.connect_orphans(&bhash) {
+			self.syncer.borrow().request_block(h);
+		}
+
 		Ok(())
 	}
 
+	fn compact_block_received(&self, cb: core::CompactBlock) -> Result<bool, p2p::Error> {
+		let bhash = cb.hash();
+		debug!("Received compact block {} from network, rebuilding it.", bhash);
+
+		// the transactions of a block we already have left the pool with it
+		if self.chain.get_block(&bhash).is_ok() {
+			return Ok(true);
+		}
+		let b = {
+			let tx_pool = self.tx_pool.read().unwrap();
+			let txs = tx_pool.transactions.values().map(|tx| tx.as_ref()).collect();
+			cb.hydrate(txs)
+		};
+		match b {
+			Some(b) => self.block_received(b).map(|_| true),
+			None => {
+				debug!("Missing transactions for compact block {}, requesting it in full.", bhash);
+				Ok(false)
+			}
+		}
+	}
+
 	fn headers_received(&self, bhs: Vec<core::BlockHeader>) -> Result<(), p2p::Error> {
 		// try to add each header to our header chain
 		let mut added_hs = vec![];
@@ -147,15 +217,68 @@ impl NetAdapter for NetToChainAdapter {
 		Some(headers)
 	}
 
-	/// Gets a full block by its hash.
+	/// Gets a full block by its hash. Blocks that have been pruned aren't
+	/// complete anymore and can't be served.
 	fn get_block(&self, h: Hash) -> Option<core::Block> {
 		let b = self.chain.get_block(&h);
+		let pruned_height = self.chain.pruned_height().unwrap_or(0);
 		match b {
+			Ok(ref b) if b.header.height > 0 && b.header.height <= pruned_height => None,
 			Ok(b) => Some(b),
 			_ => None,
 		}
 	}
 
+	/// Builds a snapshot of the sum trees at the provided block. Only blocks
+	/// at the cut-through horizon of our head, or slightly below it, are
+	/// served and the last snapshot built is reused.
+	fn get_sumtrees(&self, h: Hash) -> Option<SumTreeSnapshot> {
+		// held while building, so only one snapshot gets built at a time
+		let mut cached = self.sumtrees_snapshot.lock().unwrap();
+		if let Some(ref snapshot) = *cached {
+			if snapshot.block_hash == h {
+				return Some(snapshot.clone());
+			}
+		}
+
+		let (header, head) = match (self.chain.get_block_header(&h), self.chain.head()) {
+			(Ok(header), Ok(head)) => (header, head),
+			_ => return None,
+		};
+		let horizon = global::cut_through_horizon() as u64;
+		if head.height < header.height + horizon ||
+		   head.height > header.height + horizon + MAX_SNAPSHOT_LAG {
+			debug!("Not building sum trees snapshot at {}, not at our horizon.", h);
+			return None;
+		}
+
+		match self.chain.sumtree_snapshot(&h) {
+			Ok(snapshot) => {
+				*cached = Some(snapshot.clone());
+				Some(snapshot)
+			}
+			Err(e) => {
+				debug!("Could not build sum trees snapshot at {}: {:?}", h, e);
+				None
+			}
+		}
+	}
+
+	/// Installs the received snapshot if we're syncing from one.
+	fn sumtrees_received(&self, snapshot: SumTreeSnapshot) -> Result<(), p2p::Error> {
+		let h = snapshot.block_hash;
+		debug!("Received sum trees snapshot at {} from network.", h);
+		if !self.syncer.borrow().syncing() {
+			return Ok(());
+		}
+		if let Err(e) = self.chain.install_snapshot(snapshot) {
+			info!("Sum trees snapshot at {} refused by chain: {:?}", h, e);
+			return Err(p2p::Error::Invalid);
+		}
+		self.syncer.borrow().sumtrees_received(h);
+		Ok(())
+	}
+
 	/// Find good peers we know with the provided capability and return their
 	/// addresses.
 	fn find_peer_addrs(&self, capab: p2p::Capabilities) -> Option<Vec<SocketAddr>> {
@@ -214,6 +337,7 @@ impl NetToChainAdapter {
 			peer_store: peer_store,
 			tx_pool: tx_pool,
 			syncer: OneTime::new(),
+			sumtrees_snapshot: Mutex::new(None),
 		}
 	}
 
@@ -252,40 +376,185 @@ impl NetToChainAdapter {
 	}
 }
 
+/// Number of recent blocks for which we keep the transactions they evicted
+/// from the pool, so they can be re-admitted if the block gets reorged out.
+const MAX_EVICTED_BLOCKS: usize = 100;
+
 /// Implementation of the ChainAdapter for the network. Gets notified when the
 /// blockchain accepted a new block, asking the pool to update its state and
 /// the network to broadcast the block
 pub struct ChainToPoolAndNetAdapter {
 	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
 	p2p: OneTime<Arc<Server>>,
+	evicted: Mutex<VecDeque<(Hash, Vec<Box<core::Transaction>>)>>,
+	miner_notifier: Arc<MinerNotifier>,
 }
 
 impl ChainAdapter for ChainToPoolAndNetAdapter {
 	fn block_accepted(&self, b: &core::Block) {
-		{
-			if let Err(e) = self.tx_pool.write().unwrap().reconcile_block(b) {
-				error!("Pool could not update itself at block {}: {:?}",
-				       b.hash(),
-				       e);
+		self.reconcile_block(b);
+		self.p2p.borrow().broadcast_block(b);
+	}
+
+	fn head_updated(&self, _: &chain::Tip) {
+		self.miner_notifier.notify(MinerEvent::NewHead);
+	}
+
+	fn chain_reorg(&self, removed: &[core::Block], added: &[core::Block]) {
+		// the pool first needs to reflect the blocks of the winning branch
+		for b in added {
+			self.reconcile_block(b);
+		}
+
+		// then gets back whatever the blocks of the losing branch took out of it,
+		// best effort as anything now invalid will just be rejected
+		for b in removed {
+			let bhash = b.hash();
+			let txs = {
+				let mut evicted = self.evicted.lock().unwrap();
+				match evicted.iter().position(|entry| entry.0 == bhash) {
+					Some(idx) => evicted.remove(idx).map(|(_, txs)| txs).unwrap_or(vec![]),
+					None => vec![],
+				}
+			};
+			debug!("Re-admitting {} transactions from reorged block {}.",
+			       txs.len(),
+			       bhash);
+			let mut tx_pool = self.tx_pool.write().unwrap();
+			for tx in txs {
+				let source = pool::TxSource {
+					debug_name: "reorg".to_string(),
+					identifier: "?.?.?.?".to_string(),
+				};
+				if let Err(e) = tx_pool.add_to_memory_pool(source, *tx) {
+					debug!("Transaction from reorged block {} not re-admitted: {:?}",
+					       bhash,
+					       e);
+				}
 			}
 		}
-		self.p2p.borrow().broadcast_block(b);
+
+		pool::TransactionPool::notify_accepted(&self.tx_pool);
+
+		// the new head isn't notified on its own, broadcast it
+		if let Some(b) = added.last() {
+			self.p2p.borrow().broadcast_block(b);
+		}
 	}
 }
 
 impl ChainToPoolAndNetAdapter {
-	pub fn new(tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>)
+	pub fn new(tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	           miner_notifier: Arc<MinerNotifier>)
 	           -> ChainToPoolAndNetAdapter {
 		ChainToPoolAndNetAdapter {
 			tx_pool: tx_pool,
 			p2p: OneTime::new(),
+			evicted: Mutex::new(VecDeque::with_capacity(MAX_EVICTED_BLOCKS + 1)),
+			miner_notifier: miner_notifier,
+		}
+	}
+	pub fn init(&self, p2p: Arc<Server>) {
+		self.p2p.init(p2p);
+	}
+
+	/// Updates the pool with the provided block, keeping track of the
+	/// transactions it evicted.
+	fn reconcile_block(&self, b: &core::Block) {
+		let res = self.tx_pool.write().unwrap().reconcile_block(b);
+		// transactions whose lock height is reached may have made it in
+		pool::TransactionPool::notify_accepted(&self.tx_pool);
+		match res {
+			Ok(mut txs) => {
+				if txs.is_empty() {
+					return;
+				}
+				let bhash = b.hash();
+				let mut evicted = self.evicted.lock().unwrap();
+				if let Some(entry) = evicted.iter_mut().find(|entry| entry.0 == bhash) {
+					entry.1.append(&mut txs);
+					return;
+				}
+				evicted.push_front((bhash, txs));
+				evicted.truncate(MAX_EVICTED_BLOCKS);
+			}
+			Err(e) => {
+				error!("Pool could not update itself at block {}: {:?}",
+				       b.hash(),
+				       e);
+			}
+		}
+	}
+}
+
+/// Implementation of the PoolAdapter for the network. Gets notified when the
+/// transaction pool accepted a new transaction, asking the network to
+/// broadcast it and letting the miners know.
+pub struct PoolToNetAdapter {
+	p2p: OneTime<Arc<Server>>,
+	miner_notifier: Arc<MinerNotifier>,
+}
+
+impl pool::PoolAdapter for PoolToNetAdapter {
+	fn tx_accepted(&self, tx: &core::Transaction) {
+		self.p2p.borrow().broadcast_transaction(tx);
+		self.miner_notifier.notify(MinerEvent::PoolUpdated);
+	}
+}
+
+impl PoolToNetAdapter {
+	/// Create a new pool to net adapter
+	pub fn new(miner_notifier: Arc<MinerNotifier>) -> PoolToNetAdapter {
+		PoolToNetAdapter {
+			p2p: OneTime::new(),
+			miner_notifier: miner_notifier,
 		}
 	}
+
+	/// Setup the p2p server on the adapter
 	pub fn init(&self, p2p: Arc<Server>) {
 		self.p2p.init(p2p);
 	}
 }
 
+/// Events the mining loops get notified of, telling them the block they're
+/// working on may be outdated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MinerEvent {
+	/// The chain head moved, any work on the previous head is wasted
+	NewHead,
+	/// New transactions have been accepted in the pool
+	PoolUpdated,
+}
+
+/// Dispatches chain head and pool updates to all the mining loops that
+/// subscribed to them.
+pub struct MinerNotifier {
+	subscribers: Mutex<Vec<mpsc::Sender<MinerEvent>>>,
+}
+
+impl MinerNotifier {
+	/// Create a new notifier, without any subscriber
+	pub fn new() -> MinerNotifier {
+		MinerNotifier { subscribers: Mutex::new(vec![]) }
+	}
+
+	/// Subscribes a new mining loop, returning the receiving end of the events
+	/// it will get notified of.
+	pub fn subscribe(&self) -> mpsc::Receiver<MinerEvent> {
+		let (tx, rx) = mpsc::channel();
+		self.subscribers.lock().unwrap().push(tx);
+		rx
+	}
+
+	/// Sends the event to all subscribers, forgetting about the ones that went
+	/// away.
+	pub fn notify(&self, event: MinerEvent) {
+		let mut subscribers = self.subscribers.lock().unwrap();
+		subscribers.retain(|s| s.send(event).is_ok());
+	}
+}
+
 /// Implements the view of the blockchain required by the TransactionPool to
 /// operate. Mostly needed to break any direct lifecycle or implementation
 /// dependency between the pool and the chain.
@@ -309,4 +578,13 @@ impl pool::BlockChain for PoolToChainAdapter {
 	fn get_unspent(&self, output_ref: &Commitment) -> Option<Output> {
 		self.chain.borrow().get_unspent(output_ref)
 	}
+
+	fn get_output_height(&self, output_ref: &Commitment) -> Option<u64> {
+		let chain = self.chain.borrow();
+		chain.get_unspent(output_ref).and_then(|_| chain.get_output_height(output_ref).ok())
+	}
+
+	fn head_height(&self) -> u64 {
+		self.chain.borrow().head().map(|h| h.height).unwrap_or(0)
+	}
 }
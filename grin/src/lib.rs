@@ -31,6 +31,7 @@ extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate time;
 extern crate tokio_core;
 extern crate tokio_timer;
@@ -53,12 +54,16 @@ extern crate cuckoo_miner;
 
 mod adapters;
 mod miner;
+mod parallel;
 mod plugin;
 mod server;
 mod seed;
+mod stratumserver;
 mod sync;
 mod types;
 
 pub use server::{Server};
-pub use types::{ServerConfig, MinerConfig, Seeding, ServerStats};
+pub use stratumserver::WorkerStats;
+pub use types::{ServerConfig, MinerConfig, StratumServerConfig, Seeding, ServerStats};
+pub use parallel::{MinerThreadStats, ParallelMiner};
 pub use plugin::PluginMiner;
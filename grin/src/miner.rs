@@ -17,20 +17,21 @@
 
 use rand::{self, Rng};
 use std::sync::{Arc, RwLock};
+use std::sync::mpsc::Receiver;
 use std::thread;
 use std;
 use std::{str};
 use time;
 
-use adapters::{PoolToChainAdapter};
+use adapters::{MinerEvent, PoolToChainAdapter};
 use api;
 use core::consensus;
 use core::core;
 use core::core::Proof;
 use core::pow::cuckoo;
 use core::core::target::Difficulty;
-use core::core::{Block, BlockHeader};
-use core::core::hash::{Hash, Hashed};
+use core::core::Block;
+use core::core::hash::Hashed;
 use core::pow::MiningWorker;
 use core::ser;
 use core::ser::{AsFixedBytes};
@@ -40,15 +41,62 @@ use secp;
 use pool;
 use types::{MinerConfig, ServerConfig};
 use util;
-use wallet::{CbAmount, WalletReceiveRequest, CbData};
+use wallet::{CbAmount, WalletReceiveRequest, WalletReceiveResponse};
 
+use parallel::{MinerThreadStats, ParallelMiner};
 use plugin::PluginMiner;
 use itertools::Itertools;
 
-// Max number of transactions this miner will assemble in a block
-const MAX_TX: u32 = 5000;
+const PRE_NONCE_SIZE: usize = 177;
 
-const PRE_NONCE_SIZE: usize = 113;
+/// Default minimum number of seconds between two rebuilds of the block being
+/// mined, when new transactions have been accepted in the pool
+const DEFAULT_TX_REFRESH_SECS: u64 = 2;
+
+/// Keeps track of the chain head and pool events received by the mining loop
+/// to decide when the block being mined has become outdated.
+struct WorkState {
+	events: Receiver<MinerEvent>,
+	refresh_secs: i64,
+	started_at: i64,
+	new_head: bool,
+	pool_updated: bool,
+}
+
+impl WorkState {
+	fn new(events: Receiver<MinerEvent>, refresh_secs: u64) -> WorkState {
+		WorkState {
+			events: events,
+			refresh_secs: refresh_secs as i64,
+			started_at: time::get_time().sec,
+			new_head: false,
+			pool_updated: false,
+		}
+	}
+
+	/// Starts tracking the work on a new block, forgetting about all events
+	/// received so far as the block will reflect them.
+	fn reset(&mut self) {
+		while let Ok(_) = self.events.try_recv() {}
+		self.started_at = time::get_time().sec;
+		self.new_head = false;
+		self.pool_updated = false;
+	}
+
+	/// Whether the block being mined should be abandoned, either because the
+	/// chain head moved or because new transactions made it to the pool and
+	/// enough time has elapsed to refresh them.
+	fn is_stale(&mut self) -> bool {
+		while let Ok(event) = self.events.try_recv() {
+			match event {
+				MinerEvent::NewHead => self.new_head = true,
+				MinerEvent::PoolUpdated => self.pool_updated = true,
+			}
+		}
+		self.new_head ||
+			(self.pool_updated && time::get_time().sec >= self.started_at + self.refresh_secs)
+	}
+}
 
 /// Serializer that outputs pre and post nonce portions of a block header
 /// which can then be sent off to miner to mutate at will
@@ -115,6 +163,13 @@ pub struct Miner {
 	//Just to hold the port we're on, so this miner can be identified
 	//while watching debug output
 	debug_output_id: String,
+
+	// per-thread stats of the parallel miner, when used
+	thread_stats: Arc<RwLock<Vec<MinerThreadStats>>>,
+
+	// coinbase of the block templates built on a given head, so the wallet
+	// is only asked for one per block
+	template_coinbase: RwLock<Option<(core::hash::Hash, (core::Output, core::TxKernel))>>,
 }
 
 impl Miner {
@@ -129,6 +184,8 @@ impl Miner {
 			chain: chain_ref,
 			tx_pool: tx_pool,
 			debug_output_id: String::from("none"),
+			thread_stats: Arc::new(RwLock::new(vec![])),
+			template_coinbase: RwLock::new(None),
 		}
 	}
 
@@ -139,26 +196,52 @@ impl Miner {
 		self.debug_output_id=debug_output_id;
 	}
 
+	/// Shares the per-thread stats of the parallel miner with the provided
+	/// container, so they can be reported elsewhere.
+	pub fn set_thread_stats(&mut self, thread_stats: Arc<RwLock<Vec<MinerThreadStats>>>) {
+		self.thread_stats = thread_stats;
+	}
+
+	/// Inner part of the mining loop for the multi-threaded embedded miner
+	fn inner_loop_parallel(&self,
+	                       miner: &mut ParallelMiner,
+	                       b: &mut Block,
+	                       cuckoo_size: u32,
+	                       work: &mut WorkState)
+		-> Option<Proof> {
+		debug!("(Server ID: {}) Mining at Cuckoo{} on top of block {} at difficulty {} with {} threads.",
+		       self.debug_output_id,
+		       cuckoo_size,
+		       b.header.previous,
+		       b.header.difficulty,
+		       self.config.miner_threads.unwrap_or(1));
+
+		let sol = miner.mine_header(&mut b.header, || work.is_stale());
+
+		for stats in self.thread_stats.read().unwrap().iter() {
+			debug!("(Server ID: {}) Mining thread {} searched {} graphs at {:.2} graphs/sec.",
+			       self.debug_output_id,
+			       stats.thread_id,
+			       stats.graphs_searched,
+			       stats.graphs_per_sec);
+		}
+		sol
+	}
+
 	/// Inner part of the mining loop for cuckoo-miner asynch mode
-	pub fn inner_loop_async(&self, plugin_miner:&mut PluginMiner,
+	fn inner_loop_async(&self, plugin_miner:&mut PluginMiner,
 							difficulty:Difficulty,
 							b:&mut Block,
 							cuckoo_size: u32,
-							head:&BlockHeader,
-							latest_hash:&Hash)
+							work: &mut WorkState)
 		-> Option<Proof> {
 
-		debug!("(Server ID: {}) Mining at Cuckoo{} for at most 2 secs at height {} and difficulty {}.",
+		debug!("(Server ID: {}) Mining at Cuckoo{} at height {} and difficulty {}.",
 			self.debug_output_id,
 			cuckoo_size,
 			b.header.height,
 			b.header.difficulty);
 
-		// look for a pow for at most 2 sec on the same block (to give a chance to new
-		// transactions) and as long as the head hasn't changed
-		// Will change this to something else at some point
-		let deadline = time::get_time().sec + 2;
-
 		//Get parts of the header
 		let mut header_parts = HeaderPartWriter::default();
 		ser::Writeable::write(&b.header, &mut header_parts).unwrap();
@@ -170,7 +253,9 @@ impl Miner {
 
 		let mut sol=None;
 
-		while head.hash() == *latest_hash && time::get_time().sec < deadline {
+		// look for a pow on the same block until the head changes or new
+		// transactions are worth including
+		while !work.is_stale() {
 			if let Some(s) = job_handle.get_solution()  {
 				sol = Some(Proof::new(s.solution_nonces.to_vec()));
 				b.header.nonce=s.get_nonce_as_u64();
@@ -189,21 +274,16 @@ impl Miner {
 	}
 
 	/// The inner part of mining loop for synchronous mode
-	pub fn inner_loop_sync<T: MiningWorker>(&self,
+	fn inner_loop_sync<T: MiningWorker>(&self,
 						    miner:&mut T,
 							b:&mut Block,
 							cuckoo_size: u32,
-							head:&BlockHeader,
-							latest_hash:&mut Hash)
+							work: &mut WorkState)
 		-> Option<Proof> {
-		// look for a pow for at most 2 sec on the same block (to give a chance to new
-		// transactions) and as long as the head hasn't changed
-		let deadline = time::get_time().sec + 2;
-
-		debug!("(Server ID: {}) Mining at Cuckoo{} for at most 2 secs on block {} at difficulty {}.",
+		debug!("(Server ID: {}) Mining at Cuckoo{} on top of block {} at difficulty {}.",
 		       self.debug_output_id,
 		       cuckoo_size,
-		       latest_hash,
+		       b.header.previous,
 		       b.header.difficulty);
 		let mut iter_count = 0;
 
@@ -213,8 +293,10 @@ impl Miner {
 			self.config.slow_down_in_millis.unwrap());
 		}
 
+		// look for a pow on the same block until the head changes or new
+		// transactions are worth including
 		let mut sol=None;
-		while head.hash() == *latest_hash && time::get_time().sec < deadline {
+		while !work.is_stale() {
 
 			let pow_hash = b.hash();
 			if let Ok(proof) = miner.mine(&pow_hash[..]) {
@@ -230,7 +312,6 @@ impl Miner {
 				}
 			}
 			b.header.nonce += 1;
-			*latest_hash = self.chain.head().unwrap().last_block_h;
 			iter_count += 1;
 
 			//Artificial slow down
@@ -249,30 +330,52 @@ impl Miner {
 	}
 
 	/// Starts the mining loop, building a new block on top of the existing
-	/// chain anytime required and looking for PoW solution.
+	/// chain anytime required and looking for PoW solution. The block gets
+	/// rebuilt as soon as a new chain head is notified on the events receiver,
+	/// and when new transactions are accepted in the pool, at most every
+	/// tx_refresh_secs.
 	pub fn run_loop(&self,
 					miner_config:MinerConfig,
 					server_config:ServerConfig,
 					cuckoo_size:u32,
-					proof_size:usize) {
+					proof_size:usize,
+					events: Receiver<MinerEvent>) {
 
 		info!("(Server ID: {}) Starting miner loop.", self.debug_output_id);
 		let mut plugin_miner=None;
+		let mut parallel_miner=None;
 		let mut miner=None;
+		let num_threads = miner_config.miner_threads.unwrap_or(1);
 		if miner_config.use_cuckoo_miner  {
 			plugin_miner = Some(PluginMiner::new(consensus::EASINESS, cuckoo_size, proof_size));
 			plugin_miner.as_mut().unwrap().init(miner_config.clone(),server_config);
+		} else if num_threads > 1 {
+			let mut m = ParallelMiner::new(consensus::EASINESS, cuckoo_size, proof_size);
+			m.init(num_threads, self.thread_stats.clone());
+			parallel_miner = Some(m);
 		} else {
 			miner = Some(cuckoo::Miner::new(consensus::EASINESS, cuckoo_size, proof_size));
 		}
 
-		let mut coinbase = self.get_coinbase();
+		let refresh_secs = miner_config.tx_refresh_secs.unwrap_or(DEFAULT_TX_REFRESH_SECS);
+		let mut work = WorkState::new(events, refresh_secs);
+		let mut coinbase = self.get_coinbase().unwrap();
 
 		loop {
-			// get the latest chain state and build a block on top of it
+			// get the latest chain state and build a block on top of it, events
+			// received from here on apply to the new block
+			work.reset();
 			let head = self.chain.head_header().unwrap();
-			let mut latest_hash = self.chain.head().unwrap().last_block_h;
-			let mut b = self.build_block(&head, coinbase.clone());
+			let mut b = match self.build_block(&head, coinbase.clone()) {
+				Ok(b) => b,
+				Err(e) => {
+					// the chain or the pool moved under us, try again
+					error!("(Server ID: {}) Could not build a block to mine, retrying: {}",
+					       self.debug_output_id, e);
+					thread::sleep(std::time::Duration::from_secs(1));
+					continue;
+				}
+			};
 
 			let mut sol=None;
 			let mut use_async=false;
@@ -287,22 +390,25 @@ impl Miner {
 						b.header.difficulty.clone(),
 						&mut b,
 						cuckoo_size,
-						&head,
-						&latest_hash);
+						&mut work);
 				} else {
 					sol = self.inner_loop_sync(p,
 					&mut b,
 					cuckoo_size,
-					&head,
-					&mut latest_hash);
+					&mut work);
 				}
 			}
+			if let Some(mut m) = parallel_miner.as_mut() {
+				sol = self.inner_loop_parallel(m,
+					&mut b,
+					cuckoo_size,
+					&mut work);
+			}
 			if let Some(mut m) = miner.as_mut() {
 				sol = self.inner_loop_sync(m,
 					&mut b,
 					cuckoo_size,
-					&head,
-					&mut latest_hash);
+					&mut work);
 			}
 
 			// if we found a solution, push our block out
@@ -320,7 +426,7 @@ impl Miner {
 					error!("(Server ID: {}) Error validating mined block: {:?}",
 					self.debug_output_id, e);
 				} else {
-					coinbase = self.get_coinbase();
+					coinbase = self.get_coinbase().unwrap();
 				}
 			}
 		}
@@ -328,10 +434,20 @@ impl Miner {
 
 	/// Builds a new block with the chain head as previous and eligible
 	/// transactions from the pool.
-	fn build_block(&self,
+	pub fn build_block(&self,
+	               head: &core::BlockHeader,
+	               coinbase: (core::Output, core::TxKernel))
+	               -> Result<core::Block, String> {
+		self.build_block_with_txs(head, coinbase).map(|(b, _)| b)
+	}
+
+	/// Same as build_block, also returning the pool transactions included in
+	/// the block. Fails if the chain state changed in a way that makes the
+	/// block invalid, building again on top of the new head should do.
+	pub fn build_block_with_txs(&self,
 	               head: &core::BlockHeader,
 	               coinbase: (core::Output, core::TxKernel))
-	               -> core::Block {
+	               -> Result<(core::Block, Vec<core::Transaction>), String> {
 		let mut now_sec = time::get_time().sec;
 		let head_sec = head.timestamp.to_timespec().sec;
 		if now_sec == head_sec {
@@ -339,12 +455,15 @@ impl Miner {
 		}
 
 		let diff_iter = self.chain.difficulty_iter();
-		let difficulty = consensus::next_difficulty(diff_iter).unwrap();
+		let difficulty = consensus::next_difficulty(diff_iter).map_err(|e| format!("{:?}", e))?;
 
-		let txs_box = self.tx_pool.read().unwrap().prepare_mineable_transactions(MAX_TX);
-		let txs = txs_box.iter().map(|tx| tx.as_ref()).collect();
+		// leaving room for the coinbase output and kernel
+		let max_tx_weight = consensus::MAX_BLOCK_WEIGHT - consensus::block_weight(0, 1, 1);
+		let txs_box = self.tx_pool.read().unwrap().prepare_mineable_transactions(max_tx_weight);
+		let txs: Vec<core::Transaction> = txs_box.into_iter().map(|tx| *tx).collect();
 		let (output, kernel) = coinbase;
-		let mut b = core::Block::with_reward(head, txs, output, kernel).unwrap();
+		let mut b = core::Block::with_reward(head, txs.iter().collect(), output, kernel)
+			.map_err(|e| format!("{:?}", e))?;
 		debug!("(Server ID: {}) Built new block with {} inputs and {} outputs, difficulty: {}",
 			   self.debug_output_id,
 		       b.inputs.len(),
@@ -353,35 +472,100 @@ impl Miner {
 
 		// making sure we're not spending time mining a useless block
 		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-		b.validate(&secp).expect("Built an invalid block!");
+		b.validate(&secp).map_err(|e| format!("Built an invalid block: {:?}", e))?;
 
 		let mut rng = rand::OsRng::new().unwrap();
 		b.header.nonce = rng.gen();
 		b.header.difficulty = difficulty;
 		b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
-		b
+		self.chain
+			.set_sumtree_roots(&mut b)
+			.map_err(|e| format!("Could not set the sum tree roots of the new block: {:?}", e))?;
+		Ok((b, txs))
 	}
 
-	fn get_coinbase(&self) -> (core::Output, core::TxKernel) {
+	/// Gets the reward output and kernel for the next block to mine, either
+	/// from the wallet receiver or burnt to a random key.
+	pub fn get_coinbase(&self) -> Result<(core::Output, core::TxKernel), String> {
 		if self.config.burn_reward {
 			let mut rng = rand::OsRng::new().unwrap();
 			let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 			let skey = secp::key::SecretKey::new(&secp_inst, &mut rng);
-			core::Block::reward_output(skey, &secp_inst).unwrap()
+			core::Block::reward_output(skey, &secp_inst).map_err(|e| format!("{:?}", e))
 		} else {
 			let url = format!("{}/v1/receive/coinbase",
 			                  self.config.wallet_receiver_url.as_str());
 			let request = WalletReceiveRequest::Coinbase(CbAmount{amount: consensus::REWARD});
-			let res: CbData = api::client::post(url.as_str(),
-			                                    &request)
-				.expect(format!("(Server ID: {}) Wallet receiver unreachable, could not claim reward. Is it running?",
-				self.debug_output_id.as_str()).as_str());
-			let out_bin = util::from_hex(res.output).unwrap();
-			let kern_bin = util::from_hex(res.kernel).unwrap();
-			let output = ser::deserialize(&mut &out_bin[..]).unwrap();
-			let kernel = ser::deserialize(&mut &kern_bin[..]).unwrap();
-
-			(output, kernel)
+			let res: WalletReceiveResponse = api::client::post(url.as_str(), &request)
+				.map_err(|e| {
+					format!("(Server ID: {}) Wallet receiver unreachable, could not claim reward. Is it running? {:?}",
+					        self.debug_output_id, e)
+				})?;
+			let res = match res {
+				WalletReceiveResponse::Coinbase(cb_data) => cb_data,
+				_ => return Err("Unexpected response from the wallet receiver to a coinbase request.".to_string()),
+			};
+			let out_bin = util::from_hex(res.output).map_err(|e| format!("{:?}", e))?;
+			let kern_bin = util::from_hex(res.kernel).map_err(|e| format!("{:?}", e))?;
+			let output = ser::deserialize(&mut &out_bin[..]).map_err(|e| format!("{:?}", e))?;
+			let kernel = ser::deserialize(&mut &kern_bin[..]).map_err(|e| format!("{:?}", e))?;
+
+			Ok((output, kernel))
 		}
 	}
 }
+
+/// Builds the block templates served to external miners by the REST API
+impl api::MiningAdapter for Miner {
+	fn build_block_template(&self) -> Result<(core::Block, Vec<core::Transaction>), String> {
+		let head = self.chain.head_header().map_err(|e| format!("{:?}", e))?;
+		let head_hash = head.hash();
+
+		// all templates on the same head share the same reward, only getting
+		// a new one once the head moved
+		let mut template_coinbase = self.template_coinbase.write().unwrap();
+		let coinbase = match *template_coinbase {
+			Some((ref h, ref cb)) if *h == head_hash => cb.clone(),
+			_ => self.get_coinbase()?,
+		};
+		*template_coinbase = Some((head_hash, coinbase.clone()));
+		self.build_block_with_txs(&head, coinbase)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use adapters::{MinerEvent, MinerNotifier};
+	use super::WorkState;
+
+	#[test]
+	fn new_head_makes_work_stale() {
+		let notifier = MinerNotifier::new();
+		let mut work = WorkState::new(notifier.subscribe(), 1000);
+		assert!(!work.is_stale());
+
+		notifier.notify(MinerEvent::NewHead);
+		assert!(work.is_stale());
+		// stays stale until the miner moves on to a new block
+		assert!(work.is_stale());
+
+		notifier.notify(MinerEvent::NewHead);
+		work.reset();
+		assert!(!work.is_stale());
+	}
+
+	#[test]
+	fn pool_update_waits_for_refresh() {
+		let notifier = MinerNotifier::new();
+		let mut work = WorkState::new(notifier.subscribe(), 1000);
+		notifier.notify(MinerEvent::PoolUpdated);
+		assert!(!work.is_stale());
+
+		let mut work = WorkState::new(notifier.subscribe(), 0);
+		assert!(!work.is_stale());
+		notifier.notify(MinerEvent::PoolUpdated);
+		assert!(work.is_stale());
+		work.reset();
+		assert!(!work.is_stale());
+	}
+}
@@ -0,0 +1,188 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Multi-threaded version of the embedded Cuckoo miner. The simple Cuckoo
+//! algorithm walks a single graph sequentially, so rather than splitting the
+//! work on one graph, each thread searches its own graphs, generated from the
+//! header nonces it's been assigned.
+
+use std::sync::{mpsc, Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use time;
+
+use core::core::{BlockHeader, Proof};
+use core::core::hash::Hashed;
+use core::pow::cuckoo;
+use core::pow::cuckoo::Error;
+use core::pow::MiningWorker;
+
+/// Minimum number of seconds between two updates of a thread's statistics,
+/// so the threads don't contend for the statistics lock after every graph.
+const STATS_PUBLISH_SECS: f64 = 1.0;
+
+/// Mining statistics of a single thread of the parallel miner
+#[derive(Debug, Clone)]
+pub struct MinerThreadStats {
+	/// Index of the thread
+	pub thread_id: usize,
+	/// Number of graphs searched while mining the last block
+	pub graphs_searched: u64,
+	/// Graphs searched per second while mining the last block
+	pub graphs_per_sec: f64,
+}
+
+/// Embedded Cuckoo miner spreading its work over several threads, each with
+/// its own graph. Keeps the statistics of its threads up to date as it mines.
+pub struct ParallelMiner {
+	workers: Vec<cuckoo::Miner>,
+	ease: u32,
+	sizeshift: u32,
+	proof_size: usize,
+	stats: Arc<RwLock<Vec<MinerThreadStats>>>,
+}
+
+impl MiningWorker for ParallelMiner {
+	/// Creates a new parallel miner, running on a single thread until
+	/// initialized otherwise.
+	fn new(ease: u32, sizeshift: u32, proof_size: usize) -> ParallelMiner {
+		ParallelMiner {
+			workers: vec![cuckoo::Miner::new(ease, sizeshift, proof_size)],
+			ease: ease,
+			sizeshift: sizeshift,
+			proof_size: proof_size,
+			stats: Arc::new(RwLock::new(vec![])),
+		}
+	}
+
+	/// Searches a single graph, so only the first thread's miner can be
+	/// involved.
+	fn mine(&mut self, header: &[u8]) -> Result<Proof, Error> {
+		self.workers[0].mine(header)
+	}
+}
+
+impl ParallelMiner {
+	/// Sets the number of threads to mine with and where to report their
+	/// statistics.
+	pub fn init(&mut self, num_threads: usize, stats: Arc<RwLock<Vec<MinerThreadStats>>>) {
+		let num_threads = if num_threads == 0 { 1 } else { num_threads };
+		while self.workers.len() < num_threads {
+			self.workers.push(cuckoo::Miner::new(self.ease, self.sizeshift, self.proof_size));
+		}
+		self.workers.truncate(num_threads);
+		self.stats = stats;
+	}
+
+	/// Looks for a proof of work for the provided header with enough
+	/// difficulty. The thread at index i tries the nonces following the
+	/// header one at offset i, by steps of the number of threads. Stops when a
+	/// solution is found, setting the header nonce accordingly, or when
+	/// is_stale returns true.
+	pub fn mine_header<F>(&mut self, header: &mut BlockHeader, mut is_stale: F) -> Option<Proof>
+		where F: FnMut() -> bool
+	{
+		let num_threads = self.workers.len();
+		{
+			let mut stats = self.stats.write().unwrap();
+			*stats = (0..num_threads)
+				.map(|n| {
+					MinerThreadStats {
+						thread_id: n,
+						graphs_searched: 0,
+						graphs_per_sec: 0.0,
+					}
+				})
+				.collect();
+		}
+
+		let stop = Arc::new(AtomicBool::new(false));
+		let (sol_send, sol_recv) = mpsc::channel();
+		let mut handles = vec![];
+		for (n, mut worker) in self.workers.drain(..).enumerate() {
+			let mut bh = header.clone();
+			bh.nonce = header.nonce.wrapping_add(n as u64);
+			let stop = stop.clone();
+			let stats = self.stats.clone();
+			let sol_send = sol_send.clone();
+			handles.push(thread::spawn(move || {
+				let start = time::precise_time_s();
+				let mut last_publish = start;
+				let mut graphs = 0;
+				while !stop.load(Ordering::Relaxed) {
+					if let Ok(proof) = worker.mine(&bh.hash()[..]) {
+						if proof.clone().to_difficulty() >= bh.difficulty {
+							let _ = sol_send.send((bh.nonce, proof));
+							break;
+						}
+					}
+					bh.nonce = bh.nonce.wrapping_add(num_threads as u64);
+					graphs += 1;
+
+					let now = time::precise_time_s();
+					if now - last_publish >= STATS_PUBLISH_SECS {
+						publish_stats(&stats, n, graphs, now - start);
+						last_publish = now;
+					}
+				}
+				publish_stats(&stats, n, graphs, time::precise_time_s() - start);
+				worker
+			}));
+		}
+		drop(sol_send);
+
+		let mut sol = None;
+		loop {
+			match sol_recv.try_recv() {
+				Ok((nonce, proof)) => {
+					header.nonce = nonce;
+					sol = Some(proof);
+					break;
+				}
+				Err(mpsc::TryRecvError::Disconnected) => break,
+				Err(mpsc::TryRecvError::Empty) => {
+					if is_stale() {
+						break;
+					}
+					thread::sleep(Duration::from_millis(10));
+				}
+			}
+		}
+
+		// threads only check the stop flag between graphs, waiting for them to
+		// get our miners back
+		stop.store(true, Ordering::Relaxed);
+		for handle in handles {
+			match handle.join() {
+				Ok(worker) => self.workers.push(worker),
+				Err(_) => {
+					error!("Parallel miner thread panicked, replacing it.");
+					self.workers.push(cuckoo::Miner::new(self.ease, self.sizeshift, self.proof_size));
+				}
+			}
+		}
+		sol
+	}
+}
+
+/// Updates the statistics of the thread at index n with the number of graphs
+/// it searched in the elapsed number of seconds.
+fn publish_stats(stats: &RwLock<Vec<MinerThreadStats>>, n: usize, graphs: u64, elapsed: f64) {
+	let mut stats = stats.write().unwrap();
+	stats[n].graphs_searched = graphs;
+	if elapsed > 0.0 {
+		stats[n].graphs_per_sec = graphs as f64 / elapsed;
+	}
+}
@@ -29,14 +29,19 @@ use adapters::*;
 use api;
 use chain;
 use miner;
+use parallel::MinerThreadStats;
 use p2p;
 use pool;
 use seed;
+use stratumserver;
 use sync;
 use types::*;
 
 use core::global;
 
+/// How often spent outputs beyond the cut-through horizon get pruned
+const COMPACTION_INTERVAL_SECS: u64 = 60;
+
 /// Grin server holding internal structures.
 pub struct Server {
 	/// server config
@@ -49,6 +54,12 @@ pub struct Server {
 	chain: Arc<chain::Chain>,
 	/// in-memory transaction pool
 	tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	/// stats of the workers connected to the stratum server
+	stratum_stats: Arc<RwLock<Vec<stratumserver::WorkerStats>>>,
+	/// stats of the threads of the embedded parallel miner
+	miner_stats: Arc<RwLock<Vec<MinerThreadStats>>>,
+	/// notifies the miners of new chain heads and pool updates
+	miner_notifier: Arc<MinerNotifier>,
 }
 
 impl Server {
@@ -57,10 +68,16 @@ impl Server {
 		let mut evtlp = reactor::Core::new().unwrap();
 
 		let mut mining_config = config.mining_config.clone();
+		let stratum_config = config.stratum_config.clone();
 		let serv = Server::future(config, &evtlp.handle())?;
 		if mining_config.as_mut().unwrap().enable_mining {
 			serv.start_miner(mining_config.unwrap());
 		}
+		if let Some(stratum_config) = stratum_config {
+			if stratum_config.enable_stratum_server {
+				serv.start_stratum_server(stratum_config);
+			}
+		}
 
 		let forever = Timer::default()
 			.interval(time::Duration::from_secs(60))
@@ -77,14 +94,25 @@ impl Server {
 	/// Instantiates a new server associated with the provided future reactor.
 	pub fn future(mut config: ServerConfig, evt_handle: &reactor::Handle) -> Result<Server, Error> {
 
+		let miner_notifier = Arc::new(MinerNotifier::new());
 		let pool_adapter = Arc::new(PoolToChainAdapter::new());
-		let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(pool_adapter.clone())));
-
-		let chain_adapter = Arc::new(ChainToPoolAndNetAdapter::new(tx_pool.clone()));
+		let pool_net_adapter = Arc::new(PoolToNetAdapter::new(miner_notifier.clone()));
+		let pool_config = config.pool_config.clone().unwrap_or_default();
+		let tx_pool = Arc::new(RwLock::new(pool::TransactionPool::new(pool_config,
+		                                                              pool_adapter.clone(),
+		                                                              pool_net_adapter.clone())));
+
+		let chain_adapter = Arc::new(ChainToPoolAndNetAdapter::new(tx_pool.clone(),
+		                                                               miner_notifier.clone()));
 		let shared_chain = Arc::new(chain::Chain::init(config.db_root.clone(),
 		                                               chain_adapter.clone())?);
 		pool_adapter.set_chain(shared_chain.clone());
 
+		if config.pruning.unwrap_or(false) {
+			config.capabilities.remove(p2p::FULL_HIST);
+			start_compaction(shared_chain.clone());
+		}
+
 		let peer_store = Arc::new(p2p::PeerStore::new(config.db_root.clone())?);
 		let net_adapter = Arc::new(NetToChainAdapter::new(shared_chain.clone(),
 		                                                  tx_pool.clone(),
@@ -92,6 +120,7 @@ impl Server {
 		let p2p_server =
 			Arc::new(p2p::Server::new(config.capabilities, config.p2p_config.unwrap(), net_adapter.clone()));
 		chain_adapter.init(p2p_server.clone());
+		pool_net_adapter.init(p2p_server.clone());
 
 		let seed = seed::Seeder::new(config.capabilities, peer_store.clone(), p2p_server.clone());
 		match config.seeding_type.clone() {
@@ -104,16 +133,23 @@ impl Server {
 			}
 		}
 
-		let sync = sync::Syncer::new(shared_chain.clone(), p2p_server.clone());
+		let sync = sync::Syncer::new(shared_chain.clone(),
+		                             p2p_server.clone(),
+		                             config.fast_sync.unwrap_or(false));
 		net_adapter.start_sync(sync);
 
 		evt_handle.spawn(p2p_server.start(evt_handle.clone()).map_err(|_| ()));
 
 		info!("Starting rest apis at: {}", &config.api_http_addr);
 
+		let mining_config = config.mining_config.clone().unwrap_or_default();
+		let api_miner = Arc::new(miner::Miner::new(mining_config,
+		                                           shared_chain.clone(),
+		                                           tx_pool.clone()));
 		api::start_rest_apis(config.api_http_addr.clone(),
 		                     shared_chain.clone(),
-		                     tx_pool.clone());
+		                     tx_pool.clone(),
+		                     api_miner);
 
 		warn!("Grin server started.");
 		Ok(Server {
@@ -122,6 +158,9 @@ impl Server {
 			p2p: p2p_server,
 			chain: shared_chain,
 			tx_pool: tx_pool,
+			stratum_stats: Arc::new(RwLock::new(vec![])),
+			miner_stats: Arc::new(RwLock::new(vec![])),
+			miner_notifier: miner_notifier,
 		})
 	}
 
@@ -146,9 +185,23 @@ impl Server {
 
 		let mut miner = miner::Miner::new(config.clone(), self.chain.clone(), self.tx_pool.clone());
 		miner.set_debug_output_id(format!("Port {}",self.config.p2p_config.unwrap().port));
+		miner.set_thread_stats(self.miner_stats.clone());
 		let server_config = self.config.clone();
+		let events = self.miner_notifier.subscribe();
 		thread::spawn(move || {
-			miner.run_loop(config.clone(), server_config, cuckoo_size as u32, proof_size);
+			miner.run_loop(config.clone(), server_config, cuckoo_size as u32, proof_size, events);
+		});
+	}
+
+	/// Start the stratum server on a separate thread, handing out mining jobs
+	/// to external miners.
+	pub fn start_stratum_server(&self, config: StratumServerConfig) {
+		let stratum_server = stratumserver::StratumServer::new(config,
+		                                                       self.chain.clone(),
+		                                                       self.tx_pool.clone(),
+		                                                       self.stratum_stats.clone());
+		let _ = thread::Builder::new().name("stratum_server".to_string()).spawn(move || {
+			stratum_server.run_loop();
 		});
 	}
 
@@ -165,6 +218,21 @@ impl Server {
 		Ok(ServerStats{
 			peer_count: self.peer_count(),
 			head: self.head(),
+			stratum_workers: self.stratum_stats.read().unwrap().clone(),
+			miner_threads: self.miner_stats.read().unwrap().clone(),
 		})
 	}
 }
+
+/// Periodically prunes the chain beyond the cut-through horizon on a separate
+/// thread.
+fn start_compaction(chain: Arc<chain::Chain>) {
+	let _ = thread::Builder::new().name("compactor".to_string()).spawn(move || {
+		loop {
+			if let Err(e) = chain.compact() {
+				error!("Error while pruning the chain: {:?}", e);
+			}
+			thread::sleep(time::Duration::from_secs(COMPACTION_INTERVAL_SECS));
+		}
+	});
+}
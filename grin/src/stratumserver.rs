@@ -0,0 +1,588 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Mining server for external miners, speaking a Stratum-like JSON-RPC
+//! protocol over TCP. Each request and response is a single line of JSON.
+//! Workers login, ask for a job template holding the pre and post nonce parts
+//! of a block header to mine on and submit their solutions as shares, the
+//! ones reaching the block difficulty being added to the chain.
+
+use std::cmp;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use time;
+
+use adapters::PoolToChainAdapter;
+use chain;
+use core::consensus;
+use core::core::{Block, Proof};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::global;
+use core::pow::cuckoo::Cuckoo;
+use core::ser;
+use miner::{HeaderPartWriter, Miner};
+use pool;
+use types::{MinerConfig, StratumServerConfig};
+
+// How long the server sleeps between two checks of the workers connections
+const LOOP_SLEEP_MILLIS: u64 = 50;
+
+// Maximum length of a request line, workers sending longer ones get dropped
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+// Maximum size of the responses waiting to be written to a worker, workers
+// not reading them fast enough get dropped
+const MAX_WRITE_BUF_SIZE: usize = 1024 * 1024;
+
+// Maximum number of jobs kept for the current head, older ones being stale
+const MAX_JOBS: usize = 32;
+
+/// A JSON-RPC request sent by a worker
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcRequest {
+	id: Value,
+	jsonrpc: String,
+	method: String,
+	params: Option<Value>,
+}
+
+/// A JSON-RPC response to a worker request, or a notification when the id
+/// is null
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcResponse {
+	id: Value,
+	jsonrpc: String,
+	method: String,
+	result: Option<Value>,
+	error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RpcError {
+	code: i32,
+	message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LoginParams {
+	login: String,
+	pass: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SubmitParams {
+	height: u64,
+	job_id: u64,
+	nonce: u64,
+	pow: Vec<u32>,
+}
+
+/// Job handed out to workers. The header to mine is the concatenation of
+/// the pre nonce part, the nonce and the post nonce part.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct JobTemplate {
+	/// Height of the block to mine
+	pub height: u64,
+	/// Identifier of the job, needed to submit a solution
+	pub job_id: u64,
+	/// Minimum difficulty of the shares accepted for the job
+	pub difficulty: u64,
+	/// Hex encoded header part before the nonce
+	pub pre_nonce: String,
+	/// Hex encoded header part after the nonce
+	pub post_nonce: String,
+}
+
+/// Share accounting for a worker connected to the stratum server
+#[derive(Debug, Clone)]
+pub struct WorkerStats {
+	/// Network address of the worker
+	pub id: String,
+	/// Login provided by the worker, if any yet
+	pub login: Option<String>,
+	/// Number of valid shares submitted
+	pub num_accepted: u64,
+	/// Number of invalid shares submitted
+	pub num_rejected: u64,
+	/// Number of shares submitted for a job that was already stale
+	pub num_stale: u64,
+	/// Number of shares that were also valid blocks
+	pub num_blocks_found: u64,
+}
+
+// A worker connection, reading requests line by line and buffering the
+// responses the socket isn't ready to take yet
+struct Worker {
+	stream: TcpStream,
+	read_buf: Vec<u8>,
+	write_buf: Vec<u8>,
+	stats: WorkerStats,
+	error: bool,
+}
+
+impl Worker {
+	fn new(id: String, stream: TcpStream) -> Worker {
+		Worker {
+			stream: stream,
+			read_buf: vec![],
+			write_buf: vec![],
+			stats: WorkerStats {
+				id: id,
+				login: None,
+				num_accepted: 0,
+				num_rejected: 0,
+				num_stale: 0,
+				num_blocks_found: 0,
+			},
+			error: false,
+		}
+	}
+
+	// Reads whatever the worker sent so far without blocking and returns the
+	// complete lines received. A worker sending a line longer than the
+	// maximum is in error.
+	fn read_lines(&mut self) -> Vec<String> {
+		let mut buf = [0; 1024];
+		// anything past the maximum line size is left for the next call
+		while self.read_buf.len() <= MAX_LINE_SIZE {
+			match self.stream.read(&mut buf) {
+				Ok(0) => {
+					self.error = true;
+					break;
+				}
+				Ok(n) => self.read_buf.extend_from_slice(&buf[..n]),
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(_) => {
+					self.error = true;
+					break;
+				}
+			}
+		}
+		let mut lines = vec![];
+		while let Some(pos) = self.read_buf.iter().position(|&b| b == b'\n') {
+			let line: Vec<u8> = self.read_buf.drain(..pos + 1).collect();
+			let line = String::from_utf8_lossy(&line).trim().to_string();
+			if !line.is_empty() {
+				lines.push(line);
+			}
+		}
+		if self.read_buf.len() > MAX_LINE_SIZE {
+			debug!("Stratum worker {} sent a line over {} bytes", self.stats.id, MAX_LINE_SIZE);
+			self.read_buf.clear();
+			self.error = true;
+		}
+		lines
+	}
+
+	// Queues a response for the worker and writes as much of what's queued
+	// as the socket takes without blocking.
+	fn send(&mut self, response: &RpcResponse) {
+		let mut line = serde_json::to_string(response).unwrap();
+		line.push('\n');
+		self.write_buf.extend_from_slice(line.as_bytes());
+		if self.write_buf.len() > MAX_WRITE_BUF_SIZE {
+			debug!("Stratum worker {} has over {} bytes of responses waiting",
+			       self.stats.id, MAX_WRITE_BUF_SIZE);
+			self.write_buf.clear();
+			self.error = true;
+			return;
+		}
+		self.flush();
+	}
+
+	// Writes the queued responses until done or until the socket would block,
+	// the rest being left for the next call.
+	fn flush(&mut self) {
+		while !self.write_buf.is_empty() {
+			match self.stream.write(&self.write_buf) {
+				Ok(0) => {
+					self.error = true;
+					break;
+				}
+				Ok(n) => {
+					self.write_buf.drain(..n);
+				}
+				Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+				Err(e) => {
+					debug!("Could not write to stratum worker {}: {:?}", self.stats.id, e);
+					self.error = true;
+					break;
+				}
+			}
+		}
+	}
+}
+
+// A block being mined by the workers, along with the nonces of the shares
+// already submitted for it
+struct Job {
+	block: Block,
+	nonces: HashSet<u64>,
+}
+
+// The jobs currently being mined by the workers. All are built on the same
+// head, a new one being added every time transactions get refreshed and the
+// oldest ones dropped past the maximum. Job ids keep increasing across heads
+// so a share for a job on an older head can't be taken for a current one,
+// the id of the first job kept being first_id.
+struct Jobs {
+	head_hash: Hash,
+	height: u64,
+	first_id: u64,
+	jobs: Vec<Job>,
+	deadline: i64,
+}
+
+impl Jobs {
+	fn get_mut(&mut self, job_id: u64) -> Option<&mut Job> {
+		if job_id < self.first_id {
+			return None;
+		}
+		self.jobs.get_mut((job_id - self.first_id) as usize)
+	}
+}
+
+/// Mining server handing out jobs to external miners over TCP and checking
+/// the shares they submit.
+pub struct StratumServer {
+	config: StratumServerConfig,
+	chain: Arc<chain::Chain>,
+	miner: Miner,
+	stats: Arc<RwLock<Vec<WorkerStats>>>,
+}
+
+impl StratumServer {
+	/// Creates a new stratum server. The stats of connected workers get
+	/// published in the provided container.
+	pub fn new(config: StratumServerConfig,
+	           chain_ref: Arc<chain::Chain>,
+	           tx_pool: Arc<RwLock<pool::TransactionPool<PoolToChainAdapter>>>,
+	           stats: Arc<RwLock<Vec<WorkerStats>>>)
+	           -> StratumServer {
+		let miner_config = MinerConfig {
+			wallet_receiver_url: config.wallet_receiver_url.clone(),
+			burn_reward: config.burn_reward,
+			..Default::default()
+		};
+		let mut miner = Miner::new(miner_config, chain_ref.clone(), tx_pool);
+		miner.set_debug_output_id(format!("Stratum {}", config.stratum_server_addr));
+		StratumServer {
+			config: config,
+			chain: chain_ref,
+			miner: miner,
+			stats: stats,
+		}
+	}
+
+	/// Listens for workers and serves them jobs built on top of the chain
+	/// head, forever.
+	pub fn run_loop(&self) {
+		let listener = match TcpListener::bind(self.config.stratum_server_addr.as_str()) {
+			Ok(l) => l,
+			Err(e) => {
+				error!("Could not start the stratum server on {}: {:?}",
+				       self.config.stratum_server_addr, e);
+				return;
+			}
+		};
+		listener.set_nonblocking(true).expect("Could not make the stratum listener non blocking");
+		info!("Stratum server listening on {}", self.config.stratum_server_addr);
+
+		let mut workers: Vec<Worker> = vec![];
+		let mut jobs = Jobs {
+			head_hash: ZERO_HASH,
+			height: 0,
+			first_id: 0,
+			jobs: vec![],
+			deadline: 0,
+		};
+		// the reward of the blocks built, fetched again after each block found
+		let mut coinbase = None;
+
+		loop {
+			// accept new workers
+			loop {
+				match listener.accept() {
+					Ok((stream, addr)) => {
+						if let Err(e) = stream.set_nonblocking(true) {
+							error!("Could not setup stratum worker {}: {:?}", addr, e);
+							continue;
+						}
+						debug!("New stratum worker connected from {}", addr);
+						workers.push(Worker::new(addr.to_string(), stream));
+					}
+					Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+					Err(e) => {
+						error!("Error accepting stratum worker: {:?}", e);
+						break;
+					}
+				}
+			}
+
+			// build a new job when the head changed or to include newer
+			// transactions, notifying the workers already logged in
+			if coinbase.is_none() {
+				coinbase = match self.miner.get_coinbase() {
+					Ok(cb) => Some(cb),
+					Err(e) => {
+						error!("Could not get the reward for stratum jobs, retrying: {}", e);
+						None
+					}
+				};
+			}
+			let head = match self.chain.head_header() {
+				Ok(head) => Some(head),
+				Err(e) => {
+					error!("Could not read the chain head for stratum jobs, retrying: {:?}", e);
+					None
+				}
+			};
+			let now = time::get_time().sec;
+			let new_block = match (head, coinbase.clone()) {
+				(Some(head), Some(cb)) => {
+					if head.hash() != jobs.head_hash || now >= jobs.deadline {
+						match self.miner.build_block(&head, cb) {
+							Ok(b) => Some((head, b)),
+							Err(e) => {
+								// keep the current jobs, trying again on the next round
+								error!("Could not build a new stratum job: {}", e);
+								None
+							}
+						}
+					} else {
+						None
+					}
+				}
+				_ => None,
+			};
+			if let Some((head, b)) = new_block {
+				if head.hash() != jobs.head_hash {
+					// all the jobs on the previous head are stale now
+					jobs.first_id += jobs.jobs.len() as u64;
+					jobs.jobs.clear();
+				}
+				jobs.head_hash = head.hash();
+				jobs.height = b.header.height;
+				jobs.deadline = now + self.config.attempt_time_per_block as i64;
+				jobs.jobs.push(Job {
+					block: b,
+					nonces: HashSet::new(),
+				});
+				if jobs.jobs.len() > MAX_JOBS {
+					jobs.jobs.remove(0);
+					jobs.first_id += 1;
+				}
+
+				let job = self.job_template(&jobs);
+				for worker in workers.iter_mut().filter(|w| w.stats.login.is_some()) {
+					worker.send(&RpcResponse {
+						id: Value::Null,
+						jsonrpc: "2.0".to_string(),
+						method: "job".to_string(),
+						result: Some(serde_json::to_value(&job).unwrap()),
+						error: None,
+					});
+				}
+			}
+
+			// handle worker requests, after writing whatever is still queued
+			let mut found_block = false;
+			for worker in workers.iter_mut() {
+				worker.flush();
+				for line in worker.read_lines() {
+					let response = match serde_json::from_str::<RpcRequest>(&line) {
+						Ok(request) => {
+							let result = match request.method.as_str() {
+								"login" => self.handle_login(worker, request.params),
+								"getjobtemplate" => self.handle_getjobtemplate(worker, &jobs),
+								"submit" => {
+									let blocks_found = worker.stats.num_blocks_found;
+									let res = self.handle_submit(worker, request.params, &mut jobs);
+									found_block = found_block || worker.stats.num_blocks_found > blocks_found;
+									res
+								}
+								_ => Err(rpc_error(-32601, "Method not found")),
+							};
+							let (result, error) = match result {
+								Ok(r) => (Some(r), None),
+								Err(e) => (None, Some(e)),
+							};
+							RpcResponse {
+								id: request.id,
+								jsonrpc: "2.0".to_string(),
+								method: request.method,
+								result: result,
+								error: error,
+							}
+						}
+						Err(_) => {
+							RpcResponse {
+								id: Value::Null,
+								jsonrpc: "2.0".to_string(),
+								method: "".to_string(),
+								result: None,
+								error: Some(rpc_error(-32700, "Parse error")),
+							}
+						}
+					};
+					worker.send(&response);
+				}
+			}
+
+			// the reward for the next block has to go to a new output
+			if found_block {
+				coinbase = None;
+			}
+
+			workers.retain(|w| {
+				if w.error {
+					debug!("Stratum worker {} disconnected", w.stats.id);
+				}
+				!w.error
+			});
+			*self.stats.write().unwrap() = workers.iter().map(|w| w.stats.clone()).collect();
+
+			thread::sleep(Duration::from_millis(LOOP_SLEEP_MILLIS));
+		}
+	}
+
+	fn handle_login(&self, worker: &mut Worker, params: Option<Value>) -> Result<Value, RpcError> {
+		let params: LoginParams = parse_params(params)?;
+		info!("Stratum worker {} logged in as {}", worker.stats.id, params.login);
+		worker.stats.login = Some(params.login);
+		Ok(Value::String("ok".to_string()))
+	}
+
+	fn handle_getjobtemplate(&self, worker: &mut Worker, jobs: &Jobs) -> Result<Value, RpcError> {
+		if worker.stats.login.is_none() {
+			return Err(rpc_error(-32500, "Login first"));
+		}
+		if jobs.jobs.is_empty() {
+			return Err(rpc_error(-32000, "No job available yet, try again later"));
+		}
+		Ok(serde_json::to_value(&self.job_template(jobs)).unwrap())
+	}
+
+	// Checks the nonce and proof submitted by a worker against the job it
+	// was working on. Valid shares also reaching the block difficulty get
+	// added to the chain, a nonce can only be submitted once per job.
+	fn handle_submit(&self, worker: &mut Worker, params: Option<Value>, jobs: &mut Jobs) -> Result<Value, RpcError> {
+		if worker.stats.login.is_none() {
+			return Err(rpc_error(-32500, "Login first"));
+		}
+		let params: SubmitParams = parse_params(params)?;
+		if params.height != jobs.height {
+			worker.stats.num_stale += 1;
+			return Err(rpc_error(-32503, "Solution submitted too late"));
+		}
+		let job = match jobs.get_mut(params.job_id) {
+			Some(job) => job,
+			None => {
+				worker.stats.num_stale += 1;
+				return Err(rpc_error(-32503, "Solution submitted too late"));
+			}
+		};
+		if params.pow.len() != global::proofsize() {
+			worker.stats.num_rejected += 1;
+			return Err(rpc_error(-32502, "Invalid proof size"));
+		}
+		if job.nonces.contains(&params.nonce) {
+			worker.stats.num_rejected += 1;
+			return Err(rpc_error(-32504, "Duplicate share"));
+		}
+
+		let mut b = job.block.clone();
+		b.header.nonce = params.nonce;
+		b.header.pow = Proof::new(params.pow);
+
+		let cuckoo_size = global::sizeshift() as u32;
+		if !Cuckoo::new(&b.hash()[..], cuckoo_size).verify(b.header.pow.clone(), consensus::EASINESS as u64) {
+			worker.stats.num_rejected += 1;
+			return Err(rpc_error(-32502, "Invalid proof"));
+		}
+		let share_difficulty = b.header.pow.clone().to_difficulty();
+		if share_difficulty < self.share_difficulty(&b) {
+			worker.stats.num_rejected += 1;
+			return Err(rpc_error(-32501, "Share rejected due to low difficulty"));
+		}
+		job.nonces.insert(params.nonce);
+		worker.stats.num_accepted += 1;
+
+		if share_difficulty >= b.header.difficulty {
+			let hash = b.hash();
+			let opts = if cuckoo_size < consensus::DEFAULT_SIZESHIFT as u32 {
+				chain::EASY_POW
+			} else {
+				chain::NONE
+			};
+			match self.chain.process_block(b, opts) {
+				Ok(_) => {
+					info!("Stratum worker {} found block {}", worker.stats.id, hash);
+					worker.stats.num_blocks_found += 1;
+				}
+				Err(e) => error!("Error validating block {} from stratum worker {}: {:?}",
+				                 hash, worker.stats.id, e),
+			}
+		}
+		debug!("Stratum worker {} shares: {} accepted, {} rejected, {} stale",
+		       worker.stats.id,
+		       worker.stats.num_accepted,
+		       worker.stats.num_rejected,
+		       worker.stats.num_stale);
+		Ok(Value::String("ok".to_string()))
+	}
+
+	// Template of the latest job, with the pre and post nonce header parts
+	fn job_template(&self, jobs: &Jobs) -> JobTemplate {
+		let b = &jobs.jobs.last().unwrap().block;
+		let mut header_parts = HeaderPartWriter::default();
+		ser::Writeable::write(&b.header, &mut header_parts).unwrap();
+		let (pre, post) = header_parts.parts_as_hex_strings();
+		JobTemplate {
+			height: jobs.height,
+			job_id: jobs.first_id + (jobs.jobs.len() - 1) as u64,
+			difficulty: self.share_difficulty(b).into_num(),
+			pre_nonce: pre,
+			post_nonce: post,
+		}
+	}
+
+	// Shares have to reach the configured minimum difficulty, unless the
+	// block itself is easier
+	fn share_difficulty(&self, b: &Block) -> Difficulty {
+		cmp::min(Difficulty::from_num(self.config.minimum_share_difficulty),
+		         b.header.difficulty.clone())
+	}
+}
+
+fn parse_params<T: DeserializeOwned>(params: Option<Value>) -> Result<T, RpcError> {
+	params
+		.and_then(|p| serde_json::from_value(p).ok())
+		.ok_or(rpc_error(-32602, "Invalid params"))
+}
+
+fn rpc_error(code: i32, message: &str) -> RpcError {
+	RpcError {
+		code: code,
+		message: message.to_string(),
+	}
+}
@@ -20,11 +20,19 @@
 /// How many block bodies to download in parallel
 const MAX_BODY_DOWNLOADS: usize = 8;
 
+/// How long to wait for a sum trees snapshot before asking again
+const SUMTREES_TIMEOUT_SECS: u64 = 30;
+
+/// How many times we ask for a snapshot before falling back to downloading
+/// all full blocks
+const MAX_SUMTREES_REQUESTS: u32 = 3;
+
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Instant, Duration};
 
 use core::core::hash::{Hash, Hashed};
+use core::global;
 use chain;
 use p2p;
 use types::Error;
@@ -34,20 +42,30 @@ pub struct Syncer {
 	p2p: Arc<p2p::Server>,
 
 	sync: Mutex<bool>,
+	fast_sync: bool,
 	last_header_req: Mutex<Instant>,
 	blocks_to_download: Mutex<Vec<Hash>>,
 	blocks_downloading: Mutex<Vec<(Hash, Instant)>>,
+	// whether full blocks are being downloaded, always the case unless we're
+	// still deciding whether to sync from a snapshot
+	bodies_started: Mutex<bool>,
+	sumtrees_requests: Mutex<u32>,
+	last_sumtrees_req: Mutex<Option<Instant>>,
 }
 
 impl Syncer {
-	pub fn new(chain_ref: Arc<chain::Chain>, p2p: Arc<p2p::Server>) -> Syncer {
+	pub fn new(chain_ref: Arc<chain::Chain>, p2p: Arc<p2p::Server>, fast_sync: bool) -> Syncer {
 		Syncer {
 			chain: chain_ref,
 			p2p: p2p,
 			sync: Mutex::new(true),
+			fast_sync: fast_sync,
 			last_header_req: Mutex::new(Instant::now() - Duration::from_secs(2)),
 			blocks_to_download: Mutex::new(vec![]),
 			blocks_downloading: Mutex::new(vec![]),
+			bodies_started: Mutex::new(!fast_sync),
+			sumtrees_requests: Mutex::new(0),
+			last_sumtrees_req: Mutex::new(None),
 		}
 	}
 
@@ -71,8 +89,11 @@ impl Syncer {
 			thread::sleep(Duration::from_millis(200));
 		}
 
-		// check if we have missing full blocks for which we already have a header
-		self.init_download()?;
+		// check if we have missing full blocks for which we already have a header,
+		// when fast syncing we only know what to download once all headers are in
+		if !self.fast_sync {
+			self.init_download()?;
+		}
 
 		// main syncing loop, requests more headers and bodies periodically as long
 		// as a peer with higher difficulty exists and we're not fully caught up
@@ -95,10 +116,14 @@ impl Syncer {
 					self.request_headers()?;
 				}
 			}
+			let bodies_started = *self.bodies_started.lock().unwrap();
+			if !more_headers && !bodies_started {
+				self.sync_sumtrees()?;
+			}
 			if more_bodies {
 				self.request_bodies();
 			}
-			if !more_headers && !more_bodies {
+			if !more_headers && !more_bodies && *self.bodies_started.lock().unwrap() {
 				// TODO check we haven't been lied to on the total work
 				let mut sync = self.sync.lock().unwrap();
 				*sync = false;
@@ -119,6 +144,7 @@ impl Syncer {
 		let header_head = self.chain.get_header_head()?;
 		let full_head = self.chain.head()?;
 		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
+		*self.bodies_started.lock().unwrap() = true;
 
 		// go back the chain and insert for download all blocks we only have the
 		// head for
@@ -163,6 +189,82 @@ impl Syncer {
 		}
 	}
 
+	/// Asks our most worked peer for a snapshot of the sum trees at the
+	/// cut-through horizon if we're far enough behind for it to be worth it.
+	/// Falls back to downloading all full blocks otherwise.
+	fn sync_sumtrees(&self) -> Result<(), Error> {
+		let horizon = match self.sumtrees_horizon()? {
+			Some(h) => h,
+			None => return self.init_download(),
+		};
+
+		{
+			let last_req = self.last_sumtrees_req.lock().unwrap();
+			if let Some(t) = *last_req {
+				if Instant::now() - t < Duration::from_secs(SUMTREES_TIMEOUT_SECS) {
+					return Ok(());
+				}
+			}
+		}
+		let requests = {
+			let mut requests = self.sumtrees_requests.lock().unwrap();
+			*requests += 1;
+			*requests
+		};
+		if requests > MAX_SUMTREES_REQUESTS {
+			info!("No sum trees snapshot received, downloading all full blocks.");
+			return self.init_download();
+		}
+
+		let peer = self.p2p.most_work_peer();
+		match peer {
+			Some(ref p) if p.info.capabilities.contains(p2p::UTXO_HIST) => {
+				debug!("Asking peer {} for sum trees at horizon {}.",
+				       p.info.addr,
+				       horizon);
+				*self.last_sumtrees_req.lock().unwrap() = Some(Instant::now());
+				p.send_sumtrees_request(horizon)?;
+				Ok(())
+			}
+			_ => {
+				info!("No peer to fast sync from, downloading all full blocks.");
+				self.init_download()
+			}
+		}
+	}
+
+	/// Hash of the block at the cut-through horizon of our header chain, if
+	/// syncing from a snapshot there would save us from downloading blocks.
+	fn sumtrees_horizon(&self) -> Result<Option<Hash>, Error> {
+		if !self.fast_sync {
+			return Ok(None);
+		}
+		let header_head = self.chain.get_header_head()?;
+		let full_head = self.chain.head()?;
+		let horizon = global::cut_through_horizon() as u64;
+		if full_head.height > 0 || header_head.height <= horizon {
+			return Ok(None);
+		}
+
+		let mut header = self.chain.get_block_header(&header_head.last_block_h)?;
+		while header.height > header_head.height - horizon {
+			header = self.chain.get_block_header(&header.previous)?;
+		}
+		Ok(Some(header.hash()))
+	}
+
+	/// A sum trees snapshot was installed, we only need the full blocks after
+	/// it now.
+	pub fn sumtrees_received(&self, h: Hash) {
+		if *self.bodies_started.lock().unwrap() {
+			return;
+		}
+		info!("Fast synced to {}, downloading remaining full blocks.", h);
+		if let Err(e) = self.init_download() {
+			error!("Could not start downloading full blocks: {:?}", e);
+		}
+	}
+
 	/// We added a block, clean up the downloading structure
 	pub fn block_received(&self, bh: Hash) {
 		// just clean up the downloading list
@@ -170,6 +272,27 @@ impl Syncer {
 		bds.iter().position(|&h| h.0 == bh).map(|n| bds.remove(n));
 	}
 
+	/// Asks our most worked peer for a block we're missing outside of the
+	/// regular body downloads, either the parent of an orphan or an orphan
+	/// whose header now connects. Skipped if the block is already being
+	/// downloaded.
+	pub fn request_block(&self, h: Hash) {
+		let mut bds = self.blocks_downloading.lock().unwrap();
+		if bds.iter().any(|&(bh, _)| bh == h) {
+			return;
+		}
+		if let Some(peer) = self.p2p.most_work_peer() {
+			debug!("Asking peer {} for missing block {}.", peer.info.addr, h);
+			if let Err(e) = peer.send_block_request(h) {
+				debug!("Could not request block {}: {:?}", h, e);
+				return;
+			}
+			if self.syncing() {
+				bds.push((h, Instant::now()));
+			}
+		}
+	}
+
 	/// Request some block headers from a peer to advance us
 	fn request_headers(&self) -> Result<(), Error> {
 		{
@@ -194,11 +317,15 @@ impl Syncer {
 
 	/// We added a header, add it to the full block download list
 	pub fn headers_received(&self, bhs: Vec<Hash>) {
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
 		let hs_len = bhs.len();
-		for h in bhs {
-			// enlist for full block download
-			blocks_to_download.insert(0, h);
+		// until we know whether we'll sync from a snapshot, full blocks are left
+		// for init_download to figure out
+		if *self.bodies_started.lock().unwrap() {
+			let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
+			for h in bhs {
+				// enlist for full block download
+				blocks_to_download.insert(0, h);
+			}
 		}
 		// ask for more headers if we got as many as required
 		if hs_len == (p2p::MAX_BLOCK_HEADERS as usize) {
@@ -18,7 +18,10 @@ use std::collections::HashMap;
 use api;
 use chain;
 use p2p;
+use pool;
 use store;
+use stratumserver::WorkerStats;
+use parallel::MinerThreadStats;
 use core::global::MiningParameterMode;
 
 /// Error type wrapping underlying module errors.
@@ -95,8 +98,23 @@ pub struct ServerConfig {
 	/// Configuration for the peer-to-peer server
 	pub p2p_config: Option<p2p::P2PConfig>,
 
+	/// Whether a new node should sync from a snapshot of the sum trees at the
+	/// cut-through horizon instead of downloading every full block
+	pub fast_sync: Option<bool>,
+
+	/// Whether to prune spent outputs and their range proofs from the blocks
+	/// beyond the cut-through horizon. A pruning node can't serve the full
+	/// history anymore.
+	pub pruning: Option<bool>,
+
+	/// Configuration for the transaction pool
+	pub pool_config: Option<pool::PoolConfig>,
+
 	/// Configuration for the mining daemon
 	pub mining_config: Option<MinerConfig>,
+
+	/// Configuration for the mining server external miners connect to
+	pub stratum_config: Option<StratumServerConfig>,
 }
 
 /// Mining configuration
@@ -132,6 +150,40 @@ pub struct MinerConfig {
 	/// mining loop by adding a sleep to the thread
 	pub slow_down_in_millis: Option<u64>,
 
+	/// Minimum number of seconds between two rebuilds of the block being
+	/// mined to include the transactions newly accepted in the pool. A new
+	/// chain head always triggers an immediate rebuild. Defaults to 2 seconds.
+	pub tx_refresh_secs: Option<u64>,
+
+	/// Number of threads the embedded miner (not using cuckoo-miner) spreads
+	/// its work over, each searching its own graphs. Defaults to 1, mining on
+	/// the main mining loop thread.
+	pub miner_threads: Option<usize>,
+}
+
+/// Configuration of the stratum server, handing out mining jobs to external
+/// miners
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StratumServerConfig {
+	/// Whether to start the stratum server with the server
+	pub enable_stratum_server: bool,
+
+	/// Network address the stratum server listens on
+	pub stratum_server_addr: String,
+
+	/// Number of seconds after which a new job is built for the same block
+	/// height, to include newer transactions
+	pub attempt_time_per_block: u32,
+
+	/// Minimum difficulty of the shares submitted by workers
+	pub minimum_share_difficulty: u64,
+
+	/// Base address to the HTTP wallet receiver
+	pub wallet_receiver_url: String,
+
+	/// Attributes the reward to a random private key instead of contacting the
+	/// wallet receiver. Mostly used for tests.
+	pub burn_reward: bool,
 }
 
 impl Default for ServerConfig {
@@ -143,7 +195,11 @@ impl Default for ServerConfig {
 			seeding_type: Seeding::None,
 			seeds: None,
 			p2p_config: Some(p2p::P2PConfig::default()),
+			fast_sync: None,
+			pruning: None,
+			pool_config: Some(pool::PoolConfig::default()),
 			mining_config: Some(MinerConfig::default()),
+			stratum_config: Some(StratumServerConfig::default()),
 			mining_parameter_mode: Some(MiningParameterMode::Production),
 		}
 	}
@@ -161,6 +217,21 @@ impl Default for MinerConfig {
 			wallet_receiver_url: "http://localhost:13416".to_string(),
 			burn_reward: false,
 			slow_down_in_millis: Some(0),
+			tx_refresh_secs: None,
+			miner_threads: None,
+		}
+	}
+}
+
+impl Default for StratumServerConfig {
+	fn default() -> StratumServerConfig {
+		StratumServerConfig {
+			enable_stratum_server: false,
+			stratum_server_addr: "127.0.0.1:13417".to_string(),
+			attempt_time_per_block: 60,
+			minimum_share_difficulty: 1,
+			wallet_receiver_url: "http://localhost:13416".to_string(),
+			burn_reward: false,
 		}
 	}
 }
@@ -177,4 +248,8 @@ pub struct ServerStats {
 	pub peer_count:u32,
 	/// Chain head
 	pub head: chain::Tip,
+	/// Workers connected to the stratum server
+	pub stratum_workers: Vec<WorkerStats>,
+	/// Threads of the embedded parallel miner
+	pub miner_threads: Vec<MinerThreadStats>,
 }
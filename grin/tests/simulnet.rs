@@ -27,6 +27,7 @@ extern crate tokio_timer;
 
 mod framework;
 
+use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time;
 use std::default::Default;
@@ -37,8 +38,10 @@ use tokio_core::reactor;
 use tokio_timer::Timer;
 
 use core::consensus;
+use core::core::target::Difficulty;
 use core::global;
 use core::global::{MiningParameterMode, MINING_PARAMETER_MODE};
+use core::pow::MiningWorker;
 use wallet::WalletConfig;
 
 use framework::{LocalServerContainer, LocalServerContainerConfig, LocalServerContainerPoolConfig,
@@ -75,6 +78,29 @@ fn basic_genesis_mine() {
 
 }
 
+/// Mines a header with the multi-threaded embedded miner and checks the
+/// resulting proof of work and thread stats.
+#[test]
+fn parallel_miner_mine() {
+	let _ = env_logger::init();
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let sizeshift = global::sizeshift() as u32;
+	let mut miner = grin::ParallelMiner::new(consensus::EASINESS, sizeshift, global::proofsize());
+	let stats = Arc::new(RwLock::new(vec![]));
+	miner.init(4, stats.clone());
+
+	let mut bh = core::core::BlockHeader::default();
+	bh.difficulty = Difficulty::one();
+	let proof = miner.mine_header(&mut bh, || false).unwrap();
+	bh.pow = proof;
+	assert!(core::pow::verify_size(&bh, sizeshift));
+
+	let stats = stats.read().unwrap();
+	assert_eq!(stats.len(), 4);
+	assert!(stats.iter().all(|s| s.thread_id < 4));
+}
+
 /// Creates 5 servers, first being a seed and check that through peer address
 /// messages they all end up connected.
 #[test]
@@ -0,0 +1,182 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate grin_util as util;
+
+extern crate env_logger;
+extern crate serde_json;
+extern crate tokio_core;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time;
+
+use serde_json::Value;
+use tokio_core::reactor;
+
+use core::consensus;
+use core::core::{BlockHeader, Proof};
+use core::core::target::Difficulty;
+use core::global;
+use core::global::MiningParameterMode;
+use core::pow::{self, cuckoo, MiningWorker};
+use core::ser;
+
+/// Stratum worker connection, sending requests and reading the responses
+/// to them, skipping the job notifications in between.
+struct TestWorker {
+	stream: TcpStream,
+	reader: BufReader<TcpStream>,
+	next_id: u64,
+}
+
+impl TestWorker {
+	fn connect(addr: &str) -> TestWorker {
+		// the server binds on its own thread, give it some time
+		for _ in 0..50 {
+			if let Ok(stream) = TcpStream::connect(addr) {
+				let reader = BufReader::new(stream.try_clone().unwrap());
+				return TestWorker {
+					stream: stream,
+					reader: reader,
+					next_id: 1,
+				};
+			}
+			thread::sleep(time::Duration::from_millis(100));
+		}
+		panic!("Could not connect to the stratum server at {}", addr);
+	}
+
+	fn request(&mut self, method: &str, params: Value) -> Value {
+		let id = self.next_id;
+		self.next_id += 1;
+		let request = json_object(vec![("id", Value::from(id)),
+		                               ("jsonrpc", Value::from("2.0")),
+		                               ("method", Value::from(method)),
+		                               ("params", params)]);
+		let mut line = serde_json::to_string(&request).unwrap();
+		line.push('\n');
+		self.stream.write_all(line.as_bytes()).unwrap();
+
+		loop {
+			let mut line = String::new();
+			self.reader.read_line(&mut line).unwrap();
+			let response: Value = serde_json::from_str(&line).unwrap();
+			if response["id"] == Value::from(id) {
+				return response;
+			}
+		}
+	}
+
+	// Gets the current job, retrying until the server has built one for the
+	// given height
+	fn job(&mut self, height: u64) -> Value {
+		for _ in 0..50 {
+			let response = self.request("getjobtemplate", Value::Null);
+			if response["result"]["height"] == Value::from(height) {
+				return response["result"].clone();
+			}
+			thread::sleep(time::Duration::from_millis(100));
+		}
+		panic!("No stratum job available at height {}", height);
+	}
+}
+
+fn json_object(fields: Vec<(&str, Value)>) -> Value {
+	Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+/// Solves a job the way an external miner would, out of the pre and post
+/// nonce header parts, returning the parameters of the share to submit.
+fn solve(job: &Value) -> Value {
+	let mut header_bin = util::from_hex(job["pre_nonce"].as_str().unwrap().to_string()).unwrap();
+	header_bin.extend_from_slice(&[0; 8]);
+	header_bin.append(&mut util::from_hex(job["post_nonce"].as_str().unwrap().to_string()).unwrap());
+	header_bin.append(&mut ser::ser_vec(&Proof::zero(global::proofsize())).unwrap());
+	let mut header: BlockHeader = ser::deserialize(&mut &header_bin[..]).unwrap();
+
+	let sizeshift = global::sizeshift() as u32;
+	let mut miner = cuckoo::Miner::new(consensus::EASINESS, sizeshift, global::proofsize());
+	pow::pow_size(&mut miner,
+	              &mut header,
+	              Difficulty::from_num(job["difficulty"].as_u64().unwrap()),
+	              sizeshift)
+		.unwrap();
+
+	json_object(vec![("height", job["height"].clone()),
+	                 ("job_id", job["job_id"].clone()),
+	                 ("nonce", Value::from(header.nonce)),
+	                 ("pow", Value::from(header.pow.nonces.clone()))])
+}
+
+/// Logs a worker in, solves the job it gets and checks the block made it to
+/// the chain. Submitting the same solution again, once the head moved, has to
+/// count as a stale share.
+#[test]
+fn stratum_mine_block() {
+	let _ = env_logger::init();
+	global::set_mining_mode(MiningParameterMode::AutomatedTesting);
+
+	let db_root = "target/stratum/grin-stratum";
+	let _ = fs::remove_dir_all(db_root);
+
+	let evtlp = reactor::Core::new().unwrap();
+	let server = grin::Server::future(grin::ServerConfig {
+		                                  db_root: db_root.to_string(),
+		                                  api_http_addr: "127.0.0.1:14415".to_string(),
+		                                  p2p_config: Some(p2p::P2PConfig {
+			                                  port: 14414,
+			                                  ..p2p::P2PConfig::default()
+		                                  }),
+		                                  ..Default::default()
+		                                 },
+		                                 &evtlp.handle())
+		.unwrap();
+	let stratum_addr = "127.0.0.1:14416";
+	server.start_stratum_server(grin::StratumServerConfig {
+		enable_stratum_server: true,
+		stratum_server_addr: stratum_addr.to_string(),
+		burn_reward: true,
+		..Default::default()
+	});
+
+	let mut worker = TestWorker::connect(stratum_addr);
+	let login = json_object(vec![("login", Value::from("tester")), ("pass", Value::from(""))]);
+	assert_eq!(worker.request("login", login)["result"], Value::from("ok"));
+
+	let job = worker.job(1);
+	let share = solve(&job);
+	let response = worker.request("submit", share.clone());
+	assert!(response["error"].is_null(), "share rejected: {}", response);
+	assert_eq!(server.head().height, 1);
+
+	// the next job is on the new head, the old one is stale
+	let next_job = worker.job(2);
+	assert!(next_job["job_id"].as_u64().unwrap() > job["job_id"].as_u64().unwrap());
+	let response = worker.request("submit", share);
+	assert_eq!(response["error"]["code"], Value::from(-32503));
+
+	// stats are published at the end of each server loop
+	thread::sleep(time::Duration::from_millis(500));
+	let stats = server.get_server_stats().unwrap().stratum_workers;
+	assert_eq!(stats.len(), 1);
+	assert_eq!(stats[0].num_accepted, 1);
+	assert_eq!(stats[0].num_blocks_found, 1);
+	assert_eq!(stats[0].num_stale, 1);
+}
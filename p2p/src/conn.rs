@@ -185,6 +185,9 @@ impl Connection {
 				.from_err()
 				.and_then(move |(reader, buf)| {
 					let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
+					if header.msg_len > max_msg_len(header.msg_type) {
+						return Err(Error::Serialization(ser::Error::TooLargeReadErr));
+					}
 					Ok((reader, header))
 				})
 				.and_then(move |(reader, header)| {
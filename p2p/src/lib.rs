@@ -55,5 +55,5 @@ mod types;
 pub use server::Server;
 pub use peer::Peer;
 pub use types::{P2PConfig, NetAdapter, MAX_LOCATORS, MAX_BLOCK_HEADERS, MAX_PEER_ADDRS,
-                Capabilities, UNKNOWN, FULL_NODE, FULL_HIST, PeerInfo, Error};
+                Capabilities, UNKNOWN, FULL_NODE, FULL_HIST, UTXO_HIST, PeerInfo, Error};
 pub use store::{PeerStore, PeerData, State};
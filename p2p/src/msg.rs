@@ -21,7 +21,7 @@ use futures::future::{Future, ok};
 use tokio_core::net::TcpStream;
 use tokio_io::io::{read_exact, write_all};
 
-use core::consensus::MAX_MSG_LEN;
+use core::consensus::{MAX_MSG_LEN, MAX_SUMTREES_MSG_LEN};
 use core::core::BlockHeader;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
@@ -63,9 +63,23 @@ enum_from_primitive! {
     GetBlock,
     Block,
     Transaction,
+    GetSumTrees,
+    SumTrees,
+    CompactBlock,
   }
 }
 
+/// Maximum length of the body of a message of the provided type. Sum trees
+/// snapshots hold the whole UTXO set and get a limit of their own.
+pub fn max_msg_len(msg_type: Type) -> u64 {
+	match msg_type {
+		Type::SumTrees => MAX_SUMTREES_MSG_LEN,
+		// TODO add additional restrictions on a per-message-type basis to avoid
+		// 20MB pings
+		_ => MAX_MSG_LEN,
+	}
+}
+
 /// Future combinator to read any message where the body is a Readable. Reads
 /// the  header first, handles its validation and then reads the Readable body,
 /// allocating buffers of the right size.
@@ -76,9 +90,7 @@ pub fn read_msg<T>(conn: TcpStream) -> Box<Future<Item = (TcpStream, T), Error =
 		.from_err()
 		.and_then(|(reader, buf)| {
 			let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
-			if header.msg_len > MAX_MSG_LEN {
-				// TODO add additional restrictions on a per-message-type basis to avoid 20MB
-				// pings
+			if header.msg_len > max_msg_len(header.msg_type) {
 				return Err(Error::Serialization(ser::Error::TooLargeReadErr));
 			}
 			Ok((reader, header))
@@ -475,3 +487,72 @@ impl Readable for Empty {
 		Ok(Empty {})
 	}
 }
+
+#[cfg(test)]
+mod test {
+	extern crate secp256k1zkp as secp;
+
+	use super::*;
+	use futures::Stream;
+	use tokio_core::net::TcpListener;
+	use tokio_core::reactor::Core;
+	use self::secp::constants::MAX_PROOF_SIZE;
+	use self::secp::pedersen::{Commitment, RangeProof};
+
+	use core::core::{DEFAULT_OUTPUT, Output};
+	use core::core::hash::ZERO_HASH;
+	use core::core::snapshot::SumTreeSnapshot;
+
+	// A snapshot with 10,000 unspent outputs is over 50MB, mostly range proofs,
+	// and must still go through in a single message.
+	#[test]
+	fn send_large_sumtrees() {
+		let n = 10_000;
+		let outputs = (0..n)
+			.map(|i| {
+				let out = Output {
+					features: DEFAULT_OUTPUT,
+					commit: Commitment::from_vec(vec![(i % 256) as u8; 33]),
+					proof: RangeProof {
+						proof: [1; MAX_PROOF_SIZE],
+						plen: MAX_PROOF_SIZE,
+					},
+				};
+				(i * 2, out)
+			})
+			.collect::<Vec<_>>();
+		let snapshot = SumTreeSnapshot {
+			block_hash: ZERO_HASH,
+			output_mmr_size: n * 2,
+			output_nodes: vec![],
+			rproof_nodes: vec![],
+			outputs: outputs,
+			output_heights: (0..n).collect(),
+			kernels: vec![],
+		};
+
+		let mut body = vec![];
+		ser::serialize(&mut body, &snapshot).unwrap();
+		assert!(body.len() as u64 > max_msg_len(Type::Block));
+		assert!(body.len() as u64 <= max_msg_len(Type::SumTrees));
+
+		let mut evtlp = Core::new().unwrap();
+		let handle = evtlp.handle();
+		let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let send = TcpStream::connect(&addr, &handle)
+			.map_err(|e| Error::Connection(e))
+			.and_then(move |conn| write_msg(conn, snapshot, Type::SumTrees));
+		let recv = listener
+			.incoming()
+			.into_future()
+			.map_err(|(e, _)| Error::Connection(e))
+			.and_then(|(conn, _)| read_msg::<SumTreeSnapshot>(conn.unwrap().0));
+
+		let (_, (_, received)) = evtlp.run(send.join(recv)).unwrap();
+		assert_eq!(received.outputs.len(), n as usize);
+		assert_eq!(received.output_heights[n as usize - 1], n - 1);
+		assert_eq!(received.outputs[7].1.proof.plen, MAX_PROOF_SIZE);
+	}
+}
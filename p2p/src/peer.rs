@@ -143,6 +143,17 @@ impl Peer {
 		self.proto.send_block(b)
 	}
 
+	/// Sends the compact version of the provided block to the remote peer,
+	/// which is expected to have most of its transactions already.
+	pub fn send_compact_block(&self, cb: &core::CompactBlock) -> Result<(), Error> {
+		self.proto.send_compact_block(cb)
+	}
+
+	/// Sends the provided transaction to the remote peer.
+	pub fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		self.proto.send_transaction(tx)
+	}
+
 	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
 		self.proto.send_header_request(locator)
 	}
@@ -157,6 +168,11 @@ impl Peer {
 		self.proto.send_peer_request(capab)
 	}
 
+	pub fn send_sumtrees_request(&self, h: Hash) -> Result<(), Error> {
+		debug!("Requesting sum trees at {} from peer {}.", h, self.info.addr);
+		self.proto.send_sumtrees_request(h)
+	}
+
 	pub fn stop(&self) {
 		self.proto.close();
 	}
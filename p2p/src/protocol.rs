@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::sync::{Mutex, Arc};
+use std::time::{Duration, Instant};
 
 use futures::Future;
 use futures::sync::mpsc::UnboundedSender;
@@ -20,17 +21,25 @@ use tokio_core::net::TcpStream;
 
 use core::core;
 use core::core::hash::Hash;
+use core::core::snapshot::SumTreeSnapshot;
 use core::ser;
 use conn::TimeoutConnection;
 use msg::*;
 use types::*;
 use util::OneTime;
 
+/// Minimum time between two sum trees snapshots served to the same peer,
+/// building one being expensive
+const SUMTREES_REQUEST_INTERVAL_SECS: u64 = 20;
+
 #[allow(dead_code)]
 pub struct ProtocolV1 {
 	conn: OneTime<TimeoutConnection>,
 
 	expected_responses: Mutex<Vec<(Type, Hash)>>,
+
+	// when the peer last got a sum trees snapshot from us
+	last_sumtrees_req: Arc<Mutex<Option<Instant>>>,
 }
 
 impl ProtocolV1 {
@@ -38,6 +47,7 @@ impl ProtocolV1 {
 		ProtocolV1 {
 			conn: OneTime::new(),
 			expected_responses: Mutex::new(vec![]),
+			last_sumtrees_req: Arc::new(Mutex::new(None)),
 		}
 	}
 }
@@ -49,9 +59,11 @@ impl Protocol for ProtocolV1 {
 	          adapter: Arc<NetAdapter>)
 	          -> Box<Future<Item = (), Error = Error>> {
 
+		let last_sumtrees_req = self.last_sumtrees_req.clone();
 		let (conn, listener) = TimeoutConnection::listen(conn, move |sender, header, data| {
 			let adapt = adapter.as_ref();
-			handle_payload(adapt, sender, header, data).map_err(|_| ser::Error::CorruptedData)
+			handle_payload(adapt, sender, header, data, &last_sumtrees_req)
+				.map_err(|_| ser::Error::CorruptedData)
 		});
 
 		self.conn.init(conn);
@@ -75,6 +87,11 @@ impl Protocol for ProtocolV1 {
 		self.send_msg(Type::Block, b)
 	}
 
+	/// Serializes and sends a compact block to our remote peer
+	fn send_compact_block(&self, cb: &core::CompactBlock) -> Result<(), Error> {
+		self.send_msg(Type::CompactBlock, cb)
+	}
+
 	/// Serializes and sends a transaction to our remote peer
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
 		self.send_msg(Type::Transaction, tx)
@@ -98,6 +115,12 @@ impl Protocol for ProtocolV1 {
 		                  None)
 	}
 
+	/// Snapshots can take a while to build and send, so no response is
+	/// expected within the usual timeout.
+	fn send_sumtrees_request(&self, h: Hash) -> Result<(), Error> {
+		self.send_msg(Type::GetSumTrees, &h)
+	}
+
 	/// Close the connection to the remote peer
 	fn close(&self) {
 		// TODO some kind of shutdown signal
@@ -122,7 +145,8 @@ impl ProtocolV1 {
 fn handle_payload(adapter: &NetAdapter,
                   sender: UnboundedSender<Vec<u8>>,
                   header: MsgHeader,
-                  buf: Vec<u8>)
+                  buf: Vec<u8>,
+                  last_sumtrees_req: &Mutex<Option<Instant>>)
                   -> Result<Option<Hash>, Error> {
 	match header.msg_type {
 		Type::Ping => {
@@ -156,6 +180,21 @@ fn handle_payload(adapter: &NetAdapter,
 			let bh = b.hash();
 			adapter.block_received(b).and(Ok(Some(bh)))
 		}
+		Type::CompactBlock => {
+			let cb = ser::deserialize::<core::CompactBlock>(&mut &buf[..])?;
+			let bh = cb.hash();
+			if !adapter.compact_block_received(cb)? {
+				// missing transactions, asking for the full block instead
+				let mut body_data = vec![];
+				try!(ser::serialize(&mut body_data, &bh));
+				let mut data = vec![];
+				try!(ser::serialize(&mut data,
+				                    &MsgHeader::new(Type::GetBlock, body_data.len() as u64)));
+				data.append(&mut body_data);
+				sender.send(data).unwrap();
+			}
+			Ok(Some(bh))
+		}
 		Type::GetHeaders => {
 			// load headers from the locator
 			let loc = ser::deserialize::<Locator>(&mut &buf[..])?;
@@ -200,6 +239,36 @@ fn handle_payload(adapter: &NetAdapter,
 			let peer_addrs = ser::deserialize::<PeerAddrs>(&mut &buf[..])?;
 			adapter.peer_addrs_received(peer_addrs.peers.iter().map(|pa| pa.0).collect()).and(Ok(None))
 		}
+		Type::GetSumTrees => {
+			let h = ser::deserialize::<Hash>(&mut &buf[..])?;
+			{
+				// ignoring peers asking again too soon
+				let mut last_req = last_sumtrees_req.lock().unwrap();
+				if let Some(t) = *last_req {
+					if t.elapsed() < Duration::from_secs(SUMTREES_REQUEST_INTERVAL_SECS) {
+						debug!("Ignoring sum trees request for {}, asked too recently.", h);
+						return Ok(None);
+					}
+				}
+				*last_req = Some(Instant::now());
+			}
+			if let Some(snapshot) = adapter.get_sumtrees(h) {
+				// serialize and send the snapshot over
+				let mut body_data = vec![];
+				try!(ser::serialize(&mut body_data, &snapshot));
+				let mut data = vec![];
+				try!(ser::serialize(&mut data,
+				                    &MsgHeader::new(Type::SumTrees, body_data.len() as u64)));
+				data.append(&mut body_data);
+				sender.send(data).unwrap();
+			}
+			Ok(None)
+		}
+		Type::SumTrees => {
+			let snapshot = ser::deserialize::<SumTreeSnapshot>(&mut &buf[..])?;
+			let h = snapshot.block_hash;
+			adapter.sumtrees_received(snapshot).and(Ok(Some(h)))
+		}
 		_ => {
 			debug!("unknown message type {:?}", header.msg_type);
 			Ok(None)
@@ -16,9 +16,10 @@
 //! other peers in the network.
 
 use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use std::io;
 
@@ -30,12 +31,16 @@ use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor;
 
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
 use handshake::Handshake;
 use peer::Peer;
 use types::*;
 
+/// Number of recently broadcasted transactions we keep track of, to avoid
+/// relaying the same transaction over and over.
+const MAX_RECENT_TXS: usize = 1000;
+
 /// P2P server implementation, handling bootstrapping to find and connect to
 /// peers, receiving connections from other peers and keep track of all of them.
 pub struct Server {
@@ -44,6 +49,7 @@ pub struct Server {
 	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
 	adapter: Arc<NetAdapter>,
 	stop: RefCell<Option<futures::sync::oneshot::Sender<()>>>,
+	recent_txs: Mutex<VecDeque<Hash>>,
 }
 
 unsafe impl Sync for Server {}
@@ -59,6 +65,7 @@ impl Server {
 			peers: Arc::new(RwLock::new(Vec::new())),
 			adapter: adapter,
 			stop: RefCell::new(None),
+			recent_txs: Mutex::new(VecDeque::new()),
 		}
 	}
 
@@ -229,20 +236,49 @@ impl Server {
 		}
 	}
 
-	/// Broadcasts the provided block to all our peers. A peer implementation
-	/// may drop the broadcast request if it knows the remote peer already has
-	/// the block.
+	/// Broadcasts the provided block to all our peers, as a compact block.
+	/// Peers rebuild it from the transactions they already have and only ask
+	/// for the full block when some are missing. A peer implementation may
+	/// drop the broadcast request if it knows the remote peer already has the
+	/// block.
 	pub fn broadcast_block(&self, b: &core::Block) {
+		let cb = core::CompactBlock::from_block(b);
 		let peers = self.peers.write().unwrap();
 		for p in peers.deref() {
 			if p.is_connected() {
-				if let Err(e) = p.send_block(b) {
+				if let Err(e) = p.send_compact_block(&cb) {
 					debug!("Error sending block to peer: {:?}", e);
 				}
 			}
 		}
 	}
 
+	/// Broadcasts the provided transaction to all our peers. A transaction that
+	/// has been broadcasted recently is skipped, so that transactions coming
+	/// back to us from other peers don't get relayed endlessly.
+	pub fn broadcast_transaction(&self, tx: &core::Transaction) {
+		let h = tx.hash();
+		{
+			let mut recent_txs = self.recent_txs.lock().unwrap();
+			if recent_txs.contains(&h) {
+				return;
+			}
+			if recent_txs.len() >= MAX_RECENT_TXS {
+				recent_txs.pop_front();
+			}
+			recent_txs.push_back(h);
+		}
+
+		let peers = self.peers.write().unwrap();
+		for p in peers.deref() {
+			if p.is_connected() {
+				if let Err(e) = p.send_transaction(tx) {
+					debug!("Error sending transaction to peer: {:?}", e);
+				}
+			}
+		}
+	}
+
 	/// Number of peers we're currently connected to.
 	pub fn peer_count(&self) -> u32 {
 		self.peers.read().unwrap().len() as u32
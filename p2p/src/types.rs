@@ -23,6 +23,7 @@ use tokio_timer::TimerError;
 
 use core::core;
 use core::core::hash::Hash;
+use core::core::snapshot::SumTreeSnapshot;
 use core::core::target::Difficulty;
 use core::ser;
 
@@ -129,6 +130,9 @@ pub trait Protocol {
 	/// Relays a block to the remote peer.
 	fn send_block(&self, b: &core::Block) -> Result<(), Error>;
 
+	/// Relays a compact block to the remote peer.
+	fn send_compact_block(&self, cb: &core::CompactBlock) -> Result<(), Error>;
+
 	/// Relays a transaction to the remote peer.
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
 
@@ -141,6 +145,9 @@ pub trait Protocol {
 	/// Sends a request for some peer addresses.
 	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error>;
 
+	/// Sends a request for a snapshot of the sum trees at the provided block.
+	fn send_sumtrees_request(&self, h: Hash) -> Result<(), Error>;
+
 	/// How many bytes have been sent/received to/from the remote peer.
 	fn transmitted_bytes(&self) -> (u64, u64);
 
@@ -162,6 +169,11 @@ pub trait NetAdapter: Sync + Send {
 	/// A block has been received from one of our peers
 	fn block_received(&self, b: core::Block) -> Result<(), Error>;
 
+	/// A compact block has been received from one of our peers. Returns
+	/// whether the full block could be rebuilt from it, the full block getting
+	/// requested from the peer otherwise.
+	fn compact_block_received(&self, cb: core::CompactBlock) -> Result<bool, Error>;
+
 	/// A set of block header has been received, typically in response to a
 	/// block
 	/// header request.
@@ -175,6 +187,14 @@ pub trait NetAdapter: Sync + Send {
 	/// Gets a full block by its hash.
 	fn get_block(&self, h: Hash) -> Option<core::Block>;
 
+	/// Builds a snapshot of the sum trees at the provided block, to help a
+	/// peer sync without replaying the whole chain.
+	fn get_sumtrees(&self, h: Hash) -> Option<SumTreeSnapshot>;
+
+	/// A snapshot of the sum trees has been received, typically in response
+	/// to a sum trees request.
+	fn sumtrees_received(&self, snapshot: SumTreeSnapshot) -> Result<(), Error>;
+
 	/// Find good peers we know with the provided capability and return their
 	/// addresses.
 	fn find_peer_addrs(&self, capab: Capabilities) -> Option<Vec<SocketAddr>>;
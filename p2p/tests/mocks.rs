@@ -48,6 +48,7 @@ impl NetAdapter for DummyAdapter {
 	}
 	fn transaction_received(&self, tx: Transaction) -> Result<(), Error> { Ok(()) }
 	fn block_received(&self, b: Block) -> Result<(), Error> { Ok(()) }
+	fn compact_block_received(&self, cb: CompactBlock) -> Result<bool, Error> { Ok(true) }
 	fn headers_received(&self, bh: Vec<BlockHeader>) -> Result<(), Error> { Ok(()) }
 	fn locate_headers(&self, locator: Vec<Hash>) -> Option<Vec<BlockHeader>> {
 		None
@@ -55,6 +56,10 @@ impl NetAdapter for DummyAdapter {
 	fn get_block(&self, h: Hash) -> Option<Block> {
 		None
 	}
+	fn get_sumtrees(&self, h: Hash) -> Option<snapshot::SumTreeSnapshot> {
+		None
+	}
+	fn sumtrees_received(&self, snapshot: snapshot::SumTreeSnapshot) -> Result<(), Error> { Ok(()) }
 	fn find_peer_addrs(&self, capab: Capabilities) -> Option<Vec<SocketAddr>> {
 		None
 	}
@@ -70,6 +75,7 @@ impl NetAdapter for RejectingAdapter {
 	}
 	fn transaction_received(&self, tx: Transaction) -> Result<(), Error> { Err(Error::Invalid) }
 	fn block_received(&self, b: Block) -> Result<(), Error> { Err(Error::Invalid) }
+	fn compact_block_received(&self, cb: CompactBlock) -> Result<bool, Error> { Err(Error::Invalid) }
 	fn headers_received(&self, bh: Vec<BlockHeader>) -> Result<(), Error> { Err(Error::Invalid) }
 	fn locate_headers(&self, locator: Vec<Hash>) -> Option<Vec<BlockHeader>> {
 		None
@@ -77,6 +83,10 @@ impl NetAdapter for RejectingAdapter {
 	fn get_block(&self, h: Hash) -> Option<Block> {
 		None
 	}
+	fn get_sumtrees(&self, h: Hash) -> Option<snapshot::SumTreeSnapshot> {
+		None
+	}
+	fn sumtrees_received(&self, snapshot: snapshot::SumTreeSnapshot) -> Result<(), Error> { Err(Error::Invalid) }
 	fn find_peer_addrs(&self, capab: Capabilities) -> Option<Vec<SocketAddr>> {
 		None
 	}
@@ -78,14 +78,18 @@ impl DummyUtxoSet {
 /// need
 #[allow(dead_code)]
 pub struct DummyChainImpl {
-    utxo: RwLock<DummyUtxoSet>
+    utxo: RwLock<DummyUtxoSet>,
+    output_heights: RwLock<HashMap<Commitment, u64>>,
+    head_height: RwLock<u64>,
 }
 
 #[allow(dead_code)]
 impl DummyChainImpl {
     pub fn new() -> DummyChainImpl {
         DummyChainImpl{
-            utxo: RwLock::new(DummyUtxoSet{outputs: HashMap::new()})}
+            utxo: RwLock::new(DummyUtxoSet{outputs: HashMap::new()}),
+            output_heights: RwLock::new(HashMap::new()),
+            head_height: RwLock::new(0)}
     }
 }
 
@@ -93,6 +97,13 @@ impl BlockChain for DummyChainImpl {
     fn get_unspent(&self, commitment: &Commitment) -> Option<transaction::Output> {
         self.utxo.read().unwrap().get_output(commitment).cloned()
     }
+    fn get_output_height(&self, commitment: &Commitment) -> Option<u64> {
+        self.get_unspent(commitment).
+            and_then(|_| self.output_heights.read().unwrap().get(commitment).cloned())
+    }
+    fn head_height(&self) -> u64 {
+        *self.head_height.read().unwrap()
+    }
 }
 
 impl DummyChain for DummyChainImpl {
@@ -101,10 +112,23 @@ impl DummyChain for DummyChainImpl {
     }
     fn apply_block(&self, b: &block::Block) {
         self.utxo.write().unwrap().with_block(b);
+        let mut heights = self.output_heights.write().unwrap();
+        for output in &b.outputs {
+            heights.insert(output.commitment(), b.header.height);
+        }
+        self.store_head_height(b.header.height);
+    }
+    fn store_output_height(&self, commitment: Commitment, height: u64) {
+        self.output_heights.write().unwrap().insert(commitment, height);
+    }
+    fn store_head_height(&self, height: u64) {
+        *self.head_height.write().unwrap() = height;
     }
 }
 
 pub trait DummyChain: BlockChain {
     fn update_utxo_set(&mut self, new_utxo: DummyUtxoSet);
     fn apply_block(&self, b: &block::Block);
+    fn store_output_height(&self, commitment: Commitment, height: u64);
+    fn store_head_height(&self, height: u64);
 }
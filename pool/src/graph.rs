@@ -34,8 +34,10 @@ pub struct PoolEntry {
     pub transaction_hash: core::hash::Hash,
 
     // Metadata
-    /// Size estimate
+    /// Size estimate, as the weight of the transaction in a block
     pub size_estimate: u64,
+    /// Fee paid by the transaction
+    pub fee: u64,
     /// Receive timestamp
     pub receive_ts: time::Tm,
 }
@@ -46,13 +48,14 @@ impl PoolEntry {
         PoolEntry{
             transaction_hash: transaction_identifier(tx),
             size_estimate : estimate_transaction_size(tx),
+            fee: tx.fee,
             receive_ts: time::now()}
     }
 }
 
-/// TODO guessing this needs implementing
-fn estimate_transaction_size(_tx: &core::transaction::Transaction) -> u64 {
-    0
+/// The space a transaction takes in a block is estimated by its weight
+fn estimate_transaction_size(tx: &core::transaction::Transaction) -> u64 {
+    tx.block_weight()
 }
 
 /// An edge connecting graph vertices.
@@ -207,6 +210,71 @@ impl DirectedGraph {
     pub fn get_roots(&self) -> Vec<core::hash::Hash> {
         self.roots.iter().map(|x| x.transaction_hash).collect()
     }
+
+    /// Get all the vertices, roots included
+    pub fn get_entries(&self) -> Vec<&PoolEntry> {
+        self.roots.iter().chain(self.vertices.iter()).collect()
+    }
+
+    /// Get the parents of each vertex having some, the vertices at the
+    /// source of its incoming edges
+    pub fn get_parents(&self) -> HashMap<core::hash::Hash, Vec<core::hash::Hash>> {
+        let mut parents: HashMap<core::hash::Hash, Vec<core::hash::Hash>> = HashMap::new();
+        for edge in self.edges.values() {
+            if let (Some(src), Some(dst)) = (edge.source, edge.destination) {
+                parents.entry(dst).or_insert(vec![]).push(src);
+            }
+        }
+        parents
+    }
+
+    /// Whether the graph holds the vertex
+    pub fn contains_vertex(&self, tx_hash: &core::hash::Hash) -> bool {
+        self.roots.iter().chain(self.vertices.iter()).
+            any(|x| x.transaction_hash == *tx_hash)
+    }
+
+    /// Get the children of a vertex, the vertices at the destination of its
+    /// outgoing edges
+    pub fn get_children(&self, tx_hash: &core::hash::Hash) -> Vec<core::hash::Hash> {
+        self.edges.values().
+            filter(|x| x.source == Some(*tx_hash)).
+            filter_map(|x| x.destination).
+            collect()
+    }
+
+    /// Remove the incoming edges of a vertex
+    pub fn remove_edges_to(&mut self, tx_hash: &core::hash::Hash) -> Vec<Edge> {
+        let commitments: Vec<Commitment> = self.edges.values().
+            filter(|x| x.destination == Some(*tx_hash)).
+            map(|x| x.output).
+            collect();
+        commitments.iter().filter_map(|x| self.edges.remove(x)).collect()
+    }
+
+    /// Get the vertex paying the lowest fee per unit of weight
+    pub fn get_lowest_fee_rate(&self) -> Option<core::hash::Hash> {
+        let mut lowest: Option<&PoolEntry> = None;
+        for entry in self.roots.iter().chain(self.vertices.iter()) {
+            // comparing fee / weight ratios without losing precision
+            let is_lower = match lowest {
+                Some(x) => entry.fee * x.size_estimate < x.fee * entry.size_estimate,
+                None => true,
+            };
+            if is_lower {
+                lowest = Some(entry);
+            }
+        }
+        lowest.map(|x| x.transaction_hash)
+    }
+
+    /// Get the vertices received before the provided time
+    pub fn get_received_before(&self, ts: time::Timespec) -> Vec<core::hash::Hash> {
+        self.roots.iter().chain(self.vertices.iter()).
+            filter(|x| x.receive_ts.to_timespec() < ts).
+            map(|x| x.transaction_hash).
+            collect()
+    }
 }
 
 /// Using transaction merkle_inputs_outputs to calculate a deterministic hash;
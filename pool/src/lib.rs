@@ -29,9 +29,12 @@ mod pool;
 extern crate time;
 extern crate rand;
 extern crate log;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 extern crate grin_core as core;
 extern crate secp256k1zkp as secp;
 
 pub use pool::TransactionPool;
-pub use types::{BlockChain, TxSource, PoolError};
+pub use types::{BlockChain, PoolAdapter, NoopAdapter, PoolConfig, TxSource, PoolError};
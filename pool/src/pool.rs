@@ -14,19 +14,27 @@
 
 //! Top-level Pool type, methods, and tests
 
-use types::{Pool, BlockChain, Orphans, Parent, PoolError, TxSource, TransactionGraphContainer};
+use types::{Pool, BlockChain, PoolAdapter, PoolConfig, Orphans, Parent, PoolError, TxSource, TransactionGraphContainer};
 pub use graph;
 
 use core::core::transaction;
 use core::core::block;
 use core::core::hash;
+use core::global;
 
 use secp;
 use secp::pedersen::Commitment;
 
-use std::sync::Arc;
+use std::mem;
+use std::sync::{Arc, RwLock};
 use std::collections::HashMap;
 
+use time;
+
+/// Maximum number of blocks ahead of the next one a transaction can be time
+/// locked for the pool to hold it until its lock height.
+pub const MAX_LOCK_HEIGHT_HORIZON: u64 = 1_440;
+
 /// The pool itself.
 /// The transactions HashMap holds ownership of all transactions in the pool,
 /// keyed by their transaction hash.
@@ -37,20 +45,37 @@ pub struct TransactionPool<T> {
     pub pool : Pool,
     /// Orphans in the pool
     pub orphans: Orphans,
+    /// All the transactions in the orphans set, keyed by their transaction
+    /// hash
+    pub orphan_transactions: HashMap<hash::Hash, Box<transaction::Transaction>>,
+    /// Transactions held until the chain reaches their lock height, keyed by
+    /// their transaction hash
+    pub time_locked: HashMap<hash::Hash, Box<transaction::Transaction>>,
+
+    // transactions accepted in the pool the adapter hasn't been notified of
+    // yet, it only gets notified once the pool lock is released
+    accepted: Vec<hash::Hash>,
 
     // blockchain is a DummyChain, for now, which mimics what the future
     // chain will offer to the pool
     blockchain: Arc<T>,
+    adapter: Arc<PoolAdapter>,
+    config: PoolConfig,
 }
 
 impl<T> TransactionPool<T> where T: BlockChain {
     /// Create a new transaction pool
-    pub fn new(chain: Arc<T>) -> TransactionPool<T> {
+    pub fn new(config: PoolConfig, chain: Arc<T>, adapter: Arc<PoolAdapter>) -> TransactionPool<T> {
         TransactionPool{
             transactions: HashMap::new(),
             pool: Pool::empty(),
             orphans: Orphans::empty(),
+            orphan_transactions: HashMap::new(),
+            time_locked: HashMap::new(),
+            accepted: vec![],
             blockchain: chain,
+            adapter: adapter,
+            config: config,
         }
     }
 
@@ -92,6 +117,44 @@ impl<T> TransactionPool<T> where T: BlockChain {
 
     }
 
+    /// Whether the output designated by its commitment is created by a
+    /// transaction in the pool, spent or not. Orphans are not considered.
+    pub fn is_output_created(&self, output_commitment: &Commitment) -> bool {
+        self.pool.find_output(output_commitment).is_some()
+    }
+
+    /// Whether the output designated by its commitment, either in the
+    /// blockchain or created in the pool, is spent by a transaction in the
+    /// pool. Orphans are not considered.
+    pub fn is_output_spent(&self, output_commitment: &Commitment) -> bool {
+        self.pool.get_blockchain_spent(output_commitment).is_some() ||
+            self.pool.get_internal_spent(output_commitment).is_some()
+    }
+
+    // check_coinbase_maturity makes sure a blockchain output, if it's a
+    // coinbase, can be spent in the next block to be mined on the head. Like
+    // the chain, a coinbase whose height isn't known can't be proven mature,
+    // it's considered as created by the head.
+    fn check_coinbase_maturity(&self, output_commitment: &Commitment) -> Result<(), PoolError> {
+        let output = match self.blockchain.get_unspent(output_commitment) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if !output.features.intersects(transaction::COINBASE_OUTPUT) {
+            return Ok(());
+        }
+        let head_height = self.blockchain.head_height();
+        let height = self.blockchain.get_output_height(output_commitment).unwrap_or(head_height);
+        let lock_height = height + global::coinbase_maturity();
+        if head_height + 1 < lock_height {
+            return Err(PoolError::ImmatureCoinbase{
+                output: output_commitment.clone(),
+                lock_height: lock_height,
+            });
+        }
+        Ok(())
+    }
+
     /// Get the number of transactions in the pool
     pub fn pool_size(&self) -> usize {
         self.pool.num_transactions()
@@ -113,11 +176,30 @@ impl<T> TransactionPool<T> where T: BlockChain {
     /// if necessary, and performing any connection-related validity checks.
     /// Happens under an exclusive mutable reference gated by the write portion
     /// of a RWLock.
-    pub fn add_to_memory_pool(&mut self, _: TxSource, tx: transaction::Transaction) -> Result<(), PoolError> {
+    pub fn add_to_memory_pool(&mut self, source: TxSource, tx: transaction::Transaction) -> Result<(), PoolError> {
+        let next_height = self.blockchain.head_height() + 1;
+        let res = self.add_at_height(source, tx, next_height);
+        if res.is_ok() {
+            self.reconcile_orphans(next_height);
+        }
+        res
+    }
+
+    // add_at_height is add_to_memory_pool for a given height of the next block
+    // to be mined, which may not be known to the blockchain yet when releasing
+    // time locked transactions.
+    fn add_at_height(&mut self, _: TxSource, tx: transaction::Transaction, next_height: u64) -> Result<(), PoolError> {
         // Making sure the transaction is valid before anything else.
         let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
         tx.validate(&secp).map_err(|_| PoolError::Invalid)?;
 
+        // The fee has to cover at least the minimum required by the pool for
+        // the weight of the transaction.
+        let min_fee = self.config.accept_fee_base * tx.weight();
+        if tx.fee < min_fee {
+            return Err(PoolError::LowFeeTransaction(min_fee));
+        }
+
         // The first check invovles ensuring that an identical transaction is
         // not already in the pool's transaction set.
         // A non-authoritative similar check should be performed under the
@@ -128,10 +210,37 @@ impl<T> TransactionPool<T> where T: BlockChain {
         // The current tx.hash() method, for example, does not cover changes
         // to fees or other elements of the signature preimage.
         let tx_hash = graph::transaction_identifier(&tx);
-        if self.transactions.contains_key(&tx_hash) {
+        if self.transactions.contains_key(&tx_hash) || self.time_locked.contains_key(&tx_hash) ||
+            self.orphan_transactions.contains_key(&tx_hash) {
             return Err(PoolError::AlreadyInPool)
         }
 
+        // A transaction that can't be mined in the next block is held as is
+        // until the chain gets to its lock height, it only goes through the
+        // remaining checks at that point. We only hold it if it isn't locked
+        // too far in the future and spends outputs we know of.
+        if tx.lock_height > next_height {
+            let lock_height = tx.lock_height;
+            if lock_height - next_height > MAX_LOCK_HEIGHT_HORIZON {
+                return Err(PoolError::LockHeightTooHigh{lock_height: lock_height});
+            }
+            for input in &tx.inputs {
+                match self.search_for_best_output(&input.commitment()) {
+                    Parent::PoolTransaction{..} | Parent::BlockTransaction => {},
+                    Parent::Unknown => return Err(PoolError::TimeLockedOrphan),
+                    Parent::AlreadySpent{other_tx: x} => return Err(PoolError::DoubleSpend{other_tx: x, spent_output: input.commitment()}),
+                }
+            }
+
+            self.time_locked.insert(tx_hash, Box::new(tx));
+            self.evict_from_time_locked();
+            self.evict_from_pool();
+            if !self.time_locked.contains_key(&tx_hash) {
+                return Err(PoolError::OverCapacity);
+            }
+            return Err(PoolError::TimeLocked{lock_height: lock_height});
+        }
+
 
         // The next issue is to identify all unspent outputs that
         // this transaction will consume and make sure they exist in the set.
@@ -149,7 +258,10 @@ impl<T> TransactionPool<T> where T: BlockChain {
             // into the pool.
             match self.search_for_best_output(&input.commitment()) {
                 Parent::PoolTransaction{tx_ref: x} => pool_refs.push(base.with_source(Some(x))),
-                Parent::BlockTransaction => blockchain_refs.push(base),
+                Parent::BlockTransaction => {
+                    self.check_coinbase_maturity(&input.commitment())?;
+                    blockchain_refs.push(base)
+                },
                 Parent::Unknown => orphan_refs.push(base),
                 Parent::AlreadySpent{other_tx: x} => return Err(PoolError::DoubleSpend{other_tx: x, spent_output: input.commitment()}),
             }
@@ -185,9 +297,16 @@ impl<T> TransactionPool<T> where T: BlockChain {
             // output is unique. No further checks are necessary.
             self.pool.add_pool_transaction(pool_entry, blockchain_refs,
                 pool_refs, new_unspents);
-
-            self.reconcile_orphans().unwrap();
             self.transactions.insert(tx_hash, Box::new(tx));
+
+            // Making room if the pool got over its maximum size, which may
+            // well mean evicting the transaction we just added.
+            self.evict_from_pool();
+            if !self.transactions.contains_key(&tx_hash) {
+                return Err(PoolError::OverCapacity);
+            }
+
+            self.accepted.push(tx_hash);
             Ok(())
 
         } else {
@@ -217,12 +336,114 @@ impl<T> TransactionPool<T> where T: BlockChain {
             pool_refs.append(&mut blockchain_refs);
             self.orphans.add_orphan_transaction(pool_entry,
                 pool_refs, orphan_refs, missing_refs, new_unspents);
+            self.orphan_transactions.insert(tx_hash, Box::new(tx));
+
+            self.expire_orphans();
+            self.evict_from_orphans();
+            if !self.orphan_transactions.contains_key(&tx_hash) {
+                return Err(PoolError::OverCapacity);
+            }
 
             Err(PoolError::OrphanTransaction)
         }
 
     }
 
+    // Evicts the transactions with the lowest fee rates until the pool,
+    // counting the time locked transactions held for it, is back under its
+    // maximum size. Pool transactions go along with the ones spending their
+    // outputs.
+    fn evict_from_pool(&mut self) {
+        while self.pool.num_transactions() + self.time_locked.len() > self.config.max_pool_size {
+            let lowest_pool = self.pool.get_graph().get_lowest_fee_rate().
+                map(|x| (x, self.transactions[&x].fee, self.transactions[&x].block_weight()));
+            match (lowest_pool, self.lowest_time_locked()) {
+                // comparing fee / weight ratios without losing precision
+                (Some((_, fee, weight)), Some((locked, locked_fee, locked_weight)))
+                    if locked_fee * weight < fee * locked_weight => {
+                    self.time_locked.remove(&locked);
+                },
+                (Some((lowest, _, _)), _) => {
+                    let mut marked_transactions: HashMap<hash::Hash, ()> = HashMap::new();
+                    self.mark_transaction(lowest, &mut marked_transactions);
+                    self.sweep_transactions(marked_transactions);
+                },
+                (None, Some((locked, _, _))) => {
+                    self.time_locked.remove(&locked);
+                },
+                (None, None) => break,
+            }
+        }
+    }
+
+    // Evicts the orphans with the lowest fee rates, along with the orphans
+    // depending on them, until the orphans set is back under its maximum
+    // size.
+    fn evict_from_orphans(&mut self) {
+        while self.orphans.num_transactions() > self.config.max_orphans_size {
+            let lowest = match self.orphans.get_graph().get_lowest_fee_rate() {
+                Some(x) => x,
+                None => break,
+            };
+            for txh in self.orphans.remove_orphan_transaction(lowest) {
+                self.orphan_transactions.remove(&txh);
+            }
+        }
+    }
+
+    // Evicts the time locked transactions with the lowest fee rates until
+    // there are no more than the configured maximum.
+    fn evict_from_time_locked(&mut self) {
+        while self.time_locked.len() > self.config.max_time_locked_size {
+            match self.lowest_time_locked() {
+                Some((x, _, _)) => self.time_locked.remove(&x),
+                None => break,
+            };
+        }
+    }
+
+    // The time locked transaction paying the lowest fee rate, along with its
+    // fee and weight.
+    fn lowest_time_locked(&self) -> Option<(hash::Hash, u64, u64)> {
+        self.time_locked.iter().
+            map(|(h, tx)| (*h, tx.fee, tx.block_weight())).
+            min_by(|&(_, fee_a, weight_a), &(_, fee_b, weight_b)|
+                (fee_a * weight_b).cmp(&(fee_b * weight_a)))
+    }
+
+    // Drops the time locked transactions spending outputs that are gone,
+    // spent by a block or a pool transaction or created by a transaction
+    // evicted from the pool, as they'd be rejected at their lock height.
+    fn expire_time_locked(&mut self) {
+        let expired: Vec<hash::Hash> = self.time_locked.iter().
+            filter(|&(_, tx)| tx.inputs.iter().any(|input|
+                match self.search_for_best_output(&input.commitment()) {
+                    Parent::PoolTransaction{..} | Parent::BlockTransaction => false,
+                    _ => true,
+                })).
+            map(|(h, _)| *h).
+            collect();
+        for txh in expired {
+            self.time_locked.remove(&txh);
+        }
+    }
+
+    /// Drops the orphans that have been waiting for their parents for longer
+    /// than the configured expiry.
+    pub fn expire_orphans(&mut self) {
+        let mut cutoff = time::get_time();
+        cutoff.sec -= self.config.orphan_expiry_secs as i64;
+        let expired = self.orphans.get_graph().get_received_before(cutoff);
+        for tx_hash in expired {
+            // may already be gone along with an expired parent
+            if self.orphans.get_graph().contains_vertex(&tx_hash) {
+                for txh in self.orphans.remove_orphan_transaction(tx_hash) {
+                    self.orphan_transactions.remove(&txh);
+                }
+            }
+        }
+    }
+
     /// Check the output for a conflict with an existing output.
     ///
     /// Checks the output (by commitment) against outputs in the blockchain
@@ -314,14 +535,59 @@ impl<T> TransactionPool<T> where T: BlockChain {
         Ok(missing_refs)
     }
 
-    /// The primary goal of the reconcile_orphans method is to eliminate any
-    /// orphans who conflict with the recently accepted pool transaction.
-    /// TODO: How do we handle fishing orphans out that look like they could
-    /// be freed? Current thought is to do so under a different lock domain
-    /// so that we don't have the potential for long recursion under the write
-    /// lock.
-    pub fn reconcile_orphans(&self)-> Result<(),PoolError> {
-        Ok(())
+    /// Passes the transactions accepted by the pool since the last call on to
+    /// its adapter, including the ones released from the time locked set or
+    /// freed from the orphans. The pool lock is only held to collect them, so
+    /// the adapter (and the network broadcast behind it) never runs under it.
+    pub fn notify_accepted(pool: &RwLock<TransactionPool<T>>) {
+        let (adapter, accepted) = {
+            let mut pool = pool.write().unwrap();
+            let accepted_hashes = mem::replace(&mut pool.accepted, vec![]);
+            let accepted: Vec<transaction::Transaction> = accepted_hashes.iter().
+                filter_map(|x| pool.transactions.get(x).map(|tx| (**tx).clone())).
+                collect();
+            (pool.adapter.clone(), accepted)
+        };
+        for tx in accepted {
+            adapter.tx_accepted(&tx);
+        }
+    }
+
+    /// Moves the orphans that don't miss any of their inputs anymore, now
+    /// that the pool or the chain has them, back through the checks of
+    /// add_to_memory_pool. The ones passing make it to the pool and are
+    /// queued in accepted so they get relayed as well, the ones conflicting
+    /// with the pool or the chain are dropped. Orphans depending on a freed
+    /// one are added again after it, which frees them too if they don't miss
+    /// anything else.
+    fn reconcile_orphans(&mut self, next_height: u64) {
+        loop {
+            let freed: Vec<hash::Hash> = self.orphan_transactions.iter().
+                filter(|&(_, tx)| tx.inputs.iter().all(|input|
+                    match self.search_for_best_output(&input.commitment()) {
+                        Parent::Unknown => false,
+                        _ => true,
+                    })).
+                map(|(h, _)| *h).
+                collect();
+            if freed.is_empty() {
+                break;
+            }
+            for txh in freed {
+                // already taken out along with a freed parent
+                if !self.orphans.get_graph().contains_vertex(&txh) {
+                    continue;
+                }
+                for removed in self.orphans.remove_orphan_transaction(txh) {
+                    let tx = self.orphan_transactions.remove(&removed).unwrap();
+                    let source = TxSource{
+                        debug_name: "orphan".to_string(),
+                        identifier: "?.?.?.?".to_string(),
+                    };
+                    let _ = self.add_at_height(source, *tx, next_height);
+                }
+            }
+        }
     }
 
     /// Updates the pool with the details of a new block.
@@ -392,11 +658,32 @@ impl<T> TransactionPool<T> where T: BlockChain {
         }
         let freed_txs = self.sweep_transactions(marked_transactions);
 
-        self.reconcile_orphans().unwrap();
+        self.expire_orphans();
+        self.expire_time_locked();
+        self.release_time_locked(block.header.height + 1);
+        self.reconcile_orphans(block.header.height + 1);
 
         Ok(freed_txs)
     }
 
+    /// Moves the time locked transactions that can be mined at the provided
+    /// height to the pool, running all the checks they were spared when
+    /// first received. The ones failing them are dropped.
+    fn release_time_locked(&mut self, next_height: u64) {
+        let released: Vec<hash::Hash> = self.time_locked.iter().
+            filter(|&(_, tx)| tx.lock_height <= next_height).
+            map(|(h, _)| h.clone()).
+            collect();
+        for txh in released {
+            let tx = self.time_locked.remove(&txh).unwrap();
+            let source = TxSource{
+                debug_name: "time-locked".to_string(),
+                identifier: "?.?.?.?".to_string(),
+            };
+            let _ = self.add_at_height(source, *tx, next_height);
+        }
+    }
+
     /// The mark portion of our mark-and-sweep pool cleanup.
     ///
     /// The transaction designated by conflicting_tx is immediately marked.
@@ -455,9 +742,10 @@ impl<T> TransactionPool<T> where T: BlockChain {
 
     /// Fetch mineable transactions.
     ///
-    /// Select a set of mineable transactions for block building.
-    pub fn prepare_mineable_transactions(&self, num_to_fetch: u32) -> Vec<Box<transaction::Transaction>>{
-        self.pool.get_mineable_transactions(num_to_fetch).iter().
+    /// Select a set of mineable transactions for block building, paying the
+    /// best fees per unit of weight and fitting in max_weight.
+    pub fn prepare_mineable_transactions(&self, max_weight: u64) -> Vec<Box<transaction::Transaction>>{
+        self.pool.get_mineable_transactions(max_weight).iter().
             map(|x| self.transactions.get(x).unwrap().clone()).collect()
     }
 }
@@ -470,7 +758,10 @@ mod tests {
     use secp::key;
     use core::core::build;
     use blockchain::{DummyChain, DummyChainImpl, DummyUtxoSet};
-    use std::sync::{Arc, RwLock};
+    use std::sync::{Arc, Mutex, RwLock};
+    use std::thread;
+    use std::time::Duration;
+    use core::core::hash::Hashed;
 
     macro_rules! expect_output_parent {
         ($pool:expr, $expected:pat, $( $output:expr ),+ ) => {
@@ -629,11 +920,418 @@ mod tests {
         }
     }
 
+    #[test]
+    /// Testing the lookup of outputs created and spent by the pool
+    fn test_output_created_spent() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(5)).
+            with_output(test_output(6));
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let mut pool = test_setup(&Arc::new(dummy_chain));
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![5], vec![4])).unwrap();
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![4], vec![3])).unwrap();
+
+        // blockchain output spent in the pool
+        let c5 = test_output(5).commitment();
+        assert!(!pool.is_output_created(&c5));
+        assert!(pool.is_output_spent(&c5));
+        // pool output spent by another pool transaction
+        let c4 = test_output(4).commitment();
+        assert!(pool.is_output_created(&c4));
+        assert!(pool.is_output_spent(&c4));
+        // pool output still available
+        let c3 = test_output(3).commitment();
+        assert!(pool.is_output_created(&c3));
+        assert!(!pool.is_output_spent(&c3));
+        // untouched blockchain output
+        let c6 = test_output(6).commitment();
+        assert!(!pool.is_output_created(&c6));
+        assert!(!pool.is_output_spent(&c6));
+    }
+
+    #[test]
+    /// Coinbase outputs can't be spent before they mature
+    fn test_immature_coinbase() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let coinbase_output = test_coinbase_output(15);
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().
+            with_output(coinbase_output).
+            with_output(test_coinbase_output(20)));
+        dummy_chain.store_output_height(test_output(15).commitment(), 1);
+        dummy_chain.store_head_height(1);
+
+        let chain_ref = Arc::new(dummy_chain);
+        let mut pool = test_setup(&chain_ref);
+
+        let maturity = global::coinbase_maturity();
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![15], vec![10])) {
+            Err(PoolError::ImmatureCoinbase{output: _, lock_height}) => {
+                assert_eq!(lock_height, 1 + maturity);
+            },
+            x => panic!("Expected ImmatureCoinbase, got {:?}", x),
+        };
+        assert_eq!(pool.total_size(), 0);
+
+        // the next block to be mined is mature enough
+        chain_ref.store_head_height(maturity);
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![15], vec![10])).unwrap();
+        assert_eq!(pool.total_size(), 1);
+
+        // without its height, a coinbase can't be shown to be mature
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![20], vec![18])) {
+            Err(PoolError::ImmatureCoinbase{output: _, lock_height}) => {
+                assert_eq!(lock_height, 2 * maturity);
+            },
+            x => panic!("Expected ImmatureCoinbase, got {:?}", x),
+        };
+    }
+
+    #[test]
+    /// Time locked transactions are held until a block gets close enough
+    fn test_time_locked() {
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().with_output(test_output(15)));
+
+        let chain_ref = Arc::new(dummy_chain);
+        let mut pool = test_setup(&chain_ref);
+
+        let (tx, _) = build::transaction(vec![build::input(15, test_key(15)),
+            build::output(10, test_key(10)), build::with_fee(5),
+            build::with_lock_height(3)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx.clone()) {
+            Err(PoolError::TimeLocked{lock_height}) => assert_eq!(lock_height, 3),
+            x => panic!("Expected TimeLocked, got {:?}", x),
+        };
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::AlreadyInPool) => {},
+            x => panic!("Expected AlreadyInPool, got {:?}", x),
+        };
+        assert_eq!(pool.total_size(), 0);
+        assert_eq!(pool.time_locked.len(), 1);
+
+        // a block at height 1 isn't enough, one at height 2 is
+        let block = block::Block::new(&block::BlockHeader::default(),
+            vec![], key::ONE_KEY).unwrap();
+        assert_eq!(block.header.height, 1);
+        chain_ref.apply_block(&block);
+        pool.reconcile_block(&block).unwrap();
+        assert_eq!(pool.total_size(), 0);
+
+        let block = block::Block::new(&block.header, vec![], key::ONE_KEY).unwrap();
+        pool.reconcile_block(&block).unwrap();
+        assert_eq!(pool.total_size(), 1);
+        assert!(pool.time_locked.is_empty());
+    }
+
+    #[test]
+    /// Time locked transactions count towards the pool size, get evicted
+    /// along with the pool ones and expire once their inputs are spent
+    fn test_time_locked_eviction() {
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().
+            with_output(test_output(10)).
+            with_output(test_output(20)).
+            with_output(test_output(30)).
+            with_output(test_output(40)));
+
+        let chain_ref = Arc::new(dummy_chain);
+        let mut pool = test_setup(&chain_ref);
+        pool.config.max_pool_size = 2;
+
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![10], vec![9])).unwrap();
+        let (tx, _) = build::transaction(vec![build::input(20, test_key(20)),
+            build::output(18, test_key(18)), build::with_fee(2),
+            build::with_lock_height(3)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::TimeLocked{..}) => {},
+            x => panic!("Expected TimeLocked, got {:?}", x),
+        };
+
+        // the pool transaction pays the least
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![30], vec![27])).unwrap();
+        assert_eq!(pool.pool_size(), 1);
+        assert_eq!(pool.time_locked.len(), 1);
+        expect_output_parent!(pool, Parent::BlockTransaction, 10);
+
+        // now the time locked one does
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![10], vec![2])).unwrap();
+        assert_eq!(pool.pool_size(), 2);
+        assert!(pool.time_locked.is_empty());
+
+        // a block spending the input of a time locked transaction expires it
+        let (tx, _) = build::transaction(vec![build::input(40, test_key(40)),
+            build::output(35, test_key(35)), build::with_fee(5),
+            build::with_lock_height(5)]).unwrap();
+        pool.config.max_pool_size = 3;
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::TimeLocked{..}) => {},
+            x => panic!("Expected TimeLocked, got {:?}", x),
+        };
+        assert_eq!(pool.time_locked.len(), 1);
+
+        let block_tx = test_transaction(vec![40], vec![39]);
+        let block = block::Block::new(&block::BlockHeader::default(),
+            vec![&block_tx], key::ONE_KEY).unwrap();
+        chain_ref.apply_block(&block);
+        pool.reconcile_block(&block).unwrap();
+        assert!(pool.time_locked.is_empty());
+        assert_eq!(pool.pool_size(), 2);
+    }
+
+    #[test]
+    /// Only transactions locked within the horizon and spending known
+    /// outputs are held, up to the configured maximum
+    fn test_time_locked_limits() {
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty()
+            .with_output(test_output(15))
+            .with_output(test_output(16)));
+
+        let chain_ref = Arc::new(dummy_chain);
+        let mut pool = test_setup(&chain_ref);
+        pool.config.max_time_locked_size = 1;
+
+        let (tx, _) = build::transaction(vec![build::input(15, test_key(15)),
+            build::output(10, test_key(10)), build::with_fee(5),
+            build::with_lock_height(MAX_LOCK_HEIGHT_HORIZON + 2)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::LockHeightTooHigh{..}) => {},
+            x => panic!("Expected LockHeightTooHigh, got {:?}", x),
+        };
+
+        let (tx, _) = build::transaction(vec![build::input(17, test_key(17)),
+            build::output(10, test_key(10)), build::with_fee(7),
+            build::with_lock_height(3)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::TimeLockedOrphan) => {},
+            x => panic!("Expected TimeLockedOrphan, got {:?}", x),
+        };
+        assert!(pool.time_locked.is_empty());
+
+        let (tx, _) = build::transaction(vec![build::input(15, test_key(15)),
+            build::output(10, test_key(10)), build::with_fee(5),
+            build::with_lock_height(MAX_LOCK_HEIGHT_HORIZON + 1)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::TimeLocked{..}) => {},
+            x => panic!("Expected TimeLocked, got {:?}", x),
+        };
+
+        // a better paying transaction takes the place of the first one, a
+        // worse paying one doesn't get in
+        let (tx, _) = build::transaction(vec![build::input(16, test_key(16)),
+            build::output(10, test_key(10)), build::with_fee(6),
+            build::with_lock_height(3)]).unwrap();
+        let better_hash = graph::transaction_identifier(&tx);
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::TimeLocked{..}) => {},
+            x => panic!("Expected TimeLocked, got {:?}", x),
+        };
+        let (tx, _) = build::transaction(vec![build::input(15, test_key(15)),
+            build::output(11, test_key(11)), build::with_fee(4),
+            build::with_lock_height(3)]).unwrap();
+        match pool.add_to_memory_pool(test_source(), tx) {
+            Err(PoolError::OverCapacity) => {},
+            x => panic!("Expected OverCapacity, got {:?}", x),
+        };
+        assert_eq!(pool.time_locked.len(), 1);
+        assert!(pool.time_locked.contains_key(&better_hash));
+    }
+
+    #[test]
+    /// Transactions paying less than the minimum fee are rejected
+    fn test_low_fee() {
+        let mut dummy_chain = DummyChainImpl::new();
+        dummy_chain.update_utxo_set(DummyUtxoSet::empty().with_output(test_output(15)));
+
+        let mut pool = TransactionPool::new(PoolConfig{accept_fee_base: 1, ..Default::default()},
+            Arc::new(dummy_chain), Arc::new(NoopAdapter{}));
+
+        // 1 input and 1 output weigh 4
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![15], vec![14])) {
+            Err(PoolError::LowFeeTransaction(min_fee)) => assert_eq!(min_fee, 4),
+            x => panic!("Expected LowFeeTransaction, got {:?}", x),
+        };
+        assert_eq!(pool.total_size(), 0);
+
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![15], vec![11])).unwrap();
+        assert_eq!(pool.total_size(), 1);
+    }
+
+    #[test]
+    /// When full, the pool evicts the transactions with the lowest fee rates
+    /// along with their descendants
+    fn test_pool_eviction() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(10)).
+            with_output(test_output(20)).
+            with_output(test_output(30)).
+            with_output(test_output(40)).
+            with_output(test_output(50));
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let mut pool = test_setup(&Arc::new(dummy_chain));
+        pool.config.max_pool_size = 2;
+
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![10], vec![9])).unwrap();
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![9], vec![5])).unwrap();
+        assert_eq!(pool.pool_size(), 2);
+
+        // the first transaction pays the least, its child goes with it
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![20], vec![14])).unwrap();
+        assert_eq!(pool.pool_size(), 1);
+        expect_output_parent!(pool, Parent::BlockTransaction, 10);
+        expect_output_parent!(pool, Parent::Unknown, 9, 5);
+
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![30], vec![29])).unwrap();
+        pool.add_to_memory_pool(test_source(),
+            test_transaction(vec![40], vec![38])).unwrap();
+        assert_eq!(pool.pool_size(), 2);
+        expect_output_parent!(pool, Parent::PoolTransaction{tx_ref: _}, 14, 38);
+        expect_output_parent!(pool, Parent::Unknown, 29);
+
+        // not paying enough to replace anything
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![50], vec![49])) {
+            Err(PoolError::OverCapacity) => {},
+            x => panic!("Expected OverCapacity, got {:?}", x),
+        };
+        assert_eq!(pool.pool_size(), 2);
+        expect_output_parent!(pool, Parent::BlockTransaction, 50);
+    }
+
+    #[test]
+    /// Orphans are limited in number and expire
+    fn test_orphan_eviction() {
+        let mut pool = test_setup(&Arc::new(DummyChainImpl::new()));
+        pool.config.max_orphans_size = 1;
+
+        // the second orphan pays more and replaces the first one
+        for tx in vec![test_transaction(vec![100], vec![99]),
+                       test_transaction(vec![200], vec![190])] {
+            match pool.add_to_memory_pool(test_source(), tx) {
+                Err(PoolError::OrphanTransaction) => {},
+                x => panic!("Expected OrphanTransaction, got {:?}", x),
+            };
+            assert_eq!(pool.orphans_size(), 1);
+        }
+        match pool.add_to_memory_pool(test_source(), test_transaction(vec![300], vec![299])) {
+            Err(PoolError::OverCapacity) => {},
+            x => panic!("Expected OverCapacity, got {:?}", x),
+        };
+        assert_eq!(pool.orphans_size(), 1);
+        assert!(pool.orphans.find_output(&test_output(190).commitment()).is_some());
+
+        thread::sleep(Duration::from_millis(10));
+        pool.config.orphan_expiry_secs = 0;
+        pool.expire_orphans();
+        assert_eq!(pool.orphans_size(), 0);
+    }
+
     #[test]
     /// Testing an expected orphan
     fn test_add_orphan() {
     }
 
+    /// Adapter keeping track of the transactions the pool accepted
+    struct CountingAdapter {
+        accepted: Mutex<Vec<hash::Hash>>,
+    }
+
+    impl PoolAdapter for CountingAdapter {
+        fn tx_accepted(&self, tx: &transaction::Transaction) {
+            self.accepted.lock().unwrap().push(tx.hash());
+        }
+    }
+
+    #[test]
+    /// Only transactions making it to the pool get passed on to the adapter
+    fn test_adapter_notified() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(5)).
+            with_output(test_output(6));
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let adapter = Arc::new(CountingAdapter{accepted: Mutex::new(vec![])});
+        let pool = RwLock::new(TransactionPool::new(
+            PoolConfig{accept_fee_base: 0, ..Default::default()},
+            Arc::new(dummy_chain), adapter.clone()));
+
+        let valid_transaction = test_transaction(vec![5,6], vec![8]);
+        let tx_hash = valid_transaction.hash();
+        {
+            let mut write_pool = pool.write().unwrap();
+            write_pool.add_to_memory_pool(test_source(), valid_transaction).unwrap();
+
+            // neither a duplicate nor an orphan should be passed on
+            match write_pool.add_to_memory_pool(test_source(), test_transaction(vec![5,6], vec![8])) {
+                Err(PoolError::AlreadyInPool) => {},
+                x => panic!("Expected AlreadyInPool, got {:?}", x),
+            };
+            match write_pool.add_to_memory_pool(test_source(), test_transaction(vec![20], vec![19])) {
+                Err(PoolError::OrphanTransaction) => {},
+                x => panic!("Expected OrphanTransaction, got {:?}", x),
+            };
+        }
+
+        // the adapter only hears about it once asked to, outside of the lock
+        assert!(adapter.accepted.lock().unwrap().is_empty());
+        TransactionPool::notify_accepted(&pool);
+        assert_eq!(*adapter.accepted.lock().unwrap(), vec![tx_hash]);
+
+        // and only once
+        TransactionPool::notify_accepted(&pool);
+        assert_eq!(*adapter.accepted.lock().unwrap(), vec![tx_hash]);
+    }
+
+    #[test]
+    /// An orphan gets in the pool and is passed on to the adapter once its
+    /// parent is accepted
+    fn test_orphan_freed() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(5));
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let adapter = Arc::new(CountingAdapter{accepted: Mutex::new(vec![])});
+        let pool = RwLock::new(TransactionPool::new(
+            PoolConfig{accept_fee_base: 0, ..Default::default()},
+            Arc::new(dummy_chain), adapter.clone()));
+
+        let parent = test_transaction(vec![5], vec![4]);
+        let child = test_transaction(vec![4], vec![3]);
+        let (parent_hash, child_hash) = (parent.hash(), child.hash());
+        {
+            let mut write_pool = pool.write().unwrap();
+            match write_pool.add_to_memory_pool(test_source(), child) {
+                Err(PoolError::OrphanTransaction) => {},
+                x => panic!("Expected OrphanTransaction, got {:?}", x),
+            };
+            assert_eq!(write_pool.orphans_size(), 1);
+
+            write_pool.add_to_memory_pool(test_source(), parent).unwrap();
+            assert_eq!(write_pool.pool_size(), 2);
+            assert_eq!(write_pool.orphans_size(), 0);
+            assert!(write_pool.orphan_transactions.is_empty());
+        }
+
+        TransactionPool::notify_accepted(&pool);
+        assert_eq!(*adapter.accepted.lock().unwrap(), vec![parent_hash, child_hash]);
+    }
+
     #[test]
     /// Testing block reconciliation
     fn test_block_reconciliation() {
@@ -820,7 +1518,9 @@ mod tests {
         let mut txs: Vec<Box<transaction::Transaction>>;
         {
             let read_pool = pool.read().unwrap();
-            txs = read_pool.prepare_mineable_transactions(3);
+            // enough weight for root_tx_1, with the best fee rate, followed by
+            // child_tx_2 and its parent root_tx_3
+            txs = read_pool.prepare_mineable_transactions(40);
             assert_eq!(txs.len(), 3);
             // TODO: This is ugly, either make block::new take owned
             // txs instead of mut refs, or change
@@ -848,12 +1548,53 @@ mod tests {
     }
 
 
+    #[test]
+    /// Mineable transactions are selected by fee rate, dependent ones along
+    /// with their parents, until the max weight is reached.
+    fn test_fee_rate_selection() {
+        let mut dummy_chain = DummyChainImpl::new();
+        let new_utxo = DummyUtxoSet::empty().
+            with_output(test_output(10)).
+            with_output(test_output(20)).
+            with_output(test_output(30));
+        dummy_chain.update_utxo_set(new_utxo);
+
+        let mut pool = test_setup(&Arc::new(dummy_chain));
+
+        // all these weigh 13, fees are 2, 6, 1 and 10
+        let low_tx = test_transaction(vec![10], vec![8]);
+        let high_tx = test_transaction(vec![20], vec![14]);
+        let parent_tx = test_transaction(vec![30], vec![29]);
+        let child_tx = test_transaction(vec![29], vec![19]);
+        let low = graph::transaction_identifier(&low_tx);
+        let high = graph::transaction_identifier(&high_tx);
+        let parent = graph::transaction_identifier(&parent_tx);
+        let child = graph::transaction_identifier(&child_tx);
+        for tx in vec![low_tx, high_tx, parent_tx, child_tx] {
+            pool.add_to_memory_pool(test_source(), tx).unwrap();
+        }
+
+        let selected = |max_weight| pool.prepare_mineable_transactions(max_weight).iter().
+            map(|tx| graph::transaction_identifier(tx)).collect::<Vec<_>>();
+
+        // the child and its parent pay 11 for 26, not enough room for both
+        assert_eq!(selected(26), vec![high, low]);
+        assert_eq!(selected(39), vec![high, parent, child]);
+        assert_eq!(selected(1000), vec![high, parent, child, low]);
+        assert!(selected(12).is_empty());
+    }
+
     fn test_setup(dummy_chain: &Arc<DummyChainImpl>) -> TransactionPool<DummyChainImpl> {
         TransactionPool{
             transactions: HashMap::new(),
             pool: Pool::empty(),
             orphans: Orphans::empty(),
+            orphan_transactions: HashMap::new(),
+            time_locked: HashMap::new(),
+            accepted: vec![],
             blockchain: dummy_chain.clone(),
+            adapter: Arc::new(NoopAdapter{}),
+            config: PoolConfig{accept_fee_base: 0, ..Default::default()},
         }
     }
 
@@ -895,6 +1636,15 @@ mod tests {
             proof: ec.range_proof(0, value, output_key, output_commitment)}
     }
 
+    /// Deterministically generate a coinbase output defined by our test scheme
+    fn test_coinbase_output(value: u64) -> transaction::Output {
+        let output = test_output(value);
+        transaction::Output{
+            features: transaction::COINBASE_OUTPUT,
+            commit: output.commit,
+            proof: output.proof}
+    }
+
     /// Makes a SecretKey from a single u64
     fn test_key(value: u64) -> key::SecretKey {
         let ec = Secp256k1::with_caps(ContextFlag::Commit);
@@ -16,7 +16,8 @@
 //! and its top-level members.
 
 use std::vec::Vec;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::iter::Iterator;
 use std::fmt;
 
@@ -27,6 +28,43 @@ pub use graph;
 use core::core::transaction;
 use core::core::hash;
 
+/// Transaction pool configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolConfig {
+    /// Base fee for a transaction to be accepted by the pool. The transaction
+    /// weight is computed from its number of inputs and outputs and
+    /// multiplied by the base fee to get the minimum fee it has to pay.
+    pub accept_fee_base: u64,
+
+    /// Maximum number of transactions in the pool, including the time locked
+    /// ones held for it. When full, the transactions paying the lowest fee
+    /// rates get evicted first.
+    pub max_pool_size: usize,
+
+    /// Maximum number of orphan transactions, evicted like the pool ones.
+    pub max_orphans_size: usize,
+
+    /// Number of seconds after which an orphan whose parents never showed
+    /// up is dropped.
+    pub orphan_expiry_secs: u64,
+
+    /// Maximum number of transactions held until their lock height, evicted
+    /// by lowest fee rate as well.
+    pub max_time_locked_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            accept_fee_base: 10,
+            max_pool_size: 50_000,
+            max_orphans_size: 1_000,
+            orphan_expiry_secs: 3600,
+            max_time_locked_size: 1_000,
+        }
+    }
+}
+
 /// Placeholder: the data representing where we heard about a tx from.
 ///
 /// Used to make decisions based on transaction acceptance priority from
@@ -88,6 +126,32 @@ pub enum PoolError {
         /// The spent output
         spent_output: Commitment
     },
+    /// An attempt to spend a coinbase output before it matured
+    ImmatureCoinbase{
+        /// The coinbase output
+        output: Commitment,
+        /// Height from which it can be spent
+        lock_height: u64,
+    },
+    /// A transaction held until the chain reaches its lock height
+    TimeLocked{
+        /// Height from which the transaction can be mined
+        lock_height: u64,
+    },
+    /// A transaction locked too far in the future for the pool to hold it
+    LockHeightTooHigh{
+        /// Height from which the transaction can be mined
+        lock_height: u64,
+    },
+    /// A time locked transaction spending outputs that aren't known yet,
+    /// which the pool doesn't hold
+    TimeLockedOrphan,
+    /// A transaction paying less than the minimum fee required by the pool,
+    /// which is provided
+    LowFeeTransaction(u64),
+    /// The pool or orphans set is full and the transaction doesn't pay
+    /// enough to make room for itself
+    OverCapacity,
     /// An orphan successfully added to the orphans set
     OrphanTransaction,
 }
@@ -99,6 +163,28 @@ pub trait BlockChain {
   /// a result with its current view of the most worked chain, ignoring
   /// orphans, etc.
   fn get_unspent(&self, output_ref: &Commitment) -> Option<transaction::Output>;
+
+  /// Get the height of the block that created an unspent output, None if the
+  /// output doesn't exist, is spent or its block is unknown.
+  fn get_output_height(&self, output_ref: &Commitment) -> Option<u64>;
+
+  /// Get the height of the current head of the chain.
+  fn head_height(&self) -> u64;
+}
+
+/// Bridge between the transaction pool and the rest of the system. Handles
+/// downstream processing of valid transactions by the rest of the system, most
+/// importantly the broadcasting of transactions to our peers.
+pub trait PoolAdapter: Send + Sync {
+  /// The transaction pool has accepted this transaction as valid and added
+  /// it to its internal cache.
+  fn tx_accepted(&self, tx: &transaction::Transaction);
+}
+
+/// Dummy adapter used as a placeholder for real implementations
+pub struct NoopAdapter {}
+impl PoolAdapter for NoopAdapter {
+  fn tx_accepted(&self, _: &transaction::Transaction) {}
 }
 
 /// Pool contains the elements of the graph that are connected, in full, to
@@ -211,12 +297,157 @@ impl Pool {
         }
     }
 
-    /// Simplest possible implementation: just return the roots
-    pub fn get_mineable_transactions(&self, num_to_fetch: u32) -> Vec<hash::Hash> {
-        let mut roots = self.graph.get_roots();
-        roots.truncate(num_to_fetch as usize);
-        roots
+    /// Selects the transactions to mine by decreasing fee per unit of weight,
+    /// until no other fits in max_weight. A transaction spending outputs of
+    /// other pool transactions is ranked along with its ancestors not
+    /// selected yet, as they all have to go in the same block: a high fee
+    /// child can pull in a low fee parent. Parents are always returned
+    /// before their children.
+    ///
+    /// Package fees and weights are computed once, kept in a max-heap and
+    /// only updated for the descendants of the transactions selected.
+    pub fn get_mineable_transactions(&self, max_weight: u64) -> Vec<hash::Hash> {
+        let entries: HashMap<hash::Hash, &graph::PoolEntry> = self.graph.get_entries().
+            into_iter().map(|x| (x.transaction_hash, x)).collect();
+        let parents = self.graph.get_parents();
+        let mut children: HashMap<hash::Hash, Vec<hash::Hash>> = HashMap::new();
+        for (child, tx_parents) in parents.iter() {
+            for parent in tx_parents {
+                children.entry(*parent).or_insert(vec![]).push(*child);
+            }
+        }
+
+        let mut selected: Vec<hash::Hash> = vec![];
+        let mut selected_set: HashSet<hash::Hash> = HashSet::new();
+        let mut packages: HashMap<hash::Hash, Package> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for tx_hash in entries.keys() {
+            let mut ancestry = vec![];
+            collect_ancestry(*tx_hash, &parents, &selected_set, &mut ancestry);
+            let (fee, weight) = ancestry.iter().fold((0, 0), |(fee, weight), x|
+                (fee + entries[x].fee, weight + entries[x].size_estimate));
+            heap.push(PackageRank{tx_hash: *tx_hash, fee: fee, weight: weight, version: 0});
+            packages.insert(*tx_hash, Package{
+                ancestors: ancestry.into_iter().collect(),
+                fee: fee,
+                weight: weight,
+                version: 0,
+            });
+        }
+
+        let mut total_weight = 0;
+        while let Some(rank) = heap.pop() {
+            // skip what's already in the block and outdated ranks, a newer
+            // one has been pushed when the package changed
+            if selected_set.contains(&rank.tx_hash) ||
+                packages[&rank.tx_hash].version != rank.version {
+                continue;
+            }
+            // doesn't fit for now, ranked again if some of its ancestors get
+            // selected and its package shrinks
+            if total_weight + rank.weight > max_weight {
+                continue;
+            }
+            total_weight += rank.weight;
+
+            let mut package = vec![];
+            collect_ancestry(rank.tx_hash, &parents, &selected_set, &mut package);
+            let mut updated = HashSet::new();
+            for tx_hash in package {
+                selected_set.insert(tx_hash);
+                selected.push(tx_hash);
+
+                // removes the selected transaction from the packages of its
+                // descendants
+                let mut to_visit = children.get(&tx_hash).cloned().unwrap_or(vec![]);
+                let mut visited = HashSet::new();
+                while let Some(desc) = to_visit.pop() {
+                    if !visited.insert(desc) || selected_set.contains(&desc) {
+                        continue;
+                    }
+                    let desc_pkg = packages.get_mut(&desc).unwrap();
+                    if desc_pkg.ancestors.remove(&tx_hash) {
+                        desc_pkg.fee -= entries[&tx_hash].fee;
+                        desc_pkg.weight -= entries[&tx_hash].size_estimate;
+                        updated.insert(desc);
+                    }
+                    if let Some(desc_children) = children.get(&desc) {
+                        to_visit.extend(desc_children.iter().cloned());
+                    }
+                }
+            }
+            for tx_hash in updated {
+                if selected_set.contains(&tx_hash) {
+                    continue;
+                }
+                let pkg = packages.get_mut(&tx_hash).unwrap();
+                pkg.version += 1;
+                heap.push(PackageRank{
+                    tx_hash: tx_hash,
+                    fee: pkg.fee,
+                    weight: pkg.weight,
+                    version: pkg.version,
+                });
+            }
+        }
+        selected
+    }
+}
+
+// A transaction along with its ancestors not selected for mining yet, with
+// their total fee and weight.
+struct Package {
+    ancestors: HashSet<hash::Hash>,
+    fee: u64,
+    weight: u64,
+    version: u64,
+}
+
+// Ranks a package in the mining heap by fee per unit of weight. The version
+// tells whether the package changed since the rank was pushed.
+struct PackageRank {
+    tx_hash: hash::Hash,
+    fee: u64,
+    weight: u64,
+    version: u64,
+}
+
+impl Ord for PackageRank {
+    fn cmp(&self, other: &PackageRank) -> Ordering {
+        // comparing fee / weight ratios without losing precision
+        (self.fee * other.weight).cmp(&(other.fee * self.weight))
+    }
+}
+
+impl PartialOrd for PackageRank {
+    fn partial_cmp(&self, other: &PackageRank) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for PackageRank {
+    fn eq(&self, other: &PackageRank) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PackageRank {}
+
+// Collects a transaction along with its ancestors that haven't been selected
+// yet, each ancestor coming before its descendants.
+fn collect_ancestry(tx_hash: hash::Hash,
+    parents: &HashMap<hash::Hash, Vec<hash::Hash>>,
+    selected: &HashSet<hash::Hash>, package: &mut Vec<hash::Hash>) {
+
+    if selected.contains(&tx_hash) || package.contains(&tx_hash) {
+        return;
     }
+    if let Some(tx_parents) = parents.get(&tx_hash) {
+        for parent in tx_parents {
+            collect_ancestry(*parent, parents, selected, package);
+        }
+    }
+    package.push(tx_hash);
 }
 
 impl TransactionGraphContainer for Pool {
@@ -322,6 +553,27 @@ impl Orphans {
                 unspent_output.output_commitment(), unspent_output);
         }
     }
+
+    /// Remove an orphan transaction from the orphans set, along with all the
+    /// orphans depending on it. Outputs of other orphans it spent become
+    /// available again. Returns the hashes of all the orphans removed, the
+    /// provided one first.
+    pub fn remove_orphan_transaction(&mut self, tx_hash: hash::Hash) -> Vec<hash::Hash> {
+        let mut removed = vec![tx_hash];
+        for child in self.graph.get_children(&tx_hash) {
+            removed.append(&mut self.remove_orphan_transaction(child));
+        }
+
+        self.graph.remove_vertex(tx_hash);
+        for edge in self.graph.remove_edges_to(&tx_hash) {
+            self.available_outputs.insert(edge.output_commitment(),
+                edge.with_destination(None));
+        }
+        self.missing_outputs.retain(|_, x| x.destination_hash() != Some(tx_hash));
+        self.pool_connections.retain(|_, x| x.destination_hash() != Some(tx_hash));
+        self.available_outputs.retain(|_, x| x.source_hash() != Some(tx_hash));
+        removed
+    }
 }
 
 impl TransactionGraphContainer for Orphans {
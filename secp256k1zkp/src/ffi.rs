@@ -42,8 +42,8 @@ pub type NonceFn = unsafe extern "C" fn(nonce32: *mut c_uchar,
                                         msg32: *const c_uchar,
                                         key32: *const c_uchar,
                                         algo16: *const c_uchar,
-                                        attempt: c_uint,
-                                        data: *const c_void);
+                                        data: *const c_void,
+                                        attempt: c_uint);
 
 
 /// A Secp256k1 context, containing various precomputed values and such
@@ -249,6 +249,29 @@ extern "C" {
 	                                 msg32: *const c_uchar)
 	                                 -> c_int;
 
+	pub fn secp256k1_schnorr_generate_nonce_pair(cx: *const Context,
+	                                             pubnonce: *mut PublicKey,
+	                                             privnonce32: *mut c_uchar,
+	                                             sec32: *const c_uchar,
+	                                             msg32: *const c_uchar,
+	                                             noncefn: NonceFn,
+	                                             noncedata: *const c_void)
+	                                             -> c_int;
+
+	pub fn secp256k1_schnorr_partial_sign(cx: *const Context,
+	                                      sig64: *mut c_uchar,
+	                                      msg32: *const c_uchar,
+	                                      sec32: *const c_uchar,
+	                                      pubnonce_others: *const PublicKey,
+	                                      secnonce32: *const c_uchar)
+	                                      -> c_int;
+
+	pub fn secp256k1_schnorr_partial_combine(cx: *const Context,
+	                                         sig64: *mut c_uchar,
+	                                         sig64sin: *const *const c_uchar,
+	                                         n: size_t)
+	                                         -> c_int;
+
 	// EC
 	pub fn secp256k1_ec_seckey_verify(cx: *const Context, sk: *const c_uchar) -> c_int;
 
@@ -153,6 +153,25 @@ impl PublicKey {
 		Ok(PublicKey(pk))
 	}
 
+	/// Creates a public key from the sum of the provided ones.
+	pub fn from_combination(secp: &Secp256k1, pks: Vec<&PublicKey>) -> Result<PublicKey, Error> {
+		if pks.is_empty() {
+			return Err(InvalidPublicKey);
+		}
+		let pk_ptrs = pks.iter().map(|pk| pk.as_ptr()).collect::<Vec<_>>();
+		let mut ret = unsafe { ffi::PublicKey::blank() };
+		unsafe {
+			if ffi::secp256k1_ec_pubkey_combine(secp.ctx,
+			                                    &mut ret,
+			                                    pk_ptrs.as_ptr(),
+			                                    pk_ptrs.len() as i32) == 1 {
+				Ok(PublicKey(ret))
+			} else {
+				Err(InvalidPublicKey)
+			}
+		}
+	}
+
 	/// Creates a public key directly from a slice
 	#[inline]
 	pub fn from_slice(secp: &Secp256k1, data: &[u8]) -> Result<PublicKey, Error> {
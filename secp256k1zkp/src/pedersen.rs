@@ -174,6 +174,9 @@ pub struct ProofInfo {
 	pub success: bool,
 	/// Value that was used by the commitment
 	pub value: u64,
+	/// Blinding factor that was used by the commitment, all zeroes unless
+	/// the proof could be rewound
+	pub blinding: [u8; 32],
 	/// Message embedded in the proof
 	pub message: [u8; constants::PROOF_MSG_SIZE],
 	/// Length of the embedded message
@@ -303,6 +306,19 @@ impl Secp256k1 {
 		let mut rng = OsRng::new().unwrap();
 		let mut nonce = [0u8; 32];
 		rng.fill_bytes(&mut nonce);
+		self.range_proof_with_nonce(min, value, blind, commit, nonce)
+	}
+
+	/// Produces a range proof for the provided value, relying on the blinding
+	/// factor and commitment, as well as the provided nonce. Anyone knowing
+	/// the nonce can later rewind the proof to recover the value.
+	pub fn range_proof_with_nonce(&self,
+	                              min: u64,
+	                              value: u64,
+	                              blind: SecretKey,
+	                              commit: Commitment,
+	                              nonce: [u8; 32])
+	                              -> RangeProof {
 
 		let mut retried = false;
 		let mut proof = [0; constants::MAX_PROOF_SIZE];
@@ -369,7 +385,7 @@ impl Secp256k1 {
 	                          nonce: [u8; 32])
 	                          -> ProofInfo {
 		let mut value: u64 = 0;
-		let mut blind: [u8; 32] = unsafe { mem::uninitialized() };
+		let mut blind = [0u8; 32];
 		let mut message = [0u8; constants::PROOF_MSG_SIZE];
 		let mut mlen: i32 = 0;
 		let mut min: u64 = 0;
@@ -390,6 +406,7 @@ impl Secp256k1 {
 		ProofInfo {
 			success: success,
 			value: value,
+			blinding: if success { blind } else { [0; 32] },
 			message: message,
 			mlen: mlen,
 			min: min,
@@ -418,6 +435,7 @@ impl Secp256k1 {
 		ProofInfo {
 			success: success,
 			value: 0,
+			blinding: [0; 32],
 			message: [0; 4096],
 			mlen: 0,
 			min: min,
@@ -115,6 +115,82 @@ impl Secp256k1 {
 		};
 		Ok(PublicKey::from(pk))
 	}
+
+	/// Generates the secret and public nonces used to produce a partial
+	/// Schnorr signature of `msg` with `sk`. The public nonce has to be
+	/// communicated to the other signers, the secret one kept private.
+	pub fn generate_schnorr_nonce_pair(&self,
+	                                   msg: &Message,
+	                                   sk: &SecretKey)
+	                                   -> Result<(SecretKey, PublicKey), Error> {
+		if self.caps == ContextFlag::VerifyOnly || self.caps == ContextFlag::None {
+			return Err(Error::IncapableContext);
+		}
+
+		let mut pubnonce = unsafe { ffi::PublicKey::blank() };
+		let mut secnonce = [0; constants::SECRET_KEY_SIZE];
+		unsafe {
+			if ffi::secp256k1_schnorr_generate_nonce_pair(self.ctx,
+			                                              &mut pubnonce,
+			                                              secnonce.as_mut_ptr(),
+			                                              sk.as_ptr(),
+			                                              msg.as_ptr(),
+			                                              ffi::secp256k1_nonce_function_rfc6979,
+			                                              ptr::null()) != 1 {
+				return Err(Error::InvalidSecretKey);
+			}
+		}
+		let secnonce = try!(SecretKey::from_slice(self, &secnonce));
+		Ok((secnonce, PublicKey::from(pubnonce)))
+	}
+
+	/// Produces a partial Schnorr signature of `msg` with `sk` and our secret
+	/// nonce, given the sum of the public nonces of all other signers. Once
+	/// combined, the partial signatures verify against the sum of the public
+	/// keys of all signers.
+	pub fn partial_sign_schnorr(&self,
+	                            msg: &Message,
+	                            sk: &SecretKey,
+	                            secnonce: &SecretKey,
+	                            pubnonce_others: &PublicKey)
+	                            -> Result<Signature, Error> {
+		if self.caps == ContextFlag::VerifyOnly || self.caps == ContextFlag::None {
+			return Err(Error::IncapableContext);
+		}
+
+		let mut ret: Signature = unsafe { mem::uninitialized() };
+		unsafe {
+			if ffi::secp256k1_schnorr_partial_sign(self.ctx,
+			                                       ret.as_mut_ptr(),
+			                                       msg.as_ptr(),
+			                                       sk.as_ptr(),
+			                                       pubnonce_others.as_ptr(),
+			                                       secnonce.as_ptr()) != 1 {
+				return Err(Error::InvalidSignature);
+			}
+		}
+		Ok(ret)
+	}
+
+	/// Combines the partial Schnorr signatures of all signers into the full
+	/// signature.
+	pub fn combine_schnorr_partials(&self, sigs: &[Signature]) -> Result<Signature, Error> {
+		if sigs.is_empty() {
+			return Err(Error::InvalidSignature);
+		}
+		let sig_ptrs = sigs.iter().map(|sig| sig.as_ptr()).collect::<Vec<_>>();
+
+		let mut ret: Signature = unsafe { mem::uninitialized() };
+		unsafe {
+			if ffi::secp256k1_schnorr_partial_combine(self.ctx,
+			                                          ret.as_mut_ptr(),
+			                                          sig_ptrs.as_ptr(),
+			                                          sig_ptrs.len()) != 1 {
+				return Err(Error::InvalidSignature);
+			}
+		}
+		Ok(ret)
+	}
 }
 
 #[cfg(test)]
@@ -124,8 +200,12 @@ mod tests {
 	use Message;
 	use Secp256k1;
 	use Error::IncapableContext;
+	use ffi;
+	use key::{PublicKey, SecretKey};
 	use super::Signature;
 
+	use std::ptr;
+
 	#[test]
 	fn capabilities() {
 		let none = Secp256k1::with_caps(ContextFlag::None);
@@ -194,4 +274,55 @@ mod tests {
 		let sig2 = Signature::deserialize(&sig1.serialize());
 		assert_eq!(sig1, sig2);
 	}
+
+	#[test]
+	fn nonce_pair_derivation() {
+		let s = Secp256k1::new();
+
+		let mut msg = [0u8; 32];
+		thread_rng().fill_bytes(&mut msg);
+		let msg = Message::from_slice(&msg).unwrap();
+		let (sk, _) = s.generate_keypair(&mut thread_rng()).unwrap();
+
+		let (secnonce, pubnonce) = s.generate_schnorr_nonce_pair(&msg, &sk).unwrap();
+
+		// the library feeds the secret key to RFC6979 as its message and the
+		// message as its key, along with the Schnorr algorithm name
+		let mut nonce = [0u8; 32];
+		let ret = unsafe {
+			ffi::secp256k1_nonce_function_rfc6979(nonce.as_mut_ptr(),
+			                                      sk.as_ptr(),
+			                                      msg.as_ptr(),
+			                                      b"Schnorr+SHA256  ".as_ptr(),
+			                                      ptr::null(),
+			                                      0)
+		};
+		assert_eq!(ret, 1);
+		assert_eq!(secnonce, SecretKey::from_slice(&s, &nonce).unwrap());
+		assert_eq!(pubnonce, PublicKey::from_secret_key(&s, &secnonce).unwrap());
+	}
+
+	#[test]
+	fn partial_sign_combine() {
+		let mut s = Secp256k1::new();
+		s.randomize(&mut thread_rng());
+
+		let mut msg = [0u8; 32];
+		thread_rng().fill_bytes(&mut msg);
+		let msg = Message::from_slice(&msg).unwrap();
+
+		let (sk1, pk1) = s.generate_keypair(&mut thread_rng()).unwrap();
+		let (sk2, pk2) = s.generate_keypair(&mut thread_rng()).unwrap();
+
+		// each signer only learns the public nonce of the other
+		let (secnonce1, pubnonce1) = s.generate_schnorr_nonce_pair(&msg, &sk1).unwrap();
+		let (secnonce2, pubnonce2) = s.generate_schnorr_nonce_pair(&msg, &sk2).unwrap();
+		let sig1 = s.partial_sign_schnorr(&msg, &sk1, &secnonce1, &pubnonce2).unwrap();
+		let sig2 = s.partial_sign_schnorr(&msg, &sk2, &secnonce2, &pubnonce1).unwrap();
+
+		let sig = s.combine_schnorr_partials(&[sig1, sig2]).unwrap();
+		let pk = PublicKey::from_combination(&s, vec![&pk1, &pk2]).unwrap();
+		assert!(s.verify_schnorr(&msg, &sig, &pk).is_ok());
+		assert!(s.verify_schnorr(&msg, &sig, &pk1).is_err());
+	}
 }
@@ -130,8 +130,13 @@ fn main() {
                      .long("api_server_address")
                      .help("The api address of a running node on which to check inputs and post transactions")
                      .takes_value(true))	 	 
+				.arg(Arg::with_name("min_conf")
+                     .short("c")
+                     .long("min_conf")
+                     .help("Minimum number of confirmations an output needs before it can be spent (defaults to 1)")
+                     .takes_value(true))
                 .subcommand(SubCommand::with_name("receive")
-                            .about("Run the wallet in receiving mode. If an input file is provided, will process it, otherwise runs in server mode waiting for send requests.")
+                            .about("Run the wallet in receiving mode. If an input file is provided, will process it and print the partial transaction to return to the sender, otherwise runs in server mode waiting for send requests.")
                             .arg(Arg::with_name("input")
                                  .help("Partial transaction to receive, expects as a JSON file.")
                                  .short("i")
@@ -142,10 +147,33 @@ fn main() {
                             .arg(Arg::with_name("amount")
                                  .help("Amount to send in the smallest denomination")
                                  .index(1))
+                            .arg(Arg::with_name("fee")
+                                 .help("Fee to pay for the transaction, in the smallest denomination. Computed from the transaction size if not provided.")
+                                 .short("f")
+                                 .long("fee")
+                                 .takes_value(true))
                             .arg(Arg::with_name("dest")
                                  .help("Send the transaction to the provided server")
                                  .short("d")
                                  .long("dest")
+                                 .takes_value(true)))
+                .subcommand(SubCommand::with_name("info")
+                            .about("Shows the wallet balance: total, spendable, locked and awaiting confirmation."))
+                .subcommand(SubCommand::with_name("restore")
+                            .about("Restores the wallet outputs from the passphrase alone, scanning the chain of the node for them."))
+                .subcommand(SubCommand::with_name("txs")
+                            .about("Lists the transactions recorded by the wallet."))
+                .subcommand(SubCommand::with_name("cancel")
+                            .about("Cancels a transaction that hasn't been confirmed, unlocking the outputs it spent.")
+                            .arg(Arg::with_name("id")
+                                 .help("Id of the transaction to cancel, as listed by the txs command")
+                                 .index(1)))
+                .subcommand(SubCommand::with_name("finalize")
+                            .about("Finalizes a transaction we sent, once the receiver added its part, and broadcasts it.")
+                            .arg(Arg::with_name("input")
+                                 .help("Partial transaction returned by the receiver, expects as a JSON file.")
+                                 .short("i")
+                                 .long("input")
                                  .takes_value(true))))
     .get_matches();
 
@@ -299,6 +327,10 @@ fn wallet_command(wallet_args: &ArgMatches) {
 		wallet_config.check_node_api_http_addr = sa.to_string().clone();
 	}
 
+	if let Some(min_conf) = wallet_args.value_of("min_conf") {
+		wallet_config.minimum_confirmations = min_conf.parse().expect("Could not parse minimum confirmations as a whole number.");
+	}
+
 	match wallet_args.subcommand() {
 
 		("receive", Some(receive_args)) => {
@@ -308,7 +340,8 @@ fn wallet_command(wallet_args: &ArgMatches) {
 				file.read_to_string(&mut contents).expect(
 					"Unable to read transaction file.",
 				);
-				wallet::receive_json_tx(&wallet_config, &key, contents.as_str()).unwrap();
+				let res = wallet::receive_json_tx(&wallet_config, &key, contents.as_str()).unwrap();
+				println!("{}", res);
 			} else {
 				info!(
 					"Starting the Grin wallet receiving daemon at {}...",
@@ -333,11 +366,40 @@ fn wallet_command(wallet_args: &ArgMatches) {
 				.expect("Amount to send required")
 				.parse()
 				.expect("Could not parse amount as a whole number.");
+			let fee = send_args.value_of("fee").map(|f| {
+				f.parse().expect("Could not parse fee as a whole number.")
+			});
 			let mut dest = "stdout";
 			if let Some(d) = send_args.value_of("dest") {
 				dest = d;
 			}
-			wallet::issue_send_tx(&wallet_config, &key, amount, dest.to_string()).unwrap();
+			wallet::issue_send_tx(&wallet_config, &key, amount, fee, dest.to_string()).unwrap();
+		}
+		("finalize", Some(finalize_args)) => {
+			let f = finalize_args.value_of("input").expect("Partial transaction file required.");
+			let mut file = File::open(f).expect("Unable to open transaction file.");
+			let mut contents = String::new();
+			file.read_to_string(&mut contents).expect(
+				"Unable to read transaction file.",
+			);
+			wallet::finalize_tx(&wallet_config, &key, contents.as_str()).unwrap();
+		}
+		("info", Some(_)) => {
+			wallet::show_info(&wallet_config, &key).unwrap();
+		}
+		("restore", Some(_)) => {
+			wallet::restore(&wallet_config, &key).unwrap();
+		}
+		("txs", Some(_)) => {
+			wallet::show_txs(&wallet_config, &key).unwrap();
+		}
+		("cancel", Some(cancel_args)) => {
+			let id = cancel_args
+				.value_of("id")
+				.expect("Transaction id required")
+				.parse()
+				.expect("Could not parse transaction id as a whole number.");
+			wallet::cancel_tx(&wallet_config, &key, id).unwrap();
 		}
 		_ => panic!("Unknown wallet command, use 'grin help wallet' for details"),
 	}
@@ -24,6 +24,8 @@ extern crate byteorder;
 extern crate grin_core as core;
 extern crate rocksdb;
 
+pub mod sumtree;
+
 const SEP: u8 = ':' as u8;
 
 use std::fmt;
@@ -143,12 +145,12 @@ impl Store {
 	}
 
 	/// Produces an iterator of `Readable` types moving forward from the
-	/// provided
-	/// key.
+	/// provided key, as long as keys start with it.
 	pub fn iter<T: ser::Readable>(&self, from: &[u8]) -> SerIterator<T> {
 		let db = self.rdb.read().unwrap();
 		SerIterator {
 			iter: db.iterator(IteratorMode::From(from, Direction::Forward)),
+			prefix: from.to_vec(),
 			_marker: PhantomData,
 		}
 	}
@@ -206,6 +208,7 @@ pub struct SerIterator<T>
 	where T: ser::Readable
 {
 	iter: DBIterator,
+	prefix: Vec<u8>,
 	_marker: PhantomData<T>,
 }
 
@@ -217,7 +220,10 @@ impl<T> Iterator for SerIterator<T>
 	fn next(&mut self) -> Option<T> {
 		let next = self.iter.next();
 		next.and_then(|r| {
-			let (_, v) = r;
+			let (k, v) = r;
+			if !k.starts_with(&self.prefix) {
+				return None;
+			}
 			ser::deserialize(&mut &v[..]).ok()
 		})
 	}
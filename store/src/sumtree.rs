@@ -0,0 +1,627 @@
+// Copyright 2017 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Implementation of the persistent Backend for the prunable MMR sum-tree.
+//!
+//! The (Hash, Sum) pairs of the MMR are all the same length once serialized,
+//! so they're simply appended to a data file and read back at an offset
+//! derived from their position. Removed positions are first recorded in a
+//! log and only physically dropped from the data file during compaction,
+//! after which they're kept in a list of pruned positions to correct the
+//! offset of every remaining node.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use core::core::pmmr::{Backend, HashSum, Summable};
+use core::ser;
+
+const PMMR_DATA_FILE: &'static str = "pmmr_dat.bin";
+const PMMR_RM_LOG_FILE: &'static str = "pmmr_rm_log.bin";
+const PMMR_PRUNED_FILE: &'static str = "pmmr_pruned.bin";
+
+/// Wrapper for a file that can be read at any position (random read) but for
+/// which writes are append only. Appended data is buffered in memory and only
+/// written on sync, while still being readable. Same for rewinds, the file is
+/// only truncated on sync.
+pub struct AppendOnlyFile {
+	path: String,
+	file: File,
+	file_len: u64,
+	buffer: Vec<u8>,
+	truncate: bool,
+}
+
+impl AppendOnlyFile {
+	/// Open a file (existing or not) as append-only.
+	pub fn open(path: String) -> io::Result<AppendOnlyFile> {
+		let file = OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(path.clone())?;
+		let file_len = file.metadata()?.len();
+		Ok(AppendOnlyFile {
+			path: path,
+			file: file,
+			file_len: file_len,
+			buffer: vec![],
+			truncate: false,
+		})
+	}
+
+	/// Append data to the file, only effectively written on sync.
+	pub fn append(&mut self, buf: &mut Vec<u8>) {
+		self.buffer.append(buf);
+	}
+
+	/// Rewinds the file to the provided length, dropping everything appended
+	/// after.
+	pub fn rewind(&mut self, len: u64) {
+		if len >= self.file_len {
+			self.buffer.truncate((len - self.file_len) as usize);
+		} else {
+			self.buffer.clear();
+			self.file_len = len;
+			self.truncate = true;
+		}
+	}
+
+	/// Writes all buffered data to the file and syncs it to disk.
+	pub fn sync(&mut self) -> io::Result<()> {
+		if self.truncate {
+			self.file.set_len(self.file_len)?;
+			self.truncate = false;
+		}
+		if self.buffer.is_empty() {
+			return self.file.sync_data();
+		}
+		self.file.write_all(&self.buffer[..])?;
+		self.file.sync_data()?;
+		self.file_len += self.buffer.len() as u64;
+		self.buffer.clear();
+		Ok(())
+	}
+
+	/// Reads length bytes of data at offset, whether they've been synced or
+	/// are still buffered.
+	pub fn read(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+		let mut buf = vec![0; length];
+		if offset >= self.file_len {
+			let start = (offset - self.file_len) as usize;
+			if start + length > self.buffer.len() {
+				return Err(io::Error::new(ErrorKind::UnexpectedEof, "read past end of file"));
+			}
+			buf.copy_from_slice(&self.buffer[start..(start + length)]);
+		} else {
+			self.file.seek(SeekFrom::Start(offset))?;
+			self.file.read_exact(&mut buf)?;
+		}
+		Ok(buf)
+	}
+
+	/// Saves a copy of the synced content of the file to the target path,
+	/// skipping the records of the provided length starting at the provided
+	/// (sorted) offsets.
+	pub fn save_prune(&self, target: String, prune_offs: &[u64], record_len: usize) -> io::Result<()> {
+		let mut reader = BufReader::new(File::open(self.path.clone())?);
+		let mut writer = BufWriter::new(File::create(target)?);
+		let mut buf = vec![0; record_len];
+		let mut offset = 0;
+		let mut prune_idx = 0;
+		while offset < self.file_len {
+			reader.read_exact(&mut buf)?;
+			if prune_idx < prune_offs.len() && prune_offs[prune_idx] == offset {
+				prune_idx += 1;
+			} else {
+				writer.write_all(&buf)?;
+			}
+			offset += record_len as u64;
+		}
+		writer.flush()
+	}
+
+	/// Total length of the file, including buffered data.
+	pub fn len(&self) -> u64 {
+		self.file_len + self.buffer.len() as u64
+	}
+}
+
+/// Log file fully cached in memory containing all positions that should be
+/// eventually removed from the MMR append-only data file. Allows quick
+/// checking of whether a piece of data has been marked for deletion. When the
+/// log becomes too long, the MMR backend will actually remove chunks from the
+/// MMR data file and truncate the remove log.
+pub struct RemoveLog {
+	path: String,
+	// sorted list of all removed positions
+	removed: Vec<u64>,
+	// positions removed since the last flush
+	removed_tmp: Vec<u64>,
+	// whether the log file needs to be rewritten on flush
+	rewrite: bool,
+}
+
+impl RemoveLog {
+	/// Open the remove log file, reading all positions it contains.
+	pub fn open(path: String) -> io::Result<RemoveLog> {
+		let removed = read_ordered_vec(path.clone())?;
+		Ok(RemoveLog {
+			path: path,
+			removed: removed,
+			removed_tmp: vec![],
+			rewrite: false,
+		})
+	}
+
+	/// Marks the provided positions as removed, only saved to the log on
+	/// flush.
+	pub fn append(&mut self, elmts: Vec<u64>) {
+		for elmt in elmts {
+			if let Err(idx) = self.removed.binary_search(&elmt) {
+				self.removed.insert(idx, elmt);
+				self.removed_tmp.push(elmt);
+			}
+		}
+	}
+
+	/// Puts back the provided positions, as if they had never been removed.
+	pub fn restore(&mut self, elmts: Vec<u64>) {
+		for elmt in elmts {
+			if let Ok(idx) = self.removed.binary_search(&elmt) {
+				self.removed.remove(idx);
+				self.removed_tmp.retain(|&pos| pos != elmt);
+				self.rewrite = true;
+			}
+		}
+	}
+
+	/// Forgets all removed positions after the provided one, following a
+	/// rewind of the MMR.
+	pub fn rewind(&mut self, position: u64) {
+		let before = self.removed.len();
+		self.removed.retain(|&pos| pos <= position);
+		self.removed_tmp.retain(|&pos| pos <= position);
+		if self.removed.len() != before {
+			self.rewrite = true;
+		}
+	}
+
+	/// Appends all positions removed since the last flush to the log file.
+	pub fn flush(&mut self) -> io::Result<()> {
+		if self.rewrite {
+			// positions have been forgotten, the whole log needs rewriting
+			write_vec(self.path.clone(), &self.removed)?;
+			self.removed_tmp.clear();
+			self.rewrite = false;
+			return Ok(());
+		}
+		if self.removed_tmp.is_empty() {
+			return Ok(());
+		}
+		let mut file = OpenOptions::new()
+			.append(true)
+			.create(true)
+			.open(self.path.clone())?;
+		let mut buf = Vec::with_capacity(self.removed_tmp.len() * 8);
+		for elmt in &self.removed_tmp {
+			buf.write_u64::<BigEndian>(*elmt)?;
+		}
+		file.write_all(&buf[..])?;
+		file.sync_data()?;
+		self.removed_tmp.clear();
+		Ok(())
+	}
+
+	/// Empties the log, once all its positions have been dealt with.
+	pub fn truncate(&mut self) -> io::Result<()> {
+		File::create(self.path.clone())?.sync_all()?;
+		self.removed.clear();
+		self.removed_tmp.clear();
+		self.rewrite = false;
+		Ok(())
+	}
+
+	/// Whether the remove log currently includes the provided position.
+	pub fn includes(&self, elmt: u64) -> bool {
+		self.removed.binary_search(&elmt).is_ok()
+	}
+
+	/// Number of positions in the remove log.
+	pub fn len(&self) -> usize {
+		self.removed.len()
+	}
+}
+
+// Everything the backend needs to access under a single lock, as even reads
+// move the data file cursor.
+struct PMMRFiles {
+	hashsum_file: AppendOnlyFile,
+	remove_log: RemoveLog,
+	// sorted positions of all the nodes physically removed from the data file
+	pruned_nodes: Vec<u64>,
+}
+
+/// PMMR persistent backend implementation. Relies on multiple facilities to
+/// handle writing, reading and pruning.
+///
+/// * A main storage file appends HashSum instances as they come, buffering
+/// them until the next sync.
+/// * A remove log tracks the positions that need to be pruned from the
+/// main storage file.
+/// * A list of the positions already pruned from the main storage file,
+/// used to know where to find the remaining ones.
+pub struct PMMRBackend<T> where T: Summable {
+	data_dir: String,
+	files: Mutex<PMMRFiles>,
+	// only needed for parameterizing Backend
+	summable: PhantomData<T>,
+}
+
+impl<T> Backend<T> for PMMRBackend<T> where T: Summable {
+	/// Append the provided HashSums to the backend storage.
+	fn append(&self, data: Vec<HashSum<T>>) {
+		let mut files = self.files.lock().unwrap();
+		for d in data {
+			let mut buf = ser::ser_vec(&d).expect("HashSum serialization can't fail");
+			files.hashsum_file.append(&mut buf);
+		}
+	}
+
+	/// Get a HashSum by insertion position
+	fn get(&self, position: u64) -> Option<HashSum<T>> {
+		let mut files = self.files.lock().unwrap();
+		if files.remove_log.includes(position) {
+			return None;
+		}
+		let shift = match files.pruned_nodes.binary_search(&position) {
+			Ok(_) => return None,
+			Err(idx) => idx as u64,
+		};
+
+		let record_len = HashSum::<T>::serialized_len();
+		let offset = (position - 1 - shift) * record_len as u64;
+		if offset + record_len as u64 > files.hashsum_file.len() {
+			return None;
+		}
+		let data = match files.hashsum_file.read(offset, record_len) {
+			Ok(data) => data,
+			Err(_) => return None,
+		};
+		ser::deserialize(&mut &data[..]).ok()
+	}
+
+	/// Remove HashSums by insertion position, only recorded in the remove log
+	/// until the next compaction.
+	fn remove(&self, positions: Vec<u64>) {
+		let mut files = self.files.lock().unwrap();
+		let to_remove = positions
+			.into_iter()
+			.filter(|pos| files.pruned_nodes.binary_search(pos).is_err())
+			.collect();
+		files.remove_log.append(to_remove);
+	}
+
+	/// Rewind the backend to the provided position. Nodes already pruned by a
+	/// compaction can't be recovered, so rewinds are expected to stay above
+	/// those.
+	fn rewind(&self, position: u64) {
+		let mut files = self.files.lock().unwrap();
+		let shift = match files.pruned_nodes.binary_search(&position) {
+			Ok(idx) => idx as u64 + 1,
+			Err(idx) => idx as u64,
+		};
+		let record_len = HashSum::<T>::serialized_len() as u64;
+		files.hashsum_file.rewind((position - shift) * record_len);
+		files.remove_log.rewind(position);
+	}
+}
+
+impl<T> PMMRBackend<T> where T: Summable {
+	/// Instantiates a new PMMR backend that will use the provided directory to
+	/// store its files.
+	pub fn new(data_dir: String) -> io::Result<PMMRBackend<T>> {
+		fs::create_dir_all(data_dir.clone())?;
+		let hs_file = AppendOnlyFile::open(format!("{}/{}", data_dir, PMMR_DATA_FILE))?;
+		let rm_log = RemoveLog::open(format!("{}/{}", data_dir, PMMR_RM_LOG_FILE))?;
+		let pruned = read_ordered_vec(format!("{}/{}", data_dir, PMMR_PRUNED_FILE))?;
+
+		Ok(PMMRBackend {
+			data_dir: data_dir,
+			files: Mutex::new(PMMRFiles {
+				hashsum_file: hs_file,
+				remove_log: rm_log,
+				pruned_nodes: pruned,
+			}),
+			summable: PhantomData,
+		})
+	}
+
+	/// Total size of the PMMR stored by this backend, including intermediary
+	/// nodes and ignoring any pruning. Used to reopen the PMMR where it was.
+	pub fn unpruned_size(&self) -> u64 {
+		let files = self.files.lock().unwrap();
+		let record_len = HashSum::<T>::serialized_len() as u64;
+		files.hashsum_file.len() / record_len + files.pruned_nodes.len() as u64
+	}
+
+	/// Puts back HashSums removed since the last compaction, which are still
+	/// in the data file.
+	pub fn restore(&self, positions: Vec<u64>) {
+		let mut files = self.files.lock().unwrap();
+		files.remove_log.restore(positions);
+	}
+
+	/// Syncs all files to disk. Data appended or removed since the last sync
+	/// will be lost in case of a crash.
+	pub fn sync(&self) -> io::Result<()> {
+		let mut files = self.files.lock().unwrap();
+		files.hashsum_file.sync()?;
+		files.remove_log.flush()
+	}
+
+	/// Checks the length of the remove log to see if it should get compacted.
+	/// If so, the remove log is flushed into the pruned list, which itself gets
+	/// saved, and the main hashsum data file is rewritten, cutting the removed
+	/// data.
+	///
+	/// A new pruned list is written to a temporary file first and renamed in
+	/// place, the same goes for the data file.
+	pub fn check_compact(&self, max_len: usize) -> io::Result<()> {
+		let mut files = self.files.lock().unwrap();
+		if files.remove_log.len() < max_len {
+			return Ok(());
+		}
+		files.hashsum_file.sync()?;
+		files.remove_log.flush()?;
+
+		// 1. save the data file, minus all the removed records
+		let record_len = HashSum::<T>::serialized_len();
+		let mut prune_offs = vec![];
+		for pos in &files.remove_log.removed {
+			if let Err(shift) = files.pruned_nodes.binary_search(pos) {
+				prune_offs.push((pos - 1 - shift as u64) * record_len as u64);
+			}
+		}
+		let tmp_data_path = format!("{}/{}.prune", self.data_dir, PMMR_DATA_FILE);
+		files
+			.hashsum_file
+			.save_prune(tmp_data_path.clone(), &prune_offs[..], record_len)?;
+
+		// 2. update and save the pruned list
+		let mut pruned = files.pruned_nodes.clone();
+		pruned.extend(files.remove_log.removed.iter().cloned());
+		pruned.sort();
+		pruned.dedup();
+		let tmp_pruned_path = format!("{}/{}.prune", self.data_dir, PMMR_PRUNED_FILE);
+		write_vec(tmp_pruned_path.clone(), &pruned)?;
+
+		// 3. move everything in place and reopen the data file
+		let data_path = format!("{}/{}", self.data_dir, PMMR_DATA_FILE);
+		fs::rename(tmp_data_path, data_path.clone())?;
+		fs::rename(tmp_pruned_path, format!("{}/{}", self.data_dir, PMMR_PRUNED_FILE))?;
+		files.hashsum_file = AppendOnlyFile::open(data_path)?;
+		files.pruned_nodes = pruned;
+
+		// 4. everything in the remove log is now gone from the data file
+		files.remove_log.truncate()
+	}
+}
+
+// Reads a file of big endian u64s into a sorted vector, an absent file being
+// an empty one.
+fn read_ordered_vec(path: String) -> io::Result<Vec<u64>> {
+	let file = match File::open(path) {
+		Ok(file) => file,
+		Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+		Err(e) => return Err(e),
+	};
+	let mut reader = BufReader::new(file);
+	let mut ovec = vec![];
+	loop {
+		match reader.read_u64::<BigEndian>() {
+			Ok(elmt) => ovec.push(elmt),
+			Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e),
+		}
+	}
+	ovec.sort();
+	ovec.dedup();
+	Ok(ovec)
+}
+
+// Writes a vector of u64s to a new file, as big endian.
+fn write_vec(path: String, v: &Vec<u64>) -> io::Result<()> {
+	let mut writer = BufWriter::new(File::create(path)?);
+	for elmt in v {
+		writer.write_u64::<BigEndian>(*elmt)?;
+	}
+	writer.flush()?;
+	writer.get_ref().sync_all()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::core::pmmr::PMMR;
+	use core::ser::{Writeable, Writer};
+
+	#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+	struct TestElem([u32; 4]);
+	impl Summable for TestElem {
+		type Sum = u64;
+		fn sum(&self) -> u64 {
+			self.0[0] as u64 * 0x1000 + self.0[1] as u64 * 0x100 + self.0[2] as u64 * 0x10 +
+				self.0[3] as u64
+		}
+		fn sum_len() -> usize {
+			8
+		}
+	}
+
+	impl Writeable for TestElem {
+		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+			try!(writer.write_u32(self.0[0]));
+			try!(writer.write_u32(self.0[1]));
+			try!(writer.write_u32(self.0[2]));
+			writer.write_u32(self.0[3])
+		}
+	}
+
+	fn setup(dir: &str) -> String {
+		let data_dir = format!("./target/{}", dir);
+		let _ = fs::remove_dir_all(data_dir.clone());
+		data_dir
+	}
+
+	fn test_elems() -> Vec<TestElem> {
+		(1..10).map(|n| TestElem([0, 0, 0, n])).collect()
+	}
+
+	#[test]
+	fn pmmr_append_reopen() {
+		let data_dir = setup("sumtree_append");
+		let elems = test_elems();
+
+		let root = {
+			let backend = PMMRBackend::new(data_dir.clone()).unwrap();
+			let mut pmmr = PMMR::new(&backend);
+			for elem in &elems {
+				pmmr.push(*elem);
+			}
+			// reads work before and after sync
+			let root = pmmr.root();
+			backend.sync().unwrap();
+			assert_eq!(pmmr.root(), root);
+			assert_eq!(backend.unpruned_size(), 16);
+			root
+		};
+
+		let backend = PMMRBackend::new(data_dir).unwrap();
+		let size = backend.unpruned_size();
+		assert_eq!(size, 16);
+		let pmmr: PMMR<TestElem, _> = PMMR::at(&backend, size);
+		assert_eq!(pmmr.root(), root);
+	}
+
+	#[test]
+	fn pmmr_rewind() {
+		let data_dir = setup("sumtree_rewind");
+		let elems = test_elems();
+
+		let backend = PMMRBackend::new(data_dir.clone()).unwrap();
+		let mut pmmr = PMMR::new(&backend);
+		for elem in &elems[..4] {
+			pmmr.push(*elem);
+		}
+		backend.sync().unwrap();
+		let root4 = pmmr.root();
+		let size4 = pmmr.unpruned_size();
+
+		// rewinding buffered data
+		for elem in &elems[4..] {
+			pmmr.push(*elem);
+		}
+		pmmr.rewind(size4);
+		assert_eq!(pmmr.root(), root4);
+
+		// rewinding synced data
+		for elem in &elems[4..] {
+			pmmr.push(*elem);
+		}
+		backend.sync().unwrap();
+		pmmr.rewind(size4);
+		assert_eq!(pmmr.root(), root4);
+		backend.sync().unwrap();
+		assert_eq!(backend.unpruned_size(), size4);
+	}
+
+	#[test]
+	fn pmmr_remove_restore() {
+		let data_dir = setup("sumtree_restore");
+		let elems = test_elems();
+
+		let backend = PMMRBackend::new(data_dir.clone()).unwrap();
+		{
+			let mut pmmr = PMMR::new(&backend);
+			for elem in &elems {
+				pmmr.push(*elem);
+			}
+		}
+		backend.remove(vec![1, 2, 4]);
+		backend.sync().unwrap();
+		assert!(backend.get(2).is_none());
+
+		// removed nodes are still there to be put back until a compaction
+		backend.restore(vec![2, 4]);
+		backend.sync().unwrap();
+		let backend = PMMRBackend::<TestElem>::new(data_dir).unwrap();
+		assert!(backend.get(1).is_none());
+		assert!(backend.get(2).is_some());
+		assert!(backend.get(4).is_some());
+	}
+
+	#[test]
+	fn pmmr_prune_compact() {
+		let data_dir = setup("sumtree_prune");
+		let elems = test_elems();
+
+		let backend = PMMRBackend::new(data_dir.clone()).unwrap();
+		let root = {
+			let mut pmmr = PMMR::new(&backend);
+			for elem in &elems {
+				pmmr.push(*elem);
+			}
+			let root = pmmr.root();
+
+			// prune the first subtree entirely and one isolated leaf
+			pmmr.prune(1);
+			pmmr.prune(2);
+			pmmr.prune(4);
+			pmmr.prune(5);
+			pmmr.prune(9);
+			assert_eq!(pmmr.root(), root);
+			root
+		};
+		backend.sync().unwrap();
+
+		// nothing happens below the threshold
+		backend.check_compact(100).unwrap();
+		let data_len = fs::metadata(format!("{}/{}", data_dir, PMMR_DATA_FILE)).unwrap().len();
+		assert_eq!(data_len, 16 * HashSum::<TestElem>::serialized_len() as u64);
+
+		// compaction physically removes the pruned nodes
+		backend.check_compact(1).unwrap();
+		let data_len = fs::metadata(format!("{}/{}", data_dir, PMMR_DATA_FILE)).unwrap().len();
+		assert_eq!(data_len, 8 * HashSum::<TestElem>::serialized_len() as u64);
+		assert_eq!(backend.unpruned_size(), 16);
+		{
+			let pmmr: PMMR<TestElem, _> = PMMR::at(&backend, 16);
+			assert_eq!(pmmr.root(), root);
+		}
+
+		// and it all survives a restart, including further appends
+		let backend = PMMRBackend::new(data_dir).unwrap();
+		let mut pmmr: PMMR<TestElem, _> = PMMR::at(&backend, 16);
+		assert_eq!(pmmr.root(), root);
+		pmmr.push(TestElem([0, 0, 1, 0]));
+		assert_eq!(pmmr.unpruned_size(), 18);
+		backend.sync().unwrap();
+		assert_eq!(backend.unpruned_size(), 18);
+	}
+}
@@ -13,15 +13,15 @@
 // limitations under the License.
 
 //! Utilities to check the status of all the outputs we have stored in
-//! the wallet storage and update them.
+//! the wallet storage and update them, against both the chain and the
+//! transaction pool of a node.
 
 use api;
-use core::core::Output;
 use secp::{self, pedersen};
 use util;
 
 use extkey::ExtendedKey;
-use types::{WalletConfig, OutputStatus, WalletData};
+use types::{WalletConfig, OutputStatus, TxState, WalletData};
 
 /// Goes through the list of outputs that haven't been spent yet and check
 /// with a node whether their status has changed.
@@ -38,35 +38,94 @@ pub fn refresh_outputs(config: &WalletConfig, ext_key: &ExtendedKey) {
 				let key = ext_key.derive(&secp, out.n_child).unwrap();
 				let commitment = secp.commit(out.value, key.key).unwrap();
 
-				// TODO check the pool for unconfirmed
+				// a spending transaction in the pool is enough to consider our
+				// output spent
+				let pool_res = get_pool_output(config, commitment);
+				if let Ok(ref pool_out) = pool_res {
+					if pool_out.spent && out.status != OutputStatus::Unconfirmed {
+						out.status = OutputStatus::Spent;
+						continue;
+					}
+				}
 
 				let out_res = get_output_by_commitment(config, commitment);
 
-				if out_res.is_ok() {
-					// output is known, it's a new utxo
-					out.status = OutputStatus::Unspent;
+				if let Ok(ref utxo) = out_res {
+					// output is known, it's a new utxo, unless we locked it to spend it
+					if out.status == OutputStatus::Unconfirmed || out.status == OutputStatus::Pending {
+						out.status = OutputStatus::Unspent;
+						out.height = utxo.height;
+					}
 
-				} else if out.status == OutputStatus::Unspent {
+				} else if out.status == OutputStatus::Unspent || out.status == OutputStatus::Locked {
 					// a UTXO we can't find anymore has been spent
 					if let Err(api::Error::NotFound) = out_res {
 						out.status = OutputStatus::Spent;
 					}
+				} else if let Ok(pool_out) = pool_res {
+					// not in the chain yet, but a transaction in the pool creates it,
+					// or did until it got dropped
+					if pool_out.created && out.status == OutputStatus::Unconfirmed {
+						out.status = OutputStatus::Pending;
+					} else if !pool_out.created && out.status == OutputStatus::Pending {
+						out.status = OutputStatus::Unconfirmed;
+					}
 				} else {
 					//TODO find error with connection and return
 					//error!("Error contacting server node at {}. Is it running?", config.check_node_api_http_addr);
 				}
 			}
 		}
+
+		// transactions are confirmed once all the outputs they created for us are
+		let outputs = &wallet_data.outputs;
+		for tx in &mut wallet_data.txs {
+			if tx.state != TxState::Pending && tx.state != TxState::Broadcasted {
+				continue;
+			}
+			let confirmed = tx.outputs.iter().all(|n_child| {
+				outputs.iter().any(|out| {
+					out.fingerprint == ext_key.fingerprint && out.n_child == *n_child &&
+					out.status != OutputStatus::Unconfirmed && out.status != OutputStatus::Pending
+				})
+			});
+			if confirmed {
+				tx.state = TxState::Confirmed;
+			}
+		}
 	});
 }
 
+/// The part of the chain tip we need to know the current height.
+#[derive(Deserialize)]
+struct TipHeight {
+	height: u64,
+}
+
+/// Queries a reachable node for the height of its current chain tip.
+pub fn get_tip_height(config: &WalletConfig) -> Result<u64, api::Error> {
+	let url = format!("{}/v1/chain/tip", config.check_node_api_http_addr);
+	api::client::get::<TipHeight>(url.as_str()).map(|tip| tip.height)
+}
+
+// queries a reachable node for a given output, checking whether it's created
+// or spent by a transaction in its pool
+fn get_pool_output(config: &WalletConfig,
+                   commit: pedersen::Commitment)
+                   -> Result<api::PoolOutput, api::Error> {
+	let url = format!("{}/v1/pool/output/{}",
+	                  config.check_node_api_http_addr,
+	                  util::to_hex(commit.as_ref().to_vec()));
+	api::client::get::<api::PoolOutput>(url.as_str())
+}
+
 // queries a reachable node for a given output, checking whether it's been
-// confirmed
+// confirmed and at which height
 fn get_output_by_commitment(config: &WalletConfig,
                             commit: pedersen::Commitment)
-                            -> Result<Output, api::Error> {
+                            -> Result<api::Utxo, api::Error> {
 	let url = format!("{}/v1/chain/utxo/{}",
 	                  config.check_node_api_http_addr,
 	                  util::to_hex(commit.as_ref().to_vec()));
-	api::client::get::<Output>(url.as_str())
+	api::client::get::<api::Utxo>(url.as_str())
 }
@@ -154,6 +154,18 @@ impl ExtendedKey {
 		return identifier;
 	}
 
+	/// Nonce the range proofs of all the outputs of the wallet are built with,
+	/// so a single rewind is enough to tell whether an output is ours.
+	pub fn rewind_nonce(&self) -> [u8; 32] {
+		let mut sha = Sha256::new();
+		sha.input(&self.chaincode[..]);
+		sha.input(&self.key[..]);
+
+		let mut nonce = [0; 32];
+		sha.result(&mut nonce);
+		nonce
+	}
+
 	/// Derive an extended key from an extended key
 	pub fn derive(&self, secp: &Secp256k1, n: u32) -> Result<ExtendedKey, Error> {
 		let mut hmac = Hmac::new(Sha512::new(), &self.chaincode[..]);
@@ -0,0 +1,60 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Summary of the wallet balance.
+
+use checker;
+use extkey::ExtendedKey;
+use types::*;
+
+/// Prints the wallet balance after refreshing the status of our outputs,
+/// split between what can be spent right away, what is locked in pending
+/// transactions and what still needs confirmations (or coinbase maturity).
+pub fn show_info(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(), Error> {
+	checker::refresh_outputs(&config, ext_key);
+	let tip_height = checker::get_tip_height(config)?;
+
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		let mut total = 0;
+		let mut spendable = 0;
+		let mut locked = 0;
+		let mut unconfirmed = 0;
+		for out in &wallet_data.outputs {
+			if out.fingerprint != ext_key.fingerprint {
+				continue;
+			}
+			match out.status {
+				OutputStatus::Unspent => {
+					if out.num_confirmations(tip_height) >= config.minimum_confirmations &&
+					   out.is_mature(tip_height) {
+						spendable += out.value;
+					} else {
+						unconfirmed += out.value;
+					}
+				}
+				OutputStatus::Unconfirmed | OutputStatus::Pending => unconfirmed += out.value,
+				OutputStatus::Locked => locked += out.value,
+				OutputStatus::Spent => continue,
+			}
+			total += out.value;
+		}
+
+		println!("Wallet balance at height {}:", tip_height);
+		println!("  total: {}", total);
+		println!("  awaiting confirmation: {}", unconfirmed);
+		println!("  locked: {}", locked);
+		println!("  spendable: {}", spendable);
+		println!("  (minimum confirmations: {})", config.minimum_confirmations);
+	})
+}
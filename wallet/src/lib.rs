@@ -31,11 +31,17 @@ extern crate secp256k1zkp as secp;
 
 mod checker;
 mod extkey;
+mod info;
 mod receiver;
+mod restore;
 mod sender;
+mod txs;
 mod types;
 
 pub use extkey::ExtendedKey;
+pub use info::show_info;
 pub use receiver::{WalletReceiver, receive_json_tx};
-pub use sender::issue_send_tx;
-pub use types::{WalletConfig, WalletReceiveRequest, CbAmount, CbData};
+pub use restore::restore;
+pub use sender::{issue_send_tx, finalize_tx};
+pub use txs::{show_txs, cancel_tx};
+pub use types::{WalletConfig, WalletReceiveRequest, WalletReceiveResponse, CbAmount, CbData};
@@ -18,66 +18,59 @@
 //!
 //! The API looks like this:
 //!
-//! POST /v1/wallet/receive
+//! POST /v1/receive/receive_json_tx
 //! > {
-//! >   "amount": 10,
-//! >   "blind_sum": "a12b7f...",
-//! >   "tx": "f083de...",
+//! >   "PartialTransaction": "{ \"phase\": \"SenderInitiation\", ... }"
 //! > }
 //!
 //! < {
-//! <   "tx": "f083de...",
-//! <   "status": "ok"
+//! <   "PartialTransaction": "{ \"phase\": \"ReceiverConfirmation\", ... }"
 //! < }
 //!
-//! POST /v1/wallet/finalize
+//! POST /v1/receive/coinbase
 //! > {
-//! >   "tx": "f083de...",
-//! > }
-//!
-//! POST /v1/wallet/receive_coinbase
-//! > {
-//! >   "amount": 1,
+//! >   "Coinbase": { "amount": 1 }
 //! > }
 //!
 //! < {
-//! <   "output": "8a90bc...",
-//! <   "kernel": "f083de...",
+//! <   "Coinbase": { "output": "8a90bc...", "kernel": "f083de..." }
 //! < }
 //!
-//! Note that while at this point the finalize call is completely unecessary, a
-//! double-exchange will be required as soon as we support Schnorr signatures.
-//! So we may as well have it in place already.
+//! The partial transaction sent by the sender only includes its public nonce,
+//! we reply with our own public nonce and partial signature of the kernel. The
+//! sender then completes the signature and broadcasts the transaction, without
+//! either side ever revealing its blinding factors.
 
 use std::convert::From;
 use secp::{self};
-use secp::key::SecretKey;
+use secp::key::PublicKey;
 
 use core::core::{Block, Transaction, TxKernel, Output, build};
+use core::core::transaction::kernel_sig_msg;
 use core::ser;
 use api::{self, ApiEndpoint, Operation, ApiResult};
 use extkey::ExtendedKey;
 use types::*;
 use util;
 
-/// Dummy wrapper for the hex-encoded serialized transaction.
-#[derive(Serialize, Deserialize)]
-struct TxWrapper {
-	tx_hex: String,
-}
-
-/// Receive an already well formed JSON transaction issuance and finalize the
-/// transaction, adding our receiving output, to broadcast to the rest of the
-/// network.
-pub fn receive_json_tx(config: &WalletConfig, ext_key: &ExtendedKey, partial_tx_str: &str) -> Result<(), Error> {
+/// Receive a JSON partial transaction initiated by the sender, adding our
+/// receiving output and our partial signature of the kernel. Returns the
+/// resulting partial transaction, also as JSON, for the sender to finalize and
+/// broadcast.
+pub fn receive_json_tx(config: &WalletConfig, ext_key: &ExtendedKey, partial_tx_str: &str) -> Result<String, Error> {
 
-	let (amount, blinding, partial_tx) = partial_tx_from_json(partial_tx_str)?;
-	let final_tx = receive_transaction(&config, ext_key, amount, blinding, partial_tx)?;
-	let tx_hex = util::to_hex(ser::ser_vec(&final_tx).unwrap());
-
-	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
-	let _: TxWrapper = api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex })?;
-	Ok(())
+	let (phase, amount, sender_nonce, _, partial_tx) = partial_tx_from_json(partial_tx_str)?;
+	if phase != PartialTxPhase::SenderInitiation {
+		return Err(Error::Format(format!("Unexpected partial transaction phase {:?}.", phase)));
+	}
+	let (tx, public_nonce, part_sig) =
+		receive_transaction(&config, ext_key, amount, sender_nonce, partial_tx)?;
+
+	Ok(partial_tx_to_json(PartialTxPhase::ReceiverConfirmation,
+	                      amount,
+	                      public_nonce,
+	                      Some(part_sig),
+	                      tx))
 }
 
 /// Component used to receive coins, implements all the receiving end of the
@@ -92,7 +85,7 @@ impl ApiEndpoint for WalletReceiver {
 	type ID = String;
 	type T = String;
 	type OP_IN = WalletReceiveRequest;
-	type OP_OUT = CbData;
+	type OP_OUT = WalletReceiveResponse;
 
 	fn operations(&self) -> Vec<Operation> {
 		vec![
@@ -101,7 +94,7 @@ impl ApiEndpoint for WalletReceiver {
 		]
 	}
 
-	fn operation(&self, op: String, input: WalletReceiveRequest) -> ApiResult<CbData> {
+	fn operation(&self, op: String, input: WalletReceiveRequest) -> ApiResult<WalletReceiveResponse> {
 		match op.as_str() {
 			"coinbase" => {
 				match input {
@@ -122,10 +115,10 @@ impl ApiEndpoint for WalletReceiver {
 							ser::ser_vec(&kern).map_err(|e| {
 									api::Error::Internal(format!("Error serializing kernel: {:?}", e))
 								})?;
-						Ok(CbData {
+						Ok(WalletReceiveResponse::Coinbase(CbData {
 							output: util::to_hex(out_bin),
 							kernel: util::to_hex(kern_bin),
-						})
+						}))
 					}
 					_ => Err(api::Error::Argument(format!("Incorrect request data: {}", op))),
 				}
@@ -134,15 +127,10 @@ impl ApiEndpoint for WalletReceiver {
 				match input {
 					WalletReceiveRequest::PartialTransaction(partial_tx_str) => {
 						debug!("Operation {} with transaction {}", op, &partial_tx_str);
-						receive_json_tx(&self.config, &self.key, &partial_tx_str).map_err(|e| {
+						let res = receive_json_tx(&self.config, &self.key, &partial_tx_str).map_err(|e| {
 							api::Error::Internal(format!("Error processing partial transaction: {:?}", e))
-						}).unwrap();
-
-						//TODO: Return emptiness for now, should be a proper enum return type
-						Ok(CbData {
-							output: String::from(""),
-							kernel: String::from(""),
-						})
+						})?;
+						Ok(WalletReceiveResponse::PartialTransaction(res))
 					}
 					_ => Err(api::Error::Argument(format!("Incorrect request data: {}", op))),
 				}
@@ -169,21 +157,26 @@ fn receive_coinbase(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -
 			n_child: coinbase_key.n_child,
 			value: amount,
 			status: OutputStatus::Unconfirmed,
+			height: 0,
+			is_coinbase: true,
 		});
 		debug!("Using child {} for a new coinbase output.",
 		       coinbase_key.n_child);
 
-		Block::reward_output(coinbase_key.key, &secp).map_err(&From::from)
+		Block::reward_output_with_nonce(coinbase_key.key, ext_key.rewind_nonce(), &secp)
+			.map_err(&From::from)
 	})?
 }
 
-/// Builds a full transaction from the partial one sent to us for transfer
+/// Adds our output to the partial transaction sent to us for transfer and
+/// signs our part of the kernel. Returns the resulting transaction along with
+/// our public nonce and partial signature.
 fn receive_transaction(config: &WalletConfig,
-					   ext_key: &ExtendedKey,
+                       ext_key: &ExtendedKey,
                        amount: u64,
-                       blinding: SecretKey,
+                       sender_nonce: PublicKey,
                        partial: Transaction)
-                       -> Result<Transaction, Error> {
+                       -> Result<(Transaction, PublicKey, secp::schnorr::Signature), Error> {
 
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 
@@ -193,25 +186,43 @@ fn receive_transaction(config: &WalletConfig,
 		let next_child = wallet_data.next_child(ext_key.fingerprint);
 		let out_key = ext_key.derive(&secp, next_child).map_err(|e| Error::Key(e))?;
 
-		let (tx_final, _) = build::transaction(vec![build::initial_tx(partial),
-		                                            build::with_excess(blinding),
-		                                            build::output(amount, out_key.key)])?;
+		// the signature produced when building only covers our own output, the
+		// final one gets built by the sender out of both partial signatures
+		let (mut tx, _) =
+			build::transaction(vec![build::initial_tx(partial),
+			                        build::output_with_nonce(amount,
+			                                                 out_key.key,
+			                                                 ext_key.rewind_nonce())])?;
+		tx.excess_sig = vec![];
 
-		// make sure the resulting transaction is valid (could have been lied to
-		// on excess)
-		tx_final.validate(&secp)?;
+		let msg = kernel_sig_msg(tx.fee, tx.lock_height)?;
+		let (sec_nonce, public_nonce) = secp.generate_schnorr_nonce_pair(&msg, &out_key.key)?;
+		let part_sig = secp.partial_sign_schnorr(&msg, &out_key.key, &sec_nonce, &sender_nonce)?;
 
-		// track the new output and return the finalized transaction to broadcast
+		// track the new output and return our part of the transaction
 		wallet_data.append_output(OutputData {
 			fingerprint: out_key.fingerprint,
 			n_child: out_key.n_child,
 			value: amount,
 			status: OutputStatus::Unconfirmed,
+			height: 0,
+			is_coinbase: false,
+		});
+		wallet_data.add_tx(TxLogEntry {
+			id: 0,
+			direction: TxDirection::Received,
+			amount: amount,
+			fee: tx.fee,
+			inputs: vec![],
+			outputs: vec![out_key.n_child],
+			excess: None,
+			state: TxState::Pending,
+			sec_nonce: None,
 		});
 
 		debug!("Using child {} for a new transaction output.",
 		       out_key.n_child);
 
-		Ok(tx_final)
+		Ok((tx, public_nonce, part_sig))
 	})?
 }
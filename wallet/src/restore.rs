@@ -0,0 +1,171 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rebuilds the wallet data from the seed alone, scanning the unspent
+//! outputs of the chain for the ones we can rewind the range proof of.
+
+use std::collections::HashMap;
+
+use secp::{self, Secp256k1};
+use secp::key::SecretKey;
+
+use api;
+use checker;
+use core::core::COINBASE_OUTPUT;
+use extkey::ExtendedKey;
+use types::*;
+
+/// Number of consecutive derivation indexes without any output after which
+/// we consider none of the following ones have been used either.
+const RESTORE_KEY_GAP: u32 = 100;
+
+/// Scans the unspent outputs of every block in the chain, rewinding their
+/// range proofs with the nonce all our outputs are built with. Rewinding
+/// gives back the blinding factor, which tells us the derivation index of
+/// the key. Every output found is added to the wallet data, or updated if
+/// already there, as unspent with its value.
+///
+/// Fails if the node can't provide the outputs of any of the blocks, as some
+/// of ours could be missed otherwise.
+pub fn restore(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(), Error> {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+	let tip_height = checker::get_tip_height(config)?;
+	let nonce = ext_key.rewind_nonce();
+
+	let mut keys = HashMap::new();
+	derive_keys(&secp, ext_key, &mut keys, RESTORE_KEY_GAP)?;
+
+	let mut found = vec![];
+	for height in 1..(tip_height + 1) {
+		let url = format!("{}/v1/chain/utxos/{}", config.check_node_api_http_addr, height);
+		let block_outputs = match api::client::get::<api::BlockOutputs>(url.as_str()) {
+			Ok(block_outputs) => block_outputs,
+			Err(api::Error::NotFound) => {
+				let msg = format!("No outputs available at height {}, restore from a node with \
+				                   the full block history.",
+				                  height);
+				return Err(Error::Node(api::Error::Internal(msg)));
+			}
+			Err(e) => return Err(From::from(e)),
+		};
+
+		for out in block_outputs.outputs {
+			let info = secp.rewind_range_proof(out.commit, out.proof, nonce);
+			if !info.success {
+				continue;
+			}
+			let blinding = SecretKey::from_slice(&secp, &info.blinding)?;
+			let n_child = match keys.get(&blinding) {
+				Some(n_child) => *n_child,
+				None => {
+					warn!("Output of value {} at height {} is ours but its key is more than {} \
+					       indexes past the last one used, skipping.",
+					      info.value,
+					      height,
+					      RESTORE_KEY_GAP);
+					continue;
+				}
+			};
+			debug!("Found output of value {} for child {} at height {}.",
+			       info.value,
+			       n_child,
+			       height);
+			found.push(OutputData {
+				fingerprint: ext_key.fingerprint,
+				n_child: n_child,
+				value: info.value,
+				status: OutputStatus::Unspent,
+				height: height,
+				is_coinbase: out.features.intersects(COINBASE_OUTPUT),
+			});
+			// keep a full gap of keys past the last one used
+			derive_keys(&secp, ext_key, &mut keys, n_child + RESTORE_KEY_GAP)?;
+		}
+	}
+
+	info!("Restored {} unspent outputs from the chain.", found.len());
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		for out in found {
+			let existing = wallet_data.outputs
+				.iter_mut()
+				.find(|o| o.fingerprint == out.fingerprint && o.n_child == out.n_child);
+			match existing {
+				Some(o) => {
+					o.value = out.value;
+					o.status = OutputStatus::Unspent;
+					o.height = out.height;
+					o.is_coinbase = out.is_coinbase;
+				}
+				None => wallet_data.append_output(out),
+			}
+		}
+	})
+}
+
+/// Derives our keys up to the provided index, indexed by the key itself.
+fn derive_keys(secp: &Secp256k1,
+               ext_key: &ExtendedKey,
+               keys: &mut HashMap<SecretKey, u32>,
+               up_to: u32)
+               -> Result<(), Error> {
+	let from = keys.len() as u32 + 1;
+	for n_child in from..(up_to + 1) {
+		let key = ext_key.derive(secp, n_child)?.key;
+		keys.insert(key, n_child);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashMap;
+
+	use secp::{self, Secp256k1};
+	use secp::key::SecretKey;
+
+	use core::core::build;
+	use extkey::ExtendedKey;
+	use super::*;
+
+	// A single rewind with the wallet nonce recovers the value of our outputs
+	// and the derivation index of their key, and fails on anybody else's.
+	#[test]
+	fn rewind_finds_child() {
+		let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let ext_key = ExtendedKey::from_seed(&secp, &[1; 32]).unwrap();
+		let other_key = ExtendedKey::from_seed(&secp, &[2; 32]).unwrap();
+
+		let mut keys = HashMap::new();
+		derive_keys(&secp, &ext_key, &mut keys, RESTORE_KEY_GAP).unwrap();
+
+		let out_key = ext_key.derive(&secp, 42).unwrap().key;
+		let (tx, _) = build::transaction(vec![build::input_rand(12),
+		                                      build::output_with_nonce(10,
+		                                                               out_key,
+		                                                               ext_key.rewind_nonce()),
+		                                      build::with_fee(2)])
+			.unwrap();
+		let out = tx.outputs[0];
+
+		let info = secp.rewind_range_proof(out.commit, out.proof, ext_key.rewind_nonce());
+		assert!(info.success);
+		assert_eq!(info.value, 10);
+		let blinding = SecretKey::from_slice(&secp, &info.blinding).unwrap();
+		assert_eq!(keys.get(&blinding), Some(&42));
+
+		let info = secp.rewind_range_proof(out.commit, out.proof, other_key.rewind_nonce());
+		assert!(!info.success);
+	}
+}
@@ -12,26 +12,52 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::From;
+use std::collections::HashMap;
+use rand::thread_rng;
 use secp::{self};
-use secp::key::SecretKey;
+use secp::key::{PublicKey, SecretKey};
 
 use checker;
 use core::core::{Transaction, build};
+use core::core::transaction::kernel_sig_msg;
+use core::ser;
 use extkey::ExtendedKey;
 use types::*;
+use util;
 
 use api;
 
+/// Dummy wrapper for the hex-encoded serialized transaction.
+#[derive(Serialize, Deserialize)]
+struct TxWrapper {
+	tx_hex: String,
+}
+
 /// Issue a new transaction to the provided sender by spending some of our
-/// wallet
-/// UTXOs. The destination can be "stdout" (for command line) or a URL to the
-/// recipients wallet receiver (to be implemented).
-pub fn issue_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64, dest: String) -> Result<(), Error> {
+/// wallet UTXOs. The partial transaction only includes the public nonce of our
+/// part of the kernel signature. The destination can be "stdout" (for command
+/// line) or a URL to the recipient's wallet receiver, in which case the
+/// transaction is finalized with its response and broadcasted right away. The
+/// fee is computed from the weight of the transaction unless explicitly
+/// provided. The secret nonce matching the public one is random and kept in
+/// our transaction log until the transaction is finalized.
+pub fn issue_send_tx(config: &WalletConfig,
+                     ext_key: &ExtendedKey,
+                     amount: u64,
+                     fee: Option<u64>,
+                     dest: String)
+                     -> Result<(), Error> {
 	checker::refresh_outputs(&config, ext_key);
 
-	let (tx, blind_sum) = build_send_tx(config, ext_key, amount)?;
-	let json_tx = partial_tx_to_json(amount, blind_sum, tx);
+	let (mut tx, public_nonce) = build_send_tx(config, ext_key, amount, fee)?;
+
+	// the signature only covers our own blinding factors, never share it
+	tx.excess_sig = vec![];
+	let json_tx = partial_tx_to_json(PartialTxPhase::SenderInitiation,
+	                                 amount,
+	                                 public_nonce,
+	                                 None,
+	                                 tx);
 
 	if dest == "stdout" {
 		println!("{}", json_tx);
@@ -39,32 +65,170 @@ pub fn issue_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64,
 		let url = format!("{}/v1/receive/receive_json_tx", &dest);
 		debug!("Posting partial transaction to {}", url);
 		let request = WalletReceiveRequest::PartialTransaction(json_tx);
-		let _: CbData = api::client::post(url.as_str(), &request)
+		let res: WalletReceiveResponse = api::client::post(url.as_str(), &request)
 			.expect(&format!("Wallet receiver at {} unreachable, could not send transaction. Is it running?", url));
+		match res {
+			WalletReceiveResponse::PartialTransaction(resp_tx) => {
+				finalize_tx(config, ext_key, resp_tx.as_str())?;
+			}
+			_ => {
+				return Err(Error::Format("Unexpected response from wallet receiver.".to_string()));
+			}
+		}
 	}
 	Ok(())
 }
 
+/// Finalizes a transaction we initiated, once the receiver added its output
+/// and partial signature, and broadcasts it. Our blinding factors are derived
+/// again from the inputs and change of the transaction, which have to be
+/// outputs of our wallet. The transaction has to be pending in our log, with
+/// its secret nonce unused: the nonce is consumed before signing, so we never
+/// sign twice with it, even if the broadcast fails.
+pub fn finalize_tx(config: &WalletConfig, ext_key: &ExtendedKey, partial_tx_str: &str) -> Result<(), Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+	let (phase, _, receiver_nonce, receiver_sig, mut tx) = partial_tx_from_json(partial_tx_str)?;
+	if phase != PartialTxPhase::ReceiverConfirmation {
+		return Err(Error::Format(format!("Unexpected partial transaction phase {:?}.", phase)));
+	}
+	let receiver_sig = receiver_sig.ok_or(Error::Format("Missing receiver partial signature.".to_string()))?;
+
+	let (blind_sum, sec_nonce, tx_id) = WalletData::with_wallet(&config.data_file_dir, |wallet_data|
+		-> Result<(SecretKey, SecretKey, u32), Error> {
+		let (blind_sum, spent) = sender_blind_sum(wallet_data, ext_key, &tx)?;
+		let (sec_nonce, tx_id) = take_sec_nonce(wallet_data, spent)?;
+		Ok((blind_sum, sec_nonce, tx_id))
+	})??;
+
+	// complete the signature with our part, if the receiver lied on anything
+	// the signature won't verify against the transaction excess
+	let msg = kernel_sig_msg(tx.fee, tx.lock_height)?;
+	let part_sig = secp.partial_sign_schnorr(&msg, &blind_sum, &sec_nonce, &receiver_nonce)?;
+	let sig = secp.combine_schnorr_partials(&[part_sig, receiver_sig])?;
+	tx.excess_sig = sig.serialize();
+	let kernel = tx.validate(&secp)?;
+
+	let tx_hex = util::to_hex(ser::ser_vec(&tx).unwrap());
+	let url = format!("{}/v1/pool/push", config.check_node_api_http_addr.as_str());
+	let _: TxWrapper = api::client::post(url.as_str(), &TxWrapper { tx_hex: tx_hex })?;
+
+	// keep track of the broadcast in our log
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		if let Some(entry) = wallet_data.txs.iter_mut().find(|entry| entry.id == tx_id) {
+			entry.excess = Some(util::to_hex(kernel.excess.as_ref().to_vec()));
+			entry.state = TxState::Broadcasted;
+		}
+	})
+}
+
+/// Takes the secret nonce of the pending transaction we sent spending the
+/// outputs with the provided derivation indexes out of our log, along with
+/// the id of its entry. Fails if there's no such transaction or if its nonce
+/// has already been used.
+fn take_sec_nonce(wallet_data: &mut WalletData, spent: Vec<u32>) -> Result<(SecretKey, u32), Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+	let mut spent = spent;
+	spent.sort();
+	let entry = wallet_data.txs.iter_mut().find(|entry| {
+		let mut inputs = entry.inputs.clone();
+		inputs.sort();
+		entry.direction == TxDirection::Sent && entry.state == TxState::Pending && inputs == spent
+	});
+	let entry = entry.ok_or(Error::WalletData("No pending transaction spending these outputs.".to_string()))?;
+	let nonce_hex = entry.sec_nonce.take().ok_or(Error::WalletData(
+		format!("Transaction {} has already been finalized.", entry.id),
+	))?;
+	let sec_nonce = SecretKey::from_slice(&secp, &util::from_hex(nonce_hex)?)?;
+	Ok((sec_nonce, entry.id))
+}
+
+/// Sum of the blinding factors of the inputs and change of a transaction we
+/// built, rebuilt from the outputs tracked in our wallet. Also returns the
+/// derivation indexes of the inputs.
+fn sender_blind_sum(wallet_data: &WalletData,
+                    ext_key: &ExtendedKey,
+                    tx: &Transaction)
+                    -> Result<(SecretKey, Vec<u32>), Error> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+
+	let mut keys = HashMap::new();
+	for out in &wallet_data.outputs {
+		if out.fingerprint != ext_key.fingerprint {
+			continue;
+		}
+		let key = ext_key.derive(&secp, out.n_child)?;
+		keys.insert(secp.commit(out.value, key.key)?, (key.key, out.n_child));
+	}
+
+	let mut positive = vec![];
+	let mut negative = vec![];
+	let mut spent = vec![];
+	for input in &tx.inputs {
+		match keys.get(&input.commitment()) {
+			Some(&(key, n_child)) => {
+				negative.push(key);
+				spent.push(n_child);
+			}
+			None => {
+				return Err(Error::WalletData("Transaction input not found in wallet.".to_string()));
+			}
+		}
+	}
+	for output in &tx.outputs {
+		if let Some(&(key, _)) = keys.get(&output.commitment()) {
+			positive.push(key);
+		}
+	}
+	// we always build a change output, it shouldn't have been taken out
+	if positive.is_empty() {
+		return Err(Error::WalletData("Transaction change not found in wallet.".to_string()));
+	}
+	let blind_sum = secp.blind_sum(positive, negative)?;
+	Ok((blind_sum, spent))
+}
+
 /// Builds a transaction to send to someone from the HD seed associated with the
 /// wallet and the amount to send. Handles reading through the wallet data file,
-/// selecting outputs to spend and building the change.
-fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> Result<(Transaction, SecretKey), Error> {
+/// selecting outputs to spend, paying the fee and building the change.
+fn build_send_tx(config: &WalletConfig,
+                 ext_key: &ExtendedKey,
+                 amount: u64,
+                 fee: Option<u64>)
+                 -> Result<(Transaction, PublicKey), Error> {
 	// first, rebuild the private key from the seed
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let tip_height = checker::get_tip_height(config)?;
+	let min_conf = config.minimum_confirmations;
 
 	// operate within a lock on wallet data
 	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
 
-		// second, check from our local wallet data for outputs to spend
-		let (coins, change) = wallet_data.select(ext_key.fingerprint, amount);
+		// second, check from our local wallet data for outputs to spend, the
+		// fee depends on how many we need, which in turn depends on the fee
+		// (our change and the recipient's output are always 2 outputs)
+		let mut fee_amount = fee.unwrap_or(tx_fee(1, 2, None));
+		let (mut coins, mut change) =
+			wallet_data.select(ext_key.fingerprint, amount + fee_amount, tip_height, min_conf);
+		while fee.is_none() && change >= 0 {
+			let needed_fee = tx_fee(coins.len(), 2, None);
+			if needed_fee <= fee_amount {
+				break;
+			}
+			fee_amount = needed_fee;
+			let (new_coins, new_change) =
+				wallet_data.select(ext_key.fingerprint, amount + fee_amount, tip_height, min_conf);
+			coins = new_coins;
+			change = new_change;
+		}
 		if change < 0 {
 			return Err(Error::NotEnoughFunds((-change) as u64));
 		}
-
-		// TODO add fees, which is likely going to make this iterative
+		debug!("Sending {} with a fee of {}, using {} inputs.", amount, fee_amount, coins.len());
 
 		// third, build inputs using the appropriate key
-		let mut parts = vec![];
+		let mut parts = vec![build::with_fee(fee_amount)];
 		for coin in &coins {
 			let in_key = ext_key.derive(&secp, coin.n_child).map_err(|e| Error::Key(e))?;
 			parts.push(build::input(coin.value, in_key.key));
@@ -73,7 +237,7 @@ fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> R
 		// fourth, derive a new private for change and build the change output
 		let next_child = wallet_data.next_child(ext_key.fingerprint);
 		let change_key = ext_key.derive(&secp, next_child).map_err(|e| Error::Key(e))?;
-		parts.push(build::output(change as u64, change_key.key));
+		parts.push(build::output_with_nonce(change as u64, change_key.key, ext_key.rewind_nonce()));
 
 		// we got that far, time to start tracking the new output, finalize tx
 		// and lock the outputs used
@@ -82,11 +246,75 @@ fn build_send_tx(config: &WalletConfig, ext_key: &ExtendedKey, amount: u64) -> R
 			n_child: change_key.n_child,
 			value: change as u64,
 			status: OutputStatus::Unconfirmed,
+			height: 0,
+			is_coinbase: false,
 		});
-		for mut coin in coins {
-			coin.lock();
+		for coin in &coins {
+			wallet_data.lock_output(coin);
 		}
 
-		build::transaction(parts).map_err(&From::from)
+		// fifth, draw the secret nonce of our part of the kernel signature, it
+		// stays in our log until the transaction is finalized
+		let sec_nonce = SecretKey::new(&secp, &mut thread_rng());
+		let public_nonce = PublicKey::from_secret_key(&secp, &sec_nonce)?;
+		wallet_data.add_tx(TxLogEntry {
+			id: 0,
+			direction: TxDirection::Sent,
+			amount: amount,
+			fee: fee_amount,
+			inputs: coins.iter().map(|coin| coin.n_child).collect(),
+			outputs: vec![change_key.n_child],
+			excess: None,
+			state: TxState::Pending,
+			sec_nonce: Some(util::to_hex(sec_nonce[..].to_vec())),
+		});
+
+		let (tx, _) = build::transaction(parts)?;
+		Ok((tx, public_nonce))
 	})?
 }
+
+#[cfg(test)]
+mod test {
+	use rand::thread_rng;
+	use secp;
+	use secp::key::SecretKey;
+
+	use types::*;
+	use util;
+	use super::take_sec_nonce;
+
+	#[test]
+	fn sec_nonce_used_once() {
+		let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+		let sec_nonce = SecretKey::new(&secp, &mut thread_rng());
+		let mut wallet_data = WalletData {
+			outputs: vec![],
+			txs: vec![],
+		};
+		let id = wallet_data.add_tx(TxLogEntry {
+			id: 0,
+			direction: TxDirection::Sent,
+			amount: 10,
+			fee: 1,
+			inputs: vec![2, 1],
+			outputs: vec![3],
+			excess: None,
+			state: TxState::Pending,
+			sec_nonce: Some(util::to_hex(sec_nonce[..].to_vec())),
+		});
+
+		// only a pending transaction spending the same outputs matches
+		assert!(take_sec_nonce(&mut wallet_data, vec![1]).is_err());
+		assert_eq!(take_sec_nonce(&mut wallet_data, vec![1, 2]).unwrap(), (sec_nonce, id));
+
+		// the nonce is gone once taken
+		assert!(take_sec_nonce(&mut wallet_data, vec![1, 2]).is_err());
+		assert!(wallet_data.txs[0].sec_nonce.is_none());
+
+		// and is never used for a transaction that isn't pending anymore
+		wallet_data.txs[0].sec_nonce = Some(util::to_hex(sec_nonce[..].to_vec()));
+		wallet_data.txs[0].state = TxState::Cancelled;
+		assert!(take_sec_nonce(&mut wallet_data, vec![1, 2]).is_err());
+	}
+}
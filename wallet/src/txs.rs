@@ -0,0 +1,49 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operations on the wallet transaction log.
+
+use checker;
+use extkey::ExtendedKey;
+use types::*;
+
+/// Prints all the transactions recorded in the wallet log, after refreshing
+/// the status of our outputs.
+pub fn show_txs(config: &WalletConfig, ext_key: &ExtendedKey) -> Result<(), Error> {
+	checker::refresh_outputs(&config, ext_key);
+
+	WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		for tx in &wallet_data.txs {
+			println!("{} {:?} amount: {}, fee: {}, inputs: {}, outputs: {}, state: {:?}, excess: {}",
+			         tx.id,
+			         tx.direction,
+			         tx.amount,
+			         tx.fee,
+			         tx.inputs.len(),
+			         tx.outputs.len(),
+			         tx.state,
+			         tx.excess.clone().unwrap_or("-".to_string()));
+		}
+	})
+}
+
+/// Cancels a transaction from the log that hasn't been confirmed, unlocking
+/// the outputs it spent and forgetting the unconfirmed ones it created.
+pub fn cancel_tx(config: &WalletConfig, ext_key: &ExtendedKey, id: u32) -> Result<(), Error> {
+	let tx = WalletData::with_wallet(&config.data_file_dir, |wallet_data| {
+		wallet_data.cancel_tx(ext_key.fingerprint, id)
+	})??;
+	info!("Cancelled transaction {}, unlocked {} outputs.", tx.id, tx.inputs.len());
+	Ok(())
+}
@@ -22,10 +22,12 @@ use std::path::MAIN_SEPARATOR;
 use serde_json;
 
 use secp;
-use secp::key::SecretKey;
+use secp::key::PublicKey;
+use secp::schnorr;
 
 use api;
-use core::core::Transaction;
+use core::core::{transaction, Transaction};
+use core::global;
 use core::ser;
 use extkey;
 use util;
@@ -33,6 +35,17 @@ use util;
 const DAT_FILE: &'static str = "wallet.dat";
 const LOCK_FILE: &'static str = "wallet.lock";
 
+/// Default fee paid per unit of transaction weight
+pub const DEFAULT_BASE_FEE: u64 = 10;
+
+/// Transaction fee calculation, based on the weight of the transaction. Each
+/// output and kernel adds to the weight while inputs reduce it, as spending
+/// outputs shrinks the UTXO set and should be encouraged.
+pub fn tx_fee(input_len: usize, output_len: usize, base_fee: Option<u64>) -> u64 {
+	let use_base_fee = base_fee.unwrap_or(DEFAULT_BASE_FEE);
+	transaction::weight(input_len, output_len) * use_base_fee
+}
+
 /// Wallet errors, mostly wrappers around underlying crypto or I/O errors.
 #[derive(Debug)]
 pub enum Error {
@@ -87,6 +100,8 @@ pub struct WalletConfig {
 	pub check_node_api_http_addr: String,
 	//The directory in which wallet files are stored
 	pub data_file_dir: String,
+	//How many confirmations an output needs before it can be spent
+	pub minimum_confirmations: u64,
 }
 
 impl Default for WalletConfig {
@@ -96,17 +111,20 @@ impl Default for WalletConfig {
 			api_http_addr: "http://127.0.0.1:13415".to_string(),
 			check_node_api_http_addr: "http://127.0.0.1:13415".to_string(),
 			data_file_dir: ".".to_string(),
+			minimum_confirmations: 1,
 		}
 	}
 }
 
 /// Status of an output that's being tracked by the wallet. Can either be
-/// unconfirmed, spent, unspent, or locked (when it's been used to generate
-/// a transaction but we don't have confirmation that the transaction was
-/// broadcasted or mined).
+/// unconfirmed, pending (created by a transaction seen in the pool of the
+/// node but not mined yet), spent, unspent, or locked (when it's been used to
+/// generate a transaction but we don't have confirmation that the
+/// transaction was broadcasted or mined).
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum OutputStatus {
 	Unconfirmed,
+	Pending,
 	Unspent,
 	Locked,
 	Spent,
@@ -125,6 +143,14 @@ pub struct OutputData {
 	pub value: u64,
 	/// Current status of the output
 	pub status: OutputStatus,
+	/// Height of the chain when the output was found confirmed, 0 when
+	/// unconfirmed or unknown
+	#[serde(default)]
+	pub height: u64,
+	/// Whether the output is a block reward, which can only be spent once
+	/// mature
+	#[serde(default)]
+	pub is_coinbase: bool,
 }
 
 impl OutputData {
@@ -132,6 +158,70 @@ impl OutputData {
 	pub fn lock(&mut self) {
 		self.status = OutputStatus::Locked;
 	}
+
+	/// Number of confirmations of the output at the provided chain height.
+	/// Outputs confirmed at an unknown height are only counted as confirmed
+	/// once.
+	pub fn num_confirmations(&self, current_height: u64) -> u64 {
+		if self.status == OutputStatus::Unconfirmed || self.status == OutputStatus::Pending {
+			0
+		} else if self.height == 0 || self.height > current_height {
+			1
+		} else {
+			1 + current_height - self.height
+		}
+	}
+
+	/// Whether the output can be spent in the block following the provided
+	/// chain height. Coinbase outputs need to reach the coinbase maturity
+	/// first.
+	pub fn is_mature(&self, current_height: u64) -> bool {
+		!self.is_coinbase || self.height + global::coinbase_maturity() <= current_height + 1
+	}
+}
+
+/// Direction of a transaction, from the point of view of the wallet.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxDirection {
+	Sent,
+	Received,
+}
+
+/// State of a transaction the wallet took part in. A transaction is pending
+/// until it's been finalized and broadcasted, and confirmed once all the
+/// outputs it created for us are known to the chain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum TxState {
+	Pending,
+	Broadcasted,
+	Confirmed,
+	Cancelled,
+}
+
+/// Entry of the wallet transaction log, recording which outputs a transaction
+/// spent and created for us so it can be followed and rolled back if needed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxLogEntry {
+	/// Identifier of the transaction in the log
+	pub id: u32,
+	/// Whether we sent or received the transaction
+	pub direction: TxDirection,
+	/// Amount transferred
+	pub amount: u64,
+	/// Fee paid by the transaction
+	pub fee: u64,
+	/// Derivation indexes of our outputs spent by the transaction
+	pub inputs: Vec<u32>,
+	/// Derivation indexes of the outputs created for us by the transaction
+	pub outputs: Vec<u32>,
+	/// Kernel excess, hex encoded, once the transaction is finalized
+	pub excess: Option<String>,
+	/// Current state of the transaction
+	pub state: TxState,
+	/// Secret nonce of our part of the kernel signature for a transaction we
+	/// sent, hex encoded, until it's been used to sign
+	#[serde(default)]
+	pub sec_nonce: Option<String>,
 }
 
 /// Wallet information tracking all our outputs. Based on HD derivation and
@@ -140,11 +230,12 @@ impl OutputData {
 /// on disk, so selection algorithms are fairly primitive and non optimized.
 ///
 /// TODO optimization so everything isn't O(n) or even O(n^2)
-/// TODO account for fees
 /// TODO write locks so files don't get overwritten
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WalletData {
 	pub outputs: Vec<OutputData>,
+	#[serde(default)]
+	pub txs: Vec<TxLogEntry>,
 }
 
 impl WalletData {
@@ -192,7 +283,10 @@ impl WalletData {
 			WalletData::read(data_file_path)
 		} else {
 			// just create a new instance, it will get written afterward
-			Ok(WalletData { outputs: vec![] })
+			Ok(WalletData {
+				outputs: vec![],
+				txs: vec![],
+			})
 		}
 	}
 
@@ -220,25 +314,94 @@ impl WalletData {
 	}
 
 	/// Select a subset of unspent outputs to spend in a transaction
-	/// transferring
-	/// the provided amount.
-	pub fn select(&self, fingerprint: [u8; 4], amount: u64) -> (Vec<OutputData>, i64) {
+	/// transferring the provided amount. Only mature outputs with enough
+	/// confirmations at the current height can be spent. The smallest output
+	/// covering the whole amount is preferred, leaving as little change as
+	/// possible. If there is none, the largest outputs are used first to keep
+	/// the number of inputs low. Returns the selected outputs and the change
+	/// left (negative if the funds are insufficient).
+	pub fn select(&self,
+	              fingerprint: [u8; 4],
+	              amount: u64,
+	              current_height: u64,
+	              minimum_confirmations: u64)
+	              -> (Vec<OutputData>, i64) {
+		let mut eligible = self.outputs
+			.iter()
+			.filter(|out| {
+				out.status == OutputStatus::Unspent && out.fingerprint == fingerprint &&
+				out.num_confirmations(current_height) >= minimum_confirmations &&
+				out.is_mature(current_height)
+			})
+			.cloned()
+			.collect::<Vec<OutputData>>();
+		eligible.sort_by_key(|out| out.value);
+
+		if let Some(out) = eligible.iter().find(|out| out.value >= amount) {
+			return (vec![out.clone()], (out.value as i64) - (amount as i64));
+		}
+
 		let mut to_spend = vec![];
 		let mut input_total = 0;
-		// TODO very naive impl for now, there's definitely better coin selection
-		// algos available
-		for out in &self.outputs {
-			if out.status == OutputStatus::Unspent && out.fingerprint == fingerprint {
-				to_spend.push(out.clone());
-				input_total += out.value;
-				if input_total >= amount {
-					break;
-				}
+		for out in eligible.into_iter().rev() {
+			input_total += out.value;
+			to_spend.push(out);
+			if input_total >= amount {
+				break;
 			}
 		}
 		(to_spend, (input_total as i64) - (amount as i64))
 	}
 
+	/// Locks the provided output to avoid conflicting use.
+	pub fn lock_output(&mut self, out: &OutputData) {
+		if let Some(o) = self.outputs
+			.iter_mut()
+			.find(|o| o.fingerprint == out.fingerprint && o.n_child == out.n_child) {
+			o.lock();
+		}
+	}
+
+	/// Adds a new transaction to the log, assigning its id.
+	pub fn add_tx(&mut self, mut entry: TxLogEntry) -> u32 {
+		entry.id = self.txs.iter().map(|tx| tx.id + 1).max().unwrap_or(1);
+		let id = entry.id;
+		self.txs.push(entry);
+		id
+	}
+
+	/// Cancels a transaction from the log, unlocking the outputs it spent and
+	/// dropping the ones it created that aren't confirmed. A confirmed
+	/// transaction can't be cancelled anymore.
+	pub fn cancel_tx(&mut self, fingerprint: [u8; 4], id: u32) -> Result<TxLogEntry, Error> {
+		let idx = self.txs
+			.iter()
+			.position(|tx| tx.id == id)
+			.ok_or(Error::WalletData(format!("No transaction with id {}.", id)))?;
+		match self.txs[idx].state {
+			TxState::Confirmed | TxState::Cancelled => {
+				return Err(Error::WalletData(format!("Transaction {} is {:?}, can't cancel it.",
+				                                     id,
+				                                     self.txs[idx].state)));
+			}
+			_ => {}
+		}
+
+		let entry = self.txs[idx].clone();
+		for out in &mut self.outputs {
+			if out.fingerprint == fingerprint && out.status == OutputStatus::Locked &&
+			   entry.inputs.contains(&out.n_child) {
+				out.status = OutputStatus::Unspent;
+			}
+		}
+		self.outputs.retain(|out| {
+			out.fingerprint != fingerprint || out.status != OutputStatus::Unconfirmed ||
+			!entry.outputs.contains(&out.n_child)
+		});
+		self.txs[idx].state = TxState::Cancelled;
+		Ok(self.txs[idx].clone())
+	}
+
 	/// Next child index when we want to create a new output.
 	pub fn next_child(&self, fingerprint: [u8; 4]) -> u32 {
 		let mut max_n = 0;
@@ -251,41 +414,77 @@ impl WalletData {
 	}
 }
 
-/// Helper in serializing the information a receiver requires to build a
-/// transaction.
+/// Phase of the interactive exchange through which the sender and the
+/// receiver build a transaction together.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PartialTxPhase {
+	/// The sender provided its inputs, change and public nonce
+	SenderInitiation,
+	/// The receiver added its output, public nonce and partial signature
+	ReceiverConfirmation,
+}
+
+/// Transaction being built interactively by the sender and the receiver. Each
+/// party only shares its public nonce and partial signature of the kernel, so
+/// that neither learns the blinding factors of the other.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct JSONPartialTx {
+	phase: PartialTxPhase,
 	amount: u64,
-	blind_sum: String,
+	public_nonce: String,
+	part_sig: String,
 	tx: String,
 }
 
 /// Encodes the information for a partial transaction (not yet completed by the
-/// receiver) into JSON.
-pub fn partial_tx_to_json(receive_amount: u64, blind_sum: SecretKey, tx: Transaction) -> String {
+/// receiver or the sender) into JSON.
+pub fn partial_tx_to_json(phase: PartialTxPhase,
+                          receive_amount: u64,
+                          public_nonce: PublicKey,
+                          part_sig: Option<schnorr::Signature>,
+                          tx: Transaction)
+                          -> String {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
 	let partial_tx = JSONPartialTx {
+		phase: phase,
 		amount: receive_amount,
-		blind_sum: util::to_hex(blind_sum.as_ref().to_vec()),
+		public_nonce: util::to_hex(public_nonce.serialize_vec(&secp, true).to_vec()),
+		part_sig: part_sig.map(|sig| util::to_hex(sig.serialize())).unwrap_or(String::new()),
 		tx: util::to_hex(ser::ser_vec(&tx).unwrap()),
 	};
 	serde_json::to_string_pretty(&partial_tx).unwrap()
 }
 
-/// Reads a partial transaction encoded as JSON into the amount, sum of blinding
-/// factors and the transaction itself.
-pub fn partial_tx_from_json(json_str: &str) -> Result<(u64, SecretKey, Transaction), Error> {
+/// Reads a partial transaction encoded as JSON into the phase, amount, public
+/// nonce, partial signature (if any) and the transaction itself.
+pub fn partial_tx_from_json(json_str: &str)
+                            -> Result<(PartialTxPhase,
+                                       u64,
+                                       PublicKey,
+                                       Option<schnorr::Signature>,
+                                       Transaction),
+                                      Error> {
 	let partial_tx: JSONPartialTx = serde_json::from_str(json_str)?;
 
 	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-	let blind_bin = util::from_hex(partial_tx.blind_sum)?;
-	let blinding = SecretKey::from_slice(&secp, &blind_bin[..])?;
+	let nonce_bin = util::from_hex(partial_tx.public_nonce)?;
+	let public_nonce = PublicKey::from_slice(&secp, &nonce_bin[..])?;
+	let part_sig = if partial_tx.part_sig.is_empty() {
+		None
+	} else {
+		let sig_bin = util::from_hex(partial_tx.part_sig)?;
+		if sig_bin.len() != secp::constants::SCHNORR_SIGNATURE_SIZE {
+			return Err(Error::Format("Invalid partial signature.".to_string()));
+		}
+		Some(schnorr::Signature::deserialize(&sig_bin[..]))
+	};
 	let tx_bin = util::from_hex(partial_tx.tx)?;
 	let tx =
 		ser::deserialize(&mut &tx_bin[..]).map_err(|_| {
 				Error::Format("Could not deserialize transaction, invalid format.".to_string())
 			})?;
 
-	Ok((partial_tx.amount, blinding, tx))
+	Ok((partial_tx.phase, partial_tx.amount, public_nonce, part_sig, tx))
 }
 
 /// Amount in request to build a coinbase output.
@@ -308,3 +507,135 @@ pub struct CbData {
 	pub output: String,
 	pub kernel: String,
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn output(n_child: u32, value: u64, status: OutputStatus) -> OutputData {
+		OutputData {
+			fingerprint: [0; 4],
+			n_child: n_child,
+			value: value,
+			status: status,
+			height: 0,
+			is_coinbase: false,
+		}
+	}
+
+	#[test]
+	fn select_smallest_sufficient() {
+		let wallet_data = WalletData {
+			outputs: vec![output(1, 50, OutputStatus::Unspent),
+			              output(2, 8, OutputStatus::Unspent),
+			              output(3, 12, OutputStatus::Unspent),
+			              output(4, 10, OutputStatus::Locked)],
+			txs: vec![],
+		};
+
+		// a single output covers it, the smallest one is used
+		let (coins, change) = wallet_data.select([0; 4], 10, 1, 1);
+		assert_eq!(coins.iter().map(|c| c.n_child).collect::<Vec<_>>(), vec![3]);
+		assert_eq!(change, 2);
+
+		// otherwise the largest ones go first
+		let (coins, change) = wallet_data.select([0; 4], 60, 1, 1);
+		assert_eq!(coins.iter().map(|c| c.n_child).collect::<Vec<_>>(), vec![1, 3]);
+		assert_eq!(change, 2);
+
+		// locked outputs or outputs from other keys can't be spent
+		let (_, change) = wallet_data.select([0; 4], 80, 1, 1);
+		assert_eq!(change, -10);
+		let (coins, _) = wallet_data.select([1; 4], 1, 1, 1);
+		assert!(coins.is_empty());
+	}
+
+	#[test]
+	fn select_confirmed() {
+		let mut wallet_data = WalletData {
+			outputs: vec![output(1, 10, OutputStatus::Unspent),
+			              output(2, 10, OutputStatus::Unspent),
+			              output(3, 10, OutputStatus::Unconfirmed)],
+			txs: vec![],
+		};
+		wallet_data.outputs[0].height = 5;
+		wallet_data.outputs[1].height = 9;
+
+		// only the first one has 5 confirmations at height 9
+		assert_eq!(wallet_data.outputs[0].num_confirmations(9), 5);
+		assert_eq!(wallet_data.outputs[1].num_confirmations(9), 1);
+		assert_eq!(wallet_data.outputs[2].num_confirmations(9), 0);
+		let (coins, change) = wallet_data.select([0; 4], 15, 9, 5);
+		assert_eq!(coins.len(), 1);
+		assert_eq!(change, -5);
+
+		let (coins, change) = wallet_data.select([0; 4], 15, 9, 1);
+		assert_eq!(coins.len(), 2);
+		assert_eq!(change, 5);
+	}
+
+	#[test]
+	fn select_mature() {
+		let mut wallet_data = WalletData {
+			outputs: vec![output(1, 10, OutputStatus::Unspent)],
+			txs: vec![],
+		};
+		wallet_data.outputs[0].height = 5;
+		wallet_data.outputs[0].is_coinbase = true;
+
+		let maturity = global::coinbase_maturity();
+		let (coins, _) = wallet_data.select([0; 4], 10, 5 + maturity - 2, 1);
+		assert!(coins.is_empty());
+		let (coins, _) = wallet_data.select([0; 4], 10, 5 + maturity - 1, 1);
+		assert_eq!(coins.len(), 1);
+	}
+
+	#[test]
+	fn cancel_send() {
+		let mut wallet_data = WalletData {
+			outputs: vec![output(1, 50, OutputStatus::Unspent),
+			              output(2, 8, OutputStatus::Unspent),
+			              output(3, 39, OutputStatus::Unconfirmed)],
+			txs: vec![],
+		};
+		let coins = wallet_data.select([0; 4], 10, 1, 1).0;
+		for coin in &coins {
+			wallet_data.lock_output(coin);
+		}
+		let id = wallet_data.add_tx(TxLogEntry {
+			id: 0,
+			direction: TxDirection::Sent,
+			amount: 10,
+			fee: 1,
+			inputs: coins.iter().map(|c| c.n_child).collect(),
+			outputs: vec![3],
+			excess: None,
+			state: TxState::Pending,
+			sec_nonce: None,
+		});
+		assert_eq!(wallet_data.outputs[0].status, OutputStatus::Locked);
+
+		let entry = wallet_data.cancel_tx([0; 4], id).unwrap();
+		assert_eq!(entry.state, TxState::Cancelled);
+		assert_eq!(wallet_data.outputs.len(), 2);
+		assert_eq!(wallet_data.outputs[0].status, OutputStatus::Unspent);
+
+		// can only be cancelled once
+		assert!(wallet_data.cancel_tx([0; 4], id).is_err());
+	}
+
+	#[test]
+	fn fee_by_weight() {
+		assert_eq!(tx_fee(1, 2, None), 8 * DEFAULT_BASE_FEE);
+		assert_eq!(tx_fee(2, 1, Some(1)), 3);
+		// never less than the base fee
+		assert_eq!(tx_fee(10, 1, Some(1)), 1);
+	}
+}
+
+/// Response of the wallet receiver to a request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WalletReceiveResponse {
+	Coinbase(CbData),
+	PartialTransaction(String),
+}